@@ -13,15 +13,16 @@ use std::io::prelude::*;
 
 use std::borrow::Borrow;
 use std::boxed::Box;
+use std::collections::HashMap;
 use std::collections::hash_map::RandomState;
 use std::error::Error as StdError;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::fs::{self,File};
+use std::fs::{self,File,OpenOptions};
 use std::io;
 use std::hash::BuildHasher;
 use std::path::{Path,PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use filetime::{FileTime, set_file_times};
 use lru_cache::{LruCache,Meter};
@@ -60,10 +61,34 @@ fn get_all_files<P: AsRef<Path>>(path: P) -> Box<Iterator<Item=(PathBuf, u64)>>
     Box::new(files.into_iter().map(|(_mtime, path, size)| (path, size)))
 }
 
+/// How much effort to make for an inserted file to survive a crash or power
+/// loss. Files are always written under a temporary name and renamed into
+/// place, so a crash during an insert never leaves a truncated file under the
+/// file's key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Leave flushing to the OS. After a power loss a file may exist but be
+    /// empty or contain garbage, depending on the filesystem.
+    None,
+    /// Flush a file's contents to disk before renaming it into place. After a
+    /// power loss a file is either complete or, until the rename is flushed
+    /// too, missing.
+    Data,
+    /// Also flush the directory after renaming a file into place, so a file
+    /// that was inserted survives a power loss.
+    Full,
+}
+
 /// An LRU cache of files on disk.
 pub struct LruDiskCache<S: BuildHasher = RandomState> {
     lru: LruCache<OsString, u64, S, FileSize>,
     root: PathBuf,
+    max_entries: Option<usize>,
+    /// How long newly inserted files are protected from eviction.
+    eviction_grace: Duration,
+    /// When files still within `eviction_grace` were inserted.
+    recently_inserted: HashMap<OsString, Instant>,
+    durability: Durability,
 }
 
 /// Errors returned by this crate.
@@ -119,6 +144,42 @@ fn filetime_now() -> FileTime {
     FileTime::from_seconds_since_1970(d.as_secs(), d.subsec_nanos())
 }
 
+/// Return the temporary path a file is written to before being renamed to `path`.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().expect("Bad path?"));
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Return `true` if `path` is a temporary path, as returned by `temp_path`.
+fn is_temp_path(path: &Path) -> bool {
+    path.file_name().and_then(|n| n.to_str()).map_or(false, |n| n.starts_with('.') && n.ends_with(".tmp"))
+}
+
+/// How many seconds old a temporary file has to be to be taken as left behind.
+const STALE_TEMP_SECS: u64 = 60 * 60;
+
+/// Return `true` if the file at `path` was last modified over `STALE_TEMP_SECS` ago.
+fn is_stale(path: &Path) -> bool {
+    fs::metadata(path).and_then(|m| m.modified())
+        .map(|mtime| mtime.elapsed().map_or(false, |age| age > Duration::from_secs(STALE_TEMP_SECS)))
+        .unwrap_or(false)
+}
+
+/// Flush the directory `dir`, so the files that were renamed into it are durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened as files on Windows, where renames are
+/// journalled by NTFS anyway.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
 enum AddFile<'a> {
     AbsPath(PathBuf),
     RelPath(&'a OsStr),
@@ -135,19 +196,49 @@ impl LruDiskCache {
     /// expects to have sole maintence of the contents.
     pub fn new<T>(path: T, size: u64) -> Result<Self>
         where PathBuf: From<T>
+    {
+        LruDiskCache::with_max_entries(path, size, None)
+    }
+
+    /// Create an `LruDiskCache` like `new`, additionally limited to holding `max_entries` files
+    /// if it is not `None`.
+    ///
+    /// When either limit would be exceeded, the least-recently-used files are removed.
+    pub fn with_max_entries<T>(path: T, size: u64, max_entries: Option<usize>) -> Result<Self>
+        where PathBuf: From<T>
     {
         LruDiskCache {
             lru: LruCache::with_meter(size, FileSize),
             root: PathBuf::from(path),
+            max_entries: max_entries,
+            eviction_grace: Duration::from_secs(0),
+            recently_inserted: HashMap::new(),
+            durability: Durability::Data,
         }.init()
     }
 
     /// Return the current size of all the files in the cache.
     pub fn size(&self) -> u64 { self.lru.size() }
 
+    /// Return the number of files in the cache.
+    pub fn len(&self) -> usize { self.lru.len() }
+
     /// Return the maximum size of the cache.
     pub fn capacity(&self) -> u64 { self.lru.capacity() }
 
+    /// Return the maximum number of files in the cache, if limited.
+    pub fn max_entries(&self) -> Option<usize> { self.max_entries }
+
+    /// Protect files from eviction for `grace` after they're inserted, as long
+    /// as there are other files that can be evicted instead.
+    ///
+    /// This keeps a file that was just written from being evicted before it
+    /// has been read when the cache is under heavy write pressure.
+    pub fn set_eviction_grace(&mut self, grace: Duration) { self.eviction_grace = grace }
+
+    /// Set how durably inserted files are written. Defaults to `Durability::Data`.
+    pub fn set_durability(&mut self, durability: Durability) { self.durability = durability }
+
     /// Return the path in which the cache is stored.
     pub fn path(&self) -> &Path { self.root.as_path() }
 
@@ -158,7 +249,13 @@ impl LruDiskCache {
     fn init(mut self) -> Result<Self> {
         fs::create_dir_all(&self.root)?;
         for (file, size) in get_all_files(&self.root) {
-            if !self.can_store(size) {
+            if is_temp_path(&file) {
+                // Old ones were left behind by an insert that was interrupted,
+                // newer ones may be being written by another process.
+                if is_stale(&file) {
+                    fs::remove_file(&file).unwrap_or_else(|e| error!("Error removing temporary file `{}`: {}", file.display(), e));
+                }
+            } else if !self.can_store(size) {
                 fs::remove_file(file).unwrap_or_else(|e| error!("Error removing file `{}` which is too large for the cache ({} bytes)", e, size));
             } else {
                 self.add_file(AddFile::AbsPath(file), size)
@@ -182,9 +279,12 @@ impl LruDiskCache {
             AddFile::AbsPath(ref p) => p.strip_prefix(&self.root).expect("Bad path?").as_os_str(),
             AddFile::RelPath(p) => p,
         };
+        let new_entry = !self.lru.contains_key(rel_path);
         //TODO: ideally LRUCache::insert would give us back the entries it had to remove.
-        while self.lru.size() as u64 + size > self.lru.capacity() as u64 {
-            let (rel_path,  _) = self.lru.remove_lru().expect("Unexpectedly empty cache!");
+        while self.lru.size() as u64 + size > self.lru.capacity() as u64 || (new_entry && self.is_full()) {
+            let rel_path = self.eviction_victim();
+            self.lru.remove(&rel_path);
+            self.recently_inserted.remove(&rel_path);
             let remove_path = self.rel_to_abs_path(rel_path);
             //TODO: check that files are removable during `init`, so that this is only
             // due to outside interference.
@@ -194,6 +294,41 @@ impl LruDiskCache {
         Ok(())
     }
 
+    /// Pick the file to evict: the least-recently-used one that isn't within
+    /// its eviction grace period, or if they all are, the least-recently-used
+    /// one regardless, so the cache never exceeds its limits.
+    fn eviction_victim(&mut self) -> OsString {
+        self.unprotected_victim()
+            .or_else(|| self.lru.iter().next().map(|(rel_path, _)| rel_path.clone()))
+            .expect("Unexpectedly empty cache!")
+    }
+
+    /// The least-recently-used file that isn't within its eviction grace
+    /// period, if any.
+    fn unprotected_victim(&mut self) -> Option<OsString> {
+        self.prune_recently_inserted();
+        let recently_inserted = &self.recently_inserted;
+        self.lru.iter()
+            .map(|(rel_path, _)| rel_path)
+            .find(|rel_path| !recently_inserted.contains_key(*rel_path))
+            .cloned()
+    }
+
+    /// Forget the files whose eviction grace period is over.
+    fn prune_recently_inserted(&mut self) {
+        let now = Instant::now();
+        let grace = self.eviction_grace;
+        self.recently_inserted.retain(|_, inserted| now.duration_since(*inserted) < grace);
+    }
+
+    /// Returns `true` if adding another file would exceed the entry limit.
+    fn is_full(&self) -> bool {
+        match self.max_entries {
+            Some(max) => !self.lru.is_empty() && self.lru.len() >= max,
+            None => false,
+        }
+    }
+
     fn insert_by<K: AsRef<OsStr>, F: FnOnce(&Path) -> io::Result<()>>(&mut self, key: K, size: Option<u64>, by: F) -> Result<()> {
         if let Some(size) = size {
             if !self.can_store(size) {
@@ -202,12 +337,34 @@ impl LruDiskCache {
         }
         let rel_path = key.as_ref();
         let path = self.rel_to_abs_path(rel_path);
-        fs::create_dir_all(path.parent().expect("Bad path?"))?;
-        by(&path)?;
+        let dir = path.parent().expect("Bad path?").to_owned();
+        fs::create_dir_all(&dir)?;
+        let tmp = temp_path(&path);
+        let durability = self.durability;
+        by(&tmp)
+            .and_then(|()| {
+                match durability {
+                    Durability::None => Ok(()),
+                    // Windows can only flush handles open for writing.
+                    Durability::Data => OpenOptions::new().write(true).open(&tmp)?.sync_data(),
+                    Durability::Full => OpenOptions::new().write(true).open(&tmp)?.sync_all(),
+                }
+            })
+            .and_then(|()| fs::rename(&tmp, &path))
+            .and_then(|()| if durability == Durability::Full { sync_dir(&dir) } else { Ok(()) })
+            .or_else(|e| {
+                let _ = fs::remove_file(&tmp);
+                Err(e)
+            })?;
         let size = match size {
             Some(size) => size,
             None => fs::metadata(path)?.len(),
         };
+        // Pruned here too, as without evictions nothing else would.
+        self.prune_recently_inserted();
+        if self.eviction_grace > Duration::from_secs(0) {
+            self.recently_inserted.insert(rel_path.to_owned(), Instant::now());
+        }
         self.add_file(AddFile::RelPath(rel_path), size)
             .or_else(|e| {
                 error!("Failed to insert file `{}`: {}", rel_path.to_string_lossy(), e);
@@ -250,6 +407,103 @@ impl LruDiskCache {
         self.lru.contains_key(key.as_ref())
     }
 
+    /// Return the paths of the files in the cache, relative to its root, from
+    /// least to most recently used.
+    pub fn keys(&self) -> Vec<OsString> {
+        self.lru.iter().map(|(rel_path, _)| rel_path.clone()).collect()
+    }
+
+    /// Remove the file at `key`, if it's in the cache. Returns its size.
+    pub fn remove<K: AsRef<OsStr>>(&mut self, key: K) -> Option<u64> {
+        let rel_path = key.as_ref();
+        let file_size = self.lru.remove(rel_path)?;
+        self.recently_inserted.remove(rel_path);
+        let path = self.rel_to_abs_path(rel_path);
+        fs::remove_file(&path).unwrap_or_else(|e| error!("Error removing file from cache: `{:?}`: {}", path, e));
+        Some(file_size)
+    }
+
+    /// Remove the files that were last used before `cutoff`, according to their
+    /// last-modified time. Returns the number of files removed and their total size.
+    pub fn remove_older_than(&mut self, cutoff: SystemTime) -> (usize, u64) {
+        let (mut count, mut size) = (0, 0);
+        loop {
+            // The least-recently-used file is also the one with the oldest mtime.
+            let (rel_path, file_size) = match self.lru.iter().next() {
+                Some((rel_path, &file_size)) => (rel_path.clone(), file_size),
+                None => break,
+            };
+            let path = self.rel_to_abs_path(&rel_path);
+            match fs::metadata(&path).and_then(|m| m.modified()) {
+                Ok(mtime) if mtime >= cutoff => break,
+                _ => {}
+            }
+            self.lru.remove(&rel_path);
+            self.recently_inserted.remove(&rel_path);
+            fs::remove_file(&path).unwrap_or_else(|e| error!("Error removing file from cache: `{:?}`: {}", path, e));
+            count += 1;
+            size += file_size;
+        }
+        (count, size)
+    }
+
+    /// Change the maximum size of the cache to `size` bytes, removing
+    /// least-recently-used files until the cache fits. Returns the number of
+    /// files removed and their total size.
+    pub fn set_capacity(&mut self, size: u64) -> (usize, u64) {
+        let (mut count, mut removed) = (0, 0);
+        while self.lru.size() > size {
+            let rel_path = self.eviction_victim();
+            let file_size = self.lru.remove(&rel_path).unwrap_or(0);
+            self.recently_inserted.remove(&rel_path);
+            let path = self.rel_to_abs_path(&rel_path);
+            fs::remove_file(&path).unwrap_or_else(|e| error!("Error removing file from cache: `{:?}`: {}", path, e));
+            count += 1;
+            removed += file_size;
+        }
+        self.lru.set_capacity(size);
+        (count, removed)
+    }
+
+    /// Remove least-recently-used files outside their eviction grace period
+    /// until the cache holds at most `size` bytes, removing no more than
+    /// `max_files` files. Returns the number of files removed and their total size.
+    pub fn shrink_to(&mut self, size: u64, max_files: usize) -> (usize, u64) {
+        let (mut count, mut removed) = (0, 0);
+        while self.lru.size() > size && count < max_files {
+            let rel_path = match self.unprotected_victim() {
+                Some(rel_path) => rel_path,
+                None => break,
+            };
+            let file_size = self.lru.remove(&rel_path).unwrap_or(0);
+            self.recently_inserted.remove(&rel_path);
+            let path = self.rel_to_abs_path(&rel_path);
+            fs::remove_file(&path).unwrap_or_else(|e| error!("Error removing file from cache: `{:?}`: {}", path, e));
+            count += 1;
+            removed += file_size;
+        }
+        (count, removed)
+    }
+
+    /// Remove least-recently-used files, regardless of their eviction grace
+    /// period, until at least `bytes` bytes have been freed or the cache is
+    /// empty. Returns the number of files removed and their total size.
+    pub fn evict(&mut self, bytes: u64) -> (usize, u64) {
+        let (mut count, mut removed) = (0, 0);
+        while removed < bytes {
+            let (rel_path, file_size) = match self.lru.remove_lru() {
+                Some(entry) => entry,
+                None => break,
+            };
+            self.recently_inserted.remove(&rel_path);
+            let path = self.rel_to_abs_path(&rel_path);
+            fs::remove_file(&path).unwrap_or_else(|e| error!("Error removing file from cache: `{:?}`: {}", path, e));
+            count += 1;
+            removed += file_size;
+        }
+        (count, removed)
+    }
+
     /// Get an opened readable and seekable handle to the file at `key`, if one exists and can
     /// be opened. Updates the LRU state of the file if present.
     pub fn get<K: AsRef<OsStr>>(&mut self, key: K) -> Result<Box<ReadSeek>> {
@@ -267,12 +521,15 @@ impl LruDiskCache {
 
 #[cfg(test)]
 mod tests {
-    use super::{LruDiskCache, Error};
+    use super::{Durability, LruDiskCache, Error};
 
     use filetime::{FileTime, set_file_times};
+    use std::ffi::{OsStr, OsString};
     use std::fs::{self,File};
     use std::io::{self, Read, Write};
     use std::path::{Path,PathBuf};
+    use std::thread;
+    use std::time::{Duration, SystemTime};
     use tempdir::TempDir;
 
     struct TestFixture {
@@ -393,6 +650,43 @@ mod tests {
         assert!(!c.contains_key("file1"));
     }
 
+    #[test]
+    fn test_eviction_grace() {
+        let f = TestFixture::new();
+        f.create_file("old", 30);
+        let mut c = LruDiskCache::new(f.tmp(), 100).unwrap();
+        c.set_eviction_grace(Duration::from_secs(3600));
+        c.insert_bytes("new1", &vec![1; 40]).unwrap();
+        // Make the older file more recently used than the new one.
+        c.get("old").unwrap();
+        c.insert_bytes("new2", &vec![2; 40]).unwrap();
+        // The new file is within its grace period, so the older one is evicted instead.
+        assert!(c.contains_key("new1"));
+        assert!(!c.contains_key("old"));
+        assert_eq!(c.size(), 80);
+        // When every file is within its grace period the least-recently-used is evicted.
+        c.insert_bytes("new3", &vec![3; 40]).unwrap();
+        assert!(!c.contains_key("new1"));
+        assert!(c.contains_key("new2"));
+        assert_eq!(c.size(), 80);
+    }
+
+    #[test]
+    fn test_eviction_grace_forgotten() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 1000).unwrap();
+        c.set_durability(Durability::None);
+        c.set_eviction_grace(Duration::from_millis(500));
+        c.insert_bytes("file1", &[1; 10]).unwrap();
+        c.insert_bytes("file2", &[2; 10]).unwrap();
+        assert_eq!(c.recently_inserted.len(), 2);
+        thread::sleep(Duration::from_millis(600));
+        // Files past their grace period are forgotten without any evictions.
+        c.insert_bytes("file3", &[3; 10]).unwrap();
+        assert_eq!(c.recently_inserted.len(), 1);
+        assert!(c.recently_inserted.contains_key(OsStr::new("file3")));
+    }
+
     #[test]
     fn test_add_get_lru() {
         let f = TestFixture::new();
@@ -463,4 +757,167 @@ mod tests {
         assert!(!p2.exists());
         assert!(!p3.exists());
     }
+
+    #[test]
+    fn test_max_entries() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::with_max_entries(f.tmp(), 100, Some(2)).unwrap();
+        c.insert_bytes("a/b/c", &vec![0; 1]).unwrap();
+        c.insert_bytes("a/b/d", &vec![0; 1]).unwrap();
+        assert_eq!(c.len(), 2);
+        // Replacing an existing file shouldn't evict anything.
+        c.insert_bytes("a/b/d", &vec![0; 1]).unwrap();
+        assert_eq!(c.len(), 2);
+        assert!(c.contains_key("a/b/c"));
+        // Adding this third file should put the cache above the entry limit.
+        c.insert_bytes("x/y/z", &vec![0; 1]).unwrap();
+        assert_eq!(c.len(), 2);
+        assert_eq!(c.size(), 2);
+        // The least-recently-used file should have been removed.
+        assert!(!c.contains_key("a/b/c"));
+        assert!(c.contains_key("a/b/d"));
+        assert!(c.contains_key("x/y/z"));
+    }
+
+    #[test]
+    fn test_remove_older_than() {
+        let f = TestFixture::new();
+        // Create files explicitly in the past.
+        set_mtime_back(f.create_file("file1", 10), 100);
+        set_mtime_back(f.create_file("file2", 20), 50);
+        f.create_file("file3", 30);
+        let mut c = LruDiskCache::new(f.tmp(), 100).unwrap();
+        let cutoff = SystemTime::now() - Duration::from_secs(30);
+        assert_eq!(c.remove_older_than(cutoff), (2, 30));
+        assert_eq!(c.len(), 1);
+        assert_eq!(c.size(), 30);
+        assert!(!f.tmp().join("file1").exists());
+        assert!(!f.tmp().join("file2").exists());
+        assert!(c.contains_key("file3"));
+        // Nothing else is old enough.
+        assert_eq!(c.remove_older_than(cutoff), (0, 0));
+    }
+
+    #[test]
+    fn test_remove_older_than_forgets_grace() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 1000).unwrap();
+        c.set_durability(Durability::None);
+        c.set_eviction_grace(Duration::from_secs(3600));
+        c.insert_bytes("file1", &[1; 10]).unwrap();
+        c.insert_bytes("file2", &[2; 10]).unwrap();
+        set_mtime_back(f.tmp().join("file1"), 100);
+        let cutoff = SystemTime::now() - Duration::from_secs(30);
+        assert_eq!(c.remove_older_than(cutoff), (1, 10));
+        assert_eq!(c.recently_inserted.len(), 1);
+        assert!(c.recently_inserted.contains_key(OsStr::new("file2")));
+    }
+
+    #[test]
+    fn test_keys_remove() {
+        let f = TestFixture::new();
+        set_mtime_back(f.create_file("file1", 10), 10);
+        f.create_file("file2", 20);
+        let mut c = LruDiskCache::new(f.tmp(), 100).unwrap();
+        assert_eq!(c.keys(), vec![OsString::from("file1"), OsString::from("file2")]);
+        assert_eq!(c.remove("file1"), Some(10));
+        assert_eq!(c.remove("file1"), None);
+        assert_eq!(c.keys(), vec![OsString::from("file2")]);
+        assert_eq!(c.size(), 20);
+        assert!(!f.tmp().join("file1").exists());
+    }
+
+    #[test]
+    fn test_set_capacity() {
+        let f = TestFixture::new();
+        set_mtime_back(f.create_file("file1", 10), 10);
+        set_mtime_back(f.create_file("file2", 20), 5);
+        f.create_file("file3", 30);
+        let mut c = LruDiskCache::new(f.tmp(), 100).unwrap();
+        assert_eq!(c.set_capacity(200), (0, 0));
+        assert_eq!(c.capacity(), 200);
+        assert_eq!(c.set_capacity(40), (2, 30));
+        assert_eq!(c.capacity(), 40);
+        assert_eq!(c.size(), 30);
+        assert!(!f.tmp().join("file1").exists());
+        assert!(!f.tmp().join("file2").exists());
+        assert!(c.contains_key("file3"));
+        // New files have to fit in the new size.
+        assert!(c.insert_bytes("file4", &[0; 50]).is_err());
+    }
+
+    #[test]
+    fn test_evict() {
+        let f = TestFixture::new();
+        set_mtime_back(f.create_file("file1", 10), 10);
+        set_mtime_back(f.create_file("file2", 20), 5);
+        let mut c = LruDiskCache::new(f.tmp(), 100).unwrap();
+        c.set_eviction_grace(Duration::from_secs(60));
+        c.insert_bytes("file3", &[0; 30]).unwrap();
+        assert_eq!(c.evict(0), (0, 0));
+        assert_eq!(c.evict(15), (2, 30));
+        assert_eq!(c.size(), 30);
+        assert!(!f.tmp().join("file1").exists());
+        assert!(!f.tmp().join("file2").exists());
+        // Recently inserted files aren't protected.
+        assert_eq!(c.evict(1000), (1, 30));
+        assert_eq!(c.len(), 0);
+        assert!(!f.tmp().join("file3").exists());
+    }
+
+    #[test]
+    fn test_shrink_to() {
+        let f = TestFixture::new();
+        set_mtime_back(f.create_file("file1", 10), 15);
+        set_mtime_back(f.create_file("file2", 20), 10);
+        set_mtime_back(f.create_file("file3", 30), 5);
+        let mut c = LruDiskCache::new(f.tmp(), 1000).unwrap();
+        c.set_eviction_grace(Duration::from_secs(60));
+        c.insert_bytes("file4", &[0; 40]).unwrap();
+        assert_eq!(c.shrink_to(100, 10), (0, 0));
+        // At most `max_files` are removed at once.
+        assert_eq!(c.shrink_to(0, 1), (1, 10));
+        assert_eq!(c.shrink_to(45, 10), (2, 50));
+        assert!(!f.tmp().join("file3").exists());
+        // Recently inserted files are protected.
+        assert_eq!(c.shrink_to(0, 10), (0, 0));
+        assert_eq!(c.size(), 40);
+    }
+
+    #[test]
+    fn test_durability() {
+        let f = TestFixture::new();
+        // A temporary file left behind by an interrupted insert is removed,
+        // but not one that may still be being written.
+        set_mtime_back(f.create_file(".file1.tmp", 10), 2 * 60 * 60);
+        f.create_file(".file3.tmp", 10);
+        let mut c = LruDiskCache::new(f.tmp(), 100).unwrap();
+        assert_eq!(c.len(), 0);
+        assert!(!f.tmp().join(".file1.tmp").exists());
+        assert!(f.tmp().join(".file3.tmp").exists());
+        for &durability in &[Durability::None, Durability::Data, Durability::Full] {
+            c.set_durability(durability);
+            let key = format!("{:?}", durability);
+            c.insert_bytes(&key, &[1; 10]).unwrap();
+            assert!(c.contains_key(&key));
+            assert_eq!(fs::metadata(f.tmp().join(&key)).unwrap().len(), 10);
+            assert!(!f.tmp().join(format!(".{}.tmp", key)).exists());
+        }
+        // A failed insert leaves nothing behind.
+        assert!(c.insert_with("file2", |_| Err(io::Error::new(io::ErrorKind::Other, "oops"))).is_err());
+        assert!(!c.contains_key("file2"));
+        assert_eq!(fs::read_dir(f.tmp()).unwrap().count(), 4);
+    }
+
+    #[test]
+    fn test_existing_files_max_entries() {
+        let f = TestFixture::new();
+        // Create files explicitly in the past.
+        set_mtime_back(f.create_file("file1", 1), 10);
+        set_mtime_back(f.create_file("file2", 1), 5);
+        let c = LruDiskCache::with_max_entries(f.tmp(), 100, Some(1)).unwrap();
+        assert_eq!(c.len(), 1);
+        assert!(!c.contains_key("file1"));
+        assert!(c.contains_key("file2"));
+    }
 }
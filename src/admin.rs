@@ -0,0 +1,244 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An HTTP API for controlling a running server. Each endpoint is translated
+//! into the `Request` a client would send, so it can do no more than the
+//! commandline can.
+
+use config::parse_duration;
+use futures::{future, Future, Stream};
+use hyper::{self, Method, StatusCode};
+use hyper::header::{Authorization, Bearer, ContentType};
+use hyper::server::{Http, Request as HttpRequest, Response as HttpResponse};
+use protocol::{Request, Response};
+use serde_json::{self, Map, Value};
+use std::env;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Handle;
+use tokio_service::Service;
+
+use errors::*;
+
+/// Where to serve the admin API, and the token clients must present.
+pub struct AdminConfig {
+    pub addr: SocketAddr,
+    pub token: String,
+}
+
+/// Read the admin API configuration from `SCCACHE_ADMIN_ADDR` and
+/// `SCCACHE_ADMIN_TOKEN`, returning `None` if it isn't enabled.
+pub fn config_from_env() -> Result<Option<AdminConfig>> {
+    let addr = match env::var("SCCACHE_ADMIN_ADDR") {
+        Ok(addr) => parse_addr(&addr)?,
+        Err(_) => return Ok(None),
+    };
+    let token = match env::var("SCCACHE_ADMIN_TOKEN") {
+        Ok(ref token) if !token.is_empty() => token.to_owned(),
+        _ => bail!("SCCACHE_ADMIN_ADDR is set but SCCACHE_ADMIN_TOKEN is not"),
+    };
+    if !addr.ip().is_loopback() && env::var("SCCACHE_ADMIN_ALLOW_REMOTE").ok().map_or(true, |v| v != "1") {
+        bail!("Refusing to serve the admin API on non-loopback address {} \
+               unless SCCACHE_ADMIN_ALLOW_REMOTE=1", addr);
+    }
+    Ok(Some(AdminConfig { addr, token }))
+}
+
+/// Parse `addr` as a socket address, or as a port on the loopback address.
+fn parse_addr(addr: &str) -> Result<SocketAddr> {
+    if let Ok(port) = addr.parse::<u16>() {
+        return Ok(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), port))
+    }
+    addr.parse().chain_err(|| format!("Invalid SCCACHE_ADMIN_ADDR `{}`", addr))
+}
+
+/// Map an HTTP method, path and query string to the `Request` it stands for.
+fn route(method: &Method, path: &str, query: Option<&str>) -> ::std::result::Result<Request, (StatusCode, String)> {
+    let param = |name: &str| {
+        query.and_then(|q| {
+            q.split('&')
+                .filter_map(|kv| {
+                    let mut kv = kv.splitn(2, '=');
+                    match (kv.next(), kv.next()) {
+                        (Some(k), Some(v)) if k == name => Some(v.to_owned()),
+                        _ => None,
+                    }
+                })
+                .next()
+        })
+    };
+    match (method, path) {
+        (&Method::Get, "/stats") => Ok(Request::GetStats),
+        (&Method::Post, "/zero-stats") => Ok(Request::ZeroStats),
+        (&Method::Post, "/purge-stale-compilers") => Ok(Request::PurgeStaleCompilers),
+        (&Method::Post, "/prune") => {
+            match param("older-than") {
+                Some(age) => match parse_duration(&age) {
+                    Some(age) => Ok(Request::PruneOlderThan(age)),
+                    None => Err((StatusCode::BadRequest, format!("Invalid age `{}`", age))),
+                },
+                None => Err((StatusCode::BadRequest, "Missing `older-than` parameter".to_owned())),
+            }
+        }
+        (&Method::Post, "/read-only") => {
+            match param("enabled").as_ref().map(|s| &**s) {
+                Some("true") => Ok(Request::SetReadOnly(true)),
+                Some("false") => Ok(Request::SetReadOnly(false)),
+                _ => Err((StatusCode::BadRequest, "Expected `enabled=true` or `enabled=false`".to_owned())),
+            }
+        }
+        (_, "/stats") | (_, "/zero-stats") | (_, "/purge-stale-compilers") | (_, "/prune") | (_, "/read-only") => {
+            Err((StatusCode::MethodNotAllowed, format!("{} is not allowed on {}", method, path)))
+        }
+        _ => Err((StatusCode::NotFound, format!("No such endpoint {}", path))),
+    }
+}
+
+/// Turn the server's `Response` into the JSON body of the HTTP response.
+fn to_json(res: Response) -> ::std::result::Result<Value, (StatusCode, String)> {
+    let internal = |e: serde_json::Error| (StatusCode::InternalServerError, e.to_string());
+    match res {
+        Response::Stats(info) => serde_json::to_value(info).map_err(internal),
+        Response::Pruned(Ok(stats)) => serde_json::to_value(stats).map_err(internal),
+        Response::Pruned(Err(e)) => Err((StatusCode::InternalServerError, e)),
+        Response::ReadOnly(read_only) => {
+            let mut map = Map::new();
+            map.insert("read_only".to_owned(), Value::Bool(read_only));
+            Ok(Value::Object(map))
+        }
+        _ => Err((StatusCode::InternalServerError, "Unexpected response from server".to_owned())),
+    }
+}
+
+fn json_response(status: StatusCode, body: &Value) -> HttpResponse {
+    HttpResponse::new()
+        .with_status(status)
+        .with_header(ContentType::json())
+        .with_body(body.to_string())
+}
+
+fn error_response(status: StatusCode, message: String) -> HttpResponse {
+    let mut map = Map::new();
+    map.insert("error".to_owned(), Value::String(message));
+    json_response(status, &Value::Object(map))
+}
+
+struct AdminService<F> {
+    token: Rc<String>,
+    dispatch: Rc<F>,
+}
+
+impl<F> Service for AdminService<F>
+    where F: Fn(Request) -> SFuture<Response> + 'static,
+{
+    type Request = HttpRequest;
+    type Response = HttpResponse;
+    type Error = hyper::Error;
+    type Future = Box<Future<Item = HttpResponse, Error = hyper::Error>>;
+
+    fn call(&self, req: HttpRequest) -> Self::Future {
+        let authorized = req.headers().get::<Authorization<Bearer>>()
+            .map_or(false, |auth| constant_time_eq(auth.token.as_bytes(), self.token.as_bytes()));
+        if !authorized {
+            return Box::new(future::ok(error_response(StatusCode::Unauthorized,
+                                                      "Missing or invalid bearer token".to_owned())))
+        }
+        let request = match route(req.method(), req.path(), req.query()) {
+            Ok(request) => request,
+            Err((status, message)) => return Box::new(future::ok(error_response(status, message))),
+        };
+        debug!("admin: {} {} -> {:?}", req.method(), req.path(), request);
+        Box::new((self.dispatch)(request).then(|res| {
+            Ok(match res.map_err(|e| (StatusCode::InternalServerError, e.to_string())).and_then(to_json) {
+                Ok(body) => json_response(StatusCode::Ok, &body),
+                Err((status, message)) => error_response(status, message),
+            })
+        }))
+    }
+}
+
+/// Serve the admin API described by `config` on `handle`, passing each
+/// request on to `dispatch`.
+pub fn start<F>(config: AdminConfig, handle: &Handle, dispatch: F) -> Result<()>
+    where F: Fn(Request) -> SFuture<Response> + 'static,
+{
+    let listener = TcpListener::bind(&config.addr, handle)
+        .chain_err(|| format!("Failed to bind the admin API to {}", config.addr))?;
+    info!("Serving the admin API on {}", config.addr);
+    let token = Rc::new(config.token);
+    let dispatch = Rc::new(dispatch);
+    let http = Http::new();
+    let handle2 = handle.clone();
+    let server = listener.incoming().for_each(move |(sock, addr)| {
+        http.bind_connection(&handle2, sock, addr, AdminService {
+            token: token.clone(),
+            dispatch: dispatch.clone(),
+        });
+        Ok(())
+    });
+    handle.spawn(server.map_err(|e| error!("Admin API stopped accepting connections: {}", e)));
+    Ok(())
+}
+
+/// Compare `a` and `b` in time that depends only on their lengths, so the
+/// token can't be guessed a byte at a time from how fast it's rejected.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn test_parse_addr() {
+        assert_eq!(parse_addr("4227").unwrap(), "127.0.0.1:4227".parse().unwrap());
+        assert_eq!(parse_addr("[::1]:4227").unwrap(), "[::1]:4227".parse().unwrap());
+        assert!(parse_addr("localhost").is_err());
+    }
+
+    #[test]
+    fn test_route() {
+        match route(&Method::Get, "/stats", None) {
+            Ok(Request::GetStats) => {}
+            r => panic!("Unexpected route: {:?}", r),
+        }
+        match route(&Method::Post, "/prune", Some("older-than=30d")) {
+            Ok(Request::PruneOlderThan(age)) => assert_eq!(age, Duration::from_secs(30 * 24 * 60 * 60)),
+            r => panic!("Unexpected route: {:?}", r),
+        }
+        match route(&Method::Post, "/read-only", Some("x=1&enabled=false")) {
+            Ok(Request::SetReadOnly(false)) => {}
+            r => panic!("Unexpected route: {:?}", r),
+        }
+        assert_eq!(route(&Method::Post, "/prune", Some("older-than=soon")).unwrap_err().0,
+                   StatusCode::BadRequest);
+        assert_eq!(route(&Method::Post, "/read-only", None).unwrap_err().0, StatusCode::BadRequest);
+        assert_eq!(route(&Method::Get, "/zero-stats", None).unwrap_err().0, StatusCode::MethodNotAllowed);
+        assert_eq!(route(&Method::Post, "/shutdown", None).unwrap_err().0, StatusCode::NotFound);
+    }
+}
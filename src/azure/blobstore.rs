@@ -20,14 +20,15 @@ use crypto::hmac::Hmac;
 use crypto::mac::Mac;
 use crypto::md5::Md5;
 use crypto::sha2::Sha256;
-use futures::{Future, Stream};
-use hyper::{self, header, Method, Uri};
+use futures::Future;
+use hyper::{header, Method, Uri};
 use hyper::client::{Client, Request, HttpConnector};
 use hyper_tls::HttpsConnector;
 use std::fmt;
 use std::str::FromStr;
 use time;
 use tokio_core::reactor::Handle;
+use util::get_resumable;
 
 use errors::*;
 
@@ -83,54 +84,38 @@ impl BlobContainer {
     pub fn get(&self, key: &str, creds: &AzureCredentials) -> SFuture<Vec<u8>> {
         let url_string = format!("{}{}", self.url, key);
         let uri = Uri::from_str(&url_string).unwrap();
-        let date = time::now_utc().rfc822().to_string();
-
-        let canonical_headers = format!("x-ms-date:{}\nx-ms-version:{}\n", date, BLOB_API_VERSION);
-
-        let auth = compute_auth_header(
-            "GET",
-            "",    // content_length
-            "",    // content_md5
-            "",    // content_type
-            &canonical_headers,
-            &uri,
-            creds);
-
-        let uri_copy = uri.clone();
-        let uri_second_copy = uri.clone();
+        let creds = creds.clone();
 
-        let mut request = Request::new(Method::Get, uri);
-        request.headers_mut().set_raw("x-ms-date", date);
-        request.headers_mut().set_raw("x-ms-version", BLOB_API_VERSION);
-        request.headers_mut().set_raw("Authorization", auth);
+        get_resumable(self.client.clone(), move |offset| {
+            let date = time::now_utc().rfc822().to_string();
 
-        Box::new(self.client.request(request).chain_err(move || {
-            format!("failed GET: {}", uri_copy)
-        }).and_then(|res| {
-            if res.status().is_success() {
-                let content_length = res.headers().get::<header::ContentLength>()
-                    .map(|&header::ContentLength(len)| len);
-                Ok((res.body(), content_length))
+            // The standard Range header is part of the string to sign, so
+            // use x-ms-range instead, which is just another canonical header.
+            let range = if offset > 0 {
+                format!("x-ms-range:bytes={}-\n", offset)
             } else {
-                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+                "".to_owned()
+            };
+            let canonical_headers = format!("x-ms-date:{}\n{}x-ms-version:{}\n", date, range, BLOB_API_VERSION);
+
+            let auth = compute_auth_header(
+                "GET",
+                "",    // content_length
+                "",    // content_md5
+                "",    // content_type
+                &canonical_headers,
+                &uri,
+                &creds);
+
+            let mut request = Request::new(Method::Get, uri.clone());
+            request.headers_mut().set_raw("x-ms-date", date);
+            if offset > 0 {
+                request.headers_mut().set_raw("x-ms-range", format!("bytes={}-", offset));
             }
-        }).and_then(|(body, content_length)| {
-            body.fold(Vec::new(), |mut body, chunk| {
-                body.extend_from_slice(&chunk);
-                Ok::<_, hyper::Error>(body)
-            }).chain_err(|| {
-                "failed to read HTTP body"
-            }).and_then(move |bytes| {
-                if let Some(len) = content_length {
-                    if len != bytes.len() as u64 {
-                        bail!(format!("Bad HTTP body size read: {}, expected {}", bytes.len(), len));
-                    } else {
-                        info!("Read {} bytes from {}", bytes.len(), uri_second_copy);
-                    }
-                }
-                Ok(bytes)
-            })
-        }))
+            request.headers_mut().set_raw("x-ms-version", BLOB_API_VERSION);
+            request.headers_mut().set_raw("Authorization", auth);
+            request
+        })
     }
 
     pub fn put(&self, key: &str, content: Vec<u8>, creds: &AzureCredentials) -> SFuture<()> {
@@ -16,18 +16,20 @@ extern crate tar;
 use clap::{App, Arg, SubCommand};
 use sccache::dist::{
     self,
-    CompileCommand, InputsReader, JobId, JobAlloc, JobStatus, JobComplete, ServerId, Toolchain, ToolchainReader,
-    AllocJobResult, AssignJobResult, HeartbeatServerResult, RunJobResult, StatusResult, SubmitToolchainResult,
+    CompileCommand, InputsReader, JobId, JobAlloc, JobStats, JobStatus, JobComplete, ServerId, Toolchain, ToolchainReader, TraceId,
+    AllocJobResult, AssignJobResult, HeartbeatServerResult, RunJobResult, StatsResult, StatusResult, SubmitToolchainResult,
+    UpdateJobStatusResult,
     BuilderIncoming, SchedulerIncoming, SchedulerOutgoing, ServerIncoming, ServerOutgoing,
     TcCache,
 };
 use std::collections::{HashMap, VecDeque};
 use std::env;
-use std::io::{self, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
 use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use errors::*;
 
@@ -59,8 +61,20 @@ enum Command {
         builder: BuilderType,
         cache_dir: PathBuf,
         toolchain_cache_size: u64,
-        scheduler_addr: IpAddr,
+        scheduler_addrs: Vec<IpAddr>,
+        toolchain_verification: ToolchainVerification,
     },
+    GenerateToolchainKey {
+        private_key: PathBuf,
+        public_key: PathBuf,
+    },
+}
+
+enum ToolchainVerification {
+    /// Accept any toolchain, signed or not.
+    Disabled,
+    /// Only accept toolchains signed with the key matching this public key.
+    Required(Vec<u8>),
 }
 
 enum BuilderType {
@@ -124,7 +138,16 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
             .arg(Arg::from_usage("--overlay-bwrap-path [PATH] 'Path to the bubblewrap binary'"))
             .arg(Arg::from_usage("--cache-dir <DIR> 'Directory to use as a cache for toolchains etc'"))
             .arg(Arg::from_usage("--toolchain-cache-size <SIZE> 'Amount of space to reserve for the toolchain cache'"))
-            .arg(Arg::from_usage("--scheduler-addr <IP> 'IP address of the scheduler'"))
+            .arg(Arg::from_usage("--scheduler-addr <IP>... 'IP address of a scheduler, repeat to register with a primary and standby schedulers'")
+                .number_of_values(1))
+            .arg(Arg::from_usage("--toolchain-public-key [PATH] 'Public key used to verify toolchain signatures'")
+                .required_unless("no-toolchain-verification"))
+            .arg(Arg::from_usage("--no-toolchain-verification 'Accept toolchains without verifying their signature'")
+                .conflicts_with("toolchain-public-key"))
+        )
+        .subcommand(SubCommand::with_name("generate-toolchain-key")
+            .arg(Arg::from_usage("--private-key <PATH> 'Where to write the PKCS#8 signing key for clients'"))
+            .arg(Arg::from_usage("--public-key <PATH> 'Where to write the public key for servers'"))
         )
 }
 
@@ -145,8 +168,23 @@ fn parse() -> Result<Command> {
             };
             let cache_dir = matches.value_of("cache-dir").unwrap().into();
             let toolchain_cache_size = value_t_or_exit!(matches.value_of("toolchain-cache-size"), u64);
-            let scheduler_addr = value_t_or_exit!(matches.value_of("scheduler-addr"), IpAddr);
-            Command::Server { builder, cache_dir, toolchain_cache_size, scheduler_addr }
+            let scheduler_addrs = values_t_or_exit!(matches.values_of("scheduler-addr"), IpAddr);
+            let toolchain_verification = match matches.value_of("toolchain-public-key") {
+                Some(path) => {
+                    let mut public_key = vec![];
+                    File::open(path)
+                        .and_then(|mut f| f.read_to_end(&mut public_key))
+                        .chain_err(|| format!("failed to read toolchain public key {}", path))?;
+                    ToolchainVerification::Required(public_key)
+                },
+                None => ToolchainVerification::Disabled,
+            };
+            Command::Server { builder, cache_dir, toolchain_cache_size, scheduler_addrs, toolchain_verification }
+        },
+        ("generate-toolchain-key", Some(matches)) => {
+            let private_key = matches.value_of("private-key").unwrap().into();
+            let public_key = matches.value_of("public-key").unwrap().into();
+            Command::GenerateToolchainKey { private_key, public_key }
         },
         _ => bail!("no subcommand specified"),
     })
@@ -159,19 +197,43 @@ fn run(command: Command) -> Result<i32> {
             let http_scheduler = dist::http::Scheduler::new(scheduler);
             let _: Void = http_scheduler.start();
         },
-        Command::Server { builder, cache_dir, toolchain_cache_size, scheduler_addr } => {
+        Command::Server { builder, cache_dir, toolchain_cache_size, scheduler_addrs, toolchain_verification } => {
             let builder: Box<dist::BuilderIncoming<Error=Error>> = match builder {
                 BuilderType::Docker => Box::new(build::DockerBuilder::new()),
                 BuilderType::Overlay { ref bwrap_path, ref build_dir } =>
                     Box::new(build::OverlayBuilder::new(bwrap_path, build_dir)?)
             };
-            let server = Server::new(builder, &cache_dir, toolchain_cache_size);
-            let http_server = dist::http::Server::new(scheduler_addr, server);
+            if let ToolchainVerification::Disabled = toolchain_verification {
+                warn!("Toolchain signature verification is disabled, any submitted toolchain will be accepted");
+            }
+            let server = Server::new(builder, &cache_dir, toolchain_cache_size, toolchain_verification);
+            let http_server = dist::http::Server::new(&scheduler_addrs, server);
             let _: Void = http_server.start();
         },
+        Command::GenerateToolchainKey { private_key, public_key } => {
+            let (pkcs8, public) = dist::generate_toolchain_signing_key()?;
+            create_private_file(&private_key)?.write_all(&pkcs8)?;
+            File::create(&public_key)?.write_all(&public)?;
+            println!("Wrote toolchain signing key to {:?} and public key to {:?}", private_key, public_key);
+            Ok(0)
+        },
     }
 }
 
+/// Create `path` for writing a secret to, readable only by its owner. Fails
+/// if it already exists, since an existing file may be readable by others
+/// already, or be a key that's in use.
+fn create_private_file(path: &Path) -> io::Result<File> {
+    let mut opts = OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    opts.open(path)
+}
+
 fn init_logging() {
     if env::var("RUST_LOG").is_ok() {
         match env_logger::init() {
@@ -182,6 +244,9 @@ fn init_logging() {
 }
 
 const MAX_PER_CORE_LOAD: f64 = 10f64;
+/// How long to wait for a job to complete before forgetting which server and
+/// client it belongs to.
+const JOB_STATS_TIMEOUT_SECS: u64 = 60 * 60;
 
 //enum JobState {
 //    AllocRequested(AllocJobRequest),
@@ -202,6 +267,18 @@ pub struct Scheduler {
     finished_jobs: VecDeque<JobStatus>,
 
     servers: Mutex<HashMap<ServerId, ServerDetails>>,
+
+    stats: Mutex<SchedulerStats>,
+}
+
+/// Statistics about the jobs the scheduler has assigned.
+#[derive(Default)]
+struct SchedulerStats {
+    alloc_failures: u64,
+    servers: HashMap<ServerId, JobStats>,
+    clients: HashMap<IpAddr, JobStats>,
+    /// The client of each job that hasn't completed yet, and when it was assigned.
+    jobs: HashMap<JobId, (IpAddr, Instant)>,
 }
 
 struct ServerDetails {
@@ -212,24 +289,33 @@ struct ServerDetails {
 
 impl Scheduler {
     pub fn new() -> Self {
+        // Build servers can be registered with several schedulers, so start
+        // numbering jobs from the startup time to keep job ids from different
+        // schedulers apart.
+        let start_secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
         Scheduler {
-            job_count: Mutex::new(0),
+            job_count: Mutex::new(start_secs << 32),
             //jobs: HashMap::new(),
             finished_jobs: VecDeque::new(),
             servers: Mutex::new(HashMap::new()),
+            stats: Mutex::new(SchedulerStats::default()),
         }
     }
 }
 
 impl SchedulerIncoming for Scheduler {
     type Error = Error;
-    fn handle_alloc_job(&self, requester: &SchedulerOutgoing, tc: Toolchain) -> Result<AllocJobResult> {
+    fn handle_alloc_job(&self, requester: &SchedulerOutgoing, trace_id: &TraceId, client: IpAddr, tc: Toolchain, exclude_servers: &[ServerId]) -> Result<AllocJobResult> {
         // TODO: prune old servers
         let server_id = {
             let servers = self.servers.lock().unwrap();
             let mut best = None;
             let mut best_load: f64 = MAX_PER_CORE_LOAD;
             for (id, details) in servers.iter() {
+                // The client is retrying a job that failed on these
+                if exclude_servers.contains(id) {
+                    continue
+                }
                 let load = details.jobs_assigned as f64 / details.num_cpus as f64;
                 if load < best_load {
                     best = Some(id);
@@ -243,6 +329,8 @@ impl SchedulerIncoming for Scheduler {
                 *id
             } else {
                 let msg = format!("Insufficient capacity: {} available servers", servers.len());
+                warn!("[{}]: Not allocating a job: {}", trace_id, msg);
+                self.stats.lock().unwrap().alloc_failures += 1;
                 return Ok(AllocJobResult::Fail { msg })
             }
         };
@@ -252,7 +340,15 @@ impl SchedulerIncoming for Scheduler {
             *job_count += 1;
             job_id
         };
-        let AssignJobResult { need_toolchain } = requester.do_assign_job(server_id, job_id, tc).chain_err(|| "assign job failed")?;
+        info!("[{}]: Assigning job {} to server {:?}", trace_id, job_id, server_id);
+        let AssignJobResult { need_toolchain } = requester.do_assign_job(trace_id, server_id, job_id, tc).chain_err(|| "assign job failed")?;
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.jobs.retain(|_, &mut (_, assigned)| assigned.elapsed() < Duration::from_secs(JOB_STATS_TIMEOUT_SECS));
+            stats.jobs.insert(job_id, (client, Instant::now()));
+            stats.servers.entry(server_id).or_insert_with(JobStats::default).jobs_dispatched += 1;
+            stats.clients.entry(client).or_insert_with(JobStats::default).jobs_dispatched += 1;
+        }
         let job_alloc = JobAlloc { job_id, server_id };
         Ok(AllocJobResult::Success { job_alloc, need_toolchain })
     }
@@ -263,6 +359,34 @@ impl SchedulerIncoming for Scheduler {
         })
     }
 
+    fn handle_stats(&self) -> Result<StatsResult> {
+        let stats = self.stats.lock().unwrap();
+        Ok(StatsResult {
+            alloc_failures: stats.alloc_failures,
+            servers: stats.servers.iter().map(|(id, s)| (*id, s.clone())).collect(),
+            clients: stats.clients.iter().map(|(addr, s)| (*addr, s.clone())).collect(),
+        })
+    }
+
+    fn handle_update_job_status(&self, server_id: ServerId, job_id: JobId, status: JobStatus) -> Result<UpdateJobStatusResult> {
+        match status {
+            JobStatus::Complete { .. } | JobStatus::Failed => {},
+            JobStatus::Pending | JobStatus::Started => return Ok(UpdateJobStatusResult),
+        }
+        let mut stats = self.stats.lock().unwrap();
+        // Jobs assigned by another scheduler aren't ours to count.
+        let client = match stats.jobs.remove(&job_id) {
+            Some((client, _)) => client,
+            None => {
+                trace!("Status update from {:?} for unknown job {}", server_id, job_id);
+                return Ok(UpdateJobStatusResult)
+            },
+        };
+        record_job_status(stats.servers.entry(server_id).or_insert_with(JobStats::default), &status);
+        record_job_status(stats.clients.entry(client).or_insert_with(JobStats::default), &status);
+        Ok(UpdateJobStatusResult)
+    }
+
     fn handle_heartbeat_server(&self, server_id: ServerId, num_cpus: usize) -> Result<HeartbeatServerResult> {
         if num_cpus == 0 {
             return Err("invalid heartbeat num_cpus".into())
@@ -277,40 +401,74 @@ impl SchedulerIncoming for Scheduler {
     }
 }
 
+/// Count a finished job with `status` in `stats`.
+fn record_job_status(stats: &mut JobStats, status: &JobStatus) {
+    match *status {
+        JobStatus::Complete { build_ms, succeeded } => {
+            stats.jobs_completed += 1;
+            stats.build_ms += build_ms;
+            if !succeeded {
+                stats.compile_failures += 1;
+            }
+        },
+        JobStatus::Failed => stats.jobs_failed += 1,
+        JobStatus::Pending | JobStatus::Started => {},
+    }
+}
+
 pub struct Server {
     builder: Box<BuilderIncoming<Error=Error>>,
     cache: Mutex<TcCache>,
     job_toolchains: Mutex<HashMap<JobId, Toolchain>>,
+    toolchain_verification: ToolchainVerification,
 }
 
 impl Server {
-    pub fn new(builder: Box<BuilderIncoming<Error=Error>>, cache_dir: &Path, toolchain_cache_size: u64) -> Server {
+    fn new(builder: Box<BuilderIncoming<Error=Error>>, cache_dir: &Path, toolchain_cache_size: u64, toolchain_verification: ToolchainVerification) -> Server {
         Server {
             builder,
             cache: Mutex::new(TcCache::new(&cache_dir.join("tc"), toolchain_cache_size).unwrap()),
             job_toolchains: Mutex::new(HashMap::new()),
+            toolchain_verification,
         }
     }
 }
 
 impl ServerIncoming for Server {
     type Error = Error;
-    fn handle_assign_job(&self, job_id: JobId, tc: Toolchain) -> Result<AssignJobResult> {
+    fn handle_assign_job(&self, trace_id: &TraceId, job_id: JobId, tc: Toolchain) -> Result<AssignJobResult> {
         let need_toolchain = !self.cache.lock().unwrap().contains_toolchain(&tc);
-        assert!(self.job_toolchains.lock().unwrap().insert(job_id, tc).is_none());
+        info!("[{}]: Assigned job {} (need toolchain: {})", trace_id, job_id, need_toolchain);
+        let mut job_toolchains = self.job_toolchains.lock().unwrap();
+        if job_toolchains.contains_key(&job_id) {
+            // Another scheduler has already assigned a job with this id
+            bail!("job {} is already assigned", job_id)
+        }
+        job_toolchains.insert(job_id, tc);
         if !need_toolchain {
             // TODO: can start prepping the container now
         }
         Ok(AssignJobResult { need_toolchain })
     }
-    fn handle_submit_toolchain(&self, requester: &ServerOutgoing, job_id: JobId, tc_rdr: ToolchainReader) -> Result<SubmitToolchainResult> {
-        requester.do_update_job_status(job_id, JobStatus::Started).chain_err(|| "update job status failed")?;
+    fn handle_submit_toolchain(&self, requester: &ServerOutgoing, trace_id: &TraceId, job_id: JobId, tc_rdr: ToolchainReader, signature: Option<Vec<u8>>) -> Result<SubmitToolchainResult> {
         // TODO: need to lock the toolchain until the container has started
         // TODO: can start prepping container
         let tc = match self.job_toolchains.lock().unwrap().get(&job_id).cloned() {
             Some(tc) => tc,
-            None => return Ok(SubmitToolchainResult::JobNotFound),
+            None => {
+                warn!("[{}]: Toolchain submitted for unknown job {}", trace_id, job_id);
+                return Ok(SubmitToolchainResult::JobNotFound)
+            },
         };
+        if let ToolchainVerification::Required(ref public_key) = self.toolchain_verification {
+            let verified = signature
+                .ok_or_else(|| "toolchain is not signed".into())
+                .and_then(|sig| dist::verify_toolchain_signature(public_key, &tc, &sig));
+            if let Err(e) = verified {
+                warn!("[{}]: Rejecting toolchain {} for job {}: {}", trace_id, tc.archive_id, job_id, e);
+                return Ok(SubmitToolchainResult::InvalidSignature)
+            }
+        }
         let mut cache = self.cache.lock().unwrap();
         // TODO: this returns before reading all the data, is that valid?
         if cache.contains_toolchain(&tc) {
@@ -320,13 +478,28 @@ impl ServerIncoming for Server {
             .map(|_| SubmitToolchainResult::Success)
             .unwrap_or(SubmitToolchainResult::CannotCache))
     }
-    fn handle_run_job(&self, requester: &ServerOutgoing, job_id: JobId, command: CompileCommand, outputs: Vec<String>, inputs_rdr: InputsReader) -> Result<RunJobResult> {
+    fn handle_run_job(&self, requester: &ServerOutgoing, trace_id: &TraceId, job_id: JobId, command: CompileCommand, outputs: Vec<String>, inputs_rdr: InputsReader) -> Result<RunJobResult> {
         let tc = match self.job_toolchains.lock().unwrap().remove(&job_id) {
             Some(tc) => tc,
-            None => return Ok(RunJobResult::JobNotFound),
+            None => {
+                warn!("[{}]: Asked to run unknown job {}", trace_id, job_id);
+                return Ok(RunJobResult::JobNotFound)
+            },
+        };
+        info!("[{}]: Running job {}", trace_id, job_id);
+        let start = Instant::now();
+        let res = match self.builder.run_build(tc, command, outputs, inputs_rdr, &self.cache) {
+            Ok(res) => res,
+            Err(e) => {
+                requester.do_update_job_status(job_id, JobStatus::Failed).chain_err(|| "update job status failed")?;
+                return Err(e).chain_err(|| "run build failed")
+            },
         };
-        let res = self.builder.run_build(tc, command, outputs, inputs_rdr, &self.cache).chain_err(|| "run build failed")?;
-        requester.do_update_job_status(job_id, JobStatus::Complete).chain_err(|| "update job status failed")?;
+        let elapsed = start.elapsed();
+        info!("[{}]: Finished job {}", trace_id, job_id);
+        let build_ms = elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64;
+        let status = JobStatus::Complete { build_ms, succeeded: res.output.success() };
+        requester.do_update_job_status(job_id, status).chain_err(|| "update job status failed")?;
         Ok(RunJobResult::Complete(JobComplete { output: res.output, outputs: res.outputs }))
     }
 }
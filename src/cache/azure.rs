@@ -84,4 +84,6 @@ impl Storage for AzureBlobCache {
 
     fn current_size(&self) -> Option<u64> { None }
     fn max_size(&self) -> Option<u64> { None }
+    fn current_entries(&self) -> Option<u64> { None }
+    fn max_entries(&self) -> Option<u64> { None }
 }
\ No newline at end of file
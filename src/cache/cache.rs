@@ -15,6 +15,8 @@
 #[cfg(feature = "azure")]
 use cache::azure::AzureBlobCache;
 use cache::disk::DiskCache;
+#[cfg(feature = "gha")]
+use cache::gha::GHACache;
 #[cfg(feature = "memcached")]
 use cache::memcached::MemcachedCache;
 #[cfg(feature = "redis")]
@@ -23,24 +25,33 @@ use cache::redis::RedisCache;
 use cache::s3::S3Cache;
 #[cfg(feature = "gcs")]
 use cache::gcs::{self, GCSCache, GCSCredentialProvider, RWMode};
-use config::{self, CONFIG, CacheType};
+#[cfg(feature = "webdav")]
+use cache::webdav::WebDAVCache;
+use cache::compression::with_compression;
+use cache::shadow::{ShadowStats, ShadowStorage};
+use config::{self, CONFIG, CacheCompression, CacheType, DiskCacheDurability};
 use futures_cpupool::CpuPool;
-#[cfg(feature = "gcs")]
+use lru_disk_cache::Durability;
 use serde_json;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
+use std::fs::File;
 use std::io::{
     self,
     Read,
     Seek,
+    SeekFrom,
     Write,
 };
-#[cfg(feature = "gcs")]
-use std::fs::File;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
+use tempfile;
 use tokio_core::reactor::Handle;
 use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use zip::result::ZipError;
 use zip::write::FileOptions;
+use zstd;
 
 use errors::*;
 
@@ -64,6 +75,90 @@ impl fmt::Debug for Cache {
     }
 }
 
+/// The name of the cache entry object holding its `Provenance`.
+const PROVENANCE_OBJECT: &str = "provenance";
+
+/// The name of the cache entry object recording how the objects zip can't
+/// compress itself are compressed.
+const COMPRESSION_OBJECT: &str = "compression";
+
+/// How the objects of a cache entry that zip can't compress itself are
+/// compressed. Such objects are stored uncompressed as far as zip is
+/// concerned, so readers go by this rather than by how they're configured.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct EntryCompression {
+    /// The objects compressed with zstd, and their uncompressed sizes.
+    #[serde(default)]
+    zstd: BTreeMap<String, u64>,
+}
+
+/// Read `file`, decompressing it with zstd if `zstd`.
+fn decompressed<'a, R>(file: R, zstd: bool) -> io::Result<Box<Read + 'a>>
+    where R: Read + 'a,
+{
+    if zstd {
+        Ok(Box::new(zstd::stream::Decoder::new(file)?))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Where a cache entry came from.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// The hostname of the machine that stored the entry, if known.
+    pub hostname: Option<String>,
+    /// The version of sccache that stored the entry.
+    pub sccache_version: String,
+    /// The compiler executable that produced the entry.
+    pub compiler: PathBuf,
+    /// A digest identifying the version of the compiler.
+    pub compiler_digest: String,
+    /// When the entry was stored, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The label the entry was stored with, so it can be removed along with
+    /// the other entries bearing it.
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+impl Provenance {
+    /// Whether the compiler that produced the entry has since been removed or
+    /// replaced, for example by a compiler upgrade, so the entry will never be
+    /// hit again, given the `current` digests of the compilers that still
+    /// exist. Entries from existing compilers missing from `current` are kept.
+    pub fn is_stale(&self, current: &HashMap<PathBuf, String>) -> bool {
+        match current.get(&self.compiler) {
+            Some(digest) => *digest != self.compiler_digest,
+            None => !self.compiler.exists(),
+        }
+    }
+}
+
+/// The compilers that produced the entries of a storage, with the digests
+/// their `Provenance` records for each.
+pub type CompilerDigests = HashMap<PathBuf, HashSet<String>>;
+
+/// What was removed from a cache by `Storage::prune_older_than`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PruneStats {
+    /// The number of entries removed.
+    pub entries: u64,
+    /// The total size of the entries removed, in bytes.
+    pub bytes: u64,
+}
+
+/// What `Storage::verify` found.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct VerifyStats {
+    /// The number of entries checked.
+    pub checked: u64,
+    /// The keys of the entries that were corrupt.
+    pub corrupt: Vec<String>,
+    /// The corrupt entries that were removed.
+    pub removed: PruneStats,
+}
+
 /// Trait objects can't be bounded by more than one non-builtin trait.
 pub trait ReadSeek : Read + Seek + Send {}
 
@@ -72,6 +167,7 @@ impl<T: Read + Seek + Send> ReadSeek for T {}
 /// Data stored in the compiler cache.
 pub struct CacheRead {
     zip: ZipArchive<Box<ReadSeek>>,
+    compression: EntryCompression,
 }
 
 impl CacheRead {
@@ -79,39 +175,150 @@ impl CacheRead {
     pub fn from<R>(reader: R) -> Result<CacheRead>
         where R: ReadSeek + 'static,
     {
-        let z = ZipArchive::new(Box::new(reader) as Box<ReadSeek>).chain_err(|| {
+        let mut zip = ZipArchive::new(Box::new(reader) as Box<ReadSeek>).chain_err(|| {
             "Failed to parse cache entry"
         })?;
+        let compression = match zip.by_name(COMPRESSION_OBJECT) {
+            Ok(file) => serde_json::from_reader(file).chain_err(|| "Failed to parse cache entry compression")?,
+            Err(ZipError::FileNotFound) => EntryCompression::default(),
+            Err(e) => return Err(e).chain_err(|| "Failed to read compression from cache entry"),
+        };
         Ok(CacheRead {
-            zip: z,
+            zip,
+            compression,
         })
     }
 
+    /// Whether this cache entry has an object at `name`.
+    pub fn has_object(&mut self, name: &str) -> bool {
+        name != COMPRESSION_OBJECT && self.zip.by_name(name).is_ok()
+    }
+
+    /// Whether the object at `name` is compressed with zstd.
+    pub fn is_zstd(&self, name: &str) -> bool {
+        self.compression.zstd.contains_key(name)
+    }
+
     /// Get an object from this cache entry at `name` and write it to `to`.
     /// If the file has stored permissions, return them.
     pub fn get_object<T>(&mut self, name: &str, to: &mut T) -> Result<Option<u32>>
         where T: Write,
     {
-        let mut file = self.zip.by_name(name).chain_err(|| {
+        let file = self.zip.by_name(name).chain_err(|| {
             "Failed to read object from cache entry"
         })?;
+        let mode = file.unix_mode();
+        let mut file = decompressed(file, self.compression.zstd.contains_key(name))?;
         io::copy(&mut file, to)?;
-        Ok(file.unix_mode())
+        Ok(mode)
+    }
+
+    /// The names of the objects in this cache entry.
+    pub fn object_names(&mut self) -> Vec<String> {
+        (0..self.zip.len())
+            .filter_map(|i| self.zip.by_index(i).ok().map(|file| file.name().to_owned()))
+            .filter(|name| name != COMPRESSION_OBJECT)
+            .collect()
+    }
+
+    /// Read every object in this cache entry, checking it against its checksum.
+    pub fn verify(&mut self) -> Result<()> {
+        for i in 0..self.zip.len() {
+            let file = self.zip.by_index(i).chain_err(|| "Failed to read object from cache entry")?;
+            let name = file.name().to_owned();
+            decompressed(file, self.compression.zstd.contains_key(&name))
+                .and_then(|mut file| io::copy(&mut file, &mut io::sink()))
+                .chain_err(|| format!("Object `{}` is corrupt", name))?;
+        }
+        Ok(())
+    }
+
+    /// The total uncompressed size of the objects in this cache entry.
+    pub fn size(&mut self) -> u64 {
+        let zstd = &self.compression.zstd;
+        let zip = &mut self.zip;
+        (0..zip.len())
+            .filter_map(|i| zip.by_index(i).ok().and_then(|f| {
+                match f.name() {
+                    COMPRESSION_OBJECT => None,
+                    name => Some(zstd.get(name).cloned().unwrap_or(f.size())),
+                }
+            }))
+            .sum()
+    }
+
+    /// Get the provenance of this cache entry, if it was stored with one.
+    pub fn get_provenance(&mut self) -> Result<Option<Provenance>> {
+        match self.zip.by_name(PROVENANCE_OBJECT) {
+            Ok(file) => {
+                let file = decompressed(file, self.compression.zstd.contains_key(PROVENANCE_OBJECT))?;
+                let provenance = serde_json::from_reader(file).chain_err(|| {
+                    "Failed to parse cache entry provenance"
+                })?;
+                Ok(Some(provenance))
+            }
+            Err(ZipError::FileNotFound) => Ok(None),
+            Err(e) => Err(e).chain_err(|| "Failed to read provenance from cache entry"),
+        }
     }
 }
 
 /// Data to be stored in the compiler cache.
+///
+/// The entry is written to an anonymous temporary file rather than kept in
+/// memory, so that large compiler outputs don't have to be buffered.
 pub struct CacheWrite {
-    zip: ZipWriter<io::Cursor<Vec<u8>>>,
+    zip: ZipWriter<File>,
+    size: u64,
+    method: CompressionMethod,
+    /// The level to compress objects with zstd at, if they're compressed with zstd.
+    zstd_level: Option<i32>,
+    compression: EntryCompression,
 }
 
 impl CacheWrite {
     /// Create a new, empty cache entry.
-    pub fn new() -> CacheWrite
+    pub fn new() -> Result<CacheWrite>
+    {
+        CacheWrite::with_compression(CacheCompression::default())
+    }
+
+    /// Create a new, empty cache entry whose objects are compressed with `compression`.
+    pub fn with_compression(compression: CacheCompression) -> Result<CacheWrite>
+    {
+        let file = tempfile::tempfile().chain_err(|| "Failed to create cache entry temporary file")?;
+        let (method, zstd_level) = match compression {
+            CacheCompression::None => (CompressionMethod::Stored, None),
+            CacheCompression::Deflate => (CompressionMethod::Deflated, None),
+            // zip only stores the compressed data.
+            // zip 0.4 can't read or write its zstd method, so these are
+            // kept apart by `compression::zstd_key` instead.
+            CacheCompression::Zstd(level) => (CompressionMethod::Stored, Some(level)),
+        };
+        Ok(CacheWrite {
+            zip: ZipWriter::new(file),
+            size: 0,
+            method,
+            zstd_level,
+            compression: EntryCompression::default(),
+        })
+    }
+
+    /// Create a cache entry holding a copy of the objects of `entry`.
+    pub fn from_entry(entry: &mut CacheRead, compression: CacheCompression) -> Result<CacheWrite>
     {
-        CacheWrite {
-            zip: ZipWriter::new(io::Cursor::new(vec!())),
+        let mut copy = CacheWrite::with_compression(compression)?;
+        for i in 0..entry.zip.len() {
+            let file = entry.zip.by_index(i).chain_err(|| "Failed to read object from cache entry")?;
+            let name = file.name().to_owned();
+            if name == COMPRESSION_OBJECT {
+                continue
+            }
+            let mode = file.unix_mode();
+            let mut file = decompressed(file, entry.compression.zstd.contains_key(&name))?;
+            copy.put_object(&name, &mut file, mode)?;
         }
+        Ok(copy)
     }
 
     /// Add an object containing the contents of `from` to this cache entry at `name`.
@@ -119,21 +326,59 @@ impl CacheWrite {
     pub fn put_object<T>(&mut self, name: &str, from: &mut T, mode: Option<u32>) -> Result<()>
         where T: Read,
     {
-        let opts = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        let opts = FileOptions::default().compression_method(self.method);
         let opts = if let Some(mode) = mode { opts.unix_permissions(mode) } else { opts };
         self.zip.start_file(name, opts).chain_err(|| {
             "Failed to start cache entry object"
         })?;
-        io::copy(from, &mut self.zip)?;
+        let size = match self.zstd_level {
+            Some(level) => {
+                let mut encoder = zstd::stream::Encoder::new(&mut self.zip, level)?;
+                let size = io::copy(from, &mut encoder)?;
+                encoder.finish()?;
+                self.compression.zstd.insert(name.to_owned(), size);
+                size
+            }
+            None => io::copy(from, &mut self.zip)?,
+        };
+        self.size += size;
         Ok(())
     }
 
+    /// The total uncompressed size of the objects put in this cache entry so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Record where this cache entry came from.
+    pub fn put_provenance(&mut self, provenance: &Provenance) -> Result<()> {
+        let json = serde_json::to_vec(provenance)?;
+        self.put_object(PROVENANCE_OBJECT, &mut &json[..], None)
+    }
+
     /// Finish writing data to the cache entry writer, and return the data.
     pub fn finish(self) -> Result<Vec<u8>>
     {
-        let CacheWrite { mut zip } = self;
-        let cur = zip.finish().chain_err(|| "Failed to finish cache entry zip")?;
-        Ok(cur.into_inner())
+        let mut file = self.into_file()?;
+        let mut data = vec!();
+        file.read_to_end(&mut data).chain_err(|| "Failed to read cache entry")?;
+        Ok(data)
+    }
+
+    /// Finish writing data to the cache entry writer, and return the file
+    /// holding the data, positioned at its start.
+    pub fn into_file(self) -> Result<File>
+    {
+        let CacheWrite { mut zip, compression, .. } = self;
+        if !compression.zstd.is_empty() {
+            zip.start_file(COMPRESSION_OBJECT, FileOptions::default()).chain_err(|| {
+                "Failed to start cache entry object"
+            })?;
+            serde_json::to_writer(&mut zip, &compression)?;
+        }
+        let mut file = zip.finish().chain_err(|| "Failed to finish cache entry zip")?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(file)
     }
 }
 
@@ -162,106 +407,276 @@ pub trait Storage {
 
     /// Get the maximum storage size, if applicable.
     fn max_size(&self) -> Option<u64>;
+
+    /// Get the current number of entries, if applicable.
+    fn current_entries(&self) -> Option<u64>;
+
+    /// Get the maximum number of entries, if applicable.
+    fn max_entries(&self) -> Option<u64>;
+
+    /// How entries put in this storage should be compressed.
+    fn compression(&self) -> CacheCompression {
+        CacheCompression::default()
+    }
+
+    /// How the shadow cache mirroring this storage has fared, if there is one.
+    fn shadow_stats(&self) -> Option<ShadowStats> {
+        None
+    }
+
+    /// Remove entries that haven't been used in `age`.
+    ///
+    /// Only storage that tracks when entries were last used supports this.
+    fn prune_older_than(&self, _age: Duration) -> SFuture<PruneStats> {
+        f_err(format!("{} doesn't support pruning old entries", self.location()))
+    }
+
+    /// List the compilers that produced the entries in this storage.
+    ///
+    /// Only storage that can enumerate its entries supports this.
+    fn compiler_digests(&self) -> SFuture<CompilerDigests> {
+        f_err(format!("{} doesn't support listing the compilers of its entries", self.location()))
+    }
+
+    /// Remove entries produced by a compiler that has since been removed or
+    /// replaced, according to their `Provenance` and the `current` digests of
+    /// the compilers that still exist.
+    ///
+    /// Only storage that can enumerate its entries supports this.
+    fn purge_stale_compilers(&self, _current: HashMap<PathBuf, String>) -> SFuture<PruneStats> {
+        f_err(format!("{} doesn't support purging entries from old compilers", self.location()))
+    }
+
+    /// Change the maximum size of the cache to `size` bytes, evicting entries
+    /// until it fits, until the server is restarted.
+    fn set_max_size(&self, _size: u64) -> SFuture<PruneStats> {
+        f_err(format!("{} doesn't support changing its size", self.location()))
+    }
+
+    /// Remove entries that were stored with `label` in their `Provenance`.
+    ///
+    /// Only storage that can enumerate its entries supports this.
+    fn remove_label(&self, _label: &str) -> SFuture<PruneStats> {
+        f_err(format!("{} can't enumerate its entries, so it doesn't support removing them by label",
+                      self.location()))
+    }
+
+    /// List the keys of the entries in this storage.
+    ///
+    /// Only storage that can enumerate its entries supports this.
+    fn keys(&self) -> SFuture<Vec<String>> {
+        f_err(format!("{} can't enumerate its entries", self.location()))
+    }
+
+    /// Remove the entry stored under `key`, if there is one.
+    fn remove(&self, _key: &str) -> SFuture<()> {
+        f_err(format!("{} doesn't support removing entries", self.location()))
+    }
+
+    /// Whether this storage is configured to never write entries, so that
+    /// `put`s succeed without storing anything.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Check that every entry can be read back intact, removing those that
+    /// can't if `repair` is set. If `rate` is set, check at most that many
+    /// entries a second.
+    ///
+    /// Only storage that can enumerate its entries supports this.
+    fn verify(&self, _repair: bool, _rate: Option<u32>) -> SFuture<VerifyStats> {
+        f_err(format!("{} can't enumerate its entries, so it doesn't support verifying them",
+                      self.location()))
+    }
 }
 
-/// Get a suitable `Storage` implementation from configuration.
-pub fn storage_from_config(pool: &CpuPool, _handle: &Handle) -> Arc<Storage> {
-    for cache_type in CONFIG.caches.iter() {
-        match *cache_type {
-            CacheType::Azure(config::AzureCacheConfig) => {
-                debug!("Trying Azure Blob Store account");
-                #[cfg(feature = "azure")]
-                match AzureBlobCache::new(_handle) {
-                    Ok(storage) => {
-                        trace!("Using AzureBlobCache");
-                        return Arc::new(storage);
-                    }
-                    Err(e) => warn!("Failed to create Azure cache: {:?}", e),
-                }
-            },
-            CacheType::GCS(config::GCSCacheConfig { ref bucket, ref cred_path, rw_mode }) => {
-                debug!("Trying GCS bucket({}, {:?}, {:?})", bucket, cred_path, rw_mode);
-                #[cfg(feature = "gcs")]
+/// Create the `Storage` implementation for a single configured cache type.
+pub fn storage_from_cache_type(cache_type: &CacheType, _pool: &CpuPool, _handle: &Handle) -> Result<Arc<Storage>> {
+    match *cache_type {
+        CacheType::Azure(config::AzureCacheConfig { compression }) => {
+            debug!("Trying Azure Blob Store account");
+            #[cfg(feature = "azure")]
+            {
+                let storage = AzureBlobCache::new(_handle).chain_err(|| "Failed to create Azure cache")?;
+                trace!("Using AzureBlobCache");
+                return Ok(with_compression(Arc::new(storage), compression));
+            }
+            #[cfg(not(feature = "azure"))]
+            bail!("sccache was built without Azure support");
+        },
+        CacheType::GCS(config::GCSCacheConfig { ref bucket, ref cred_path, rw_mode, compression }) => {
+            debug!("Trying GCS bucket({}, {:?}, {:?})", bucket, cred_path, rw_mode);
+            #[cfg(feature = "gcs")]
+            {
+                let service_account_key_opt: Option<gcs::ServiceAccountKey> =
+                    if let Some(ref cred_path) = *cred_path
                 {
-                    let service_account_key_opt: Option<gcs::ServiceAccountKey> =
-                        if let Some(ref cred_path) = *cred_path
-                    {
-                        // Attempt to read the service account key from file
-                        let service_account_key_res: Result<gcs::ServiceAccountKey> = (|| {
-                            let mut file = File::open(&cred_path)?;
-                            let mut service_account_json = String::new();
-                            file.read_to_string(&mut service_account_json)?;
-                            Ok(serde_json::from_str(&service_account_json)?)
-                        })();
-
-                        // warn! if an error was encountered reading the key from the file
-                        if let Err(ref e) = service_account_key_res {
-                            warn!("Failed to parse service account credentials from file: {:?}. \
-                                Continuing without authentication.", e);
-                        }
-
-                        service_account_key_res.ok()
-                    } else {
-                        warn!("No SCCACHE_GCS_KEY_PATH specified-- no authentication will be used.");
-                        None
-                    };
-
-                    let gcs_read_write_mode = match rw_mode {
-                        config::GCSCacheRWMode::ReadOnly => RWMode::ReadOnly,
-                        config::GCSCacheRWMode::ReadWrite => RWMode::ReadWrite,
-                    };
-
-                    let gcs_cred_provider =
-                        service_account_key_opt.map(|path|
-                            GCSCredentialProvider::new(gcs_read_write_mode, path));
-
-                    match GCSCache::new(bucket.to_owned(), gcs_cred_provider, gcs_read_write_mode, _handle) {
-                        Ok(s) => {
-                            trace!("Using GCSCache");
-                            return Arc::new(s);
-                        }
-                        Err(e) => warn!("Failed to create GCS Cache: {:?}", e),
-                    }
-                }
-            },
-            CacheType::Memcached(config::MemcachedCacheConfig { ref url }) => {
-                debug!("Trying Memcached({})", url);
-                #[cfg(feature = "memcached")]
-                match MemcachedCache::new(&url, pool) {
-                    Ok(s) => {
-                        trace!("Using Memcached: {}", url);
-                        return Arc::new(s);
-                    }
-                    Err(e) => warn!("Failed to create MemcachedCache: {:?}", e),
-                }
-            },
-            CacheType::Redis(config::RedisCacheConfig { ref url }) => {
-                debug!("Trying Redis({})", url);
-                #[cfg(feature = "redis")]
-                match RedisCache::new(&url, pool) {
-                    Ok(s) => {
-                        trace!("Using Redis: {}", url);
-                        return Arc::new(s);
-                    }
-                    Err(e) => warn!("Failed to create RedisCache: {:?}", e),
-                }
-            },
-            CacheType::S3(config::S3CacheConfig { ref bucket, ref endpoint }) => {
-                debug!("Trying S3Cache({}, {})", bucket, endpoint);
-                #[cfg(feature = "s3")]
-                match S3Cache::new(&bucket, &endpoint, _handle) {
-                    Ok(s) => {
-                        trace!("Using S3Cache");
-                        return Arc::new(s);
+                    // Attempt to read the service account key from file
+                    let service_account_key_res: Result<gcs::ServiceAccountKey> = (|| {
+                        let mut file = File::open(&cred_path)?;
+                        let mut service_account_json = String::new();
+                        file.read_to_string(&mut service_account_json)?;
+                        Ok(serde_json::from_str(&service_account_json)?)
+                    })();
+
+                    // warn! if an error was encountered reading the key from the file
+                    if let Err(ref e) = service_account_key_res {
+                        warn!("Failed to parse service account credentials from file: {:?}. \
+                            Continuing without authentication.", e);
                     }
-                    Err(e) => warn!("Failed to create S3Cache: {:?}", e),
-                }
-            },
+
+                    service_account_key_res.ok()
+                } else {
+                    warn!("No SCCACHE_GCS_KEY_PATH specified-- no authentication will be used.");
+                    None
+                };
+
+                let gcs_read_write_mode = match rw_mode {
+                    config::GCSCacheRWMode::ReadOnly => RWMode::ReadOnly,
+                    config::GCSCacheRWMode::ReadWrite => RWMode::ReadWrite,
+                };
+
+                let gcs_cred_provider =
+                    service_account_key_opt.map(|path|
+                        GCSCredentialProvider::new(gcs_read_write_mode, path));
+
+                let storage = GCSCache::new(bucket.to_owned(), gcs_cred_provider, gcs_read_write_mode, _handle)
+                    .chain_err(|| "Failed to create GCS Cache")?;
+                trace!("Using GCSCache");
+                return Ok(with_compression(Arc::new(storage), compression));
+            }
+            #[cfg(not(feature = "gcs"))]
+            bail!("sccache was built without GCS support");
+        },
+        CacheType::GHA(config::GHACacheConfig { ref version, compression }) => {
+            debug!("Trying GitHub Actions cache({})", version);
+            #[cfg(feature = "gha")]
+            {
+                let storage = GHACache::from_env(version, _pool)
+                    .chain_err(|| "Failed to create GitHub Actions cache")?;
+                trace!("Using GitHub Actions cache: {}", storage.location());
+                return Ok(with_compression(Arc::new(storage), compression));
+            }
+            #[cfg(not(feature = "gha"))]
+            bail!("sccache was built without GitHub Actions cache support");
+        },
+        CacheType::Memcached(config::MemcachedCacheConfig { ref url, max_object_size, compression }) => {
+            debug!("Trying Memcached({}, max object size {})", url, max_object_size);
+            #[cfg(feature = "memcached")]
+            {
+                let storage = MemcachedCache::new(&url, max_object_size, _pool).chain_err(|| "Failed to create MemcachedCache")?;
+                trace!("Using Memcached: {}", url);
+                return Ok(with_compression(Arc::new(storage), compression));
+            }
+            #[cfg(not(feature = "memcached"))]
+            bail!("sccache was built without Memcached support");
+        },
+        CacheType::Redis(config::RedisCacheConfig {
+            ref url, cluster, ref ca_bundle, skip_hostname_verification, max_object_size, compression,
+        }) => {
+            debug!("Trying Redis({}, cluster {}, CA bundle {:?}, skip hostname verification {}, max object size {})",
+                   url, cluster, ca_bundle, skip_hostname_verification, max_object_size);
+            #[cfg(feature = "redis")]
+            {
+                let storage = RedisCache::new(&url, cluster, ca_bundle.as_ref().map(|p| p.as_path()),
+                                             skip_hostname_verification, max_object_size, _pool)
+                    .chain_err(|| "Failed to create RedisCache")?;
+                trace!("Using Redis: {}", url);
+                return Ok(with_compression(Arc::new(storage), compression));
+            }
+            #[cfg(not(feature = "redis"))]
+            bail!("sccache was built without Redis support");
+        },
+        CacheType::S3(config::S3CacheConfig {
+            ref bucket, ref endpoint, multipart_threshold, part_size, upload_concurrency, compression,
+            server_side_encryption,
+        }) => {
+            debug!("Trying S3Cache({}, {})", bucket, endpoint);
+            #[cfg(feature = "s3")]
+            {
+                use simples3::ServerSideEncryption;
+                let sse = server_side_encryption.map(|sse| match sse {
+                    config::S3ServerSideEncryption::Aes256 => ServerSideEncryption::Aes256,
+                });
+                let storage = S3Cache::new(&bucket, &endpoint, multipart_threshold, part_size, upload_concurrency, sse, _handle)
+                    .chain_err(|| "Failed to create S3Cache")?;
+                trace!("Using S3Cache");
+                return Ok(with_compression(Arc::new(storage), compression));
+            }
+            #[cfg(not(feature = "s3"))]
+            bail!("sccache was built without S3 support");
+        },
+        CacheType::WebDAV(config::WebDAVCacheConfig { ref url, ref username, ref password, compression }) => {
+            debug!("Trying WebDAV({})", url);
+            #[cfg(feature = "webdav")]
+            {
+                let storage = WebDAVCache::new(url, username.as_ref().map(|s| s.as_str()),
+                                               password.as_ref().map(|s| s.as_str()), _handle)
+                    .chain_err(|| "Failed to create WebDAV cache")?;
+                trace!("Using WebDAV cache: {}", storage.location());
+                return Ok(with_compression(Arc::new(storage), compression));
+            }
+            #[cfg(not(feature = "webdav"))]
+            bail!("sccache was built without WebDAV support");
+        },
+    }
+}
+
+/// Mirror the operations on `storage` to the configured shadow cache, if any.
+pub fn shadow_storage_from_config(storage: Arc<Storage>, pool: &CpuPool, handle: &Handle) -> Arc<Storage> {
+    let cache_type = match CONFIG.shadow_cache {
+        Some(ref cache_type) => cache_type,
+        None => return storage,
+    };
+    match storage_from_cache_type(cache_type, pool, handle) {
+        Ok(shadow) => {
+            info!("Mirroring cache operations to shadow cache {}", shadow.location());
+            Arc::new(ShadowStorage::new(storage, shadow, pool, handle))
+        }
+        Err(e) => {
+            warn!("Failed to create shadow cache: {:?}", e);
+            storage
+        }
+    }
+}
+
+/// Get a suitable `Storage` implementation from configuration.
+pub fn storage_from_config(pool: &CpuPool, handle: &Handle) -> Arc<Storage> {
+    configured_storage_from_config(pool, handle).unwrap_or_else(|| {
+        info!("No configured caches successful, falling back to default");
+        disk_storage_from_config(pool)
+    })
+}
+
+/// Get the first of the configured caches that can be used, if any.
+pub fn configured_storage_from_config(pool: &CpuPool, handle: &Handle) -> Option<Arc<Storage>> {
+    for cache_type in CONFIG.caches.iter() {
+        match storage_from_cache_type(cache_type, pool, handle) {
+            Ok(storage) => return Some(storage),
+            Err(e) => warn!("{:?}", e),
         }
     }
+    None
+}
 
-    info!("No configured caches successful, falling back to default");
-    let (dir, size) = (&CONFIG.fallback_cache.dir, CONFIG.fallback_cache.size);
-    trace!("Using DiskCache({:?}, {})", dir, size);
-    Arc::new(DiskCache::new(dir, size, pool))
+/// Get the local disk cache `Storage` from configuration.
+pub fn disk_storage_from_config(pool: &CpuPool) -> Arc<Storage> {
+    let (dir, size, max_entries) = (&CONFIG.fallback_cache.dir, CONFIG.fallback_cache.size, CONFIG.fallback_cache.max_entries);
+    trace!("Using DiskCache({:?}, {}, {:?})", dir, size, max_entries);
+    let mut storage = DiskCache::new(dir, size, max_entries, pool);
+    storage.set_eviction_grace(Duration::from_secs(CONFIG.fallback_cache.eviction_grace_secs));
+    storage.set_durability(match CONFIG.fallback_cache.durability {
+        DiskCacheDurability::None => Durability::None,
+        DiskCacheDurability::Data => Durability::Data,
+        DiskCacheDurability::Full => Durability::Full,
+    });
+    storage.set_min_free_space(CONFIG.fallback_cache.min_free_space);
+    let (high, low) = (CONFIG.fallback_cache.eviction_high_watermark, CONFIG.fallback_cache.eviction_low_watermark);
+    if let Err(e) = storage.set_eviction_watermarks(high, low) {
+        warn!("{}", e);
+    }
+    if CONFIG.fallback_cache.background_eviction {
+        storage.start_background_eviction();
+    }
+    with_compression(Arc::new(storage), CONFIG.fallback_cache.compression)
 }
@@ -0,0 +1,126 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storing cache entries that are larger than a backend allows a single
+//! value to be.
+//!
+//! Such an entry is split into chunks stored under keys of their own, and a
+//! manifest listing them is stored under the entry's key once all the chunks
+//! have been written, so a partially written entry is never read. A manifest
+//! whose chunks aren't all present, because some were evicted, is a miss.
+
+use serde_json;
+use util::Digest;
+
+use errors::*;
+
+/// Distinguishes a manifest from a cache entry, which is a zip file.
+const MANIFEST_MAGIC: &[u8] = b"sccache-chunks\n";
+
+/// The chunks an entry was split into.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChunkManifest {
+    /// The total size of the entry.
+    pub size: u64,
+    /// The keys of the chunks, in order.
+    pub chunks: Vec<String>,
+}
+
+/// The values to store for a cache entry.
+pub enum ChunkedWrite {
+    /// The entry fits in a single value.
+    Whole(Vec<u8>),
+    /// The entry is stored as these chunks, by key, followed by `manifest`
+    /// under the entry's key.
+    Chunked {
+        chunks: Vec<(String, Vec<u8>)>,
+        manifest: Vec<u8>,
+    },
+}
+
+/// Split `data`, the entry to store under `key`, into values of at most `max_size` bytes.
+pub fn split(key: &str, data: Vec<u8>, max_size: u64) -> Result<ChunkedWrite> {
+    if data.len() as u64 <= max_size {
+        return Ok(ChunkedWrite::Whole(data))
+    }
+    // Naming the chunks after the entry's contents means concurrent writers
+    // of different entries under the same key can't mix up their chunks.
+    let mut m = Digest::new();
+    m.update(&data);
+    let digest = m.finish();
+    let chunks = data.chunks(max_size.max(1) as usize)
+        .enumerate()
+        .map(|(i, chunk)| (format!("{}.chunk-{}-{}", key, &digest[..16], i), chunk.to_vec()))
+        .collect::<Vec<_>>();
+    let manifest = ChunkManifest {
+        size: data.len() as u64,
+        chunks: chunks.iter().map(|&(ref key, _)| key.clone()).collect(),
+    };
+    let mut bytes = MANIFEST_MAGIC.to_vec();
+    serde_json::to_writer(&mut bytes, &manifest)?;
+    Ok(ChunkedWrite::Chunked { chunks, manifest: bytes })
+}
+
+/// Parse `data` as a manifest, or return `None` if it's an ordinary entry.
+pub fn parse_manifest(data: &[u8]) -> Option<Result<ChunkManifest>> {
+    if !data.starts_with(MANIFEST_MAGIC) {
+        return None
+    }
+    Some(serde_json::from_slice(&data[MANIFEST_MAGIC.len()..]).chain_err(|| "Invalid chunk manifest"))
+}
+
+/// Reassemble an entry from the chunks listed in `manifest`, or return `None`
+/// if any of them is missing.
+pub fn join(manifest: &ChunkManifest, chunks: Vec<Option<Vec<u8>>>) -> Option<Vec<u8>> {
+    if chunks.len() != manifest.chunks.len() {
+        return None
+    }
+    let mut data = Vec::with_capacity(manifest.size as usize);
+    for chunk in chunks {
+        data.extend(chunk?);
+    }
+    if data.len() as u64 == manifest.size { Some(data) } else { None }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_join() {
+        match split("key", b"small".to_vec(), 5).unwrap() {
+            ChunkedWrite::Whole(data) => assert_eq!(data, b"small"),
+            ChunkedWrite::Chunked { .. } => panic!("Unexpected chunking"),
+        }
+        let (chunks, manifest) = match split("key", b"PK0123456789".to_vec(), 5).unwrap() {
+            ChunkedWrite::Chunked { chunks, manifest } => (chunks, manifest),
+            ChunkedWrite::Whole(_) => panic!("Expected chunking"),
+        };
+        assert_eq!(chunks.iter().map(|&(_, ref c)| &c[..]).collect::<Vec<_>>(),
+                   vec![&b"PK012"[..], b"34567", b"89"]);
+        assert!(chunks.iter().all(|&(ref k, _)| k.starts_with("key.chunk-")));
+        assert!(parse_manifest(b"PK0123456789").is_none());
+        let manifest = parse_manifest(&manifest).unwrap().unwrap();
+        assert_eq!(manifest.chunks, chunks.iter().map(|&(ref k, _)| k.clone()).collect::<Vec<_>>());
+        let fetched = || chunks.iter().map(|&(_, ref c)| Some(c.clone())).collect::<Vec<_>>();
+        assert_eq!(join(&manifest, fetched()), Some(b"PK0123456789".to_vec()));
+        // Losing any chunk loses the entry.
+        let mut evicted = fetched();
+        evicted[1] = None;
+        assert_eq!(join(&manifest, evicted), None);
+        let mut truncated = fetched();
+        truncated[2] = Some(vec![]);
+        assert_eq!(join(&manifest, truncated), None);
+    }
+}
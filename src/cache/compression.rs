@@ -0,0 +1,279 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    CompilerDigests,
+    PruneStats,
+    Storage,
+    VerifyStats,
+};
+use cache::shadow::ShadowStats;
+use config::CacheCompression;
+use futures::Future;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use errors::*;
+
+/// A `Storage` that asks for the entries put in another `Storage` to be
+/// compressed with the configured `CacheCompression`.
+pub struct CompressionStorage {
+    inner: Arc<Storage>,
+    compression: CacheCompression,
+}
+
+impl CompressionStorage {
+    /// Wrap `inner`, compressing its entries with `compression`.
+    pub fn new(inner: Arc<Storage>, compression: CacheCompression) -> CompressionStorage {
+        CompressionStorage { inner, compression }
+    }
+}
+
+/// The key entries for `key` compressed with zstd are stored under.
+///
+/// The zip crate can't handle zip's own zstd method, so such entries are zip
+/// files whose objects zip takes as stored, and that versions of sccache predating zstd
+/// support would restore undecoded. Keeping them under a key of their own
+/// means those versions never find them. Keys already in that namespace, as
+/// listed by `Storage::keys`, are left as they are.
+pub fn zstd_key(key: &str) -> String {
+    if key.ends_with(ZSTD_KEY_SUFFIX) {
+        key.to_owned()
+    } else {
+        format!("{}{}", key, ZSTD_KEY_SUFFIX)
+    }
+}
+
+/// What `zstd_key` appends to keys.
+const ZSTD_KEY_SUFFIX: &str = "-zstd";
+
+/// Wrap `storage` so its entries are compressed with `compression`, unless
+/// that's the default anyway.
+pub fn with_compression(storage: Arc<Storage>, compression: CacheCompression) -> Arc<Storage> {
+    if compression == CacheCompression::default() {
+        return storage
+    }
+    Arc::new(CompressionStorage::new(storage, compression))
+}
+
+impl Storage for CompressionStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        match self.compression {
+            // Fall back to entries written without zstd, which are readable
+            // whatever they're compressed with.
+            CacheCompression::Zstd(_) => {
+                let inner = self.inner.clone();
+                let key = key.to_owned();
+                Box::new(self.inner.get(&zstd_key(&key)).and_then(move |cache| match cache {
+                    Cache::Miss => inner.get(&key),
+                    cache => f_ok(cache),
+                }))
+            }
+            _ => self.inner.get(key),
+        }
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        match self.compression {
+            CacheCompression::Zstd(_) => self.inner.put(&zstd_key(key), entry),
+            _ => self.inner.put(key, entry),
+        }
+    }
+
+    fn location(&self) -> String { self.inner.location() }
+    fn current_size(&self) -> Option<u64> { self.inner.current_size() }
+    fn max_size(&self) -> Option<u64> { self.inner.max_size() }
+    fn current_entries(&self) -> Option<u64> { self.inner.current_entries() }
+    fn max_entries(&self) -> Option<u64> { self.inner.max_entries() }
+    fn compression(&self) -> CacheCompression { self.compression }
+    fn shadow_stats(&self) -> Option<ShadowStats> { self.inner.shadow_stats() }
+
+    fn prune_older_than(&self, age: Duration) -> SFuture<PruneStats> {
+        self.inner.prune_older_than(age)
+    }
+
+    fn compiler_digests(&self) -> SFuture<CompilerDigests> {
+        self.inner.compiler_digests()
+    }
+
+    fn purge_stale_compilers(&self, current: HashMap<PathBuf, String>) -> SFuture<PruneStats> {
+        self.inner.purge_stale_compilers(current)
+    }
+
+    fn set_max_size(&self, size: u64) -> SFuture<PruneStats> {
+        self.inner.set_max_size(size)
+    }
+
+    fn remove_label(&self, label: &str) -> SFuture<PruneStats> {
+        self.inner.remove_label(label)
+    }
+
+    fn verify(&self, repair: bool, rate: Option<u32>) -> SFuture<VerifyStats> {
+        self.inner.verify(repair, rate)
+    }
+
+    fn keys(&self) -> SFuture<Vec<String>> {
+        self.inner.keys()
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        match self.compression {
+            // `get` may have found the entry under either key.
+            CacheCompression::Zstd(_) if zstd_key(key) != key => {
+                let inner = self.inner.clone();
+                let key = key.to_owned();
+                Box::new(self.inner.remove(&zstd_key(&key)).and_then(move |()| inner.remove(&key)))
+            }
+            _ => self.inner.remove(key),
+        }
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cache::CacheRead;
+    use cache::disk::DiskCache;
+    use config::DEFAULT_ZSTD_LEVEL;
+    use futures::Future;
+    use futures_cpupool::CpuPool;
+    use std::fs;
+    use std::io::Cursor;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_compression_override() {
+        let td = TempDir::new("sccache").unwrap();
+        let pool = CpuPool::new(1);
+        let data = vec![0; 64 * 1024];
+        let put = |storage: &Storage, key: &str| {
+            let mut entry = CacheWrite::with_compression(storage.compression()).unwrap();
+            entry.put_object("obj", &mut Cursor::new(data.clone()), None).unwrap();
+            storage.put(key, entry).wait().unwrap();
+        };
+        let entry_size = |key: &str| fs::metadata(td.path().join(&key[0..1]).join(&key[1..2]).join(key)).unwrap().len();
+        let disk = Arc::new(DiskCache::new(&td.path(), 1 << 20, None, &pool));
+        let deflated = with_compression(disk.clone(), CacheCompression::Deflate);
+        assert_eq!(deflated.compression(), CacheCompression::Deflate);
+        put(&*deflated, "abcdef");
+        assert!(entry_size("abcdef") < 1024);
+        let stored = with_compression(disk.clone(), CacheCompression::None);
+        assert_eq!(stored.compression(), CacheCompression::None);
+        put(&*stored, "bcdefg");
+        assert!(entry_size("bcdefg") > data.len() as u64);
+        let zstd = with_compression(disk.clone(), CacheCompression::Zstd(19));
+        assert_eq!(zstd.compression(), CacheCompression::Zstd(19));
+        put(&*zstd, "cdefgh");
+        assert!(entry_size(&zstd_key("cdefgh")) < 1024);
+        // Either is readable through the other, and all through zstd.
+        for &(storage, key) in &[(&stored, "abcdef"), (&deflated, "bcdefg"),
+                                 (&zstd, "abcdef"), (&zstd, "cdefgh")] {
+            match storage.get(key).wait().unwrap() {
+                Cache::Hit(mut entry) => {
+                    let mut obj = vec![];
+                    entry.get_object("obj", &mut obj).unwrap();
+                    assert_eq!(obj, data);
+                }
+                _ => panic!("Expected a hit for {}", key),
+            }
+        }
+    }
+
+    #[test]
+    fn test_codec_round_trip() {
+        let data = (0..256 * 1024u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let codecs = [CacheCompression::None, CacheCompression::Deflate,
+                      CacheCompression::Zstd(1), CacheCompression::Zstd(19)];
+        for &codec in &codecs {
+            let mut entry = CacheWrite::with_compression(codec).unwrap();
+            entry.put_object("obj", &mut Cursor::new(data.clone()), Some(0o755)).unwrap();
+            entry.put_object("stdout", &mut Cursor::new(&b"hello"[..]), None).unwrap();
+            let stored = entry.finish().unwrap();
+            match codec {
+                CacheCompression::None => assert!(stored.len() > data.len()),
+                _ => assert!(stored.len() < data.len() / 10, "{:?} didn't compress", codec),
+            }
+            let mut entry = CacheRead::from(Cursor::new(stored)).unwrap();
+            entry.verify().unwrap();
+            assert_eq!(vec!["obj".to_owned(), "stdout".to_owned()], entry.object_names());
+            assert_eq!(data.len() as u64 + 5, entry.size());
+            // Copying an entry recompresses it with the copy's codec.
+            for &to in &codecs {
+                let copy = CacheWrite::from_entry(&mut entry, to).unwrap().finish().unwrap();
+                let mut copy = CacheRead::from(Cursor::new(copy)).unwrap();
+                let mut obj = vec![];
+                assert_eq!(Some(0o755), copy.get_object("obj", &mut obj).unwrap().map(|mode| mode & 0o777));
+                assert_eq!(obj, data);
+                let mut stdout = vec![];
+                copy.get_object("stdout", &mut stdout).unwrap();
+                assert_eq!(&b"hello"[..], &stdout[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_zstd_keys() {
+        let td = TempDir::new("sccache").unwrap();
+        let pool = CpuPool::new(1);
+        let data = vec![1; 64 * 1024];
+        let disk = Arc::new(DiskCache::new(&td.path(), 1 << 20, None, &pool));
+        let zstd = with_compression(disk.clone(), CacheCompression::Zstd(DEFAULT_ZSTD_LEVEL));
+        let mut entry = CacheWrite::with_compression(zstd.compression()).unwrap();
+        entry.put_object("obj", &mut Cursor::new(data.clone()), None).unwrap();
+        zstd.put("abcdef", entry).wait().unwrap();
+        // Readers that don't use zstd, older versions included, don't see it.
+        let default = with_compression(disk.clone(), CacheCompression::default());
+        match default.get("abcdef").wait().unwrap() {
+            Cache::Miss => {}
+            cache => panic!("Expected a miss, got {:?}", cache),
+        }
+        let mut entry = CacheWrite::new().unwrap();
+        entry.put_object("obj", &mut Cursor::new(data.clone()), None).unwrap();
+        default.put("bcdefg", entry).wait().unwrap();
+        assert_eq!("abcdef-zstd", zstd_key("abcdef"));
+        assert_eq!("abcdef-zstd", zstd_key("abcdef-zstd"));
+        // But readers using zstd see both.
+        for key in &["abcdef", "bcdefg"] {
+            match zstd.get(key).wait().unwrap() {
+                Cache::Hit(mut entry) => {
+                    let mut obj = vec![];
+                    entry.get_object("obj", &mut obj).unwrap();
+                    assert_eq!(obj, data);
+                    // Each is read with the codec it was written with.
+                    assert_eq!(*key == "abcdef", entry.is_zstd("obj"));
+                }
+                _ => panic!("Expected a hit for {}", key),
+            }
+        }
+        // Removing an entry through zstd removes it under either key.
+        let mut entry = CacheWrite::new().unwrap();
+        entry.put_object("obj", &mut Cursor::new(data.clone()), None).unwrap();
+        default.put("abcdef", entry).wait().unwrap();
+        zstd.remove("abcdef").wait().unwrap();
+        for storage in &[&zstd, &default] {
+            match storage.get("abcdef").wait().unwrap() {
+                Cache::Miss => {}
+                cache => panic!("Expected a miss, got {:?}", cache),
+            }
+        }
+    }
+}
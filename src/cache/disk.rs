@@ -16,15 +16,29 @@ use cache::{
     Cache,
     CacheRead,
     CacheWrite,
+    CompilerDigests,
+    PruneStats,
+    Provenance,
     Storage,
+    VerifyStats,
+};
+use config::{
+    MinFreeSpace,
+    default_disk_cache_eviction_high_watermark,
+    default_disk_cache_eviction_low_watermark,
 };
 use futures_cpupool::CpuPool;
-use lru_disk_cache::LruDiskCache;
+use lru_disk_cache::{Durability, LruDiskCache};
 use lru_disk_cache::Error as LruError;
-use std::ffi::OsStr;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io;
 use std::path::{Path,PathBuf};
-use std::sync::{Arc, Mutex};
-use std::time::{Instant, Duration};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Instant, Duration, SystemTime};
+use util;
 
 use errors::*;
 
@@ -35,19 +49,210 @@ pub struct DiskCache {
     lru: Arc<Mutex<LruDiskCache>>,
     /// Thread pool to execute disk I/O
     pool: CpuPool,
+    /// How much of the disk to leave free, regardless of the cache size.
+    min_free_space: Option<MinFreeSpace>,
+    /// Wakes the background evictor, if there is one.
+    evictor: Option<Arc<Evictor>>,
+    /// The percentages of its maximum size past which the cache evicts
+    /// entries, and that it evicts them down to.
+    watermarks: (u64, u64),
+}
+
+/// The most entries the background evictor removes at once, before letting
+/// other users of the cache in.
+const EVICTION_BATCH: usize = 64;
+
+/// Wakes the thread evicting entries in the background.
+struct Evictor {
+    pending: Mutex<bool>,
+    wake: Condvar,
+}
+
+impl Evictor {
+    fn wake(&self) {
+        *self.pending.lock().unwrap() = true;
+        self.wake.notify_one();
+    }
 }
 
 impl DiskCache {
-    /// Create a new `DiskCache` rooted at `root`, with `max_size` as the maximum cache size on-disk, in bytes.
+    /// Create a new `DiskCache` rooted at `root`, with `max_size` as the maximum cache size on-disk, in bytes,
+    /// and `max_entries`, if set, as the maximum number of entries.
     pub fn new<T: AsRef<OsStr>>(root: &T,
                                 max_size: u64,
+                                max_entries: Option<usize>,
                                 pool: &CpuPool) -> DiskCache {
         DiskCache {
             //TODO: change this function to return a Result
-            lru: Arc::new(Mutex::new(LruDiskCache::new(root, max_size).expect("Couldn't instantiate disk cache!"))),
+            lru: Arc::new(Mutex::new(LruDiskCache::with_max_entries(root, max_size, max_entries).expect("Couldn't instantiate disk cache!"))),
             pool: pool.clone(),
+            min_free_space: None,
+            evictor: None,
+            watermarks: (default_disk_cache_eviction_high_watermark(), default_disk_cache_eviction_low_watermark()),
         }
     }
+
+    /// Once the cache is fuller than `high` percent of its maximum size,
+    /// evict entries down to `low` percent, so that storing entries in a full
+    /// cache doesn't evict a few entries every time. Must be called before
+    /// `start_background_eviction` to affect it.
+    pub fn set_eviction_watermarks(&mut self, high: u64, low: u64) -> Result<()> {
+        if low > high || high > 100 {
+            bail!("Invalid eviction watermarks {}% and {}%, expected the low one to be at most the high one, \
+                   and both at most 100%", high, low);
+        }
+        self.watermarks = (high, low);
+        Ok(())
+    }
+
+    /// Evict entries on a background thread rather than while storing new
+    /// ones, once the cache is past its high watermark. Entries are still
+    /// evicted while storing if the cache would exceed its limits.
+    pub fn start_background_eviction(&mut self) {
+        let evictor = Arc::new(Evictor { pending: Mutex::new(false), wake: Condvar::new() });
+        let lru = Arc::downgrade(&self.lru);
+        let thread_evictor = evictor.clone();
+        let low = self.watermarks.1;
+        let spawned = thread::Builder::new()
+            .name("disk-cache-evictor".to_owned())
+            .spawn(move || evict_in_background(lru, &thread_evictor, low));
+        match spawned {
+            Ok(_) => self.evictor = Some(evictor),
+            Err(e) => warn!("Couldn't start evicting in the background: {}", e),
+        }
+    }
+
+    /// Protect newly written entries from eviction for `grace`.
+    pub fn set_eviction_grace(&self, grace: Duration) {
+        self.lru.lock().unwrap().set_eviction_grace(grace);
+    }
+
+    /// Set how durably new entries are written.
+    pub fn set_durability(&self, durability: Durability) {
+        self.lru.lock().unwrap().set_durability(durability);
+    }
+
+    /// Stop storing entries when the disk has less than `min_free_space` left.
+    pub fn set_min_free_space(&mut self, min_free_space: Option<MinFreeSpace>) {
+        self.min_free_space = min_free_space;
+    }
+}
+
+/// `percent` percent of `capacity`, rounded to the nearest byte so that
+/// small capacities don't end up with watermarks of 0.
+fn watermark(capacity: u64, percent: u64) -> u64 {
+    // Split up so as not to overflow for capacities near `u64::max_value()`.
+    capacity / 100 * percent + (capacity % 100 * percent + 50) / 100
+}
+
+/// Evict entries from `lru` down to `low` percent of its maximum size
+/// whenever `evictor` is woken, until the cache is gone.
+fn evict_in_background(lru: Weak<Mutex<LruDiskCache>>, evictor: &Evictor, low: u64) {
+    loop {
+        {
+            let mut pending = evictor.pending.lock().unwrap();
+            while !*pending {
+                pending = evictor.wake.wait_timeout(pending, Duration::from_secs(1)).unwrap().0;
+                if !*pending && lru.upgrade().is_none() {
+                    return
+                }
+            }
+            *pending = false;
+        }
+        let cache = match lru.upgrade() {
+            Some(cache) => cache,
+            None => return,
+        };
+        let (mut entries, mut bytes) = (0, 0);
+        loop {
+            // Both the accounting and the files are only changed with the
+            // lock held, so concurrent puts always see a consistent size.
+            let mut lru = cache.lock().unwrap();
+            let low = watermark(lru.capacity(), low);
+            let (n, b) = lru.shrink_to(low, EVICTION_BATCH);
+            entries += n;
+            bytes += b;
+            if n < EVICTION_BATCH {
+                break
+            }
+        }
+        debug!("Evicted {} entries ({} bytes) in the background", entries, bytes);
+    }
+}
+
+/// Make room for an entry of `size` bytes while leaving `min_free_space` of
+/// the disk free, evicting entries regardless of their grace period if needed.
+fn ensure_free_space(lru: &mut LruDiskCache, min_free_space: MinFreeSpace, size: u64) -> Result<()> {
+    let root = lru.path().to_owned();
+    let (available, total) = match util::disk_space(&root) {
+        Ok(space) => space,
+        Err(e) => {
+            warn!("Couldn't get the free space of {:?}: {}", root, e);
+            return Ok(())
+        }
+    };
+    let wanted = min_free_space.bytes(total) + size;
+    if available >= wanted {
+        return Ok(())
+    }
+    let (entries, bytes) = lru.evict(wanted - available);
+    debug!("Low on disk space, evicted {} entries ({} bytes)", entries, bytes);
+    let available = util::disk_space(&root)?.0;
+    if available < wanted {
+        bail!(ErrorKind::WriteSuppressed(format!("only {} bytes free on {:?}", available, root)));
+    }
+    Ok(())
+}
+
+/// Check that the cache entry at `path` can be read back intact.
+fn verify_entry(path: &Path) -> Result<()> {
+    CacheRead::from(File::open(path)?)?.verify()
+}
+
+/// The provenance of the entry at `path`, if it has one and can be read.
+fn read_provenance(path: &Path) -> Option<Provenance> {
+    File::open(path)
+        .map_err(Error::from)
+        .and_then(CacheRead::from)
+        .and_then(|mut entry| entry.get_provenance())
+        .unwrap_or(None)
+}
+
+/// Call `f` with the `Provenance` of each entry in `lru` that has one, along
+/// with the key it's stored under. Entries are read without holding the lock,
+/// so compilations can carry on using the cache meanwhile.
+fn for_each_provenance<F>(lru: &Mutex<LruDiskCache>, mut f: F)
+    where F: FnMut(&OsString, &Path, Provenance),
+{
+    let keys = lru.lock().unwrap().keys();
+    for rel_path in keys {
+        let path = lru.lock().unwrap().rel_to_abs_path(&rel_path);
+        if let Some(provenance) = read_provenance(&path) {
+            f(&rel_path, &path, provenance);
+        }
+    }
+}
+
+/// Remove the entries in `lru` whose `Provenance` matches `f`. Entries that
+/// predate provenance or can't be read are kept.
+fn remove_where<F>(lru: &Mutex<LruDiskCache>, f: F) -> PruneStats
+    where F: Fn(&Provenance) -> bool,
+{
+    let mut stats = PruneStats::default();
+    for_each_provenance(lru, |rel_path, path, provenance| {
+        if !f(&provenance) {
+            return
+        }
+        let mut lru = lru.lock().unwrap();
+        // The entry may have been replaced since it was read.
+        if read_provenance(path).map_or(false, |p| f(&p)) {
+            if let Some(size) = lru.remove(rel_path) {
+                stats.entries += 1;
+                stats.bytes += size;
+            }
+        }
+    });
+    stats
 }
 
 /// Make a path to the cache entry with key `key`.
@@ -81,15 +286,35 @@ impl Storage for DiskCache {
     }
 
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
-        // We should probably do this on a background thread if we're going to buffer
-        // everything in memory...
         trace!("DiskCache::finish_put({})", key);
         let lru = self.lru.clone();
         let key = make_key_path(key);
+        let min_free_space = self.min_free_space;
+        let evictor = self.evictor.clone();
+        let (high, low) = self.watermarks;
         Box::new(self.pool.spawn_fn(move || {
             let start = Instant::now();
-            let v = entry.finish()?;
-            lru.lock().unwrap().insert_bytes(key, &v)?;
+            let mut data = entry.into_file()?;
+            let size = data.metadata()?.len();
+            let mut lru = lru.lock().unwrap();
+            if !lru.can_store(size) {
+                return Err(LruError::FileTooLarge.into())
+            }
+            if let Some(min_free_space) = min_free_space {
+                ensure_free_space(&mut lru, min_free_space, size)?;
+            }
+            lru.insert_with(key, |mut f| io::copy(&mut data, &mut f).map(|_| ()))?;
+            if lru.size() > watermark(lru.capacity(), high) {
+                match evictor {
+                    Some(evictor) => evictor.wake(),
+                    None => {
+                        // With the lock held, as in the background.
+                        let low = watermark(lru.capacity(), low);
+                        let (entries, bytes) = lru.shrink_to(low, usize::max_value());
+                        debug!("Evicted {} entries ({} bytes) down to the low watermark", entries, bytes);
+                    }
+                }
+            }
             Ok(start.elapsed())
         }))
     }
@@ -100,4 +325,292 @@ impl Storage for DiskCache {
 
     fn current_size(&self) -> Option<u64> { Some(self.lru.lock().unwrap().size()) }
     fn max_size(&self) -> Option<u64> { Some(self.lru.lock().unwrap().capacity()) }
+    fn current_entries(&self) -> Option<u64> { Some(self.lru.lock().unwrap().len() as u64) }
+    fn max_entries(&self) -> Option<u64> { self.lru.lock().unwrap().max_entries().map(|n| n as u64) }
+
+    fn prune_older_than(&self, age: Duration) -> SFuture<PruneStats> {
+        let lru = self.lru.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            let cutoff = SystemTime::now() - age;
+            let (entries, bytes) = lru.lock().unwrap().remove_older_than(cutoff);
+            Ok(PruneStats { entries: entries as u64, bytes })
+        }))
+    }
+
+    fn compiler_digests(&self) -> SFuture<CompilerDigests> {
+        let lru = self.lru.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            let mut digests = CompilerDigests::new();
+            for_each_provenance(&lru, |_, _, provenance| {
+                digests.entry(provenance.compiler).or_insert_with(Default::default)
+                    .insert(provenance.compiler_digest);
+            });
+            Ok(digests)
+        }))
+    }
+
+    fn purge_stale_compilers(&self, current: HashMap<PathBuf, String>) -> SFuture<PruneStats> {
+        let lru = self.lru.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            Ok(remove_where(&lru, |provenance| provenance.is_stale(&current)))
+        }))
+    }
+
+    fn set_max_size(&self, size: u64) -> SFuture<PruneStats> {
+        let lru = self.lru.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            let (entries, bytes) = lru.lock().unwrap().set_capacity(size);
+            Ok(PruneStats { entries: entries as u64, bytes })
+        }))
+    }
+
+    fn remove_label(&self, label: &str) -> SFuture<PruneStats> {
+        let lru = self.lru.clone();
+        let label = label.to_owned();
+        Box::new(self.pool.spawn_fn(move || {
+            Ok(remove_where(&lru, |provenance| provenance.label.as_ref() == Some(&label)))
+        }))
+    }
+
+    fn verify(&self, repair: bool, rate: Option<u32>) -> SFuture<VerifyStats> {
+        let lru = self.lru.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            let interval = rate.map(|rate| Duration::from_secs(1) / rate.max(1));
+            let mut stats = VerifyStats::default();
+            let keys = lru.lock().unwrap().keys();
+            for rel_path in keys {
+                let start = Instant::now();
+                // Entries are read without holding the lock, so compilations
+                // can carry on using the cache meanwhile.
+                let path = lru.lock().unwrap().rel_to_abs_path(&rel_path);
+                let res = verify_entry(&path);
+                if res.is_err() && !path.exists() {
+                    // Evicted since the keys were listed.
+                    continue
+                }
+                stats.checked += 1;
+                if let Err(e) = res {
+                    let key = Path::new(&rel_path).file_name().unwrap_or(rel_path.as_os_str()).to_string_lossy().into_owned();
+                    warn!("Cache entry {} is corrupt: {}", key, e);
+                    if repair {
+                        let mut lru = lru.lock().unwrap();
+                        // The entry may have been replaced since it was checked.
+                        if verify_entry(&path).is_err() {
+                            if let Some(size) = lru.remove(&rel_path) {
+                                stats.removed.entries += 1;
+                                stats.removed.bytes += size;
+                            }
+                        }
+                    }
+                    stats.corrupt.push(key);
+                }
+                if let Some(interval) = interval {
+                    let elapsed = start.elapsed();
+                    if elapsed < interval {
+                        thread::sleep(interval - elapsed);
+                    }
+                }
+            }
+            Ok(stats)
+        }))
+    }
+
+    fn keys(&self) -> SFuture<Vec<String>> {
+        let lru = self.lru.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            let keys = lru.lock().unwrap().keys();
+            Ok(keys.iter()
+               .filter_map(|rel_path| Path::new(rel_path).file_name())
+               .map(|key| key.to_string_lossy().into_owned())
+               .collect())
+        }))
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        let lru = self.lru.clone();
+        let path = make_key_path(key);
+        Box::new(self.pool.spawn_fn(move || {
+            lru.lock().unwrap().remove(&path);
+            Ok(())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::Future;
+    use std::fs::{self, OpenOptions};
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    use tempdir::TempDir;
+    use test::utils::incompressible_data;
+
+    #[test]
+    fn test_verify() {
+        let td = TempDir::new("sccache").unwrap();
+        let pool = CpuPool::new(1);
+        let cache = DiskCache::new(&td.path(), 1 << 20, None, &pool);
+        for key in &["aaaaaa", "bbbbbb", "cccccc"] {
+            let mut entry = CacheWrite::new().unwrap();
+            // Data that doesn't compress, so the byte flipped below is in it.
+            let data = incompressible_data();
+            entry.put_object("obj", &mut Cursor::new(data), None).unwrap();
+            cache.put(key, entry).wait().unwrap();
+        }
+        // Truncate one entry, as a crash might, and flip a byte in the object of another.
+        let truncated = td.path().join(make_key_path("bbbbbb"));
+        let len = fs::metadata(&truncated).unwrap().len();
+        OpenOptions::new().write(true).open(&truncated).unwrap().set_len(len / 2).unwrap();
+        let flipped = td.path().join(make_key_path("cccccc"));
+        let mut f = OpenOptions::new().read(true).write(true).open(&flipped).unwrap();
+        let mut byte = [0];
+        f.seek(SeekFrom::Start(40)).unwrap();
+        f.read_exact(&mut byte).unwrap();
+        f.seek(SeekFrom::Start(40)).unwrap();
+        f.write_all(&[!byte[0]]).unwrap();
+        drop(f);
+
+        let stats = cache.verify(false, None).wait().unwrap();
+        assert_eq!(stats.checked, 3);
+        let mut corrupt = stats.corrupt.clone();
+        corrupt.sort();
+        assert_eq!(corrupt, vec!["bbbbbb".to_owned(), "cccccc".to_owned()]);
+        assert_eq!(stats.removed, PruneStats::default());
+        assert!(truncated.exists());
+
+        let stats = cache.verify(true, Some(1000)).wait().unwrap();
+        assert_eq!(stats.removed.entries, 2);
+        assert!(!truncated.exists() && !flipped.exists());
+        assert_eq!(cache.current_entries(), Some(1));
+        match cache.get("aaaaaa").wait().unwrap() {
+            Cache::Hit(_) => {}
+            c => panic!("Expected the intact entry to be kept, got {:?}", c),
+        }
+    }
+
+    #[test]
+    fn test_purge_stale_compilers() {
+        let td = TempDir::new("sccache").unwrap();
+        let pool = CpuPool::new(1);
+        let cache = DiskCache::new(&td.path(), 1 << 20, None, &pool);
+        let cc = td.path().join("cc");
+        File::create(&cc).unwrap();
+        let entries = [("aaaaaa", &cc, "old"), ("bbbbbb", &cc, "new"),
+                       ("cccccc", &td.path().join("gone"), "new")];
+        for &(key, compiler, digest) in &entries {
+            let mut entry = CacheWrite::new().unwrap();
+            entry.put_provenance(&Provenance {
+                hostname: None,
+                sccache_version: "0".to_owned(),
+                compiler: compiler.clone(),
+                compiler_digest: digest.to_owned(),
+                timestamp: 0,
+                label: None,
+            }).unwrap();
+            cache.put(key, entry).wait().unwrap();
+        }
+        let digests = cache.compiler_digests().wait().unwrap();
+        assert_eq!(2, digests.len());
+        assert_eq!(2, digests[&cc].len());
+        let mut current = HashMap::new();
+        current.insert(cc.clone(), "new".to_owned());
+        assert_eq!(2, cache.purge_stale_compilers(current).wait().unwrap().entries);
+        assert_eq!(Some(1), cache.current_entries());
+        // Entries from compilers that exist but weren't detected are kept.
+        assert_eq!(0, cache.purge_stale_compilers(HashMap::new()).wait().unwrap().entries);
+    }
+
+    #[test]
+    fn test_min_free_space() {
+        let td = TempDir::new("sccache").unwrap();
+        let pool = CpuPool::new(1);
+        let mut cache = DiskCache::new(&td.path(), 1 << 20, None, &pool);
+        let put = |cache: &DiskCache, key: &str| {
+            let mut entry = CacheWrite::new().unwrap();
+            entry.put_object("obj", &mut Cursor::new(vec![1; 1024]), None).unwrap();
+            cache.put(key, entry).wait()
+        };
+        cache.set_min_free_space(Some(MinFreeSpace::Bytes(0)));
+        put(&cache, "aaaaaa").unwrap();
+        assert_eq!(cache.current_entries(), Some(1));
+        // No disk is ever entirely free, so this evicts everything and
+        // suppresses the write.
+        cache.set_min_free_space(Some(MinFreeSpace::Percent(100)));
+        match put(&cache, "bbbbbb") {
+            Err(Error(ErrorKind::WriteSuppressed(_), _)) => {}
+            r => panic!("Expected a suppressed write, got {:?}", r),
+        }
+        assert_eq!(cache.current_entries(), Some(0));
+        assert!(!td.path().join(make_key_path("aaaaaa")).exists());
+    }
+
+    #[test]
+    fn test_background_eviction() {
+        let td = TempDir::new("sccache").unwrap();
+        let pool = CpuPool::new(1);
+        let max_size = 100 * 1024;
+        let mut cache = DiskCache::new(&td.path(), max_size, None, &pool);
+        cache.start_background_eviction();
+        let data = incompressible_data();
+        // More than fits, so that evicting only while storing would leave the
+        // cache nearly full.
+        for i in 0..30 {
+            let mut entry = CacheWrite::new().unwrap();
+            entry.put_object("obj", &mut Cursor::new(data.clone()), None).unwrap();
+            cache.put(&format!("{:06}", i), entry).wait().unwrap();
+        }
+        for _ in 0..500 {
+            if cache.current_size().unwrap() <= watermark(max_size, default_disk_cache_eviction_low_watermark()) {
+                break
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(cache.current_size().unwrap() <= watermark(max_size, default_disk_cache_eviction_low_watermark()));
+        // The oldest entries were evicted and the newest kept.
+        assert!(!td.path().join(make_key_path("000000")).exists());
+        assert!(td.path().join(make_key_path("000029")).exists());
+    }
+
+    #[test]
+    fn test_watermark() {
+        assert_eq!(watermark(1000, 90), 900);
+        assert_eq!(watermark(50, 90), 45);
+        assert_eq!(watermark(10, 95), 10);
+        assert_eq!(watermark(10, 90), 9);
+        assert_eq!(watermark(1, 40), 0);
+        assert_eq!(watermark(199, 50), 100);
+        assert_eq!(watermark(u64::max_value(), 100), u64::max_value());
+    }
+
+    #[test]
+    fn test_eviction_watermarks() {
+        let td = TempDir::new("sccache").unwrap();
+        let pool = CpuPool::new(1);
+        let max_size = 100 * 1024;
+        let mut cache = DiskCache::new(&td.path(), max_size, None, &pool);
+        assert!(cache.set_eviction_watermarks(50, 80).is_err());
+        assert!(cache.set_eviction_watermarks(101, 80).is_err());
+        cache.set_eviction_watermarks(80, 50).unwrap();
+        let data = incompressible_data();
+        let mut evictions = 0;
+        let mut size = 0;
+        for i in 0..30 {
+            let mut entry = CacheWrite::new().unwrap();
+            entry.put_object("obj", &mut Cursor::new(data.clone()), None).unwrap();
+            cache.put(&format!("{:06}", i), entry).wait().unwrap();
+            let new_size = cache.current_size().unwrap();
+            assert!(new_size <= watermark(max_size, 80));
+            if new_size < size {
+                // Going past the high watermark evicted down to the low one at once.
+                assert!(new_size <= watermark(max_size, 50));
+                evictions += 1;
+            }
+            size = new_size;
+        }
+        // Rather than a few entries on every put once the cache was full.
+        assert!(evictions > 0 && evictions < 10, "{} evictions", evictions);
+        // The oldest entries were evicted and the newest kept.
+        assert!(!td.path().join(make_key_path("000000")).exists());
+        assert!(td.path().join(make_key_path("000029")).exists());
+    }
 }
@@ -0,0 +1,326 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Encrypting cache entries at rest.
+//!
+//! An encrypted entry is a cache entry holding two objects: a header naming
+//! the key it was encrypted with, and the original entry encrypted with
+//! ChaCha20-Poly1305. The cache key is authenticated along with it, so an
+//! entry can't be passed off as the result stored under another key.
+
+use base64;
+use cache::{
+    Cache,
+    CacheRead,
+    CacheWrite,
+    CompilerDigests,
+    PruneStats,
+    Storage,
+    VerifyStats,
+};
+use cache::shadow::ShadowStats;
+use config::{CacheCompression, EncryptionConfig};
+use futures::Future;
+use ring::aead::{self, CHACHA20_POLY1305, OpeningKey, SealingKey};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use errors::*;
+
+/// The name of the object holding an encrypted entry's `EncryptionHeader`.
+const HEADER_OBJECT: &str = "encryption";
+/// The name of the object holding the encrypted entry itself.
+const CIPHERTEXT_OBJECT: &str = "ciphertext";
+const ALGORITHM: &str = "chacha20-poly1305";
+
+/// How an entry was encrypted.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct EncryptionHeader {
+    algorithm: String,
+    key_id: String,
+    /// The base64 encoded nonce.
+    nonce: String,
+}
+
+/// A `Storage` that encrypts entries before storing them in another
+/// `Storage`, and decrypts them when they're read back.
+pub struct EncryptedStorage {
+    inner: Arc<Storage>,
+    /// The id of the key new entries are encrypted with, if any.
+    key_id: Option<String>,
+    keys: Arc<HashMap<String, Vec<u8>>>,
+    rng: SystemRandom,
+}
+
+impl EncryptedStorage {
+    /// Wrap `inner`, encrypting new entries with the key `key_id` and reading
+    /// entries encrypted with any of `keys`. If `key_id` is `None`, new entries
+    /// are stored unencrypted.
+    pub fn new(inner: Arc<Storage>, key_id: Option<String>, keys: HashMap<String, Vec<u8>>) -> Result<EncryptedStorage> {
+        for (id, key) in keys.iter() {
+            if key.len() != CHACHA20_POLY1305.key_len() {
+                bail!("Encryption key `{}` is {} bytes long, expected {}", id, key.len(), CHACHA20_POLY1305.key_len())
+            }
+        }
+        if let Some(ref id) = key_id {
+            if !keys.contains_key(id) {
+                bail!("No encryption key with id `{}` is configured", id)
+            }
+        }
+        Ok(EncryptedStorage { inner, key_id, keys: Arc::new(keys), rng: SystemRandom::new() })
+    }
+
+    /// Encrypt `entry`, to be stored under `key`, with the key `key_id`.
+    fn encrypt(&self, key: &str, key_id: &str, entry: CacheWrite) -> Result<CacheWrite> {
+        let mut nonce = vec![0; CHACHA20_POLY1305.nonce_len()];
+        self.rng.fill(&mut nonce).map_err(|_| Error::from("Failed to generate a nonce"))?;
+        let ciphertext = seal(&self.keys[key_id], &nonce, key, entry.finish()?)?;
+        let header = EncryptionHeader {
+            algorithm: ALGORITHM.to_owned(),
+            key_id: key_id.to_owned(),
+            nonce: base64::encode(&nonce),
+        };
+        // The ciphertext doesn't compress.
+        let mut encrypted = CacheWrite::with_compression(CacheCompression::None)?;
+        encrypted.put_object(HEADER_OBJECT, &mut &serde_json::to_vec(&header)?[..], None)?;
+        encrypted.put_object(CIPHERTEXT_OBJECT, &mut &ciphertext[..], None)?;
+        Ok(encrypted)
+    }
+}
+
+/// Wrap `storage` in an `EncryptedStorage` if `config` has any keys.
+pub fn encrypted_storage_from_config(storage: Arc<Storage>, config: &EncryptionConfig) -> Result<Arc<Storage>> {
+    if config.key_id.is_none() && config.keys.is_empty() {
+        return Ok(storage)
+    }
+    let keys = config.keys.iter()
+        .map(|(id, path)| -> Result<_> {
+            let key = read_key_file(path).chain_err(|| format!("Failed to read encryption key `{}` from {:?}", id, path))?;
+            Ok((id.clone(), key))
+        })
+        .collect::<Result<_>>()?;
+    Ok(Arc::new(EncryptedStorage::new(storage, config.key_id.clone(), keys)?))
+}
+
+fn read_key_file(path: &Path) -> Result<Vec<u8>> {
+    let mut key = String::new();
+    File::open(path)?.read_to_string(&mut key)?;
+    base64::decode(key.trim()).chain_err(|| "Key isn't valid base64")
+}
+
+/// Encrypt `data`, to be stored under `cache_key`.
+fn seal(key: &[u8], nonce: &[u8], cache_key: &str, mut data: Vec<u8>) -> Result<Vec<u8>> {
+    let key = SealingKey::new(&CHACHA20_POLY1305, key).map_err(|_| Error::from("Invalid encryption key"))?;
+    let tag_len = CHACHA20_POLY1305.tag_len();
+    data.extend(vec![0; tag_len]);
+    let len = aead::seal_in_place(&key, nonce, cache_key.as_bytes(), &mut data, tag_len)
+        .map_err(|_| Error::from("Failed to encrypt cache entry"))?;
+    data.truncate(len);
+    Ok(data)
+}
+
+/// Decrypt and authenticate `data`, which was stored under `cache_key`.
+fn open(key: &[u8], nonce: &[u8], cache_key: &str, mut data: Vec<u8>) -> Result<Vec<u8>> {
+    let key = OpeningKey::new(&CHACHA20_POLY1305, key).map_err(|_| Error::from("Invalid encryption key"))?;
+    let len = aead::open_in_place(&key, nonce, cache_key.as_bytes(), 0, &mut data)
+        .map_err(|_| Error::from("Failed to decrypt cache entry, it may have been tampered with"))?
+        .len();
+    data.truncate(len);
+    Ok(data)
+}
+
+/// Decrypt `entry`, stored under `key`. Entries encrypted with a key that
+/// isn't in `keys` are misses, as are unencrypted entries if `required`.
+fn decrypt(keys: &HashMap<String, Vec<u8>>, required: bool, key: &str, mut entry: CacheRead) -> Result<Cache> {
+    if !entry.has_object(HEADER_OBJECT) {
+        if required {
+            debug!("Ignoring unencrypted cache entry {}", key);
+            return Ok(Cache::Miss)
+        }
+        return Ok(Cache::Hit(entry))
+    }
+    let mut header = vec![];
+    entry.get_object(HEADER_OBJECT, &mut header)?;
+    let header: EncryptionHeader = serde_json::from_slice(&header)
+        .chain_err(|| "Failed to parse cache entry encryption header")?;
+    let secret = match keys.get(&header.key_id) {
+        Some(secret) if header.algorithm == ALGORITHM => secret,
+        _ => {
+            debug!("Cache entry {} was encrypted with unknown {} key `{}`, ignoring it",
+                   key, header.algorithm, header.key_id);
+            return Ok(Cache::Miss)
+        }
+    };
+    let nonce = base64::decode(&header.nonce).chain_err(|| "Invalid cache entry nonce")?;
+    let mut ciphertext = vec![];
+    entry.get_object(CIPHERTEXT_OBJECT, &mut ciphertext)?;
+    let data = open(secret, &nonce, key, ciphertext)?;
+    Ok(Cache::Hit(CacheRead::from(Cursor::new(data))?))
+}
+
+impl Storage for EncryptedStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let keys = self.keys.clone();
+        let required = self.key_id.is_some();
+        let key = key.to_owned();
+        Box::new(self.inner.get(&key).and_then(move |cache| match cache {
+            Cache::Hit(entry) => decrypt(&keys, required, &key, entry),
+            cache => Ok(cache),
+        }))
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let entry = match self.key_id {
+            Some(ref key_id) => ftry!(self.encrypt(key, key_id, entry)),
+            None => entry,
+        };
+        self.inner.put(key, entry)
+    }
+
+    fn location(&self) -> String { format!("{}, encrypted", self.inner.location()) }
+    fn current_size(&self) -> Option<u64> { self.inner.current_size() }
+    fn max_size(&self) -> Option<u64> { self.inner.max_size() }
+    fn current_entries(&self) -> Option<u64> { self.inner.current_entries() }
+    fn max_entries(&self) -> Option<u64> { self.inner.max_entries() }
+    fn compression(&self) -> CacheCompression { self.inner.compression() }
+    fn shadow_stats(&self) -> Option<ShadowStats> { self.inner.shadow_stats() }
+
+    fn prune_older_than(&self, age: Duration) -> SFuture<PruneStats> {
+        self.inner.prune_older_than(age)
+    }
+
+    fn compiler_digests(&self) -> SFuture<CompilerDigests> {
+        self.inner.compiler_digests()
+    }
+
+    fn purge_stale_compilers(&self, current: HashMap<PathBuf, String>) -> SFuture<PruneStats> {
+        self.inner.purge_stale_compilers(current)
+    }
+
+    fn set_max_size(&self, size: u64) -> SFuture<PruneStats> {
+        self.inner.set_max_size(size)
+    }
+
+    fn remove_label(&self, label: &str) -> SFuture<PruneStats> {
+        self.inner.remove_label(label)
+    }
+
+    fn verify(&self, repair: bool, rate: Option<u32>) -> SFuture<VerifyStats> {
+        self.inner.verify(repair, rate)
+    }
+
+    fn keys(&self) -> SFuture<Vec<String>> {
+        self.inner.keys()
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        self.inner.remove(key)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cache::disk::DiskCache;
+    use futures_cpupool::CpuPool;
+    use std::fs;
+    use tempdir::TempDir;
+
+    fn entry() -> CacheWrite {
+        let mut entry = CacheWrite::new().unwrap();
+        entry.put_object("obj", &mut Cursor::new(b"secret object".to_vec()), None).unwrap();
+        entry
+    }
+
+    fn keys(ids: &[&str]) -> HashMap<String, Vec<u8>> {
+        ids.iter().enumerate().map(|(i, id)| (id.to_string(), vec![i as u8; 32])).collect()
+    }
+
+    fn get_object(cache: Cache) -> Option<Vec<u8>> {
+        match cache {
+            Cache::Hit(mut entry) => {
+                let mut obj = vec![];
+                entry.get_object("obj", &mut obj).ok().map(|_| obj)
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_encrypted_storage() {
+        let td = TempDir::new("sccache").unwrap();
+        let pool = CpuPool::new(1);
+        let disk: Arc<Storage> = Arc::new(DiskCache::new(&td.path(), 1 << 20, None, &pool));
+        let old = EncryptedStorage::new(disk.clone(), Some("old".to_owned()), keys(&["old"])).unwrap();
+        old.put("abcdef", entry()).wait().unwrap();
+        assert_eq!(get_object(old.get("abcdef").wait().unwrap()), Some(b"secret object".to_vec()));
+        // The entry isn't readable without the key.
+        let mut raw = vec![];
+        File::open(td.path().join("a/b/abcdef")).unwrap().read_to_end(&mut raw).unwrap();
+        assert!(!raw.windows(6).any(|w| w == b"secret"));
+        assert_eq!(get_object(disk.get("abcdef").wait().unwrap()), None);
+
+        // After rotating keys, entries encrypted with the old key can still be read.
+        let rotated = EncryptedStorage::new(disk.clone(), Some("new".to_owned()), keys(&["old", "new"])).unwrap();
+        assert_eq!(get_object(rotated.get("abcdef").wait().unwrap()), Some(b"secret object".to_vec()));
+        // Once the old key is dropped, they're misses.
+        let new = EncryptedStorage::new(disk.clone(), Some("new".to_owned()), keys(&["new"])).unwrap();
+        match new.get("abcdef").wait().unwrap() {
+            Cache::Miss => {}
+            c => panic!("Expected a miss for an unknown key, got {:?}", c),
+        }
+        // Unencrypted entries are misses while encryption is on.
+        disk.put("123456", entry()).wait().unwrap();
+        match new.get("123456").wait().unwrap() {
+            Cache::Miss => {}
+            c => panic!("Expected a miss for an unencrypted entry, got {:?}", c),
+        }
+    }
+
+    #[test]
+    fn test_encrypted_entry_bound_to_key() {
+        let td = TempDir::new("sccache").unwrap();
+        let pool = CpuPool::new(1);
+        let disk: Arc<Storage> = Arc::new(DiskCache::new(&td.path(), 1 << 20, None, &pool));
+        let storage = EncryptedStorage::new(disk.clone(), Some("k".to_owned()), keys(&["k"])).unwrap();
+        storage.put("abcdef", entry()).wait().unwrap();
+        // An entry copied to another key fails to authenticate.
+        fs::create_dir_all(td.path().join("f/e")).unwrap();
+        fs::copy(td.path().join("a/b/abcdef"), td.path().join("f/e/fedcba")).unwrap();
+        let disk: Arc<Storage> = Arc::new(DiskCache::new(&td.path(), 1 << 20, None, &pool));
+        let storage = EncryptedStorage::new(disk, Some("k".to_owned()), keys(&["k"])).unwrap();
+        assert!(storage.get("fedcba").wait().is_err());
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        let pool = CpuPool::new(1);
+        let td = TempDir::new("sccache").unwrap();
+        let disk: Arc<Storage> = Arc::new(DiskCache::new(&td.path(), 1 << 20, None, &pool));
+        assert!(EncryptedStorage::new(disk.clone(), Some("missing".to_owned()), keys(&["k"])).is_err());
+        let mut short = keys(&[]);
+        short.insert("short".to_owned(), vec![0; 16]);
+        assert!(EncryptedStorage::new(disk, None, short).is_err());
+    }
+}
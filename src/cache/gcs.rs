@@ -39,6 +39,7 @@ use serde_json;
 use tokio_core::reactor::Handle;
 use url::form_urlencoded;
 use url::percent_encoding::{percent_encode, PATH_SEGMENT_ENCODE_SET, QUERY_ENCODE_SET};
+use util::{get_resumable, range_from};
 
 use errors::*;
 
@@ -79,26 +80,16 @@ impl Bucket {
         };
 
         Box::new(creds_opt_future.and_then(move |creds_opt| {
-            let mut request = Request::new(Method::Get, url.parse().unwrap());
-            if let Some(creds) = creds_opt {
-                request.headers_mut()
-                    .set(Authorization(Bearer { token: creds.token }));
-            }
-            client.request(request).chain_err(move || {
-                format!("failed GET: {}", url)
-            }).and_then(|res| {
-                if res.status().is_success() {
-                    Ok(res.body())
-                } else {
-                    Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            get_resumable(client, move |offset| {
+                let mut request = Request::new(Method::Get, url.parse().unwrap());
+                if let Some(ref creds) = creds_opt {
+                    request.headers_mut()
+                        .set(Authorization(Bearer { token: creds.token.clone() }));
+                }
+                if offset > 0 {
+                    request.headers_mut().set(range_from(offset));
                 }
-            }).and_then(|body| {
-                body.fold(Vec::new(), |mut body, chunk| {
-                    body.extend_from_slice(&chunk);
-                    Ok::<_, hyper::Error>(body)
-                }).chain_err(|| {
-                    "failed to read HTTP body"
-                })
+                request
             })
         }))
     }
@@ -375,6 +366,15 @@ impl Storage for GCSCache {
         format!("GCS, bucket: {}", self.bucket)
     }
 
+    fn is_read_only(&self) -> bool {
+        match self.rw_mode {
+            RWMode::ReadOnly => true,
+            RWMode::ReadWrite => false,
+        }
+    }
+
     fn current_size(&self) -> Option<u64> { None }
     fn max_size(&self) -> Option<u64> { None }
+    fn current_entries(&self) -> Option<u64> { None }
+    fn max_entries(&self) -> Option<u64> { None }
 }
@@ -0,0 +1,375 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A cache in the GitHub Actions cache service, through the API the Actions
+//! runner gives jobs at `ACTIONS_CACHE_URL`.
+//!
+//! Each entry is stored as a cache of its own, keyed by the entry's key. A
+//! cache is stored by reserving it, uploading its contents in chunks and
+//! committing it, after which it can't be changed.
+
+use cache::{
+    Cache,
+    CacheRead,
+    CacheWrite,
+    Storage,
+};
+use errors::*;
+use futures_cpupool::CpuPool;
+use reqwest::{self, StatusCode};
+use reqwest::header::{Authorization, Bearer, ContentType, Headers};
+use std::env;
+use std::io::{Cursor, Read};
+use std::time::{
+    Duration,
+    Instant,
+};
+use util::Digest;
+
+/// The version of the API the requests are made for.
+const API_VERSION: &str = "application/json;api-version=6.0-preview.1";
+
+/// The size of the chunks entries are uploaded in.
+const CHUNK_SIZE: usize = 32 * 1024 * 1024;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReserveRequest<'a> {
+    key: &'a str,
+    version: &'a str,
+    cache_size: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReserveResponse {
+    cache_id: u64,
+}
+
+#[derive(Serialize)]
+struct CommitRequest {
+    size: u64,
+}
+
+/// A stored cache, as found by a lookup.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheEntry {
+    cache_key: String,
+    /// Where to download the cache from, without authentication.
+    archive_location: String,
+}
+
+/// A cache that stores entries in the GitHub Actions cache service.
+#[derive(Clone)]
+pub struct GHACache {
+    /// The URL of the cache API.
+    url: String,
+    token: String,
+    /// The digest of the configured version, which the service keeps caches
+    /// with the same key but different versions apart by.
+    version: String,
+    chunk_size: usize,
+    client: reqwest::Client,
+    pool: CpuPool,
+}
+
+impl GHACache {
+    /// Create a new `GHACache` using the cache service at `url`, authenticating with `token`,
+    /// and keeping entries stored with different `version`s apart.
+    pub fn new(url: &str, token: &str, version: &str, pool: &CpuPool) -> Result<GHACache> {
+        let mut url = url.to_owned();
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        url.push_str("_apis/artifactcache/");
+        let mut m = Digest::new();
+        m.update(b"sccache\0");
+        m.update(version.as_bytes());
+        Ok(GHACache {
+            url,
+            token: token.to_owned(),
+            version: m.finish(),
+            chunk_size: CHUNK_SIZE,
+            client: reqwest::Client::new(),
+            pool: pool.clone(),
+        })
+    }
+
+    /// Create a new `GHACache` using the cache service the Actions runner gives jobs.
+    pub fn from_env(version: &str, pool: &CpuPool) -> Result<GHACache> {
+        let url = env::var("ACTIONS_CACHE_URL")
+            .chain_err(|| "ACTIONS_CACHE_URL must be set, as it is in GitHub Actions jobs")?;
+        let token = env::var("ACTIONS_RUNTIME_TOKEN")
+            .chain_err(|| "ACTIONS_RUNTIME_TOKEN must be set, as it is in GitHub Actions jobs")?;
+        GHACache::new(&url, &token, version, pool)
+    }
+
+    fn headers(&self) -> Headers {
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer { token: self.token.clone() }));
+        headers.set_raw("Accept", API_VERSION);
+        headers
+    }
+
+    /// Where to download the cache stored under `key` from, if there is one.
+    fn lookup(&self, key: &str) -> Result<Option<String>> {
+        let url = format!("{}cache?keys={}&version={}", self.url, key, self.version);
+        let mut res = self.client.get(&url).headers(self.headers()).send()?;
+        match res.status() {
+            StatusCode::NoContent => Ok(None),
+            status if status.is_success() => {
+                let entry: CacheEntry = res.json()?;
+                // Caches whose keys only start with `key` are returned too.
+                if entry.cache_key == key {
+                    Ok(Some(entry.archive_location))
+                } else {
+                    Ok(None)
+                }
+            }
+            status => bail!("Looking up {} in the GitHub Actions cache failed: {}", key, status),
+        }
+    }
+
+    /// Reserve a cache of `size` bytes to store under `key`, returning its ID,
+    /// or `None` if one is already stored or being stored under it.
+    fn reserve(&self, key: &str, size: u64) -> Result<Option<u64>> {
+        let request = ReserveRequest { key, version: &self.version, cache_size: size };
+        let mut res = self.client.post(&format!("{}caches", self.url))
+            .headers(self.headers())
+            .json(&request)
+            .send()?;
+        match res.status() {
+            StatusCode::Conflict => Ok(None),
+            status if status.is_success() => Ok(Some(res.json::<ReserveResponse>()?.cache_id)),
+            status => bail!("Reserving {} in the GitHub Actions cache failed: {}", key, status),
+        }
+    }
+
+    /// Upload `data` to the reserved cache `id` and commit it.
+    fn upload(&self, id: u64, data: &[u8]) -> Result<()> {
+        let url = format!("{}caches/{}", self.url, id);
+        for (i, chunk) in data.chunks(self.chunk_size).enumerate() {
+            let start = i * self.chunk_size;
+            let mut headers = self.headers();
+            headers.set(ContentType::octet_stream());
+            headers.set_raw("Content-Range", format!("bytes {}-{}/*", start, start + chunk.len() - 1));
+            let res = self.client.patch(&url).headers(headers).body(chunk.to_vec()).send()?;
+            if !res.status().is_success() {
+                bail!("Uploading to GitHub Actions cache {} failed: {}", id, res.status());
+            }
+        }
+        let res = self.client.post(&url)
+            .headers(self.headers())
+            .json(&CommitRequest { size: data.len() as u64 })
+            .send()?;
+        if !res.status().is_success() {
+            bail!("Committing GitHub Actions cache {} failed: {}", id, res.status());
+        }
+        Ok(())
+    }
+}
+
+impl Storage for GHACache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let key = format!("sccache-{}", key);
+        let me = self.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            let location = match me.lookup(&key)? {
+                Some(location) => location,
+                None => return Ok(Cache::Miss),
+            };
+            let mut res = me.client.get(&location).send()?;
+            if !res.status().is_success() {
+                bail!("Downloading {} from the GitHub Actions cache failed: {}", key, res.status());
+            }
+            let mut data = vec![];
+            res.read_to_end(&mut data)?;
+            CacheRead::from(Cursor::new(data)).map(Cache::Hit)
+        }))
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let key = format!("sccache-{}", key);
+        let me = self.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            let start = Instant::now();
+            let data = entry.finish()?;
+            match me.reserve(&key, data.len() as u64)? {
+                Some(id) => me.upload(id, &data)?,
+                // Keys are digests of the inputs, so whoever got there first
+                // is storing the same entry.
+                None => debug!("{} is already stored in the GitHub Actions cache", key),
+            }
+            Ok(start.elapsed())
+        }))
+    }
+
+    fn location(&self) -> String {
+        format!("GitHub Actions cache: {}", self.url)
+    }
+
+    fn current_size(&self) -> Option<u64> { None }
+    fn max_size(&self) -> Option<u64> { None }
+    fn current_entries(&self) -> Option<u64> { None }
+    fn max_entries(&self) -> Option<u64> { None }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::Future;
+    use std::sync::{Arc, Mutex};
+    use test::utils::{MockRequest, incompressible_data, mock_http_server};
+
+    /// Start a mock JSON API server replying to each request with the
+    /// status and body `reply` returns for it, and return its URL.
+    fn mock_server<F>(reply: F) -> String
+        where F: Fn(&MockRequest) -> (u16, Vec<u8>) + Send + Sync + 'static
+    {
+        let addr = mock_http_server(move |request| {
+            let (status, body) = reply(request);
+            (status, vec![("Content-Type", "application/json".to_owned())], body)
+        });
+        format!("http://{}/", addr)
+    }
+
+    fn entry() -> CacheWrite {
+        let mut entry = CacheWrite::new().unwrap();
+        entry.put_object("obj", &mut Cursor::new(incompressible_data()), None).unwrap();
+        entry
+    }
+
+    #[test]
+    fn test_reserve_upload_commit() {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let uploaded = Arc::new(Mutex::new(vec![]));
+        let committed = Arc::new(Mutex::new(false));
+        let url = Arc::new(Mutex::new(String::new()));
+        let server = {
+            let (requests, uploaded, committed, url) = (requests.clone(), uploaded.clone(), committed.clone(), url.clone());
+            mock_server(move |request| {
+                requests.lock().unwrap().push(request.clone());
+                match (&request.method[..], &request.path[..]) {
+                    ("POST", "/_apis/artifactcache/caches") => (201, br#"{"cacheId":42}"#.to_vec()),
+                    ("PATCH", "/_apis/artifactcache/caches/42") => {
+                        uploaded.lock().unwrap().extend_from_slice(&request.body);
+                        (204, vec![])
+                    }
+                    ("POST", "/_apis/artifactcache/caches/42") => {
+                        *committed.lock().unwrap() = true;
+                        (204, vec![])
+                    }
+                    ("GET", path) if path.starts_with("/_apis/artifactcache/cache?keys=sccache-") => {
+                        if *committed.lock().unwrap() {
+                            let key = &path["/_apis/artifactcache/cache?keys=".len()..path.find('&').unwrap()];
+                            let body = format!(r#"{{"cacheKey":"{}","archiveLocation":"{}archive/42"}}"#,
+                                               key, url.lock().unwrap());
+                            (200, body.into_bytes())
+                        } else {
+                            (204, vec![])
+                        }
+                    }
+                    ("GET", "/archive/42") => (200, uploaded.lock().unwrap().clone()),
+                    _ => (404, vec![]),
+                }
+            })
+        };
+        *url.lock().unwrap() = server.clone();
+
+        let pool = CpuPool::new(1);
+        let mut cache = GHACache::new(&server, "secret", "v1", &pool).unwrap();
+        cache.chunk_size = 1024;
+        match cache.get("abcdef").wait().unwrap() {
+            Cache::Miss => {}
+            _ => panic!("Expected a cache miss"),
+        }
+        let entry = entry();
+        let size = entry.size();
+        cache.put("abcdef", entry).wait().unwrap();
+        match cache.get("abcdef").wait().unwrap() {
+            Cache::Hit(mut entry) => {
+                let mut obj = vec![];
+                entry.get_object("obj", &mut obj).unwrap();
+                assert_eq!(4096, obj.len());
+            }
+            _ => panic!("Expected a cache hit"),
+        }
+
+        let requests = requests.lock().unwrap();
+        assert!(requests.iter().all(|r| r.path.starts_with("/archive/") ||
+                                   r.headers.get("authorization").map(|a| &a[..]) == Some("Bearer secret")));
+        let reserve = &requests[1];
+        assert_eq!(("POST", "/_apis/artifactcache/caches"), (&reserve.method[..], &reserve.path[..]));
+        let reserve: ::serde_json::Value = ::serde_json::from_slice(&reserve.body).unwrap();
+        assert_eq!(Some("sccache-abcdef"), reserve["key"].as_str());
+        // Uploaded in chunks, each saying where it goes.
+        let patches = requests.iter().filter(|r| r.method == "PATCH").collect::<Vec<_>>();
+        assert!(patches.len() > 1);
+        assert_eq!(Some("bytes 0-1023/*"), patches[0].headers.get("content-range").map(|r| &r[..]));
+        assert_eq!(Some("bytes 1024-2047/*"), patches[1].headers.get("content-range").map(|r| &r[..]));
+        let uploaded_size = uploaded.lock().unwrap().len() as u64;
+        assert!(uploaded_size >= size);
+        // Then committed with the total size.
+        let commit = requests.iter().position(|r| r.method == "POST" && r.path == "/_apis/artifactcache/caches/42").unwrap();
+        assert!(requests[..commit].iter().any(|r| r.method == "PATCH"));
+        assert!(requests[commit..].iter().all(|r| r.method != "PATCH"));
+        let commit: ::serde_json::Value = ::serde_json::from_slice(&requests[commit].body).unwrap();
+        assert_eq!(Some(uploaded_size), commit["size"].as_u64());
+    }
+
+    #[test]
+    fn test_failed_upload() {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let server = {
+            let requests = requests.clone();
+            mock_server(move |request| {
+                requests.lock().unwrap().push(request.clone());
+                match (&request.method[..], &request.path[..]) {
+                    ("POST", "/_apis/artifactcache/caches") => (201, br#"{"cacheId":42}"#.to_vec()),
+                    _ => (500, vec![]),
+                }
+            })
+        };
+        let pool = CpuPool::new(1);
+        let cache = GHACache::new(&server, "secret", "v1", &pool).unwrap();
+        assert!(cache.put("abcdef", entry()).wait().is_err());
+        let requests = requests.lock().unwrap();
+        let methods = requests.iter().map(|r| &r.method[..]).collect::<Vec<_>>();
+        // The upload stops at the first failed chunk, without committing.
+        assert_eq!(vec!["POST", "PATCH"], methods);
+    }
+
+    #[test]
+    fn test_reserve_conflict() {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let server = {
+            let requests = requests.clone();
+            mock_server(move |request| {
+                requests.lock().unwrap().push(request.clone());
+                match (&request.method[..], &request.path[..]) {
+                    // Another job is already storing the entry.
+                    ("POST", "/_apis/artifactcache/caches") => (409, br#"{"message":"Cache already exists."}"#.to_vec()),
+                    _ => (500, vec![]),
+                }
+            })
+        };
+        let pool = CpuPool::new(1);
+        let cache = GHACache::new(&server, "secret", "v1", &pool).unwrap();
+        cache.put("abcdef", entry()).wait().unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(1, requests.len());
+        assert_eq!("POST", requests[0].method);
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    CompilerDigests,
+    PruneStats,
+    Storage,
+    VerifyStats,
+};
+use cache::shadow::ShadowStats;
+use config::CacheCompression;
+use futures::Future;
+use futures::sync::oneshot;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use errors::*;
+
+/// The state shared by a `LimitedStorage` and its outstanding `Permit`s.
+struct Limiter {
+    /// The number of operations that may start without waiting.
+    available: usize,
+    /// Operations waiting for one of the outstanding ones to finish.
+    waiting: VecDeque<oneshot::Sender<Permit>>,
+}
+
+/// Held by an operation while it is in flight. `None` once it has been
+/// handed on, or turned out not to be needed.
+struct Permit(Option<Rc<RefCell<Limiter>>>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let limiter = match self.0.take() {
+            Some(limiter) => limiter,
+            None => return,
+        };
+        // Hand the permit on to the first waiter that's still waiting. A
+        // waiter that goes away after being handed it drops it in turn.
+        loop {
+            let tx = limiter.borrow_mut().waiting.pop_front();
+            match tx {
+                Some(tx) => match tx.send(Permit(Some(limiter.clone()))) {
+                    Ok(()) => return,
+                    Err(mut permit) => { permit.0.take(); }
+                },
+                None => {
+                    limiter.borrow_mut().available += 1;
+                    return
+                }
+            }
+        }
+    }
+}
+
+/// A `Storage` that limits how many `get`s and `put`s may be in flight in
+/// another `Storage` at once. Operations beyond the limit wait in order.
+///
+/// An operation's place is given up once its future finishes or is
+/// dropped. When the other `Storage` gives up on an operation, as a
+/// `TimeoutStorage` does, the backend may still be working on it.
+pub struct LimitedStorage {
+    inner: Arc<Storage>,
+    limiter: Rc<RefCell<Limiter>>,
+}
+
+impl LimitedStorage {
+    /// Wrap `inner`, allowing at most `limit` operations in flight at once.
+    pub fn new(inner: Arc<Storage>, limit: usize) -> LimitedStorage {
+        LimitedStorage {
+            inner,
+            limiter: Rc::new(RefCell::new(Limiter {
+                available: cmp::max(limit, 1),
+                waiting: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// Wait for the right to start an operation.
+    fn acquire(&self) -> SFuture<Permit> {
+        let mut limiter = self.limiter.borrow_mut();
+        if limiter.available > 0 {
+            limiter.available -= 1;
+            return f_ok(Permit(Some(self.limiter.clone())))
+        }
+        let (tx, rx) = oneshot::channel();
+        limiter.waiting.push_back(tx);
+        Box::new(rx.map_err(|_| "Storage limiter was dropped".into()))
+    }
+}
+
+impl Storage for LimitedStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let inner = self.inner.clone();
+        let key = key.to_owned();
+        Box::new(self.acquire().and_then(move |permit| {
+            inner.get(&key).then(move |res| {
+                drop(permit);
+                res
+            })
+        }))
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let inner = self.inner.clone();
+        let key = key.to_owned();
+        Box::new(self.acquire().and_then(move |permit| {
+            inner.put(&key, entry).then(move |res| {
+                drop(permit);
+                res
+            })
+        }))
+    }
+
+    fn location(&self) -> String { self.inner.location() }
+    fn current_size(&self) -> Option<u64> { self.inner.current_size() }
+    fn max_size(&self) -> Option<u64> { self.inner.max_size() }
+    fn current_entries(&self) -> Option<u64> { self.inner.current_entries() }
+    fn max_entries(&self) -> Option<u64> { self.inner.max_entries() }
+    fn compression(&self) -> CacheCompression { self.inner.compression() }
+    fn shadow_stats(&self) -> Option<ShadowStats> { self.inner.shadow_stats() }
+
+    fn prune_older_than(&self, age: Duration) -> SFuture<PruneStats> {
+        self.inner.prune_older_than(age)
+    }
+
+    fn compiler_digests(&self) -> SFuture<CompilerDigests> {
+        self.inner.compiler_digests()
+    }
+
+    fn purge_stale_compilers(&self, current: HashMap<PathBuf, String>) -> SFuture<PruneStats> {
+        self.inner.purge_stale_compilers(current)
+    }
+
+    fn set_max_size(&self, size: u64) -> SFuture<PruneStats> {
+        self.inner.set_max_size(size)
+    }
+
+    fn remove_label(&self, label: &str) -> SFuture<PruneStats> {
+        self.inner.remove_label(label)
+    }
+
+    fn verify(&self, repair: bool, rate: Option<u32>) -> SFuture<VerifyStats> {
+        self.inner.verify(repair, rate)
+    }
+
+    fn keys(&self) -> SFuture<Vec<String>> {
+        self.inner.keys()
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        self.inner.remove(key)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::sync::oneshot;
+    use std::cell::Cell;
+    use std::sync::Mutex;
+    use tokio_core::reactor::Core;
+
+    /// A `Storage` whose `get`s finish when the test says so.
+    struct BlockingStorage {
+        in_flight: Rc<Cell<usize>>,
+        gets: Mutex<VecDeque<oneshot::Receiver<()>>>,
+    }
+
+    impl Storage for BlockingStorage {
+        fn get(&self, _key: &str) -> SFuture<Cache> {
+            let in_flight = self.in_flight.clone();
+            in_flight.set(in_flight.get() + 1);
+            let rx = self.gets.lock().unwrap().pop_front().unwrap();
+            Box::new(rx.then(move |_| {
+                in_flight.set(in_flight.get() - 1);
+                Ok(Cache::Miss)
+            }))
+        }
+        fn put(&self, _key: &str, _entry: CacheWrite) -> SFuture<Duration> { f_ok(Duration::new(0, 0)) }
+        fn location(&self) -> String { "blocking".to_owned() }
+        fn current_size(&self) -> Option<u64> { None }
+        fn max_size(&self) -> Option<u64> { None }
+        fn current_entries(&self) -> Option<u64> { None }
+        fn max_entries(&self) -> Option<u64> { None }
+    }
+
+    #[test]
+    fn test_limit() {
+        let mut core = Core::new().unwrap();
+        let in_flight = Rc::new(Cell::new(0));
+        let finished = Rc::new(Cell::new(0));
+        let (mut txs, rxs): (VecDeque<_>, VecDeque<_>) = (0..3).map(|_| oneshot::channel::<()>()).unzip();
+        let storage = LimitedStorage::new(Arc::new(BlockingStorage {
+            in_flight: in_flight.clone(),
+            gets: Mutex::new(rxs),
+        }), 2);
+        for _ in 0..3 {
+            let finished = finished.clone();
+            core.handle().spawn(storage.get("key").then(move |_| {
+                finished.set(finished.get() + 1);
+                Ok(())
+            }));
+        }
+        let mut turn = || for _ in 0..10 { core.turn(Some(Duration::from_millis(1))) };
+        // Two gets start right away, and the third waits for one to finish.
+        turn();
+        assert_eq!((in_flight.get(), finished.get()), (2, 0));
+        txs.pop_front().unwrap().send(()).unwrap();
+        turn();
+        assert_eq!((in_flight.get(), finished.get()), (2, 1));
+        for tx in txs {
+            tx.send(()).unwrap();
+        }
+        turn();
+        assert_eq!((in_flight.get(), finished.get()), (0, 3));
+    }
+}
@@ -19,6 +19,7 @@ use cache::{
     CacheWrite,
     Storage,
 };
+use cache::chunked::{self, ChunkedWrite};
 use errors::*;
 use futures_cpupool::CpuPool;
 use memcached::client::Client;
@@ -39,13 +40,16 @@ thread_local! {
 #[derive(Clone)]
 pub struct MemcachedCache {
     url: String,
+    max_object_size: u64,
     pool: CpuPool,
 }
 
 impl MemcachedCache {
-    pub fn new(url: &str, pool: &CpuPool) -> Result<MemcachedCache> {
+    /// Create a new `MemcachedCache`, storing entries larger than `max_object_size` in chunks.
+    pub fn new(url: &str, max_object_size: u64, pool: &CpuPool) -> Result<MemcachedCache> {
         Ok(MemcachedCache {
             url: url.to_owned(),
+            max_object_size,
             pool: pool.clone(),
         })
     }
@@ -72,9 +76,27 @@ impl Storage for MemcachedCache {
         let key = key.to_owned();
         let me = self.clone();
         Box::new(self.pool.spawn_fn(move || {
-            me.exec(|c| c.get(&key.as_bytes()))
-            .map(|(d, _)| CacheRead::from(Cursor::new(d)).map(Cache::Hit))
-            .unwrap_or(Ok(Cache::Miss))
+            let d = match me.exec(|c| c.get(&key.as_bytes())) {
+                Ok((d, _)) => d,
+                Err(_) => return Ok(Cache::Miss),
+            };
+            let d = match chunked::parse_manifest(&d) {
+                Some(manifest) => {
+                    let manifest = manifest?;
+                    let chunks = manifest.chunks.iter()
+                        .map(|chunk| me.exec(|c| c.get(chunk.as_bytes())).ok().map(|(d, _)| d))
+                        .collect();
+                    match chunked::join(&manifest, chunks) {
+                        Some(joined) => joined,
+                        None => {
+                            debug!("Chunks of {} have been evicted", key);
+                            return Ok(Cache::Miss)
+                        }
+                    }
+                }
+                None => d,
+            };
+            CacheRead::from(Cursor::new(d)).map(Cache::Hit)
         }))
     }
 
@@ -84,7 +106,15 @@ impl Storage for MemcachedCache {
         Box::new(self.pool.spawn_fn(move || {
             let start = Instant::now();
             let d = entry.finish()?;
-            me.exec(|c| c.set_noreply(&key.as_bytes(), &d, 0, 0))?;
+            match chunked::split(&key, d, me.max_object_size)? {
+                ChunkedWrite::Whole(d) => me.exec(|c| c.set_noreply(&key.as_bytes(), &d, 0, 0))?,
+                ChunkedWrite::Chunked { chunks, manifest } => {
+                    for (chunk_key, chunk) in chunks {
+                        me.exec(|c| c.set_noreply(chunk_key.as_bytes(), &chunk, 0, 0))?;
+                    }
+                    me.exec(|c| c.set_noreply(&key.as_bytes(), &manifest, 0, 0))?;
+                }
+            }
             Ok(start.elapsed())
         }))
     }
@@ -93,6 +123,18 @@ impl Storage for MemcachedCache {
         format!("Memcached: {}", self.url)
     }
 
+    fn remove(&self, key: &str) -> SFuture<()> {
+        let key = key.to_owned();
+        let me = self.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            // Without a reply, so that a missing key isn't an error.
+            me.exec(|c| c.delete_noreply(key.as_bytes()))?;
+            Ok(())
+        }))
+    }
+
     fn current_size(&self) -> Option<u64> { None }
     fn max_size(&self) -> Option<u64> { None }
+    fn current_entries(&self) -> Option<u64> { None }
+    fn max_entries(&self) -> Option<u64> { None }
 }
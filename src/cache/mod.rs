@@ -15,7 +15,22 @@
 #[cfg(feature = "azure")]
 pub mod azure;
 pub mod cache;
+#[cfg(any(feature = "redis", feature = "memcached"))]
+pub mod chunked;
+pub mod compression;
 pub mod disk;
+pub mod encrypted;
+#[cfg(feature = "gha")]
+pub mod gha;
+pub mod limit;
+pub mod oci;
+pub mod readonly;
+pub mod shadow;
+pub mod timeout;
+pub mod trace;
+pub mod warm;
+#[cfg(feature = "webdav")]
+pub mod webdav;
 #[cfg(feature = "memcached")]
 pub mod memcached;
 #[cfg(feature = "redis")]
@@ -0,0 +1,755 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Exporting the entries of a cache as an OCI image layout, and importing
+//! them back into a cache, and pushing such images to and pulling them from
+//! a registry.
+//!
+//! Each image is a single artifact whose one layer is a tar of the entries,
+//! each named by its key. Registries are authenticated with using the
+//! credentials in the docker config, as `docker login` stores them.
+
+use base64;
+use cache::{Cache, CacheRead, CacheWrite, Storage};
+use config::CacheCompression;
+use reqwest::{self, Method, RedirectPolicy, StatusCode};
+use reqwest::header::Headers;
+use ring::digest::{self, SHA256};
+use serde::Serialize;
+use serde_json;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tar;
+use tokio_core::reactor::Core;
+
+use errors::*;
+
+/// The media type of the artifact's config, which describes the export.
+pub const CONFIG_MEDIA_TYPE: &str = "application/vnd.sccache.cache.config.v1+json";
+/// The media type of the layer holding the cache entries.
+pub const LAYER_MEDIA_TYPE: &str = "application/vnd.sccache.cache.layer.v1.tar";
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: String,
+    digest: String,
+    size: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    schema_version: u32,
+    #[serde(default)]
+    media_type: Option<String>,
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Index {
+    schema_version: u32,
+    manifests: Vec<Descriptor>,
+}
+
+/// The artifact's config.
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportConfig {
+    sccache_version: String,
+    entries: u64,
+}
+
+/// Format `bytes` as lowercase hex, most significant nibble first, as OCI
+/// digests are.
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn blob_path(dir: &Path, digest: &str) -> Result<PathBuf> {
+    let hex = match digest.split_at(digest.find(':').unwrap_or(0)) {
+        ("sha256", hex) if hex.len() == 65 && hex[1..].chars().all(|c| c.is_ascii_hexdigit()) => &hex[1..],
+        _ => bail!("Unsupported blob digest `{}`", digest),
+    };
+    Ok(dir.join("blobs").join("sha256").join(hex))
+}
+
+/// The digest and size of the file at `path`.
+fn digest_file(path: &Path) -> Result<(String, u64)> {
+    let mut f = File::open(path)?;
+    let mut ctx = digest::Context::new(&SHA256);
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break
+        }
+        ctx.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((format!("sha256:{}", to_hex(ctx.finish().as_ref())), size))
+}
+
+/// Store `value` as a JSON blob in the layout at `dir`.
+fn write_json_blob<T: Serialize>(dir: &Path, media_type: &str, value: &T) -> Result<Descriptor> {
+    let data = serde_json::to_vec(value)?;
+    let digest = format!("sha256:{}", to_hex(digest::digest(&SHA256, &data).as_ref()));
+    File::create(blob_path(dir, &digest)?)?.write_all(&data)?;
+    Ok(Descriptor {
+        media_type: media_type.to_owned(),
+        digest,
+        size: data.len() as u64,
+        annotations: BTreeMap::new(),
+    })
+}
+
+/// Read the blob for `descriptor` from the layout at `dir`, checking its digest.
+fn read_blob(dir: &Path, descriptor: &Descriptor) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    File::open(blob_path(dir, &descriptor.digest)?)?.read_to_end(&mut data)?;
+    let digest = format!("sha256:{}", to_hex(digest::digest(&SHA256, &data).as_ref()));
+    if digest != descriptor.digest || data.len() as u64 != descriptor.size {
+        bail!("Blob {} doesn't match its digest", descriptor.digest)
+    }
+    Ok(data)
+}
+
+/// Whether `key` can safely be stored under, as the keys sccache generates can.
+fn is_valid_key(key: &str) -> bool {
+    key.len() >= 2 && !key.starts_with('.') &&
+        key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Export the entries of `storage` as an OCI image layout in `dir`, tagged
+/// `tag`, and return how many were exported.
+pub fn export(core: &mut Core, storage: &Storage, dir: &Path, tag: &str) -> Result<u64> {
+    let keys = core.run(storage.keys())?;
+    fs::create_dir_all(dir.join("blobs").join("sha256"))?;
+    let tmp_layer = dir.join("blobs").join("sha256").join("layer.tmp");
+    let mut layer = tar::Builder::new(File::create(&tmp_layer)?);
+    let mut entries = 0;
+    for key in keys {
+        let mut entry = match core.run(storage.get(&key)) {
+            Ok(Cache::Hit(entry)) => entry,
+            // Evicted since the keys were listed.
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("Not exporting cache entry {}: {}", key, e);
+                continue
+            }
+        };
+        let data = CacheWrite::from_entry(&mut entry, CacheCompression::default())?.finish()?;
+        let mut header = tar::Header::new_ustar();
+        header.set_path(&key)?;
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        header.set_entry_type(tar::EntryType::file());
+        header.set_cksum();
+        layer.append(&header, &data[..])?;
+        entries += 1;
+    }
+    layer.into_inner()?;
+    let (digest, size) = digest_file(&tmp_layer)?;
+    fs::rename(&tmp_layer, blob_path(dir, &digest)?)?;
+    let layer = Descriptor {
+        media_type: LAYER_MEDIA_TYPE.to_owned(),
+        digest,
+        size,
+        annotations: BTreeMap::new(),
+    };
+    let config = ExportConfig {
+        sccache_version: env!("CARGO_PKG_VERSION").to_owned(),
+        entries,
+    };
+    let manifest = Manifest {
+        schema_version: 2,
+        media_type: Some(MANIFEST_MEDIA_TYPE.to_owned()),
+        config: write_json_blob(dir, CONFIG_MEDIA_TYPE, &config)?,
+        layers: vec![layer],
+    };
+    let manifest = write_json_blob(dir, MANIFEST_MEDIA_TYPE, &manifest)?;
+    add_to_index(dir, manifest, tag)?;
+    Ok(entries)
+}
+
+/// Add `manifest` to the index of the layout at `dir`, tagged `tag`, in
+/// place of any image already tagged `tag` but keeping the other images.
+/// The blobs of the image replaced are left in the layout.
+fn add_to_index(dir: &Path, mut manifest: Descriptor, tag: &str) -> Result<()> {
+    let path = dir.join("index.json");
+    // Edited as JSON, so that whatever other tools put in it is kept.
+    let mut index = match File::open(&path) {
+        Ok(f) => serde_json::from_reader(f).chain_err(|| "Invalid OCI image index")?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            serde_json::to_value(&Index { schema_version: 2, manifests: vec![] })?
+        }
+        Err(e) => return Err(e.into()),
+    };
+    {
+        let manifests = match index.as_object_mut().and_then(|i| i.get_mut("manifests")).and_then(|m| m.as_array_mut()) {
+            Some(manifests) => manifests,
+            None => bail!("Invalid OCI image index"),
+        };
+        let ref_name = format!("/annotations/{}", REF_NAME_ANNOTATION);
+        manifests.retain(|m| m.pointer(&ref_name).and_then(|t| t.as_str()) != Some(tag));
+        manifest.annotations.insert(REF_NAME_ANNOTATION.to_owned(), tag.to_owned());
+        manifests.push(serde_json::to_value(&manifest)?);
+    }
+    let tmp = dir.join("index.json.tmp");
+    File::create(&tmp)?.write_all(&serde_json::to_vec(&index)?)?;
+    fs::rename(&tmp, &path)?;
+    File::create(dir.join("oci-layout"))?.write_all(br#"{"imageLayoutVersion":"1.0.0"}"#)?;
+    Ok(())
+}
+
+/// Find the manifest of the image tagged `tag`, or the only one, in the
+/// layout at `dir`.
+fn find_manifest(dir: &Path, tag: Option<&str>) -> Result<Descriptor> {
+    let index: Index = serde_json::from_reader(File::open(dir.join("index.json"))
+        .chain_err(|| format!("{:?} is not an OCI image layout", dir))?)
+        .chain_err(|| "Invalid OCI image index")?;
+    let mut candidates = index.manifests.into_iter()
+        .filter(|m| m.media_type == MANIFEST_MEDIA_TYPE)
+        .filter(|m| tag.map_or(true, |tag| m.annotations.get(REF_NAME_ANNOTATION).map(|t| &t[..]) == Some(tag)));
+    match (candidates.next(), candidates.next()) {
+        (Some(manifest), None) => Ok(manifest),
+        (None, _) => bail!("No image tagged {:?} in {:?}", tag.unwrap_or("<any>"), dir),
+        (Some(_), Some(_)) => bail!("{:?} holds more than one image, choose one with a tag", dir),
+    }
+}
+
+/// Import the entries of the sccache artifact tagged `tag`, or the only one,
+/// in the OCI image layout at `dir` into `storage`, replacing any entries
+/// with the same keys, and return how many were imported.
+pub fn import(core: &mut Core, storage: &Storage, dir: &Path, tag: Option<&str>) -> Result<u64> {
+    let manifest = find_manifest(dir, tag)?;
+    let manifest: Manifest = serde_json::from_slice(&read_blob(dir, &manifest)?)
+        .chain_err(|| "Invalid OCI image manifest")?;
+    if manifest.config.media_type != CONFIG_MEDIA_TYPE {
+        bail!("The image is not an sccache cache export")
+    }
+    let mut entries = 0;
+    for layer in manifest.layers.iter().filter(|l| l.media_type == LAYER_MEDIA_TYPE) {
+        let path = blob_path(dir, &layer.digest)?;
+        if digest_file(&path)? != (layer.digest.clone(), layer.size) {
+            bail!("Blob {} doesn't match its digest", layer.digest)
+        }
+        let mut archive = tar::Archive::new(File::open(&path)?);
+        for file in archive.entries()? {
+            let mut file = file?;
+            let key = file.path()?.to_string_lossy().into_owned();
+            if !is_valid_key(&key) {
+                bail!("Invalid cache key `{}` in export", key)
+            }
+            let mut data = vec![];
+            file.read_to_end(&mut data)?;
+            let mut entry = CacheRead::from(Cursor::new(data)).chain_err(|| format!("Invalid cache entry {}", key))?;
+            let entry = CacheWrite::from_entry(&mut entry, storage.compression())?;
+            core.run(storage.put(&key, entry)).chain_err(|| format!("Failed to store cache entry {}", key))?;
+            entries += 1;
+        }
+    }
+    Ok(entries)
+}
+
+/// The registry images without one in their reference are on.
+const DOCKER_HUB_REGISTRY: &str = "registry-1.docker.io";
+/// The key the docker config keeps the credentials for Docker Hub under.
+const DOCKER_HUB_AUTH_KEY: &str = "https://index.docker.io/v1/";
+
+/// Where an image is in a registry.
+#[derive(Debug, PartialEq)]
+pub struct Reference {
+    registry: String,
+    repository: String,
+    tag: String,
+}
+
+impl Reference {
+    /// Parse a reference like `registry.example.com/team/sccache:latest`. As
+    /// with docker, images without a registry are on Docker Hub, and those
+    /// without a tag are tagged `latest`.
+    pub fn parse(reference: &str) -> Result<Reference> {
+        if reference.contains('@') {
+            bail!("Images can only be referred to by tag, not digest: {}", reference)
+        }
+        let (name, tag) = match reference.rfind(':') {
+            Some(i) if !reference[i..].contains('/') => (&reference[..i], &reference[i + 1..]),
+            _ => (reference, "latest"),
+        };
+        let (registry, repository) = match name.find('/') {
+            Some(i) if name[..i].contains('.') || name[..i].contains(':') || &name[..i] == "localhost" => {
+                (name[..i].to_owned(), name[i + 1..].to_owned())
+            }
+            Some(_) => (DOCKER_HUB_REGISTRY.to_owned(), name.to_owned()),
+            None => (DOCKER_HUB_REGISTRY.to_owned(), format!("library/{}", name)),
+        };
+        if name.is_empty() || repository.is_empty() || tag.is_empty() {
+            bail!("Invalid image reference: {}", reference)
+        }
+        Ok(Reference { registry, repository, tag: tag.to_owned() })
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}:{}", self.registry, self.repository, self.tag)
+    }
+}
+
+/// A username and password to log in to a registry with.
+type Credentials = (String, String);
+
+#[derive(Deserialize)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, DockerAuth>,
+    #[serde(default, rename = "credsStore")]
+    creds_store: Option<String>,
+    #[serde(default, rename = "credHelpers")]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct DockerAuth {
+    /// The base64 of `username:password`.
+    #[serde(default)]
+    auth: Option<String>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    access_token: Option<String>,
+}
+
+/// The host a docker config `auths` key is for. Keys may be URLs, such as
+/// `https://index.docker.io/v1/`.
+fn auth_key_host(key: &str) -> &str {
+    let key = key.find("://").map_or(key, |i| &key[i + 3..]);
+    key.split('/').next().unwrap_or(key)
+}
+
+/// The credentials for `registry` in the docker config, if any, asking the
+/// credential helper configured for it if there is one.
+fn docker_credentials(registry: &str) -> Result<Option<Credentials>> {
+    let path = match env::var_os("DOCKER_CONFIG").map(PathBuf::from).or_else(|| env::home_dir().map(|home| home.join(".docker"))) {
+        Some(dir) => dir.join("config.json"),
+        None => return Ok(None),
+    };
+    let config: DockerConfig = match File::open(&path) {
+        Ok(f) => serde_json::from_reader(f).chain_err(|| format!("Invalid docker config {:?}", path))?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let (host, server) = if registry == DOCKER_HUB_REGISTRY {
+        ("index.docker.io", DOCKER_HUB_AUTH_KEY)
+    } else {
+        (registry, registry)
+    };
+    if let Some(helper) = config.cred_helpers.get(host).or(config.creds_store.as_ref()) {
+        if let Some(credentials) = credential_helper(helper, server)? {
+            return Ok(Some(credentials))
+        }
+    }
+    let auth = match config.auths.into_iter().find(|&(ref key, _)| auth_key_host(key) == host) {
+        Some((_, auth)) => auth,
+        None => return Ok(None),
+    };
+    if let Some(encoded) = auth.auth {
+        let invalid = || format!("Invalid credentials for {} in {:?}", host, path);
+        let decoded = base64::decode(&encoded).chain_err(&invalid)?;
+        let decoded = String::from_utf8(decoded).chain_err(&invalid)?;
+        return match decoded.find(':') {
+            Some(i) => Ok(Some((decoded[..i].to_owned(), decoded[i + 1..].to_owned()))),
+            None => Err(invalid().into()),
+        }
+    }
+    Ok(auth.username.and_then(|username| auth.password.map(|password| (username, password))))
+}
+
+/// Ask the docker credential `helper` for the credentials for `server`, if
+/// it has any.
+fn credential_helper(helper: &str, server: &str) -> Result<Option<Credentials>> {
+    let program = format!("docker-credential-{}", helper);
+    let mut child = Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .chain_err(|| format!("Failed to run {}", program))?;
+    child.stdin.take().unwrap().write_all(server.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        debug!("{} has no credentials for {}", program, server);
+        return Ok(None)
+    }
+    let credentials: HelperCredentials = serde_json::from_slice(&output.stdout)
+        .chain_err(|| format!("Invalid output from {}", program))?;
+    Ok(Some((credentials.username, credentials.secret)))
+}
+
+/// Split a `WWW-Authenticate` challenge into its scheme and its parameters,
+/// with their names lowercased.
+fn parse_challenge(challenge: &str) -> (String, HashMap<String, String>) {
+    let challenge = challenge.trim();
+    let (scheme, mut rest) = match challenge.find(' ') {
+        Some(i) => (&challenge[..i], &challenge[i + 1..]),
+        None => (challenge, ""),
+    };
+    let mut params = HashMap::new();
+    loop {
+        rest = rest.trim_left_matches(|c: char| c == ',' || c.is_whitespace());
+        let eq = match rest.find('=') {
+            Some(eq) => eq,
+            None => break,
+        };
+        let name = rest[..eq].trim().to_lowercase();
+        rest = &rest[eq + 1..];
+        let value = if rest.starts_with('"') {
+            let end = rest[1..].find('"').map_or(rest.len(), |i| i + 1);
+            let value = &rest[1..end];
+            rest = &rest[(end + 1).min(rest.len())..];
+            value
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let value = rest[..end].trim();
+            rest = &rest[end..];
+            value
+        };
+        params.insert(name, value.to_owned());
+    }
+    (scheme.to_lowercase(), params)
+}
+
+/// The first value of the `name` header of `res`.
+fn header(res: &reqwest::Response, name: &str) -> Option<String> {
+    res.headers().get_raw(name)
+        .and_then(|v| v.one())
+        .map(|v| String::from_utf8_lossy(v).into_owned())
+}
+
+/// A repository in a registry, and how to authorize requests to it.
+struct Repository {
+    client: reqwest::Client,
+    /// The scheme and host of the registry.
+    origin: String,
+    /// The URL of the repository's API.
+    url: String,
+    authorization: Option<String>,
+}
+
+impl Repository {
+    /// Connect to the repository of `reference`, getting authorization for
+    /// `actions` on it, `pull` or `pull,push`, if the registry asks for it.
+    fn connect(reference: &Reference, actions: &str) -> Result<Repository> {
+        // Local registries for testing usually don't have certificates.
+        let scheme = if reference.registry.starts_with("localhost") || reference.registry.starts_with("127.0.0.1") {
+            "http"
+        } else {
+            "https"
+        };
+        let origin = format!("{}://{}", scheme, reference.registry);
+        // Redirects are followed by hand, as blobs are often served from
+        // elsewhere, which mustn't be sent the registry's authorization.
+        let client = reqwest::Client::builder().redirect(RedirectPolicy::none()).build()?;
+        let res = client.get(&format!("{}/v2/", origin)).send()
+            .chain_err(|| format!("Failed to connect to registry {}", reference.registry))?;
+        let authorization = match res.status() {
+            status if status.is_success() => None,
+            StatusCode::Unauthorized => {
+                let challenge = header(&res, "WWW-Authenticate").unwrap_or_default();
+                let credentials = docker_credentials(&reference.registry)?;
+                let scope = format!("repository:{}:{}", reference.repository, actions);
+                Some(authorize(&client, &challenge, credentials, &scope)?)
+            }
+            status => bail!("Registry {} answered {}", reference.registry, status),
+        };
+        let url = format!("{}/v2/{}", origin, reference.repository);
+        Ok(Repository { client, origin, url, authorization })
+    }
+
+    fn request(&self, method: Method, url: &str, mut headers: Headers) -> reqwest::RequestBuilder {
+        if let Some(ref authorization) = self.authorization {
+            headers.set_raw("Authorization", authorization.clone());
+        }
+        let mut req = self.client.request(method, url);
+        req.headers(headers);
+        req
+    }
+
+    /// Upload the blob at `path` with `digest`, unless the registry has it.
+    fn push_blob(&self, path: &Path, digest: &str) -> Result<()> {
+        let url = format!("{}/blobs/{}", self.url, digest);
+        if self.request(Method::Head, &url, Headers::new()).send()?.status().is_success() {
+            debug!("Registry already has blob {}", digest);
+            return Ok(())
+        }
+        let res = self.request(Method::Post, &format!("{}/blobs/uploads/", self.url), Headers::new()).send()?;
+        if res.status() != StatusCode::Accepted {
+            bail!("Starting the upload of blob {} failed: {}", digest, res.status())
+        }
+        let location = header(&res, "Location").ok_or("The registry didn't say where to upload to")?;
+        let location = if location.starts_with('/') { format!("{}{}", self.origin, location) } else { location };
+        let separator = if location.contains('?') { '&' } else { '?' };
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Type", "application/octet-stream");
+        let res = self.request(Method::Put, &format!("{}{}digest={}", location, separator, digest), headers)
+            .body(File::open(path)?)
+            .send()?;
+        if res.status() != StatusCode::Created {
+            bail!("Uploading blob {} failed: {}", digest, res.status())
+        }
+        Ok(())
+    }
+
+    /// Download the blob for `descriptor` to `path`, checking its digest.
+    fn pull_blob(&self, descriptor: &Descriptor, path: &Path) -> Result<()> {
+        let url = format!("{}/blobs/{}", self.url, descriptor.digest);
+        let mut res = self.request(Method::Get, &url, Headers::new()).send()?;
+        if res.status().is_redirection() {
+            let location = header(&res, "Location").ok_or("The registry redirected nowhere")?;
+            let location = if location.starts_with('/') { format!("{}{}", self.origin, location) } else { location };
+            res = self.client.get(&location).send()?;
+        }
+        if !res.status().is_success() {
+            bail!("Downloading blob {} failed: {}", descriptor.digest, res.status())
+        }
+        let tmp = path.with_extension("tmp");
+        io::copy(&mut res, &mut File::create(&tmp)?)?;
+        if digest_file(&tmp)? != (descriptor.digest.clone(), descriptor.size) {
+            fs::remove_file(&tmp)?;
+            bail!("Blob {} doesn't match its digest", descriptor.digest)
+        }
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// The `Authorization` header to send for `scope`, as the `WWW-Authenticate`
+/// `challenge` asks for: `credentials` themselves, or a token from the
+/// registry's token service, which is given the `credentials` if there are
+/// any, and otherwise gives anonymous access if it allows it.
+fn authorize(client: &reqwest::Client, challenge: &str, credentials: Option<Credentials>, scope: &str) -> Result<String> {
+    let basic = credentials.map(|(username, password)| {
+        format!("Basic {}", base64::encode(format!("{}:{}", username, password).as_bytes()))
+    });
+    let (scheme, params) = parse_challenge(challenge);
+    match &scheme[..] {
+        "basic" => return basic.ok_or_else(|| "The registry needs credentials, log in to it with `docker login`".into()),
+        "bearer" => {}
+        _ => bail!("Unsupported registry authentication: {}", challenge),
+    }
+    let realm = params.get("realm").ok_or("The registry didn't say where to get a token")?;
+    let mut url = reqwest::Url::parse(realm).chain_err(|| format!("Invalid token service {}", realm))?;
+    {
+        let mut query = url.query_pairs_mut();
+        if let Some(service) = params.get("service") {
+            query.append_pair("service", service);
+        }
+        query.append_pair("scope", scope);
+    }
+    let mut headers = Headers::new();
+    if let Some(basic) = basic {
+        headers.set_raw("Authorization", basic);
+    }
+    let mut res = client.get(url).headers(headers).send()?;
+    if !res.status().is_success() {
+        bail!("Getting a token from {} failed: {}", realm, res.status())
+    }
+    let token: TokenResponse = res.json()?;
+    match token.token.or(token.access_token) {
+        Some(token) => Ok(format!("Bearer {}", token)),
+        None => bail!("{} didn't return a token", realm),
+    }
+}
+
+/// Push the image tagged `tag` in the layout at `dir` to `reference`.
+pub fn push(dir: &Path, tag: &str, reference: &Reference) -> Result<()> {
+    let descriptor = find_manifest(dir, Some(tag))?;
+    let data = read_blob(dir, &descriptor)?;
+    let manifest: Manifest = serde_json::from_slice(&data).chain_err(|| "Invalid OCI image manifest")?;
+    let repository = Repository::connect(reference, "pull,push")?;
+    for blob in iter::once(&manifest.config).chain(manifest.layers.iter()) {
+        repository.push_blob(&blob_path(dir, &blob.digest)?, &blob.digest)?;
+    }
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", descriptor.media_type.clone());
+    let url = format!("{}/manifests/{}", repository.url, reference.tag);
+    let res = repository.request(Method::Put, &url, headers).body(data).send()?;
+    if res.status() != StatusCode::Created {
+        bail!("Pushing the manifest of {} failed: {}", reference, res.status())
+    }
+    Ok(())
+}
+
+/// Pull the image at `reference` into the layout at `dir`, tagged with the
+/// reference's tag.
+pub fn pull(reference: &Reference, dir: &Path) -> Result<()> {
+    let repository = Repository::connect(reference, "pull")?;
+    let mut headers = Headers::new();
+    headers.set_raw("Accept", MANIFEST_MEDIA_TYPE);
+    let url = format!("{}/manifests/{}", repository.url, reference.tag);
+    let mut res = repository.request(Method::Get, &url, headers).send()?;
+    if !res.status().is_success() {
+        bail!("Pulling the manifest of {} failed: {}", reference, res.status())
+    }
+    let mut data = vec![];
+    res.read_to_end(&mut data)?;
+    let manifest: Manifest = serde_json::from_slice(&data).chain_err(|| "Invalid OCI image manifest")?;
+    if manifest.config.media_type != CONFIG_MEDIA_TYPE {
+        bail!("{} is not an sccache cache export", reference)
+    }
+    fs::create_dir_all(dir.join("blobs").join("sha256"))?;
+    for blob in iter::once(&manifest.config).chain(manifest.layers.iter()) {
+        repository.pull_blob(blob, &blob_path(dir, &blob.digest)?)?;
+    }
+    let digest = format!("sha256:{}", to_hex(digest::digest(&SHA256, &data).as_ref()));
+    File::create(blob_path(dir, &digest)?)?.write_all(&data)?;
+    let descriptor = Descriptor {
+        media_type: MANIFEST_MEDIA_TYPE.to_owned(),
+        digest,
+        size: data.len() as u64,
+        annotations: BTreeMap::new(),
+    };
+    add_to_index(dir, descriptor, &reference.tag)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cache::disk::DiskCache;
+    use futures_cpupool::CpuPool;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_export_import() {
+        let td = TempDir::new("sccache").unwrap();
+        let mut core = Core::new().unwrap();
+        let pool = CpuPool::new(1);
+        let from = DiskCache::new(&td.path().join("from"), 1 << 20, None, &pool);
+        for key in &["aaaaaa", "bbbbbb"] {
+            let mut entry = CacheWrite::new().unwrap();
+            entry.put_object("obj", &mut Cursor::new(key.as_bytes()), Some(0o755)).unwrap();
+            core.run(from.put(key, entry)).unwrap();
+        }
+        let layout = td.path().join("layout");
+        assert_eq!(export(&mut core, &from, &layout, "latest").unwrap(), 2);
+        assert!(layout.join("oci-layout").is_file());
+
+        let to = DiskCache::new(&td.path().join("to"), 1 << 20, None, &pool);
+        assert!(import(&mut core, &to, &layout, Some("other")).is_err());
+        assert_eq!(import(&mut core, &to, &layout, None).unwrap(), 2);
+        for key in &["aaaaaa", "bbbbbb"] {
+            match core.run(to.get(key)).unwrap() {
+                Cache::Hit(mut entry) => {
+                    let mut obj = vec![];
+                    assert!(entry.get_object("obj", &mut obj).unwrap().is_some());
+                    assert_eq!(obj, key.as_bytes());
+                }
+                _ => panic!("Expected {} to be imported", key),
+            }
+        }
+
+        // A tampered layer isn't imported.
+        let index: Index = serde_json::from_reader(File::open(layout.join("index.json")).unwrap()).unwrap();
+        let manifest: Manifest = serde_json::from_slice(&read_blob(&layout, &index.manifests[0]).unwrap()).unwrap();
+        let layer = blob_path(&layout, &manifest.layers[0].digest).unwrap();
+        let mut data = vec![];
+        File::open(&layer).unwrap().read_to_end(&mut data).unwrap();
+        data[600] ^= 1;
+        File::create(&layer).unwrap().write_all(&data).unwrap();
+        assert!(import(&mut core, &to, &layout, Some("latest")).is_err());
+    }
+
+    #[test]
+    fn test_export_keeps_other_tags() {
+        let td = TempDir::new("sccache").unwrap();
+        let mut core = Core::new().unwrap();
+        let pool = CpuPool::new(1);
+        let cache = DiskCache::new(&td.path().join("cache"), 1 << 20, None, &pool);
+        let layout = td.path().join("layout");
+        assert_eq!(export(&mut core, &cache, &layout, "empty").unwrap(), 0);
+        let mut entry = CacheWrite::new().unwrap();
+        entry.put_object("obj", &mut Cursor::new(b"data".to_vec()), None).unwrap();
+        core.run(cache.put("aaaaaa", entry)).unwrap();
+        assert_eq!(export(&mut core, &cache, &layout, "full").unwrap(), 1);
+        assert_eq!(export(&mut core, &cache, &layout, "full").unwrap(), 1);
+
+        let index: Index = serde_json::from_reader(File::open(layout.join("index.json")).unwrap()).unwrap();
+        assert_eq!(index.manifests.len(), 2);
+        assert!(find_manifest(&layout, None).is_err());
+        let to = DiskCache::new(&td.path().join("to"), 1 << 20, None, &pool);
+        assert_eq!(import(&mut core, &to, &layout, Some("empty")).unwrap(), 0);
+        assert_eq!(import(&mut core, &to, &layout, Some("full")).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_parse_reference() {
+        let reference = |registry: &str, repository: &str, tag: &str| Reference {
+            registry: registry.to_owned(),
+            repository: repository.to_owned(),
+            tag: tag.to_owned(),
+        };
+        assert_eq!(Reference::parse("sccache").unwrap(), reference(DOCKER_HUB_REGISTRY, "library/sccache", "latest"));
+        assert_eq!(Reference::parse("team/sccache:v1").unwrap(), reference(DOCKER_HUB_REGISTRY, "team/sccache", "v1"));
+        assert_eq!(Reference::parse("ghcr.io/team/sccache").unwrap(), reference("ghcr.io", "team/sccache", "latest"));
+        assert_eq!(Reference::parse("localhost:5000/sccache:ci").unwrap(), reference("localhost:5000", "sccache", "ci"));
+        assert!(Reference::parse("ghcr.io/team/sccache@sha256:0123").is_err());
+        assert!(Reference::parse("sccache:").is_err());
+    }
+
+    #[test]
+    fn test_parse_challenge() {
+        let (scheme, params) = parse_challenge(r#"Bearer realm="https://auth.example.com/token",service="registry.example.com",scope="repository:a/b:pull""#);
+        assert_eq!(scheme, "bearer");
+        assert_eq!(params["realm"], "https://auth.example.com/token");
+        assert_eq!(params["service"], "registry.example.com");
+        assert_eq!(params["scope"], "repository:a/b:pull");
+        let (scheme, params) = parse_challenge(r#"Basic realm="Registry Realm""#);
+        assert_eq!(scheme, "basic");
+        assert_eq!(params["realm"], "Registry Realm");
+    }
+}
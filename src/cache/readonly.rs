@@ -0,0 +1,157 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    CompilerDigests,
+    PruneStats,
+    Storage,
+    VerifyStats,
+};
+use cache::shadow::ShadowStats;
+use config::CacheCompression;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use errors::*;
+
+/// A `Storage` that can be switched at runtime to discard writes while still
+/// serving reads from another `Storage`.
+pub struct ReadOnlyStorage {
+    inner: Arc<Storage>,
+    read_only: Arc<AtomicBool>,
+}
+
+impl ReadOnlyStorage {
+    /// Wrap `inner`, discarding writes while `read_only` is set.
+    pub fn new(inner: Arc<Storage>, read_only: Arc<AtomicBool>) -> ReadOnlyStorage {
+        ReadOnlyStorage { inner, read_only }
+    }
+}
+
+impl Storage for ReadOnlyStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        self.inner.get(key)
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        if self.read_only.load(Ordering::SeqCst) {
+            debug!("Cache is read-only, not storing {}", key);
+            return f_ok(Duration::new(0, 0))
+        }
+        self.inner.put(key, entry)
+    }
+
+    fn location(&self) -> String { self.inner.location() }
+    fn current_size(&self) -> Option<u64> { self.inner.current_size() }
+    fn max_size(&self) -> Option<u64> { self.inner.max_size() }
+    fn current_entries(&self) -> Option<u64> { self.inner.current_entries() }
+    fn max_entries(&self) -> Option<u64> { self.inner.max_entries() }
+    fn compression(&self) -> CacheCompression { self.inner.compression() }
+    fn shadow_stats(&self) -> Option<ShadowStats> { self.inner.shadow_stats() }
+
+    fn prune_older_than(&self, age: Duration) -> SFuture<PruneStats> {
+        self.inner.prune_older_than(age)
+    }
+
+    fn compiler_digests(&self) -> SFuture<CompilerDigests> {
+        self.inner.compiler_digests()
+    }
+
+    fn purge_stale_compilers(&self, current: HashMap<PathBuf, String>) -> SFuture<PruneStats> {
+        self.inner.purge_stale_compilers(current)
+    }
+
+    fn set_max_size(&self, size: u64) -> SFuture<PruneStats> {
+        self.inner.set_max_size(size)
+    }
+
+    fn remove_label(&self, label: &str) -> SFuture<PruneStats> {
+        self.inner.remove_label(label)
+    }
+
+    fn verify(&self, repair: bool, rate: Option<u32>) -> SFuture<VerifyStats> {
+        self.inner.verify(repair, rate)
+    }
+
+    fn keys(&self) -> SFuture<Vec<String>> {
+        self.inner.keys()
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        if self.read_only.load(Ordering::SeqCst) {
+            debug!("Cache is read-only, not removing {}", key);
+            return f_ok(())
+        }
+        self.inner.remove(key)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst) || self.inner.is_read_only()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cache::disk::DiskCache;
+    use futures::Future;
+    use futures_cpupool::CpuPool;
+    use std::io::Cursor;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_read_only_toggle() {
+        let td = TempDir::new("sccache").unwrap();
+        let pool = CpuPool::new(1);
+        let read_only = Arc::new(AtomicBool::new(true));
+        let storage = ReadOnlyStorage::new(Arc::new(DiskCache::new(&td.path(), 1 << 20, None, &pool)),
+                                           read_only.clone());
+        let entry = || {
+            let mut entry = CacheWrite::new().unwrap();
+            entry.put_object("obj", &mut Cursor::new(b"data".to_vec()), None).unwrap();
+            entry
+        };
+        storage.put("abcdef", entry()).wait().unwrap();
+        match storage.get("abcdef").wait().unwrap() {
+            Cache::Miss => {}
+            _ => panic!("Expected a miss while read-only"),
+        }
+        assert!(storage.is_read_only());
+        read_only.store(false, Ordering::SeqCst);
+        assert!(!storage.is_read_only());
+        storage.put("abcdef", entry()).wait().unwrap();
+        match storage.get("abcdef").wait().unwrap() {
+            Cache::Hit(_) => {}
+            _ => panic!("Expected a hit after leaving read-only mode"),
+        }
+        // Removals are discarded while read-only too.
+        read_only.store(true, Ordering::SeqCst);
+        storage.remove("abcdef").wait().unwrap();
+        read_only.store(false, Ordering::SeqCst);
+        match storage.get("abcdef").wait().unwrap() {
+            Cache::Hit(_) => {}
+            _ => panic!("Expected removing while read-only to leave the entry"),
+        }
+        storage.remove("abcdef").wait().unwrap();
+        match storage.get("abcdef").wait().unwrap() {
+            Cache::Miss => {}
+            _ => panic!("Expected a miss after removing the entry"),
+        }
+    }
+}
@@ -13,66 +13,589 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use base64;
 use cache::{
     Cache,
     CacheRead,
     CacheWrite,
+    PruneStats,
     Storage,
 };
+use cache::chunked::{self, ChunkedWrite};
 use errors::*;
 use futures_cpupool::CpuPool;
+use native_tls::{
+    Certificate,
+    HandshakeError,
+    TlsConnector,
+};
 use redis::{
+    self,
     cmd,
     Client,
+    Cmd,
     Commands,
-    Connection,
+    ConnectionAddr,
+    ConnectionInfo,
+    ConnectionLike,
+    FromRedisValue,
     InfoDict,
+    IntoConnectionInfo,
+    Parser,
+    RedisError,
+    RedisResult,
+    Value,
 };
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
-use std::io::Cursor;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::time::{
     Duration,
     Instant,
 };
 
+/// How many redirections to follow for one command on a Redis Cluster.
+const MAX_REDIRECTIONS: usize = 5;
+
+/// The number of hash slots keys are spread over on a Redis Cluster.
+const CLUSTER_SLOTS: u16 = 16384;
+
+/// The first byte of a TLS alert record.
+const TLS_ALERT: u8 = 0x15;
+
+/// The first byte of a TLS handshake record.
+const TLS_HANDSHAKE: u8 = 0x16;
+
 /// A cache that stores entries in a Redis.
 #[derive(Clone)]
 pub struct RedisCache {
     url: String,
-    client: Client,
+    backend: Backend,
+    max_object_size: u64,
     pool: CpuPool,
 }
 
+/// Where a `RedisCache` stores entries.
+#[derive(Clone)]
+enum Backend {
+    /// A single server.
+    Single(Server),
+    /// A Redis Cluster.
+    Cluster(Arc<ClusterClient>),
+}
+
+/// How to reach a single server.
+#[derive(Clone)]
+enum Server {
+    /// A TCP address, connected to with TLS for `rediss://` URLs.
+    Tcp(String, u16, Connector),
+    /// Another kind of address, such as a Unix socket, connected to by the redis crate.
+    Other(Client),
+}
+
+/// A connection for one cache operation.
+enum Session<'a> {
+    Single(Connection),
+    Cluster(&'a ClusterClient),
+}
+
 impl RedisCache {
-    /// Create a new `RedisCache`.
-    pub fn new(url: &str, pool: &CpuPool) -> Result<RedisCache> {
+    /// Create a new `RedisCache`, storing entries larger than `max_object_size` in chunks.
+    /// If `cluster`, `url` is a comma-separated list of the URLs of nodes of a Redis Cluster
+    /// to find the rest of it through. TLS connections for `rediss://` URLs trust the
+    /// certificates in `ca_bundle` as well as the system's, and don't check they're for
+    /// the host connected to if `skip_hostname_verification`.
+    pub fn new(url: &str,
+               cluster: bool,
+               ca_bundle: Option<&Path>,
+               skip_hostname_verification: bool,
+               max_object_size: u64,
+               pool: &CpuPool) -> Result<RedisCache> {
+        let backend = if cluster {
+            Backend::Cluster(Arc::new(ClusterClient::new(url, ca_bundle, skip_hostname_verification)?))
+        } else {
+            let (info, tls) = parse_url(url)?;
+            let tcp = match *info.addr {
+                ConnectionAddr::Tcp(ref host, port) => Some((host.clone(), port)),
+                _ => None,
+            };
+            let server = match tcp {
+                Some((host, port)) => {
+                    let connector = Connector::new(tls, info.passwd.clone(), info.db,
+                                                   ca_bundle, skip_hostname_verification)?;
+                    Server::Tcp(host, port, connector)
+                }
+                None if tls => bail!("TLS is only supported for TCP addresses: {}", url),
+                None => Server::Other(Client::open(info)?),
+            };
+            Backend::Single(server)
+        };
         Ok(RedisCache {
             url: url.to_owned(),
-            client: Client::open(url)?,
+            backend,
+            max_object_size,
             pool: pool.clone(),
         })
     }
 
-    /// Returns a connection with configured read and write timeouts.
+    /// Returns a connection with configured read and write timeouts, to the
+    /// first seed node of a cluster.
     fn connect(&self) -> Result<Connection> {
-        self.client.get_connection()
-            .map_err(|e| e.into())
-            .and_then(|c| {
+        match self.backend {
+            Backend::Single(Server::Tcp(ref host, port, ref connector)) => {
+                connector.connect(host, port).map(Connection::Stream)
+            }
+            Backend::Single(Server::Other(ref client)) => {
+                let c = client.get_connection()?;
                 c.set_read_timeout(Some(Duration::from_millis(10_000)))?;
                 c.set_write_timeout(Some(Duration::from_millis(10_000)))?;
-                Ok(c)
-            })
+                Ok(Connection::Native(c))
+            }
+            Backend::Cluster(ref cluster) => cluster.connect(&cluster.seeds[0]).map(Connection::Stream),
+        }
+    }
+
+    fn session(&self) -> Result<Session> {
+        match self.backend {
+            Backend::Single(_) => self.connect().map(Session::Single),
+            Backend::Cluster(ref cluster) => Ok(Session::Cluster(cluster)),
+        }
+    }
+}
+
+/// Parse a Redis URL, returning whether it's a `rediss://` URL for a TLS
+/// connection.
+fn parse_url(url: &str) -> Result<(ConnectionInfo, bool)> {
+    // The redis crate doesn't know the scheme, but the URLs are otherwise the same.
+    if url.starts_with("rediss://") {
+        let info = format!("redis://{}", &url["rediss://".len()..])[..].into_connection_info()?;
+        Ok((info, true))
+    } else {
+        Ok((url.into_connection_info()?, false))
+    }
+}
+
+/// The DER encodings of the certificates in `pem`.
+fn pem_certificates(pem: &str) -> Result<Vec<Vec<u8>>> {
+    let mut certs = vec![];
+    let mut cert = None;
+    for line in pem.lines().map(str::trim) {
+        if line == "-----BEGIN CERTIFICATE-----" {
+            cert = Some(String::new());
+        } else if line == "-----END CERTIFICATE-----" {
+            if let Some(cert) = cert.take() {
+                certs.push(base64::decode(&cert).chain_err(|| "Invalid PEM certificate")?);
+            }
+        } else if let Some(ref mut cert) = cert {
+            cert.push_str(line);
+        }
+    }
+    Ok(certs)
+}
+
+/// A TLS connector trusting the certificates in the PEM file at `ca_bundle`
+/// as well as the system's.
+fn tls_connector(ca_bundle: Option<&Path>) -> Result<TlsConnector> {
+    let mut builder = TlsConnector::builder()?;
+    if let Some(path) = ca_bundle {
+        let mut pem = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut pem))
+            .chain_err(|| format!("Failed to read the CA bundle {}", path.display()))?;
+        let certs = pem_certificates(&pem)
+            .chain_err(|| format!("Failed to parse the CA bundle {}", path.display()))?;
+        if certs.is_empty() {
+            bail!("No certificates in the CA bundle {}", path.display());
+        }
+        for der in certs {
+            builder.add_root_certificate(Certificate::from_der(&der)?)?;
+        }
+    }
+    Ok(builder.build()?)
+}
+
+/// Opens connections to servers over TCP, with TLS if configured for it.
+#[derive(Clone)]
+struct Connector {
+    /// The TLS connector to use, and whether it checks certificates are for
+    /// the host connected to.
+    tls: Option<(Arc<TlsConnector>, bool)>,
+    passwd: Option<String>,
+    db: i64,
+}
+
+impl Connector {
+    fn new(tls: bool,
+           passwd: Option<String>,
+           db: i64,
+           ca_bundle: Option<&Path>,
+           skip_hostname_verification: bool) -> Result<Connector> {
+        let tls = if tls {
+            Some((Arc::new(tls_connector(ca_bundle)?), !skip_hostname_verification))
+        } else {
+            None
+        };
+        Ok(Connector { tls, passwd, db })
+    }
+
+    /// Open a connection to `host` with configured read and write timeouts,
+    /// and authenticate and select the database on it.
+    fn connect(&self, host: &str, port: u16) -> Result<StreamConnection> {
+        let tcp = TcpStream::connect((host, port))?;
+        tcp.set_read_timeout(Some(Duration::from_millis(10_000)))?;
+        tcp.set_write_timeout(Some(Duration::from_millis(10_000)))?;
+        let stream: Box<Stream> = match self.tls {
+            Some((ref tls, verify_hostname)) => {
+                let res = if verify_hostname {
+                    tls.connect(host, tcp)
+                } else {
+                    tls.danger_connect_without_providing_domain_for_certificate_verification_and_server_name_indication(tcp)
+                };
+                match res {
+                    Ok(stream) => Box::new(stream),
+                    Err(HandshakeError::Failure(e)) => {
+                        return Err(e).chain_err(|| format!("TLS handshake with {}:{} failed", host, port))
+                    }
+                    Err(HandshakeError::Interrupted(_)) => bail!("TLS handshake with {}:{} timed out", host, port),
+                }
+            }
+            None => Box::new(tcp),
+        };
+        let c = StreamConnection {
+            stream: RefCell::new(BufReader::new(stream)),
+            tls: self.tls.is_some(),
+            replied: Cell::new(false),
+            db: self.db,
+        };
+        if let Some(ref passwd) = self.passwd {
+            cmd("AUTH").arg(&passwd[..]).query::<()>(&c)?;
+        }
+        if self.db != 0 {
+            cmd("SELECT").arg(self.db).query::<()>(&c)?;
+        }
+        Ok(c)
+    }
+}
+
+/// A plaintext or TLS stream to a server.
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// A connection to a server over TCP, with or without TLS.
+struct StreamConnection {
+    stream: RefCell<BufReader<Box<Stream>>>,
+    tls: bool,
+    /// Whether the server has replied on this connection yet.
+    replied: Cell<bool>,
+    db: i64,
+}
+
+impl StreamConnection {
+    fn read_value(&self, stream: &mut BufReader<Box<Stream>>) -> RedisResult<Value> {
+        if !self.tls && !self.replied.get() {
+            let first = match stream.fill_buf() {
+                Ok(buf) => buf.first().cloned(),
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => None,
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(e) = tls_only_error(first) {
+                return Err(e)
+            }
+        }
+        self.replied.set(true);
+        Parser::new(stream).parse_value()
+    }
+}
+
+/// The error for a plaintext connection whose first reply starts with
+/// `first`, if that shows the server only accepts TLS connections: such
+/// servers answer plaintext with a TLS alert, or just hang up or reset the
+/// connection.
+fn tls_only_error(first: Option<u8>) -> Option<RedisError> {
+    match first {
+        None | Some(TLS_ALERT) | Some(TLS_HANDSHAKE) => {
+            Some(RedisError::from((redis::ErrorKind::IoError,
+                                   "The Redis server didn't reply in the Redis protocol",
+                                   "it seems to only accept TLS connections, use a rediss:// URL for it".to_owned())))
+        }
+        Some(_) => None,
+    }
+}
+
+impl ConnectionLike for StreamConnection {
+    fn req_packed_command(&self, cmd: &[u8]) -> RedisResult<Value> {
+        let mut stream = self.stream.borrow_mut();
+        stream.get_mut().write_all(cmd)?;
+        self.read_value(&mut stream)
+    }
+
+    fn req_packed_commands(&self, cmd: &[u8], offset: usize, count: usize) -> RedisResult<Vec<Value>> {
+        let mut stream = self.stream.borrow_mut();
+        stream.get_mut().write_all(cmd)?;
+        let mut values = vec![];
+        for i in 0..offset + count {
+            let value = self.read_value(&mut stream)?;
+            if i >= offset {
+                values.push(value);
+            }
+        }
+        Ok(values)
+    }
+
+    fn get_db(&self) -> i64 { self.db }
+}
+
+/// The SCAN pattern matching the keys sccache stores entries under: the hex
+/// SHA-512 digests of compilations, followed by `.chunk-...` for chunks.
+fn prune_pattern() -> String {
+    let mut pattern = "[0-9a-f]".repeat(128);
+    pattern.push('*');
+    pattern
+}
+
+/// A connection to a single server.
+enum Connection {
+    Stream(StreamConnection),
+    Native(redis::Connection),
+}
+
+impl ConnectionLike for Connection {
+    fn req_packed_command(&self, cmd: &[u8]) -> RedisResult<Value> {
+        match *self {
+            Connection::Stream(ref c) => c.req_packed_command(cmd),
+            Connection::Native(ref c) => c.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands(&self, cmd: &[u8], offset: usize, count: usize) -> RedisResult<Vec<Value>> {
+        match *self {
+            Connection::Stream(ref c) => c.req_packed_commands(cmd, offset, count),
+            Connection::Native(ref c) => c.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match *self {
+            Connection::Stream(ref c) => c.get_db(),
+            Connection::Native(ref c) => c.get_db(),
+        }
+    }
+}
+
+impl<'a> Session<'a> {
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        match *self {
+            Session::Single(ref c) => Ok(c.get(key)?),
+            Session::Cluster(cluster) => cluster.query(key, cmd("GET").arg(key)),
+        }
+    }
+
+    /// Get the values of `keys`, which on a cluster may be served by different nodes.
+    fn get_many(&self, keys: &[String]) -> Result<Vec<Vec<u8>>> {
+        match *self {
+            Session::Single(ref c) => Ok(cmd("MGET").arg(keys).query(c)?),
+            Session::Cluster(cluster) => keys.iter().map(|key| cluster.query(key, cmd("GET").arg(key))).collect(),
+        }
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+        match *self {
+            Session::Single(ref c) => Ok(c.set::<&str, &[u8], ()>(key, value)?),
+            Session::Cluster(cluster) => cluster.query(key, cmd("SET").arg(key).arg(value)),
+        }
+    }
+
+    fn del(&self, key: &str) -> Result<()> {
+        match *self {
+            Session::Single(ref c) => Ok(c.del::<&str, ()>(key)?),
+            Session::Cluster(cluster) => cluster.query(key, cmd("DEL").arg(key)),
+        }
+    }
+
+    fn set_many(&self, pairs: &[(String, Vec<u8>)]) -> Result<()> {
+        match *self {
+            Session::Single(ref c) => Ok(cmd("MSET").arg(pairs).query(c)?),
+            Session::Cluster(cluster) => {
+                for &(ref key, ref value) in pairs {
+                    cluster.query::<()>(key, cmd("SET").arg(key).arg(&value[..]))?;
+                }
+                Ok(())
+            }
+        }
     }
 }
 
+/// The nodes of a Redis Cluster, found by following the redirections of
+/// the nodes a command is sent to.
+struct ClusterClient {
+    /// The addresses of the nodes to start from.
+    seeds: Vec<String>,
+    /// Opens connections to the nodes.
+    connector: Connector,
+    /// The address of the node serving each hash slot, as far as known.
+    slots: Mutex<HashMap<u16, String>>,
+    /// Idle connections to nodes, by address.
+    connections: Mutex<HashMap<String, StreamConnection>>,
+}
+
+/// Where a Redis Cluster node redirected a command to.
+#[derive(Debug, PartialEq, Eq)]
+enum Redirect {
+    /// The slot has moved to the node for good.
+    Moved(u16, String),
+    /// The slot is being migrated, and this command should go to the node.
+    Ask(String),
+}
+
+impl ClusterClient {
+    fn new(urls: &str, ca_bundle: Option<&Path>, skip_hostname_verification: bool) -> Result<ClusterClient> {
+        let mut seeds = vec![];
+        let mut passwd = None;
+        let mut use_tls = None;
+        for url in urls.split(',').map(str::trim).filter(|url| !url.is_empty()) {
+            let (info, tls) = parse_url(url)?;
+            match *info.addr {
+                ConnectionAddr::Tcp(ref host, port) => seeds.push(format!("{}:{}", host, port)),
+                _ => bail!("Redis Cluster nodes must have TCP addresses: {}", url),
+            }
+            if *use_tls.get_or_insert(tls) != tls {
+                bail!("Either all or none of the Redis Cluster nodes must have rediss:// URLs");
+            }
+            passwd = passwd.or(info.passwd);
+        }
+        if seeds.is_empty() {
+            bail!("No Redis Cluster nodes given");
+        }
+        let connector = Connector::new(use_tls == Some(true), passwd, 0, ca_bundle, skip_hostname_verification)?;
+        Ok(ClusterClient {
+            seeds,
+            connector,
+            slots: Mutex::new(HashMap::new()),
+            connections: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Open a connection to the node at `addr`.
+    fn connect(&self, addr: &str) -> Result<StreamConnection> {
+        match addr.rfind(':').map(|i| (&addr[..i], addr[i + 1..].parse())) {
+            Some((host, Ok(port))) => self.connector.connect(host, port),
+            _ => bail!("Invalid Redis Cluster node address: {}", addr),
+        }
+    }
+
+    /// Run `command` on `key` on the node serving it.
+    fn query<T: FromRedisValue>(&self, key: &str, command: &Cmd) -> Result<T> {
+        let slot = key_slot(key);
+        let mut node = self.slots.lock().unwrap().get(&slot).cloned()
+            .unwrap_or_else(|| self.seeds[slot as usize % self.seeds.len()].clone());
+        let mut asking = false;
+        for _ in 0..MAX_REDIRECTIONS {
+            let res = self.query_node(&node, command, asking);
+            match res.as_ref().err().and_then(|e| parse_redirect(&e.to_string())) {
+                Some(Redirect::Moved(slot, to)) => {
+                    trace!("Redis Cluster slot {} moved to {}", slot, to);
+                    self.slots.lock().unwrap().insert(slot, to.clone());
+                    node = to;
+                    asking = false;
+                }
+                Some(Redirect::Ask(to)) => {
+                    node = to;
+                    asking = true;
+                }
+                None => return res,
+            }
+        }
+        bail!("Too many Redis Cluster redirections for {}", key)
+    }
+
+    /// Run `command` on the node at `addr`, reusing an idle connection to it
+    /// if there is one.
+    fn query_node<T: FromRedisValue>(&self, addr: &str, command: &Cmd, asking: bool) -> Result<T> {
+        let idle = self.connections.lock().unwrap().remove(addr);
+        let c = match idle {
+            Some(c) => c,
+            None => self.connect(addr)?,
+        };
+        let res = if asking {
+            cmd("ASKING").query::<()>(&c).and_then(|_| command.query(&c))
+        } else {
+            command.query(&c)
+        };
+        match res {
+            // The connection may be broken, don't reuse it.
+            Err(ref e) if e.kind() == redis::ErrorKind::IoError => {}
+            _ => { self.connections.lock().unwrap().insert(addr.to_owned(), c); }
+        }
+        Ok(res?)
+    }
+}
+
+/// The Redis Cluster hash slot of `key`: the CRC16 of the key, or of the
+/// part in the first non-empty `{...}` in it, modulo the number of slots.
+fn key_slot(key: &str) -> u16 {
+    let key = key.as_bytes();
+    let hashed = key.iter().position(|&b| b == b'{')
+        .and_then(|open| {
+            match key[open + 1..].iter().position(|&b| b == b'}') {
+                Some(len) if len > 0 => Some(&key[open + 1..open + 1 + len]),
+                _ => None,
+            }
+        })
+        .unwrap_or(key);
+    crc16(hashed) % CLUSTER_SLOTS
+}
+
+/// CRC16/XMODEM, as Redis Cluster uses.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// The redirection in a Redis Cluster error such as `MOVED 3999 127.0.0.1:6381`
+/// or `ASK 3999 127.0.0.1:6381`, if it is one.
+fn parse_redirect(error: &str) -> Option<Redirect> {
+    let words = error.split_whitespace().map(|w| w.trim_matches(':')).collect::<Vec<_>>();
+    words.iter().position(|&w| w == "MOVED" || w == "ASK").and_then(|i| {
+        match (words.get(i + 1).and_then(|slot| slot.parse().ok()), words.get(i + 2)) {
+            (Some(slot), Some(addr)) if words[i] == "MOVED" => Some(Redirect::Moved(slot, addr.to_string())),
+            (Some(_), Some(addr)) => Some(Redirect::Ask(addr.to_string())),
+            _ => None,
+        }
+    })
+}
+
 impl Storage for RedisCache {
     /// Open a connection and query for a key.
     fn get(&self, key: &str) -> SFuture<Cache> {
         let key = key.to_owned();
         let me = self.clone();
         Box::new(self.pool.spawn_fn(move || {
-            let c = me.connect()?;
-            let d = c.get::<&str, Vec<u8>>(&key)?;
+            let c = me.session()?;
+            let mut d = c.get(&key)?;
+            if let Some(manifest) = chunked::parse_manifest(&d) {
+                let manifest = manifest?;
+                let chunks = c.get_many(&manifest.chunks)?
+                    .into_iter()
+                    .map(|chunk| if chunk.is_empty() { None } else { Some(chunk) })
+                    .collect();
+                match chunked::join(&manifest, chunks) {
+                    Some(joined) => d = joined,
+                    None => {
+                        debug!("Chunks of {} have been evicted", key);
+                        return Ok(Cache::Miss)
+                    }
+                }
+            }
             if d.is_empty() {
                 Ok(Cache::Miss)
             } else {
@@ -88,21 +611,44 @@ impl Storage for RedisCache {
         let me = self.clone();
         Box::new(self.pool.spawn_fn(move || {
             let start = Instant::now();
-            let c = me.connect()?;
+            let c = me.session()?;
             let d = entry.finish()?;
-            c.set::<&str, Vec<u8>, ()>(&key, d)?;
+            match chunked::split(&key, d, me.max_object_size)? {
+                ChunkedWrite::Whole(d) => c.set(&key, &d)?,
+                ChunkedWrite::Chunked { chunks, manifest } => {
+                    c.set_many(&chunks)?;
+                    c.set(&key, &manifest)?;
+                }
+            }
             Ok(start.elapsed())
         }))
     }
 
+    /// Delete the entry under `key`. The chunks of a chunked entry are
+    /// left for Redis to evict.
+    fn remove(&self, key: &str) -> SFuture<()> {
+        let key = key.to_owned();
+        let me = self.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            me.session()?.del(&key)
+        }))
+    }
+
     /// Returns the cache location.
     fn location(&self) -> String {
-        format!("Redis: {}", self.url)
+        match self.backend {
+            Backend::Single(_) => format!("Redis: {}", self.url),
+            Backend::Cluster(_) => format!("Redis Cluster: {}", self.url),
+        }
     }
 
     /// Returns the current cache size. This value is aquired via
-    /// the Redis INFO command (used_memory).
+    /// the Redis INFO command (used_memory). Each node of a cluster only
+    /// knows its own, so the result is None for clusters.
     fn current_size(&self) -> Option<u64> {
+        if let Backend::Cluster(_) = self.backend {
+            return None
+        }
         self.connect().ok()
             .and_then(|c| cmd("INFO").query(&c).ok())
             .and_then(|i: InfoDict| i.get("used_memory"))
@@ -110,8 +656,11 @@ impl Storage for RedisCache {
 
     /// Returns the maximum cache size. This value is read via
     /// the Redis CONFIG command (maxmemory). If the server has no
-    /// configured limit, the result is None.
+    /// configured limit, or it's a cluster, the result is None.
     fn max_size(&self) -> Option<u64> {
+        if let Backend::Cluster(_) = self.backend {
+            return None
+        }
         self.connect().ok()
             .and_then(|c| cmd("CONFIG").arg("GET").arg("maxmemory").query(&c).ok())
             .and_then(|h: HashMap<String, usize>| h.get("maxmemory").map(|s| *s))
@@ -123,4 +672,239 @@ impl Storage for RedisCache {
                 }
             })
     }
+
+    /// Returns the current number of keys. This value is aquired via
+    /// the Redis DBSIZE command, or None for clusters, where each
+    /// node only counts its own.
+    fn current_entries(&self) -> Option<u64> {
+        if let Backend::Cluster(_) = self.backend {
+            return None
+        }
+        self.connect().ok()
+            .and_then(|c| cmd("DBSIZE").query(&c).ok())
+    }
+
+    /// Redis has no limit on the number of keys, it evicts according
+    /// to its maxmemory policy.
+    fn max_entries(&self) -> Option<u64> { None }
+
+    /// Deletes entries whose idle time, from the Redis OBJECT IDLETIME
+    /// command, is at least `age`. Only string values under keys shaped
+    /// like sccache's hash keys and their chunks are considered, so other
+    /// data in the database is left alone.
+    fn prune_older_than(&self, age: Duration) -> SFuture<PruneStats> {
+        let me = self.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            if let Backend::Cluster(_) = me.backend {
+                bail!("Pruning isn't supported on a Redis Cluster");
+            }
+            let c = me.connect()?;
+            let keys: Vec<String> = c.scan_match(&prune_pattern())?.collect();
+            let mut stats = PruneStats::default();
+            for key in keys {
+                let kind: String = cmd("TYPE").arg(&key).query(&c)?;
+                if kind != "string" {
+                    continue
+                }
+                let idle: Option<u64> = cmd("OBJECT").arg("IDLETIME").arg(&key).query(&c)?;
+                if idle.map_or(false, |idle| idle >= age.as_secs()) {
+                    let len: u64 = cmd("STRLEN").arg(&key).query(&c)?;
+                    let deleted: u64 = c.del(&key)?;
+                    if deleted > 0 {
+                        stats.entries += 1;
+                        stats.bytes += len;
+                    }
+                }
+            }
+            Ok(stats)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::Future;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[test]
+    fn test_prune_pattern() {
+        let pattern = prune_pattern();
+        assert_eq!(128 * "[0-9a-f]".len() + 1, pattern.len());
+        assert!(pattern.starts_with("[0-9a-f][0-9a-f]"));
+        assert!(pattern.ends_with("]*"));
+    }
+
+    #[test]
+    fn test_key_slot() {
+        assert_eq!(12739, key_slot("123456789"));
+        assert_eq!(12182, key_slot("foo"));
+        assert_eq!(key_slot("user1000"), key_slot("{user1000}.following"));
+        assert_eq!(key_slot("{}.following"), crc16(b"{}.following") % CLUSTER_SLOTS);
+    }
+
+    #[test]
+    fn test_parse_redirect() {
+        assert_eq!(Some(Redirect::Moved(3999, "127.0.0.1:6381".to_owned())),
+                   parse_redirect("MOVED 3999 127.0.0.1:6381"));
+        assert_eq!(Some(Redirect::Moved(3999, "127.0.0.1:6381".to_owned())),
+                   parse_redirect("MOVED: 3999 127.0.0.1:6381"));
+        assert_eq!(Some(Redirect::Ask("10.0.0.2:7000".to_owned())),
+                   parse_redirect("ASK: 3999 10.0.0.2:7000"));
+        assert_eq!(None, parse_redirect("ERR unknown command"));
+    }
+
+    #[test]
+    fn test_parse_url() {
+        let (info, tls) = parse_url("rediss://:hunter2@redis.example.com:6380/2").unwrap();
+        assert!(tls);
+        match *info.addr {
+            ConnectionAddr::Tcp(ref host, port) => assert_eq!(("redis.example.com", 6380), (&host[..], port)),
+            _ => panic!("Expected a TCP address"),
+        }
+        assert_eq!(info.passwd, Some("hunter2".to_owned()));
+        assert_eq!(info.db, 2);
+        let (info, tls) = parse_url("redis://redis.example.com/").unwrap();
+        assert!(!tls);
+        match *info.addr {
+            ConnectionAddr::Tcp(ref host, port) => assert_eq!(("redis.example.com", 6379), (&host[..], port)),
+            _ => panic!("Expected a TCP address"),
+        }
+    }
+
+    #[test]
+    fn test_pem_certificates() {
+        let pem = "-----BEGIN CERTIFICATE-----\nYWJj\nZGVm\n-----END CERTIFICATE-----\n\
+                   junk\n\
+                   -----BEGIN CERTIFICATE-----\r\nZ2hp\r\n-----END CERTIFICATE-----\r\n";
+        assert_eq!(pem_certificates(pem).unwrap(), vec![b"abcdef".to_vec(), b"ghi".to_vec()]);
+        assert!(pem_certificates("").unwrap().is_empty());
+        assert!(pem_certificates("-----BEGIN CERTIFICATE-----\n!!\n-----END CERTIFICATE-----\n").is_err());
+    }
+
+    #[test]
+    fn test_plaintext_to_tls_server() {
+        let pool = CpuPool::new(1);
+        // A TLS server answers a plaintext command with a protocol version alert.
+        let tls_server = mock_server(|_| vec![TLS_ALERT, 0x03, 0x01, 0x00, 0x02, 0x02, 0x46]);
+        // Or it may just hang up.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let hangs_up = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let _ = stream.read(&mut [0; 64]);
+            }
+        });
+        for addr in &[tls_server, hangs_up] {
+            let cache = RedisCache::new(&format!("redis://{}/", addr), false, None, false, 1 << 20, &pool).unwrap();
+            let err = cache.get("abcdef").wait().err().expect("Expected an error");
+            assert!(err.to_string().contains("rediss://"), "Unexpected error: {}", err);
+        }
+    }
+
+    /// Read a command sent to a mock Redis server.
+    fn read_command(r: &mut BufReader<TcpStream>) -> Option<Vec<Vec<u8>>> {
+        let mut line = String::new();
+        if r.read_line(&mut line).ok()? == 0 {
+            return None
+        }
+        let n: usize = line.trim()[1..].parse().ok()?;
+        let mut args = vec![];
+        for _ in 0..n {
+            line.clear();
+            r.read_line(&mut line).ok()?;
+            let len: usize = line.trim()[1..].parse().ok()?;
+            let mut arg = vec![0; len + 2];
+            r.read_exact(&mut arg).ok()?;
+            arg.truncate(len);
+            args.push(arg);
+        }
+        Some(args)
+    }
+
+    /// Start a mock Redis server replying to each command with `reply`, and
+    /// return its address.
+    fn mock_server<F>(reply: F) -> String
+        where F: Fn(Vec<Vec<u8>>) -> Vec<u8> + Send + Sync + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let reply = Arc::new(reply);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let reply = reply.clone();
+                thread::spawn(move || {
+                    let mut w = stream.try_clone().unwrap();
+                    let mut r = BufReader::new(stream);
+                    while let Some(command) = read_command(&mut r) {
+                        w.write_all(&reply(command)).unwrap();
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_cluster_moved() {
+        // The node actually serving every slot.
+        let values = Arc::new(Mutex::new(HashMap::new()));
+        let node = {
+            let values = values.clone();
+            mock_server(move |command| {
+                let mut values = values.lock().unwrap();
+                match &command[0][..] {
+                    b"SET" => {
+                        values.insert(command[1].clone(), command[2].clone());
+                        b"+OK\r\n".to_vec()
+                    }
+                    b"GET" => match values.get(&command[1]) {
+                        Some(v) => {
+                            let mut reply = format!("${}\r\n", v.len()).into_bytes();
+                            reply.extend_from_slice(v);
+                            reply.extend_from_slice(b"\r\n");
+                            reply
+                        }
+                        None => b"$-1\r\n".to_vec(),
+                    },
+                    _ => b"-ERR unknown command\r\n".to_vec(),
+                }
+            })
+        };
+        // The seed node, which redirects everything.
+        let redirects = Arc::new(Mutex::new(0));
+        let seed = {
+            let (node, redirects) = (node.clone(), redirects.clone());
+            mock_server(move |command| {
+                *redirects.lock().unwrap() += 1;
+                let key = String::from_utf8(command[1].clone()).unwrap();
+                format!("-MOVED {} {}\r\n", key_slot(&key), node).into_bytes()
+            })
+        };
+
+        let pool = CpuPool::new(1);
+        let cache = RedisCache::new(&format!("redis://{}/", seed), true, None, false, 1 << 20, &pool).unwrap();
+        cache.put("abcdef", CacheWrite::new().unwrap()).wait().unwrap();
+        match cache.get("abcdef").wait().unwrap() {
+            Cache::Hit(_) => {}
+            _ => panic!("Expected a cache hit"),
+        }
+        // The get went straight to the node the slot moved to.
+        assert_eq!(1, *redirects.lock().unwrap());
+        assert!(values.lock().unwrap().contains_key(&b"abcdef"[..].to_vec()));
+        match cache.get("ghijkl").wait().unwrap() {
+            Cache::Miss => {}
+            _ => panic!("Expected a cache miss"),
+        }
+        assert_eq!(2, *redirects.lock().unwrap());
+        // No single node can tell how big the whole cluster is.
+        assert_eq!(None, cache.current_size());
+        assert_eq!(None, cache.current_entries());
+        assert_eq!(None, cache.max_size());
+        assert_eq!(2, *redirects.lock().unwrap());
+    }
 }
@@ -25,27 +25,45 @@ use simples3::{
     ChainProvider,
     ProfileProvider,
     ProvideAwsCredentials,
+    ServerSideEncryption,
     Ssl,
 };
+use std::cmp;
 use std::env;
-use std::io;
+use std::io::{self, Read};
 use std::rc::Rc;
 use std::time::{Instant, Duration};
 use tokio_core::reactor::Handle;
 
 use errors::*;
 
+/// S3 rejects multipart uploads with parts smaller than this, other than the last.
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
 /// A cache that stores entries in Amazon S3.
 pub struct S3Cache {
     /// The S3 bucket.
     bucket: Rc<Bucket>,
     /// Credentials provider.
     provider: AutoRefreshingProvider<ChainProvider>,
+    /// Entries larger than this are uploaded as a multipart upload.
+    multipart_threshold: u64,
+    /// The size of the parts of multipart uploads.
+    part_size: u64,
+    /// How many parts to upload at once.
+    upload_concurrency: usize,
 }
 
 impl S3Cache {
-    /// Create a new `S3Cache` storing data in `bucket`.
-    pub fn new(bucket: &str, endpoint: &str, handle: &Handle) -> Result<S3Cache> {
+    /// Create a new `S3Cache` storing data in `bucket`, encrypted at rest with
+    /// `sse` if that's `Some`.
+    pub fn new(bucket: &str,
+               endpoint: &str,
+               multipart_threshold: u64,
+               part_size: u64,
+               upload_concurrency: usize,
+               sse: Option<ServerSideEncryption>,
+               handle: &Handle) -> Result<S3Cache> {
         let home = env::home_dir().ok_or("Couldn't find home directory")?;
         let profile_providers = vec![
             ProfileProvider::with_configuration(home.join(".aws").join("credentials"), "default"),
@@ -56,10 +74,13 @@ impl S3Cache {
         ];
         let provider = AutoRefreshingProvider::new(ChainProvider::with_profile_providers(profile_providers, handle));
         //TODO: configurable SSL
-        let bucket = Rc::new(Bucket::new(bucket, endpoint, Ssl::No, handle)?);
+        let bucket = Rc::new(Bucket::new(bucket, endpoint, Ssl::No, sse, handle)?);
         Ok(S3Cache {
             bucket: bucket,
             provider: provider,
+            multipart_threshold: multipart_threshold,
+            part_size: cmp::max(part_size, MIN_PART_SIZE),
+            upload_concurrency: cmp::max(upload_concurrency, 1),
         })
     }
 }
@@ -88,8 +109,15 @@ impl Storage for S3Cache {
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
         let key = normalize_key(&key);
         let start = Instant::now();
-        let data = match entry.finish() {
-            Ok(data) => data,
+        // The entry is only read into memory whole if it's uploaded in one
+        // request; multipart uploads read each part from the file as it's
+        // uploaded.
+        let mut file = match entry.into_file() {
+            Ok(file) => file,
+            Err(e) => return f_err(e),
+        };
+        let len = match file.metadata() {
+            Ok(metadata) => metadata.len(),
             Err(e) => return f_err(e),
         };
         let credentials = self.provider.credentials().chain_err(|| {
@@ -97,10 +125,17 @@ impl Storage for S3Cache {
         });
 
         let bucket = self.bucket.clone();
+        let (multipart_threshold, part_size, upload_concurrency) =
+            (self.multipart_threshold, self.part_size, self.upload_concurrency);
         let response = credentials.and_then(move |credentials| {
-            bucket.put(&key, data, &credentials).chain_err(|| {
-                "failed to put cache entry in s3"
-            })
+            let put = if len > multipart_threshold {
+                bucket.put_multipart(&key, file, len, &credentials, part_size as usize, upload_concurrency)
+            } else {
+                let mut data = Vec::with_capacity(len as usize);
+                ftry!(file.read_to_end(&mut data));
+                bucket.put(&key, data, &credentials)
+            };
+            put.chain_err(|| "failed to put cache entry in s3")
         });
 
         Box::new(response.map(move |_| start.elapsed()))
@@ -110,6 +145,19 @@ impl Storage for S3Cache {
         format!("S3, bucket: {}", self.bucket)
     }
 
+    fn remove(&self, key: &str) -> SFuture<()> {
+        let key = normalize_key(key);
+        let credentials = self.provider.credentials().chain_err(|| {
+            "failed to get AWS credentials"
+        });
+        let bucket = self.bucket.clone();
+        Box::new(credentials.and_then(move |credentials| {
+            bucket.delete(&key, &credentials).chain_err(|| "failed to remove cache entry from s3")
+        }))
+    }
+
     fn current_size(&self) -> Option<u64> { None }
     fn max_size(&self) -> Option<u64> { None }
+    fn current_entries(&self) -> Option<u64> { None }
+    fn max_entries(&self) -> Option<u64> { None }
 }
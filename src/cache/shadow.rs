@@ -0,0 +1,263 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheRead,
+    CacheWrite,
+    CompilerDigests,
+    PruneStats,
+    Storage,
+    VerifyStats,
+};
+use config::CacheCompression;
+use futures::Future;
+use futures::sync::oneshot;
+use futures_cpupool::CpuPool;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_core::reactor::Handle;
+
+use errors::*;
+
+/// How a shadow cache fared next to the cache actually in use.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ShadowStats {
+    /// The location of the shadow cache.
+    pub location: String,
+    /// The number of lookups that hit in the shadow cache.
+    pub hits: u64,
+    /// The number of lookups that missed in the shadow cache.
+    pub misses: u64,
+    /// The number of lookups that failed in the shadow cache.
+    pub read_errors: u64,
+    /// The number of lookups that hit in the primary cache but not the shadow cache.
+    pub primary_only_hits: u64,
+    /// The total time spent on lookups in the shadow cache.
+    pub read_duration: Duration,
+    /// The number of entries stored in the shadow cache.
+    pub writes: u64,
+    /// The number of entries that couldn't be stored in the shadow cache.
+    pub write_errors: u64,
+    /// The total time spent storing entries in the shadow cache.
+    pub write_duration: Duration,
+}
+
+/// A `Storage` that mirrors every `get` and `put` on a primary `Storage` to a
+/// shadow `Storage`, to compare the two. Results are always served from the
+/// primary; the shadow's are only recorded in `ShadowStats`, and never delay
+/// or fail the primary's.
+pub struct ShadowStorage {
+    primary: Arc<Storage>,
+    shadow: Arc<Storage>,
+    stats: Arc<Mutex<ShadowStats>>,
+    pool: CpuPool,
+    handle: Handle,
+}
+
+impl ShadowStorage {
+    /// Mirror the operations on `primary` to `shadow`, copying entries on
+    /// `pool` and running the shadow's operations on `handle`.
+    pub fn new(primary: Arc<Storage>, shadow: Arc<Storage>, pool: &CpuPool, handle: &Handle) -> ShadowStorage {
+        let stats = ShadowStats { location: shadow.location(), ..Default::default() };
+        ShadowStorage {
+            primary,
+            shadow,
+            stats: Arc::new(Mutex::new(stats)),
+            pool: pool.clone(),
+            handle: handle.clone(),
+        }
+    }
+}
+
+impl Storage for ShadowStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let (tx, rx) = oneshot::channel();
+        let stats = self.stats.clone();
+        let owned_key = key.to_owned();
+        let start = Instant::now();
+        let shadow = self.shadow.get(key).then(move |res| {
+            let duration = start.elapsed();
+            let shadow_hit = {
+                let mut stats = stats.lock().unwrap();
+                stats.read_duration += duration;
+                match res {
+                    Ok(Cache::Hit(_)) => { stats.hits += 1; true }
+                    Ok(_) => { stats.misses += 1; false }
+                    Err(e) => {
+                        debug!("Shadow cache lookup of {} failed: {}", owned_key, e);
+                        stats.read_errors += 1;
+                        false
+                    }
+                }
+            };
+            rx.then(move |primary_hit| {
+                if let (Ok(true), false) = (primary_hit, shadow_hit) {
+                    info!("Shadow cache missed {}, which hit in the primary cache", owned_key);
+                    stats.lock().unwrap().primary_only_hits += 1;
+                }
+                Ok::<_, ()>(())
+            })
+        });
+        self.handle.spawn(shadow);
+        Box::new(self.primary.get(key).then(move |res| {
+            let hit = match res { Ok(Cache::Hit(_)) => true, _ => false };
+            drop(tx.send(hit));
+            res
+        }))
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        // Entries can only be written once, so copy this one for each storage,
+        // off the event loop since that means recompressing it.
+        let (primary_compression, shadow_compression) = (self.primary.compression(), self.shadow.compression());
+        let copies = self.pool.spawn_fn(move || -> Result<_> {
+            let mut entry = CacheRead::from(entry.into_file()?)?;
+            let primary = CacheWrite::from_entry(&mut entry, primary_compression)?;
+            let shadow = CacheWrite::from_entry(&mut entry, shadow_compression)?;
+            Ok((primary, shadow))
+        });
+        let (primary, shadow) = (self.primary.clone(), self.shadow.clone());
+        let stats = self.stats.clone();
+        let handle = self.handle.clone();
+        let key = key.to_owned();
+        Box::new(copies.and_then(move |(primary_entry, shadow_entry)| {
+            let shadow_key = key.clone();
+            let shadow_put = shadow.put(&key, shadow_entry).then(move |res| {
+                let mut stats = stats.lock().unwrap();
+                match res {
+                    Ok(duration) => {
+                        stats.writes += 1;
+                        stats.write_duration += duration;
+                    }
+                    Err(e) => {
+                        debug!("Shadow cache write of {} failed: {}", shadow_key, e);
+                        stats.write_errors += 1;
+                    }
+                }
+                Ok::<_, ()>(())
+            });
+            handle.spawn(shadow_put);
+            primary.put(&key, primary_entry)
+        }))
+    }
+
+    fn location(&self) -> String { self.primary.location() }
+    fn current_size(&self) -> Option<u64> { self.primary.current_size() }
+    fn max_size(&self) -> Option<u64> { self.primary.max_size() }
+    fn current_entries(&self) -> Option<u64> { self.primary.current_entries() }
+    fn max_entries(&self) -> Option<u64> { self.primary.max_entries() }
+    fn compression(&self) -> CacheCompression { self.primary.compression() }
+    fn shadow_stats(&self) -> Option<ShadowStats> { Some(self.stats.lock().unwrap().clone()) }
+
+    fn prune_older_than(&self, age: Duration) -> SFuture<PruneStats> {
+        self.primary.prune_older_than(age)
+    }
+
+    fn compiler_digests(&self) -> SFuture<CompilerDigests> {
+        self.primary.compiler_digests()
+    }
+
+    fn purge_stale_compilers(&self, current: HashMap<PathBuf, String>) -> SFuture<PruneStats> {
+        self.primary.purge_stale_compilers(current)
+    }
+
+    fn set_max_size(&self, size: u64) -> SFuture<PruneStats> {
+        self.primary.set_max_size(size)
+    }
+
+    fn remove_label(&self, label: &str) -> SFuture<PruneStats> {
+        self.primary.remove_label(label)
+    }
+
+    fn verify(&self, repair: bool, rate: Option<u32>) -> SFuture<VerifyStats> {
+        self.primary.verify(repair, rate)
+    }
+
+    fn keys(&self) -> SFuture<Vec<String>> {
+        self.primary.keys()
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        let owned_key = key.to_owned();
+        self.handle.spawn(self.shadow.remove(key).then(move |res| {
+            if let Err(e) = res {
+                debug!("Shadow cache removal of {} failed: {}", owned_key, e);
+            }
+            Ok(())
+        }));
+        self.primary.remove(key)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.primary.is_read_only()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cache::disk::DiskCache;
+    use std::io::Cursor;
+    use tempdir::TempDir;
+    use tokio_core::reactor::Core;
+
+    /// Run `core` until the shadow operations in flight are reflected in
+    /// `storage`'s stats, according to `done`.
+    fn settle<F: Fn(&ShadowStats) -> bool>(core: &mut Core, storage: &ShadowStorage, done: F) -> ShadowStats {
+        for _ in 0..1000 {
+            let stats = storage.shadow_stats().unwrap();
+            if done(&stats) {
+                return stats
+            }
+            core.turn(Some(Duration::from_millis(10)));
+        }
+        panic!("Shadow operations didn't finish: {:?}", storage.shadow_stats());
+    }
+
+    #[test]
+    fn test_shadow_storage() {
+        let td = TempDir::new("sccache").unwrap();
+        let pool = CpuPool::new(1);
+        let mut core = Core::new().unwrap();
+        let primary = Arc::new(DiskCache::new(&td.path().join("primary"), 1 << 20, None, &pool));
+        let shadow = Arc::new(DiskCache::new(&td.path().join("shadow"), 1 << 20, None, &pool));
+        let storage = ShadowStorage::new(primary.clone(), shadow.clone(), &pool, &core.handle());
+        let put = |storage: &Storage, key: &str| {
+            let mut entry = CacheWrite::new().unwrap();
+            entry.put_object("obj", &mut Cursor::new(vec![1; 1024]), None).unwrap();
+            storage.put(key, entry)
+        };
+        // Only in the primary.
+        core.run(put(&*primary, "aaaaaa")).unwrap();
+        // In both.
+        core.run(put(&storage, "bbbbbb")).unwrap();
+        let stats = settle(&mut core, &storage, |s| s.writes + s.write_errors == 1);
+        assert_eq!((stats.writes, stats.write_errors), (1, 0));
+        assert_eq!(shadow.current_entries(), Some(1));
+        assert_eq!(storage.current_entries(), Some(2));
+
+        for key in &["aaaaaa", "bbbbbb", "cccccc"] {
+            match (key, core.run(storage.get(key)).unwrap()) {
+                (&"cccccc", Cache::Miss) | (_, Cache::Hit(_)) => {}
+                (_, c) => panic!("Unexpected result for {}: {:?}", key, c),
+            }
+        }
+        let stats = settle(&mut core, &storage, |s| s.hits + s.misses + s.read_errors == 3 && s.primary_only_hits == 1);
+        assert_eq!(stats.location, shadow.location());
+        assert_eq!((stats.hits, stats.misses, stats.read_errors), (1, 2, 0));
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    CompilerDigests,
+    PruneStats,
+    Storage,
+    VerifyStats,
+};
+use cache::shadow::ShadowStats;
+use config::CacheCompression;
+use futures::{Future, IntoFuture};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
+
+use errors::*;
+
+/// A `Storage` that gives up on `get`s and `put`s in another `Storage` that
+/// take longer than a timeout, failing them with `ErrorKind::StorageTimeout`.
+///
+/// The operation itself isn't cancelled, a blocking backend may still be
+/// waiting on its endpoint, but nothing waits for it any more.
+pub struct TimeoutStorage {
+    inner: Arc<Storage>,
+    handle: Handle,
+    timeout: Duration,
+}
+
+impl TimeoutStorage {
+    /// Wrap `inner`, timing out its operations after `timeout`.
+    pub fn new(inner: Arc<Storage>, handle: &Handle, timeout: Duration) -> TimeoutStorage {
+        TimeoutStorage { inner, handle: handle.clone(), timeout }
+    }
+
+    fn with_timeout<T: 'static>(&self, operation: &'static str, future: SFuture<T>) -> SFuture<T> {
+        let duration = self.timeout;
+        let timeout = Timeout::new(duration, &self.handle).into_future().flatten()
+            .map(|()| None)
+            .chain_err(|| "timeout error");
+        Box::new(future.map(Some).select(timeout).then(move |res| {
+            match res {
+                Ok((Some(item), _timeout)) => Ok(item),
+                Ok((None, _future)) => {
+                    warn!("Cache {} timed out after {}s", operation, duration.as_secs());
+                    Err(ErrorKind::StorageTimeout(operation, duration).into())
+                }
+                Err((e, _other)) => Err(e),
+            }
+        }))
+    }
+}
+
+impl Storage for TimeoutStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        self.with_timeout("read", self.inner.get(key))
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        self.with_timeout("write", self.inner.put(key, entry))
+    }
+
+    fn location(&self) -> String { self.inner.location() }
+    fn current_size(&self) -> Option<u64> { self.inner.current_size() }
+    fn max_size(&self) -> Option<u64> { self.inner.max_size() }
+    fn current_entries(&self) -> Option<u64> { self.inner.current_entries() }
+    fn max_entries(&self) -> Option<u64> { self.inner.max_entries() }
+    fn compression(&self) -> CacheCompression { self.inner.compression() }
+    fn shadow_stats(&self) -> Option<ShadowStats> { self.inner.shadow_stats() }
+
+    fn prune_older_than(&self, age: Duration) -> SFuture<PruneStats> {
+        self.inner.prune_older_than(age)
+    }
+
+    fn compiler_digests(&self) -> SFuture<CompilerDigests> {
+        self.inner.compiler_digests()
+    }
+
+    fn purge_stale_compilers(&self, current: HashMap<PathBuf, String>) -> SFuture<PruneStats> {
+        self.inner.purge_stale_compilers(current)
+    }
+
+    fn set_max_size(&self, size: u64) -> SFuture<PruneStats> {
+        self.inner.set_max_size(size)
+    }
+
+    fn remove_label(&self, label: &str) -> SFuture<PruneStats> {
+        self.inner.remove_label(label)
+    }
+
+    fn verify(&self, repair: bool, rate: Option<u32>) -> SFuture<VerifyStats> {
+        self.inner.verify(repair, rate)
+    }
+
+    fn keys(&self) -> SFuture<Vec<String>> {
+        self.inner.keys()
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        self.inner.remove(key)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+    use test::utils::StallingStorage;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_timeout() {
+        let mut core = Core::new().unwrap();
+        let storage = TimeoutStorage::new(Arc::new(StallingStorage), &core.handle(), Duration::from_millis(100));
+        let start = Instant::now();
+        match core.run(storage.get("key")) {
+            Err(Error(ErrorKind::StorageTimeout("read", _), _)) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Expected the read to time out"),
+        }
+        match core.run(storage.put("key", CacheWrite::new().unwrap())) {
+            Err(Error(ErrorKind::StorageTimeout("write", _), _)) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Expected the write to time out"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,400 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recording the cache operations of a real build, and replaying them against
+//! a cache to benchmark it without compiling anything.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    CompilerDigests,
+    PruneStats,
+    Storage,
+    VerifyStats,
+};
+use cache::shadow::ShadowStats;
+use config::CacheCompression;
+use futures::Future;
+use serde_json;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_core::reactor::Core;
+use util::{fmt_duration_as_secs, Digest};
+
+use errors::*;
+
+/// The kind of a recorded cache operation.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TraceOp {
+    #[serde(rename = "get")]
+    Get,
+    #[serde(rename = "put")]
+    Put,
+}
+
+/// The outcome of a recorded cache operation.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TraceResult {
+    #[serde(rename = "hit")]
+    Hit,
+    #[serde(rename = "miss")]
+    Miss,
+    #[serde(rename = "ok")]
+    Ok,
+    #[serde(rename = "error")]
+    Error,
+}
+
+/// A single recorded cache operation, stored as one line of JSON in a trace file.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TraceEvent {
+    pub op: TraceOp,
+    /// The hash key of the cache entry.
+    pub key: String,
+    /// The total uncompressed size of the entry's objects, or 0 for a miss.
+    pub size: u64,
+    pub result: TraceResult,
+    /// When the operation started, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    /// How long the operation took, in milliseconds.
+    pub duration_ms: u64,
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64
+}
+
+/// A `Storage` that records every `get` and `put` to a trace file before
+/// passing them on to another `Storage`.
+pub struct TracingStorage {
+    inner: Arc<Storage>,
+    out: Arc<Mutex<File>>,
+}
+
+impl TracingStorage {
+    /// Trace operations on `inner`, appending them to the file at `path`.
+    pub fn new(inner: Arc<Storage>, path: &Path) -> Result<TracingStorage> {
+        let out = OpenOptions::new().create(true).append(true).open(path)
+            .chain_err(|| format!("Failed to open trace file {:?}", path))?;
+        Ok(TracingStorage { inner, out: Arc::new(Mutex::new(out)) })
+    }
+}
+
+/// Wrap `storage` in a `TracingStorage` if `SCCACHE_TRACE_FILE` is set.
+pub fn trace_storage_from_env(storage: Arc<Storage>) -> Arc<Storage> {
+    match env::var_os("SCCACHE_TRACE_FILE") {
+        Some(path) => match TracingStorage::new(storage.clone(), path.as_ref()) {
+            Ok(tracing) => {
+                info!("Recording cache operations to {:?}", path);
+                Arc::new(tracing)
+            }
+            Err(e) => {
+                warn!("{}", e.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(": "));
+                storage
+            }
+        },
+        None => storage,
+    }
+}
+
+fn record(out: &Mutex<File>, op: TraceOp, key: String, size: u64, result: TraceResult,
+          start: SystemTime, duration: Duration) {
+    let event = TraceEvent {
+        op,
+        key,
+        size,
+        result,
+        timestamp_ms: start.duration_since(UNIX_EPOCH).map(millis).unwrap_or(0),
+        duration_ms: millis(duration),
+    };
+    let res = serde_json::to_vec(&event).map_err(Error::from).and_then(|mut line| {
+        line.push(b'\n');
+        out.lock().unwrap().write_all(&line)?;
+        Ok(())
+    });
+    if let Err(e) = res {
+        warn!("Failed to record cache operation: {}", e);
+    }
+}
+
+impl Storage for TracingStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let (start, instant) = (SystemTime::now(), Instant::now());
+        let out = self.out.clone();
+        let key = key.to_owned();
+        Box::new(self.inner.get(&key).then(move |res| {
+            let duration = instant.elapsed();
+            let (result, size, res) = match res {
+                Ok(Cache::Hit(mut entry)) => {
+                    let size = entry.size();
+                    (TraceResult::Hit, size, Ok(Cache::Hit(entry)))
+                }
+                Ok(cache) => (TraceResult::Miss, 0, Ok(cache)),
+                Err(e) => (TraceResult::Error, 0, Err(e)),
+            };
+            record(&out, TraceOp::Get, key, size, result, start, duration);
+            res
+        }))
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let (start, instant) = (SystemTime::now(), Instant::now());
+        let out = self.out.clone();
+        let key = key.to_owned();
+        let size = entry.size();
+        Box::new(self.inner.put(&key, entry).then(move |res| {
+            let result = if res.is_ok() { TraceResult::Ok } else { TraceResult::Error };
+            record(&out, TraceOp::Put, key, size, result, start, instant.elapsed());
+            res
+        }))
+    }
+
+    fn location(&self) -> String { self.inner.location() }
+    fn current_size(&self) -> Option<u64> { self.inner.current_size() }
+    fn max_size(&self) -> Option<u64> { self.inner.max_size() }
+    fn current_entries(&self) -> Option<u64> { self.inner.current_entries() }
+    fn max_entries(&self) -> Option<u64> { self.inner.max_entries() }
+    fn compression(&self) -> CacheCompression { self.inner.compression() }
+    fn shadow_stats(&self) -> Option<ShadowStats> { self.inner.shadow_stats() }
+
+    fn prune_older_than(&self, age: Duration) -> SFuture<PruneStats> {
+        self.inner.prune_older_than(age)
+    }
+
+    fn compiler_digests(&self) -> SFuture<CompilerDigests> {
+        self.inner.compiler_digests()
+    }
+
+    fn purge_stale_compilers(&self, current: HashMap<PathBuf, String>) -> SFuture<PruneStats> {
+        self.inner.purge_stale_compilers(current)
+    }
+
+    fn set_max_size(&self, size: u64) -> SFuture<PruneStats> {
+        self.inner.set_max_size(size)
+    }
+
+    fn remove_label(&self, label: &str) -> SFuture<PruneStats> {
+        self.inner.remove_label(label)
+    }
+
+    fn verify(&self, repair: bool, rate: Option<u32>) -> SFuture<VerifyStats> {
+        self.inner.verify(repair, rate)
+    }
+
+    fn keys(&self) -> SFuture<Vec<String>> {
+        self.inner.keys()
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        self.inner.remove(key)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+}
+
+/// Read the events from the trace file at `path`.
+pub fn read_trace(path: &Path) -> Result<Vec<TraceEvent>> {
+    let file = File::open(path).chain_err(|| format!("Failed to open trace file {:?}", path))?;
+    let mut events = vec!();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+        let event = serde_json::from_str(&line).chain_err(|| {
+            format!("Invalid trace event on line {}", i + 1)
+        })?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+/// Incompressible stand-in contents for a replayed `put`, so that it stores
+/// about as many bytes as the original entry did.
+struct ReplayData {
+    state: u64,
+    remaining: u64,
+}
+
+impl Read for ReplayData {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = ::std::cmp::min(buf.len() as u64, self.remaining) as usize;
+        for b in &mut buf[..n] {
+            // xorshift64
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 7;
+            self.state ^= self.state << 17;
+            *b = self.state as u8;
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// The results of replaying a trace.
+#[derive(Debug, Default)]
+pub struct ReplayStats {
+    pub gets: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub get_errors: u64,
+    pub get_time: Duration,
+    pub bytes_read: u64,
+    pub puts: u64,
+    pub put_errors: u64,
+    pub put_time: Duration,
+    pub bytes_written: u64,
+    pub elapsed: Duration,
+}
+
+impl ReplayStats {
+    /// Print the results in a human-readable format.
+    pub fn print(&self) {
+        let avg = |total: Duration, n: u64| {
+            if n > 0 { total / n as u32 } else { Default::default() }
+        };
+        let rate = |bytes: u64, time: Duration| {
+            let secs = time.as_secs() as f64 + time.subsec_nanos() as f64 / 1e9;
+            if secs > 0.0 { bytes as f64 / secs / (1024.0 * 1024.0) } else { 0.0 }
+        };
+        println!("{:<24} {}", "Gets", self.gets);
+        println!("{:<24} {}", "Hits", self.hits);
+        println!("{:<24} {}", "Misses", self.misses);
+        println!("{:<24} {}", "Get errors", self.get_errors);
+        println!("{:<24} {} s", "Average get time", fmt_duration_as_secs(&avg(self.get_time, self.gets)));
+        println!("{:<24} {:.2} MiB/s", "Read throughput", rate(self.bytes_read, self.get_time));
+        println!("{:<24} {}", "Puts", self.puts);
+        println!("{:<24} {}", "Put errors", self.put_errors);
+        println!("{:<24} {} s", "Average put time", fmt_duration_as_secs(&avg(self.put_time, self.puts)));
+        println!("{:<24} {:.2} MiB/s", "Write throughput", rate(self.bytes_written, self.put_time));
+        println!("{:<24} {} s", "Total time", fmt_duration_as_secs(&self.elapsed));
+    }
+}
+
+/// The key a replay uses for the recorded `key`. Replayed puts store random
+/// data, so they must never land on a real entry: hashing with a prefix of
+/// its own gives keys that look like any other, spread the same way across
+/// the cache, but that no compile will ever look up.
+fn replay_key(key: &str) -> String {
+    let mut m = Digest::new();
+    m.update(b"sccache-replay-trace:");
+    m.update(key.as_bytes());
+    m.finish()
+}
+
+/// Issue the operations in `events` against `storage` one after another, as
+/// fast as possible. A `put` stores incompressible data of the recorded size.
+/// The operations use keys of their own, see `replay_key`, so replaying
+/// against a cache in use neither overwrites nor reads its entries.
+pub fn replay(core: &mut Core, storage: &Storage, events: &[TraceEvent]) -> ReplayStats {
+    let mut stats = ReplayStats::default();
+    let start = Instant::now();
+    for event in events {
+        let op_start = Instant::now();
+        let key = replay_key(&event.key);
+        match event.op {
+            TraceOp::Get => {
+                stats.gets += 1;
+                match core.run(storage.get(&key)) {
+                    Ok(Cache::Hit(mut entry)) => {
+                        stats.hits += 1;
+                        stats.bytes_read += entry.size();
+                    }
+                    Ok(_) => stats.misses += 1,
+                    Err(e) => {
+                        debug!("Replayed get of {} failed: {}", key, e);
+                        stats.get_errors += 1;
+                    }
+                }
+                stats.get_time += op_start.elapsed();
+            }
+            TraceOp::Put => {
+                stats.puts += 1;
+                let mut data = ReplayData { state: 0x2545_f491_4f6c_dd1d, remaining: event.size };
+                let res = CacheWrite::new()
+                    .and_then(|mut entry| {
+                        entry.put_object("obj", &mut data, None)?;
+                        Ok(entry)
+                    })
+                    .and_then(|entry| core.run(storage.put(&key, entry)));
+                match res {
+                    Ok(_) => stats.bytes_written += event.size,
+                    Err(e) => {
+                        debug!("Replayed put of {} failed: {}", key, e);
+                        stats.put_errors += 1;
+                    }
+                }
+                stats.put_time += op_start.elapsed();
+            }
+        }
+    }
+    stats.elapsed = start.elapsed();
+    stats
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cache::disk::DiskCache;
+    use futures_cpupool::CpuPool;
+    use std::u64;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_record_and_replay() {
+        let td = TempDir::new("sccache").unwrap();
+        let pool = CpuPool::new(1);
+        let mut core = Core::new().unwrap();
+        let trace_path = td.path().join("trace");
+        let disk = DiskCache::new(&td.path().join("cache"), u64::MAX, None, &pool);
+        let storage = TracingStorage::new(Arc::new(disk), &trace_path).unwrap();
+
+        match core.run(storage.get("abcd")).unwrap() {
+            Cache::Miss => {}
+            _ => panic!("Expected a cache miss"),
+        }
+        let mut entry = CacheWrite::new().unwrap();
+        entry.put_object("obj", &mut &b"some data"[..], None).unwrap();
+        core.run(storage.put("abcd", entry)).unwrap();
+        match core.run(storage.get("abcd")).unwrap() {
+            Cache::Hit(_) => {}
+            _ => panic!("Expected a cache hit"),
+        }
+
+        let events = read_trace(&trace_path).unwrap();
+        let ops = events.iter().map(|e| (e.op, e.key.as_str(), e.size, e.result)).collect::<Vec<_>>();
+        assert_eq!(ops, vec![(TraceOp::Get, "abcd", 0, TraceResult::Miss),
+                             (TraceOp::Put, "abcd", 9, TraceResult::Ok),
+                             (TraceOp::Get, "abcd", 9, TraceResult::Hit)]);
+
+        // Replaying against an empty cache gives the same hits and misses.
+        let disk = DiskCache::new(&td.path().join("replay"), u64::MAX, None, &pool);
+        let stats = replay(&mut core, &disk, &events);
+        assert_eq!((2, 1, 1, 0), (stats.gets, stats.hits, stats.misses, stats.get_errors));
+        assert_eq!((1, 0, 9), (stats.puts, stats.put_errors, stats.bytes_written));
+        assert_eq!(9, stats.bytes_read);
+        // Without touching the recorded keys.
+        match core.run(disk.get("abcd")).unwrap() {
+            Cache::Miss => {}
+            _ => panic!("Replay wrote to a recorded key"),
+        }
+    }
+}
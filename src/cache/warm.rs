@@ -0,0 +1,141 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Copying the entries of a local cache to a remote one, so that what a
+//! developer compiled benefits everyone sharing the remote cache.
+
+use cache::{Cache, CacheWrite, Storage};
+use futures::{future, Future, IntoFuture};
+use futures::future::Loop;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Handle, Timeout};
+
+use errors::*;
+
+/// Put the entries of `local` that `remote` doesn't have in `remote`, at most
+/// `rate` a second, stopping once `limit` has passed. Entries that can't be
+/// copied are skipped. Resolve to how many were copied.
+pub fn upload_missing(local: Arc<Storage>,
+                      remote: Arc<Storage>,
+                      rate: Option<u32>,
+                      limit: Duration,
+                      handle: &Handle) -> SFuture<u64> {
+    let start = Instant::now();
+    let interval = rate.map(|rate| Duration::from_secs(1) / rate.max(1));
+    let handle = handle.clone();
+    Box::new(local.keys().and_then(move |keys| {
+        future::loop_fn((keys.into_iter(), 0), move |(mut keys, uploaded)| -> SFuture<_> {
+            let key = match keys.next() {
+                Some(key) => key,
+                None => return f_ok(Loop::Break(uploaded)),
+            };
+            if start.elapsed() >= limit {
+                info!("Stopped uploading the local cache after {:?}", limit);
+                return f_ok(Loop::Break(uploaded))
+            }
+            let copied = copy_missing(local.clone(), remote.clone(), key);
+            let handle = handle.clone();
+            Box::new(copied.and_then(move |copied| -> SFuture<_> {
+                let uploaded = uploaded + copied as u64;
+                match interval {
+                    Some(interval) if copied => {
+                        let timeout = Timeout::new(interval, &handle).into_future().flatten();
+                        Box::new(timeout.map(move |_| Loop::Continue((keys, uploaded))).chain_err(|| "timeout error"))
+                    }
+                    _ => f_ok(Loop::Continue((keys, uploaded))),
+                }
+            }))
+        })
+    }))
+}
+
+/// Put the entry for `key` from `local` in `remote` if `remote` doesn't have
+/// it. Resolve to whether it was copied.
+fn copy_missing(local: Arc<Storage>, remote: Arc<Storage>, key: String) -> SFuture<bool> {
+    Box::new(remote.get(&key).then(move |res| -> SFuture<bool> {
+        match res {
+            Ok(Cache::Miss) => {}
+            Ok(_) => return f_ok(false),
+            Err(e) => {
+                debug!("Not uploading cache entry {}: {}", key, e);
+                return f_ok(false)
+            }
+        }
+        let copied = local.get(&key).and_then({
+            let key = key.clone();
+            move |res| -> SFuture<bool> {
+                let entry = match res {
+                    Cache::Hit(mut entry) => ftry!(CacheWrite::from_entry(&mut entry, remote.compression())),
+                    // Evicted since the keys were listed.
+                    _ => return f_ok(false),
+                };
+                Box::new(remote.put(&key, entry).map(|_| true))
+            }
+        });
+        Box::new(copied.then(move |res| {
+            match res {
+                Ok(copied) => Ok(copied),
+                Err(e) => {
+                    warn!("Failed to upload cache entry {}: {}", key, e);
+                    Ok(false)
+                }
+            }
+        }))
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cache::disk::DiskCache;
+    use futures_cpupool::CpuPool;
+    use std::io::Cursor;
+    use tempdir::TempDir;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_upload_missing() {
+        let td = TempDir::new("sccache").unwrap();
+        let mut core = Core::new().unwrap();
+        let pool = CpuPool::new(1);
+        let local: Arc<Storage> = Arc::new(DiskCache::new(&td.path().join("local"), 1 << 20, None, &pool));
+        let remote: Arc<Storage> = Arc::new(DiskCache::new(&td.path().join("remote"), 1 << 20, None, &pool));
+        for key in &["aaaaaa", "bbbbbb", "cccccc"] {
+            let mut entry = CacheWrite::new().unwrap();
+            entry.put_object("obj", &mut Cursor::new(key.as_bytes()), None).unwrap();
+            core.run(local.put(key, entry)).unwrap();
+        }
+        let mut entry = CacheWrite::new().unwrap();
+        entry.put_object("obj", &mut Cursor::new(&b"remote"[..]), None).unwrap();
+        core.run(remote.put("bbbbbb", entry)).unwrap();
+
+        let upload = upload_missing(local.clone(), remote.clone(), None, Duration::from_secs(60), &core.handle());
+        let uploaded = core.run(upload).unwrap();
+        assert_eq!(2, uploaded);
+        for &(key, contents) in &[("aaaaaa", &b"aaaaaa"[..]), ("bbbbbb", &b"remote"[..]), ("cccccc", &b"cccccc"[..])] {
+            match core.run(remote.get(key)).unwrap() {
+                Cache::Hit(mut entry) => {
+                    let mut obj = vec![];
+                    entry.get_object("obj", &mut obj).unwrap();
+                    assert_eq!(contents, &obj[..]);
+                }
+                _ => panic!("{} missing from the remote cache", key),
+            }
+        }
+        // Everything is there now, so nothing more is uploaded.
+        let upload = upload_missing(local, remote.clone(), Some(1000), Duration::from_secs(60), &core.handle());
+        assert_eq!(0, core.run(upload).unwrap());
+    }
+}
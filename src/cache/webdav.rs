@@ -0,0 +1,365 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A cache on a WebDAV server, such as the artifact servers many CI setups
+//! already run on-premises.
+//!
+//! Entries are stored under the base URL in the same layout as the local
+//! disk cache, `a/b/abcdef...`, so that no collection gets too big. The
+//! collections are created on demand, the first time an entry is stored in
+//! them.
+
+use cache::{
+    Cache,
+    CacheRead,
+    CacheWrite,
+    Storage,
+};
+use futures::future::{self, Loop};
+use futures::{stream, Future, Stream};
+use hyper::{Method, StatusCode, Uri};
+use hyper::client::{Client, HttpConnector, Request, Response};
+use hyper::header::{Authorization, Basic, ContentLength};
+use hyper_tls::HttpsConnector;
+use std::io;
+use std::time::{
+    Duration,
+    Instant,
+};
+use tokio_core::reactor::{Handle, Timeout};
+
+use errors::*;
+
+type HyperClient = Client<HttpsConnector<HttpConnector>>;
+
+/// How many times a request the server fails with a server error is retried.
+const MAX_RETRIES: u32 = 3;
+
+/// How long to wait before the first retry. The wait doubles for each one after.
+const INITIAL_BACKOFF_MS: u64 = 100;
+
+/// The path of the entry `key` relative to the base URL.
+fn key_path(key: &str) -> String {
+    format!("{}/{}/{}", &key[0..1], &key[1..2], key)
+}
+
+/// The collections `path` is in, outermost first, each ending in `/`.
+fn parent_collections(path: &str) -> Vec<&str> {
+    path.match_indices('/').map(|(i, _)| &path[..i + 1]).collect()
+}
+
+fn read_body(res: Response) -> SFuture<Vec<u8>> {
+    Box::new(res.body().concat2().map_err(Into::into).map(|body| body.to_vec()))
+}
+
+/// A cache that stores entries on a WebDAV server.
+#[derive(Clone)]
+pub struct WebDAVCache {
+    /// The base URL, ending in `/`.
+    url: String,
+    auth: Option<Basic>,
+    client: HyperClient,
+    handle: Handle,
+}
+
+impl WebDAVCache {
+    /// Create a new `WebDAVCache` storing entries under `url`, authenticating
+    /// with basic auth if `username` or `password` are given.
+    pub fn new(url: &str, username: Option<&str>, password: Option<&str>, handle: &Handle)
+               -> Result<WebDAVCache> {
+        let mut url = url.to_owned();
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        url.parse::<Uri>().chain_err(|| format!("invalid WebDAV URL: {}", url))?;
+        let auth = if username.is_some() || password.is_some() {
+            Some(Basic {
+                username: username.unwrap_or("").to_owned(),
+                password: password.map(str::to_owned),
+            })
+        } else {
+            None
+        };
+        Ok(WebDAVCache {
+            url,
+            auth,
+            client: Client::configure()
+                        .connector(HttpsConnector::new(1, handle)?)
+                        .build(handle),
+            handle: handle.clone(),
+        })
+    }
+
+    /// Create a `method` request for `path`, relative to the base URL.
+    fn request(&self, method: Method, path: &str) -> Request {
+        let url = format!("{}{}", self.url, path);
+        let mut request = Request::new(method, url.parse().unwrap());
+        if let Some(ref auth) = self.auth {
+            request.headers_mut().set(Authorization(auth.clone()));
+        }
+        request
+    }
+
+    /// Send the request `make_request` builds, building and sending it again
+    /// with exponential backoff while the server replies with a server error.
+    /// Once the retries run out the last response is returned.
+    fn send<F>(&self, make_request: F) -> SFuture<Response>
+        where F: Fn() -> Request + 'static,
+    {
+        let client = self.client.clone();
+        let handle = self.handle.clone();
+        Box::new(future::loop_fn(0, move |retries| {
+            let request = make_request();
+            let (method, uri) = (request.method().clone(), request.uri().clone());
+            let handle = handle.clone();
+            client.request(request).chain_err(move || {
+                format!("failed {} {}", method, uri)
+            }).and_then(move |res| -> SFuture<Loop<Response, u32>> {
+                if !res.status().is_server_error() || retries >= MAX_RETRIES {
+                    return f_ok(Loop::Break(res))
+                }
+                let backoff = Duration::from_millis(INITIAL_BACKOFF_MS << retries);
+                warn!("WebDAV server failed with {}, retrying in {:?}", res.status(), backoff);
+                let timeout = ftry!(Timeout::new(backoff, &handle));
+                Box::new(timeout.map(move |()| Loop::Continue(retries + 1)).map_err(Into::into))
+            })
+        }))
+    }
+
+    /// Upload `data` to `path`, returning the status the server replied with.
+    fn put_data(&self, path: &str, data: Vec<u8>) -> SFuture<StatusCode> {
+        debug!("PUT {}{}", self.url, path);
+        let (me, path) = (self.clone(), path.to_owned());
+        Box::new(self.send(move || {
+            let mut request = me.request(Method::Put, &path);
+            request.headers_mut().set(ContentLength(data.len() as u64));
+            request.set_body(data.clone());
+            request
+        }).map(|res| res.status()))
+    }
+
+    /// Create the collections `path` is in that don't exist yet.
+    fn make_collections(&self, path: &str) -> SFuture<()> {
+        let collections = parent_collections(path).into_iter().map(str::to_owned).collect::<Vec<_>>();
+        let me = self.clone();
+        Box::new(stream::iter_ok(collections).for_each(move |collection| {
+            debug!("MKCOL {}{}", me.url, collection);
+            let (me, path) = (me.clone(), collection.clone());
+            me.clone().send(move || me.request(Method::Extension("MKCOL".to_owned()), &path)).and_then(move |res| -> Result<()> {
+                match res.status() {
+                    StatusCode::Created => Ok(()),
+                    // It already exists.
+                    StatusCode::MethodNotAllowed => Ok(()),
+                    status => {
+                        Err(Error::from(ErrorKind::BadHTTPStatus(status)))
+                            .chain_err(|| format!("failed to create WebDAV collection {}", collection))
+                    }
+                }
+            })
+        }))
+    }
+}
+
+/// Whether `status` is the reply to a successful `PUT`.
+fn stored(status: StatusCode) -> bool {
+    match status {
+        StatusCode::Created | StatusCode::NoContent | StatusCode::Ok => true,
+        _ => false,
+    }
+}
+
+impl Storage for WebDAVCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let path = key_path(key);
+        debug!("GET {}{}", self.url, path);
+        let me = self.clone();
+        Box::new(self.send(move || me.request(Method::Get, &path)).and_then(|res| -> SFuture<Cache> {
+            match res.status() {
+                StatusCode::NotFound => f_ok(Cache::Miss),
+                status if status.is_success() => Box::new(read_body(res).and_then(|data| {
+                    CacheRead::from(io::Cursor::new(data)).map(Cache::Hit)
+                })),
+                status => f_err(ErrorKind::BadHTTPStatus(status)),
+            }
+        }))
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let start = Instant::now();
+        let data = ftry!(entry.finish());
+        let path = key_path(key);
+        let me = self.clone();
+        let response = self.put_data(&path, data.clone()).and_then(move |status| -> SFuture<StatusCode> {
+            match status {
+                // Servers reply 409 to a `PUT` into a collection that doesn't
+                // exist, or sometimes 404.
+                StatusCode::Conflict | StatusCode::NotFound => {
+                    debug!("Creating the WebDAV collections for {}", path);
+                    let me2 = me.clone();
+                    Box::new(me.make_collections(&path).and_then(move |()| me2.put_data(&path, data)))
+                }
+                status => f_ok(status),
+            }
+        });
+        Box::new(response.and_then(move |status| -> Result<Duration> {
+            if !stored(status) {
+                bail!(ErrorKind::BadHTTPStatus(status))
+            }
+            Ok(start.elapsed())
+        }).chain_err(|| "failed to put cache entry on the WebDAV server"))
+    }
+
+    fn location(&self) -> String {
+        format!("WebDAV, url: {}", self.url)
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        let path = key_path(key);
+        debug!("DELETE {}{}", self.url, path);
+        let me = self.clone();
+        Box::new(self.send(move || me.request(Method::Delete, &path)).and_then(|res| -> Result<()> {
+            match res.status() {
+                StatusCode::NotFound => Ok(()),
+                status if status.is_success() => Ok(()),
+                status => bail!(ErrorKind::BadHTTPStatus(status)),
+            }
+        }).chain_err(|| "failed to remove cache entry from the WebDAV server"))
+    }
+
+    fn current_size(&self) -> Option<u64> { None }
+    fn max_size(&self) -> Option<u64> { None }
+    fn current_entries(&self) -> Option<u64> { None }
+    fn max_entries(&self) -> Option<u64> { None }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex};
+    use test::utils::{MockRequest, mock_http_server};
+    use tokio_core::reactor::Core;
+
+    /// Start a mock server replying to each request with the status and
+    /// body `reply` returns for it, and return its URL.
+    fn mock_server<F>(reply: F) -> String
+        where F: Fn(&MockRequest) -> (u16, Vec<u8>) + Send + Sync + 'static
+    {
+        let addr = mock_http_server(move |request| {
+            let (status, body) = reply(request);
+            (status, vec![], body)
+        });
+        format!("http://{}/", addr)
+    }
+
+    /// The collection `path` is in.
+    fn parent(path: &str) -> &str {
+        let trimmed = path.trim_right_matches('/');
+        &trimmed[..trimmed.rfind('/').unwrap() + 1]
+    }
+
+    #[test]
+    fn test_parent_collections() {
+        assert_eq!("a/b/abcdef", key_path("abcdef"));
+        assert_eq!(vec!["a/", "a/b/"], parent_collections("a/b/abcdef"));
+    }
+
+    #[test]
+    fn test_put_creates_collections() {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let server = {
+            let requests = requests.clone();
+            let collections = Mutex::new(vec!["/dav/".to_owned()].into_iter().collect::<HashSet<_>>());
+            let files = Mutex::new(HashMap::new());
+            mock_server(move |request| {
+                requests.lock().unwrap().push(request.clone());
+                let mut collections = collections.lock().unwrap();
+                let mut files = files.lock().unwrap();
+                let parent_exists = collections.contains(parent(&request.path));
+                match &request.method[..] {
+                    "MKCOL" if collections.contains(&request.path) => (405, vec![]),
+                    "MKCOL" if parent_exists => {
+                        collections.insert(request.path.clone());
+                        (201, vec![])
+                    }
+                    "PUT" if parent_exists => {
+                        files.insert(request.path.clone(), request.body.clone());
+                        (201, vec![])
+                    }
+                    "MKCOL" | "PUT" => (409, vec![]),
+                    "GET" => match files.get(&request.path) {
+                        Some(body) => (200, body.clone()),
+                        None => (404, vec![]),
+                    },
+                    _ => (405, vec![]),
+                }
+            })
+        };
+
+        let mut core = Core::new().unwrap();
+        let cache = WebDAVCache::new(&format!("{}dav", server), Some("user"), Some("pass"), &core.handle()).unwrap();
+        match core.run(cache.get("abcdef")).unwrap() {
+            Cache::Miss => {}
+            _ => panic!("Expected a cache miss"),
+        }
+        let mut entry = CacheWrite::new().unwrap();
+        entry.put_object("obj", &mut io::Cursor::new(&b"hello"[..]), None).unwrap();
+        core.run(cache.put("abcdef", entry)).unwrap();
+        match core.run(cache.get("abcdef")).unwrap() {
+            Cache::Hit(mut entry) => {
+                let mut obj = vec![];
+                entry.get_object("obj", &mut obj).unwrap();
+                assert_eq!(&b"hello"[..], &obj[..]);
+            }
+            _ => panic!("Expected a cache hit"),
+        }
+
+        let requests = requests.lock().unwrap();
+        let made = requests.iter().map(|r| (&r.method[..], &r.path[..])).collect::<Vec<_>>();
+        assert_eq!(vec![
+            ("GET", "/dav/a/b/abcdef"),
+            // The collections don't exist yet, so they're created and the
+            // entry uploaded again.
+            ("PUT", "/dav/a/b/abcdef"),
+            ("MKCOL", "/dav/a/"),
+            ("MKCOL", "/dav/a/b/"),
+            ("PUT", "/dav/a/b/abcdef"),
+            ("GET", "/dav/a/b/abcdef"),
+        ], made);
+        assert_eq!(requests[1].body, requests[4].body);
+        assert!(requests.iter().all(|r| r.headers.get("authorization").map(|a| &a[..]) == Some("Basic dXNlcjpwYXNz")));
+    }
+
+    #[test]
+    fn test_retry_server_errors() {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let server = {
+            let requests = requests.clone();
+            mock_server(move |request| {
+                let mut requests = requests.lock().unwrap();
+                requests.push(request.clone());
+                if requests.len() < 3 { (503, vec![]) } else { (404, vec![]) }
+            })
+        };
+        let mut core = Core::new().unwrap();
+        let cache = WebDAVCache::new(&server, None, None, &core.handle()).unwrap();
+        match core.run(cache.get("abcdef")).unwrap() {
+            Cache::Miss => {}
+            _ => panic!("Expected a cache miss"),
+        }
+        let requests = requests.lock().unwrap();
+        assert_eq!(3, requests.len());
+        assert!(requests.iter().all(|r| r.method == "GET" && !r.headers.contains_key("authorization")));
+    }
+}
@@ -17,6 +17,9 @@ use protocol::{Request, Response};
 use retry::retry;
 use bincode;
 use errors::*;
+use num_cpus;
+use std::env;
+use std::fs::{self, File, OpenOptions};
 use std::io::{
     self,
     BufReader,
@@ -24,8 +27,18 @@ use std::io::{
     Read,
 };
 use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 use util;
 
+/// How long to wait between attempts to acquire a `RequestSlot`.
+const REQUEST_SLOT_POLL_MS: u64 = 10;
+
+/// How long a notice is remembered before it's shown again, in case the
+/// setup it was about has changed since.
+const NOTICE_EXPIRY_SECS: u64 = 24 * 60 * 60;
+
 /// A connection to an sccache server.
 pub struct ServerConnection {
     /// A reader for the socket connected to the server.
@@ -89,3 +102,193 @@ pub fn connect_with_retry(port: u16) -> io::Result<ServerConnection> {
                                 "Connection to server timed out")),
     }
 }
+
+/// The maximum number of clients that may have a request outstanding to the
+/// server at once, or `None` if unlimited.
+///
+/// This is controlled by `SCCACHE_MAX_CLIENT_REQUESTS`, where 0 disables the
+/// limit, and defaults to four times the number of CPUs.
+pub fn max_client_requests() -> Option<usize> {
+    match env::var("SCCACHE_MAX_CLIENT_REQUESTS").ok().and_then(|s| s.parse().ok()) {
+        Some(0) => None,
+        Some(n) => Some(n),
+        None => Some(num_cpus::get() * 4),
+    }
+}
+
+/// One of a limited number of slots for talking to the server, shared between
+/// all client processes.
+///
+/// Each slot is a lock file, and the lock is held until this is dropped or the
+/// process exits, whichever comes first. Clients only hold one while they
+/// connect and submit a request, not while the server works on it. Under very
+/// high build parallelism this makes clients queue up locally instead of
+/// overwhelming the server's accept queue and falling back to uncached
+/// compiles.
+pub struct RequestSlot {
+    _file: File,
+}
+
+impl RequestSlot {
+    /// Wait until one of `slots` slots for the server on `port` is free and take it.
+    pub fn acquire(port: u16, slots: usize) -> io::Result<RequestSlot> {
+        let dir = slots_dir(port)?;
+        Self::acquire_in(&dir, slots)
+    }
+
+    fn acquire_in(dir: &Path, slots: usize) -> io::Result<RequestSlot> {
+        trace!("RequestSlot::acquire_in({:?}, {})", dir, slots);
+        let paths = (0..slots).map(|i| dir.join(format!("slot-{}", i))).collect::<Vec<PathBuf>>();
+        let mut waited = false;
+        loop {
+            for path in &paths {
+                if let Some(file) = try_lock_slot(path)? {
+                    if waited {
+                        debug!("Acquired request slot {:?}", path);
+                    }
+                    return Ok(RequestSlot { _file: file });
+                }
+            }
+            if !waited {
+                debug!("All {} request slots are busy, waiting", slots);
+                waited = true;
+            }
+            thread::sleep(Duration::from_millis(REQUEST_SLOT_POLL_MS));
+        }
+    }
+}
+
+/// Whether this is the first time a client of the server on `port` noticed
+/// `what`, so that messages about it are only shown once rather than on
+/// every compile.
+///
+/// Each notice is recorded as a file next to the request slots, so this
+/// holds across client processes. The record expires after a day, or when
+/// sccache is upgraded, and the notice is then shown again.
+pub fn first_notice(port: u16, what: &str) -> bool {
+    match slots_dir(port) {
+        Ok(dir) => first_notice_in(&dir, what, Duration::from_secs(NOTICE_EXPIRY_SECS)),
+        Err(_) => true,
+    }
+}
+
+fn first_notice_in(dir: &Path, what: &str, expiry: Duration) -> bool {
+    let mut digest = util::Digest::new();
+    digest.update(env!("CARGO_PKG_VERSION").as_bytes());
+    digest.update(what.as_bytes());
+    let path = dir.join(format!("notice-{}", digest.finish()));
+    let expired = fs::metadata(&path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|mtime| mtime.elapsed().ok())
+        .map_or(false, |age| age >= expiry);
+    if expired {
+        let _ = fs::remove_file(&path);
+    }
+    match OpenOptions::new().write(true).create_new(true).open(&path) {
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => false,
+        _ => true,
+    }
+}
+
+/// The directory holding the slots for the server on `port`, private to the
+/// current user so nobody else can hold their slots or plant files in it.
+#[cfg(unix)]
+fn slots_dir(port: u16) -> io::Result<PathBuf> {
+    use libc;
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+
+    let uid = unsafe { libc::getuid() };
+    let dir = env::temp_dir().join(format!("sccache-client-slots-{}-{}", uid, port));
+    match fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+    let meta = fs::symlink_metadata(&dir)?;
+    if !meta.is_dir() || meta.uid() != uid || meta.permissions().mode() & 0o077 != 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  format!("{:?} isn't a directory private to this user", dir)));
+    }
+    Ok(dir)
+}
+
+/// The directory holding the slots for the server on `port`. The temporary
+/// directory is already private to the current user.
+#[cfg(windows)]
+fn slots_dir(port: u16) -> io::Result<PathBuf> {
+    let dir = env::temp_dir().join(format!("sccache-client-slots-{}", port));
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+#[cfg(unix)]
+fn try_lock_slot(path: &Path) -> io::Result<Option<File>> {
+    use libc;
+    use std::os::unix::io::AsRawFd;
+
+    let file = OpenOptions::new().create(true).write(true).open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(Some(file));
+    }
+    let err = io::Error::last_os_error();
+    if err.kind() == io::ErrorKind::WouldBlock {
+        Ok(None)
+    } else {
+        Err(err)
+    }
+}
+
+#[cfg(windows)]
+fn try_lock_slot(path: &Path) -> io::Result<Option<File>> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    // Opening without sharing fails while another process has the slot open.
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    match OpenOptions::new().create(true).write(true).share_mode(0).open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(ref e) if e.raw_os_error() == Some(ERROR_SHARING_VIOLATION) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_request_slots() {
+        let td = TempDir::new("sccache").unwrap();
+        let a = RequestSlot::acquire_in(td.path(), 2).unwrap();
+        let _b = RequestSlot::acquire_in(td.path(), 2).unwrap();
+        assert!(try_lock_slot(&td.path().join("slot-0")).unwrap().is_none());
+        assert!(try_lock_slot(&td.path().join("slot-1")).unwrap().is_none());
+        drop(a);
+        let _c = RequestSlot::acquire_in(td.path(), 2).unwrap();
+    }
+
+    #[test]
+    fn test_first_notice() {
+        let td = TempDir::new("sccache").unwrap();
+        let day = Duration::from_secs(NOTICE_EXPIRY_SECS);
+        assert!(first_notice_in(td.path(), "a", day));
+        assert!(!first_notice_in(td.path(), "a", day));
+        assert!(first_notice_in(td.path(), "b", day));
+        // Once expired, the notice is shown again, and then remembered afresh.
+        assert!(first_notice_in(td.path(), "a", Duration::from_secs(0)));
+        assert!(!first_notice_in(td.path(), "a", day));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_slots_dir_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = slots_dir(0).unwrap();
+        assert_eq!(0o700, fs::metadata(&dir).unwrap().permissions().mode() & 0o777);
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(slots_dir(0).is_err());
+        fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -17,10 +17,12 @@ use clap::{
     AppSettings,
     Arg,
 };
+use config::{parse_duration, parse_size};
 use errors::*;
 use std::env;
-use std::ffi::OsString;
-use std::path::PathBuf;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use which::which_in;
 
 arg_enum!{
@@ -32,6 +34,25 @@ arg_enum!{
     }
 }
 
+/// Where to export a cache to or import one from.
+#[derive(Debug)]
+pub enum OciTarget {
+    /// An OCI image layout in a directory.
+    Dir(PathBuf),
+    /// An image in a registry, such as `registry.example.com/sccache:latest`.
+    Registry(String),
+}
+
+impl OciTarget {
+    /// `docker://REF` for an image in a registry, otherwise a directory.
+    fn parse(arg: &OsStr, cwd: &Path) -> OciTarget {
+        match arg.to_str() {
+            Some(arg) if arg.starts_with("docker://") => OciTarget::Registry(arg["docker://".len()..].to_owned()),
+            _ => OciTarget::Dir(cwd.join(arg)),
+        }
+    }
+}
+
 /// A specific command to run.
 pub enum Command {
     /// Show cache statistics and exit.
@@ -44,6 +65,50 @@ pub enum Command {
     StartServer,
     /// Stop background server.
     StopServer,
+    /// Package the toolchains of compilers the server has seen, without submitting them.
+    ListToolchains,
+    /// Detect the given compilers, or the usual ones on `PATH`, and report what sccache supports for each.
+    ListCompilers(Option<Vec<String>>),
+    /// Check the configuration and whether each configured component works.
+    CheckConfig,
+    /// Print the resolved configuration.
+    DumpConfig,
+    /// Show the job statistics gathered by the dist scheduler.
+    DistStats,
+    /// Show where the cache entry with the given hash key came from.
+    ShowProvenance(String),
+    /// Remove cache entries that haven't been used in the given time.
+    PruneOlderThan(Duration),
+    /// Remove every entry from the local disk cache, as `ccache -C` does.
+    /// Refused if another cache is configured, rather than clearing that.
+    ClearLocalCache,
+    /// Remove cache entries produced by compilers that have since been removed or replaced.
+    PurgeStaleCompilers,
+    /// Change the maximum size of the cache, in bytes, until the server restarts.
+    SetMaxSize(u64),
+    /// Remove cache entries stored with the given label.
+    RemoveLabel(String),
+    /// Check that every cache entry is intact.
+    VerifyCache {
+        /// Remove the corrupt entries.
+        repair: bool,
+        /// The most entries to check a second.
+        rate: Option<u32>,
+    },
+    /// Replay the cache operations recorded in a trace file against the configured cache.
+    ReplayTrace(PathBuf),
+    /// Export the configured cache as an OCI image, to a directory or a registry.
+    ExportOci {
+        target: OciTarget,
+        /// The tag to give the exported image in a directory.
+        tag: String,
+    },
+    /// Import the cache entries in an OCI image into the configured cache.
+    ImportOci {
+        target: OciTarget,
+        /// The tag of the image to import, if the directory holds more than one.
+        tag: Option<String>,
+    },
     /// Run a compiler command.
     Compile {
         /// The binary to execute.
@@ -74,8 +139,29 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
             "-s --show-stats 'show cache statistics'
              -z, --zero-stats 'zero statistics counters'
              --start-server  'start background server'
-             --stop-server   'stop background server'"
+             --stop-server   'stop background server'
+             --list-toolchains 'package the toolchains of compilers used since the server started, without submitting them'
+             --check-config  'check the configuration and that the configured caches work, without starting the server'
+             --dump-config   'print the configuration resolved from the config file, environment and defaults as JSON'
+             --dist-stats    'show the jobs, build times and failures of each build server and client, as seen by the dist scheduler'
+             --purge-stale-compilers 'remove cache entries produced by compilers that have since been removed or upgraded'
+             --verify-cache  'check that every cache entry can be read back intact, and report the corrupt ones'
+             --ccache-compat 'interpret the rest of the commandline as ccache would, for scripts written for ccache'"
                 )
+        .arg(Arg::from_usage("--compilers [LIST] 'report the detected kind and version of the comma-separated compilers in LIST, given as --compilers=LIST, or of the usual compilers on PATH, and whether sccache can cache or distribute them'")
+             .min_values(0)
+             .require_equals(true))
+        .arg(Arg::from_usage("--show-provenance [KEY] 'show where the cache entry with hash key KEY came from'"))
+        .arg(Arg::from_usage("--prune-older-than [AGE] 'remove cache entries not used in AGE, such as 30d, 12h, 45m or 90s'"))
+        .arg(Arg::from_usage("--remove-label [LABEL] 'remove cache entries stored with SCCACHE_CACHE_LABEL=LABEL'"))
+        .arg(Arg::from_usage("--replay-trace [FILE] 'replay the cache operations recorded in FILE against the configured cache and report how long they took'"))
+        .arg(Arg::from_usage("--repair 'with --verify-cache, remove the corrupt entries'")
+             .requires("verify-cache"))
+        .arg(Arg::from_usage("--verify-rate [RATE] 'with --verify-cache, check at most RATE entries a second'")
+             .requires("verify-cache"))
+        .arg(Arg::from_usage("--export-oci [TARGET] 'export the entries of the configured cache as an OCI image, to the image layout in the directory TARGET or, with TARGET docker://REF, to the image REF in a registry'"))
+        .arg(Arg::from_usage("--import-oci [TARGET] 'import the cache entries exported as an OCI image, from the image layout in the directory TARGET or, with TARGET docker://REF, from the image REF in a registry, into the configured cache'"))
+        .arg(Arg::from_usage("--oci-tag [TAG] 'with --export-oci to a directory, tag the image TAG instead of latest; with --import-oci from a directory, import the image tagged TAG'"))
         .arg(Arg::from_usage("--stats-format  'set output format of statistics'")
              .possible_values(&StatsFormat::variants())
              .default_value("text"))
@@ -86,6 +172,88 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
                 )
 }
 
+/// Whether `exe` is this sccache executable, either by path or as found on `PATH`.
+fn is_sccache(exe: &OsStr, cwd: &Path) -> bool {
+    // Only look at executables with sccache's own name, as a compiler name
+    // such as `gcc` may be a link to sccache that's meant to be resolved to
+    // the real compiler.
+    let stem = Path::new(exe).file_stem().map(|s| s.to_string_lossy().to_lowercase());
+    if stem.as_ref().map(|s| &**s) != Some(env!("CARGO_PKG_NAME")) {
+        return false
+    }
+    let this = match env::current_exe().and_then(|e| e.canonicalize()) {
+        Ok(this) => this,
+        Err(_) => return false,
+    };
+    which_in(exe, env::var_os("PATH"), cwd).ok()
+        .and_then(|path| path.canonicalize().ok())
+        .map_or(false, |path| path == this)
+}
+
+/// Parse a ccache commandline, `args` without the executable, into the
+/// equivalent `Command`. ccache options without an equivalent are an error,
+/// rather than being ignored.
+fn parse_ccache_args(args: &[OsString], cwd: PathBuf) -> Result<Command> {
+    let (first, rest) = match args.split_first() {
+        Some((first, rest)) => (first, rest),
+        None => bail!("No command specified"),
+    };
+    let option = match first.to_str() {
+        Some(option) if option.starts_with('-') => option,
+        // Anything else is a compiler, as in `ccache gcc -c foo.c`.
+        _ => {
+            return Ok(Command::Compile {
+                exe: first.to_owned(),
+                cmdline: rest.to_vec(),
+                cwd: cwd,
+                env_vars: env::vars_os().collect(),
+            })
+        }
+    };
+    // Options that take a value accept it as the next argument or after `=`.
+    let (option, value) = match option.find('=') {
+        Some(i) if option.starts_with("--") => (&option[..i], Some(&option[i + 1..])),
+        _ if option.starts_with("-M") && option.len() > 2 => ("-M", Some(&option[2..])),
+        _ => (option, None),
+    };
+    let takes_value = ["-M", "--max-size", "--evict-older-than"].contains(&option);
+    let (value, extra) = match value {
+        Some(value) => (Some(value.to_owned()), rest),
+        None if takes_value => match rest.split_first() {
+            Some((value, extra)) => (Some(value.to_string_lossy().into_owned()), extra),
+            None => bail!("ccache option `{}` requires a value", option),
+        },
+        None => (None, rest),
+    };
+    if let Some(extra) = extra.first() {
+        bail!("sccache only supports one ccache option at a time, got `{}` after `{}`",
+              extra.to_string_lossy(), option);
+    }
+    match (option, value) {
+        ("-s", None) | ("--show-stats", None) => Ok(Command::ShowStats(StatsFormat::text)),
+        ("-z", None) | ("--zero-stats", None) => Ok(Command::ZeroStats),
+        ("-C", None) | ("--clear", None) => Ok(Command::ClearLocalCache),
+        ("-p", None) | ("--show-config", None) => Ok(Command::DumpConfig),
+        ("-M", Some(size)) | ("--max-size", Some(size)) => {
+            // Like ccache, a size without a suffix is in gigabytes, and 0 means unlimited.
+            let bytes = match size.parse::<u64>() {
+                Ok(0) => Some(u64::max_value()),
+                Ok(gigs) => gigs.checked_mul(1024 * 1024 * 1024),
+                Err(_) => parse_size(&size),
+            };
+            match bytes {
+                Some(bytes) => Ok(Command::SetMaxSize(bytes)),
+                None => bail!("Invalid size `{}`, expected a number optionally followed by K, M, G or T", size),
+            }
+        }
+        ("--evict-older-than", Some(age)) => match parse_duration(&age) {
+            Some(age) => Ok(Command::PruneOlderThan(age)),
+            None => bail!("Invalid age `{}`, expected a number followed by s, m, h or d", age),
+        },
+        _ => bail!("ccache option `{}` is not supported by sccache", option),
+    }
+}
+
 /// Parse the commandline into a `Command` to execute.
 pub fn parse() -> Result<Command> {
     trace!("parse");
@@ -101,6 +269,8 @@ pub fn parse() -> Result<Command> {
             match exe.file_stem().and_then(|s| s.to_str()).map(|s| s.to_lowercase()) {
                 // If the executable has its standard name, do nothing.
                 Some(ref e) if e == env!("CARGO_PKG_NAME") => {}
+                // Installed as ccache, so accept ccache's commandline.
+                Some(ref e) if e == "ccache" => return parse_ccache_args(&args[1..], cwd),
                 // Otherwise, if it was copied/hardlinked under a different $name, act
                 // as if it were invoked with `sccache $name`, but avoid $name resolving
                 // to ourselves again if it's in the PATH.
@@ -125,11 +295,27 @@ pub fn parse() -> Result<Command> {
             }
         }
     }
+    if !internal_start_server && args.get(1).map_or(false, |arg| arg == "--ccache-compat") {
+        return parse_ccache_args(&args[2..], cwd)
+    }
     let matches = get_app().get_matches_from(args);
 
     let show_stats = matches.is_present("show-stats");
     let start_server = matches.is_present("start-server");
     let stop_server = matches.is_present("stop-server");
+    let list_toolchains = matches.is_present("list-toolchains");
+    let check_config = matches.is_present("check-config");
+    let dump_config = matches.is_present("dump-config");
+    let dist_stats = matches.is_present("dist-stats");
+    let purge_stale_compilers = matches.is_present("purge-stale-compilers");
+    let verify_cache = matches.is_present("verify-cache");
+    let list_compilers = matches.is_present("compilers");
+    let show_provenance = matches.value_of("show-provenance");
+    let prune_older_than = matches.value_of("prune-older-than");
+    let remove_label = matches.value_of("remove-label");
+    let replay_trace = matches.value_of_os("replay-trace");
+    let export_oci = matches.value_of_os("export-oci");
+    let import_oci = matches.value_of_os("import-oci");
     let zero_stats = matches.is_present("zero-stats");
     let cmd = matches.values_of_os("cmd");
     // Ensure that we've only received one command to run.
@@ -141,6 +327,19 @@ pub fn parse() -> Result<Command> {
         show_stats,
         start_server,
         stop_server,
+        list_toolchains,
+        check_config,
+        dump_config,
+        dist_stats,
+        purge_stale_compilers,
+        verify_cache,
+        list_compilers,
+        is_some(&show_provenance),
+        is_some(&prune_older_than),
+        is_some(&remove_label),
+        is_some(&replay_trace),
+        is_some(&export_oci),
+        is_some(&import_oci),
         is_some(&cmd),
             ].iter()
         .filter(|&&x| x).count() > 1 {
@@ -156,10 +355,59 @@ pub fn parse() -> Result<Command> {
         Ok(Command::StartServer)
     } else if stop_server {
         Ok(Command::StopServer)
+    } else if list_toolchains {
+        Ok(Command::ListToolchains)
+    } else if check_config {
+        Ok(Command::CheckConfig)
+    } else if dump_config {
+        Ok(Command::DumpConfig)
+    } else if dist_stats {
+        Ok(Command::DistStats)
+    } else if purge_stale_compilers {
+        Ok(Command::PurgeStaleCompilers)
+    } else if verify_cache {
+        let rate = match matches.value_of("verify-rate") {
+            Some(rate) => match rate.parse::<u32>() {
+                Ok(rate) if rate > 0 => Some(rate),
+                _ => bail!("Invalid rate `{}`, expected a positive number of entries a second", rate),
+            },
+            None => None,
+        };
+        Ok(Command::VerifyCache { repair: matches.is_present("repair"), rate })
+    } else if list_compilers {
+        let compilers = matches.value_of("compilers").map(|list| {
+            list.split(',').filter(|c| !c.is_empty()).map(|c| c.to_owned()).collect()
+        });
+        Ok(Command::ListCompilers(compilers))
+    } else if let Some(key) = show_provenance {
+        Ok(Command::ShowProvenance(key.to_owned()))
+    } else if let Some(age) = prune_older_than {
+        match parse_duration(age) {
+            Some(age) => Ok(Command::PruneOlderThan(age)),
+            None => bail!("Invalid age `{}`, expected a number followed by s, m, h or d", age),
+        }
+    } else if let Some(label) = remove_label {
+        Ok(Command::RemoveLabel(label.to_owned()))
+    } else if let Some(path) = replay_trace {
+        Ok(Command::ReplayTrace(cwd.join(path)))
+    } else if let Some(target) = export_oci {
+        let tag = matches.value_of("oci-tag").unwrap_or("latest").to_owned();
+        Ok(Command::ExportOci { target: OciTarget::parse(target, &cwd), tag })
+    } else if let Some(target) = import_oci {
+        let tag = matches.value_of("oci-tag").map(|t| t.to_owned());
+        Ok(Command::ImportOci { target: OciTarget::parse(target, &cwd), tag })
     } else if zero_stats {
         Ok(Command::ZeroStats)
     } else if let Some(mut args) = cmd {
-        if let Some(exe) = args.next() {
+        if let Some(mut exe) = args.next() {
+            // Setting both RUSTC_WRAPPER and RUSTC_WORKSPACE_WRAPPER to sccache
+            // runs `sccache sccache rustc ...`.
+            while is_sccache(exe, &cwd) {
+                exe = match args.next() {
+                    Some(exe) => exe,
+                    None => bail!("No compile command"),
+                };
+            }
             let cmdline = args.map(|s| s.to_owned()).collect::<Vec<_>>();
             Ok(Command::Compile {
                 exe: exe.to_owned(),
@@ -174,3 +422,81 @@ pub fn parse() -> Result<Command> {
         bail!("No command specified");
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_ccache(args: &[&str]) -> Result<Command> {
+        parse_ccache_args(&args.iter().map(OsString::from).collect::<Vec<_>>(), "/".into())
+    }
+
+    #[test]
+    fn test_parse_ccache_args() {
+        match parse_ccache(&["-s"]) {
+            Ok(Command::ShowStats(StatsFormat::text)) => {}
+            _ => panic!("Expected show-stats"),
+        }
+        match parse_ccache(&["--zero-stats"]) {
+            Ok(Command::ZeroStats) => {}
+            _ => panic!("Expected zero-stats"),
+        }
+        match parse_ccache(&["-C"]) {
+            Ok(Command::ClearLocalCache) => {}
+            _ => panic!("Expected clearing the cache"),
+        }
+        for args in &[&["-M", "5G"][..], &["-M5G"][..], &["--max-size=5G"][..], &["--max-size", "5"][..]] {
+            match parse_ccache(args) {
+                Ok(Command::SetMaxSize(size)) => assert_eq!(size, 5 * 1024 * 1024 * 1024),
+                _ => panic!("Expected setting the max size from {:?}", args),
+            }
+        }
+        match parse_ccache(&["-M", "0"]) {
+            Ok(Command::SetMaxSize(size)) => assert_eq!(size, u64::max_value()),
+            _ => panic!("Expected an unlimited max size"),
+        }
+        match parse_ccache(&["gcc", "-c", "foo.c"]) {
+            Ok(Command::Compile { exe, cmdline, .. }) => {
+                assert_eq!(exe, OsString::from("gcc"));
+                assert_eq!(cmdline, vec![OsString::from("-c"), OsString::from("foo.c")]);
+            }
+            _ => panic!("Expected a compile"),
+        }
+        assert!(parse_ccache(&["-M"]).is_err());
+        assert!(parse_ccache(&["-M", "lots"]).is_err());
+        assert!(parse_ccache(&["-s", "-z"]).is_err());
+        assert!(parse_ccache(&["-F", "1000"]).is_err());
+        assert!(parse_ccache(&["--cleanup"]).is_err());
+        assert!(parse_ccache(&[]).is_err());
+    }
+
+    #[test]
+    fn test_compilers_list_is_optional() {
+        let matches = get_app().get_matches_from_safe(&["sccache", "--compilers"]).unwrap();
+        assert!(matches.is_present("compilers"));
+        assert_eq!(matches.value_of("compilers"), None);
+        let matches = get_app().get_matches_from_safe(&["sccache", "--compilers=gcc,clang"]).unwrap();
+        assert_eq!(matches.value_of("compilers"), Some("gcc,clang"));
+    }
+
+    #[test]
+    fn test_verify_options_require_verify_cache() {
+        assert!(get_app().get_matches_from_safe(&["sccache", "--repair"]).is_err());
+        assert!(get_app().get_matches_from_safe(&["sccache", "--verify-rate", "10"]).is_err());
+        let matches = get_app().get_matches_from_safe(&["sccache", "--verify-cache", "--repair", "--verify-rate", "10"]).unwrap();
+        assert!(matches.is_present("repair"));
+        assert_eq!(matches.value_of("verify-rate"), Some("10"));
+    }
+
+    #[test]
+    fn test_parse_oci_target() {
+        match OciTarget::parse(OsStr::new("docker://ghcr.io/team/sccache:v1"), Path::new("/work")) {
+            OciTarget::Registry(ref reference) if reference == "ghcr.io/team/sccache:v1" => {}
+            t => panic!("Expected a registry, got {:?}", t),
+        }
+        match OciTarget::parse(OsStr::new("layout"), Path::new("/work")) {
+            OciTarget::Dir(ref dir) if dir == Path::new("/work/layout") => {}
+            t => panic!("Expected a directory, got {:?}", t),
+        }
+    }
+}
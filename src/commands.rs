@@ -15,14 +15,24 @@
 use atty::{self, Stream};
 use bincode;
 use byteorder::{ByteOrder, BigEndian};
+use cache::{Cache, CacheWrite, Provenance, PruneStats, Storage, VerifyStats, storage_from_cache_type, storage_from_config};
+use cache::oci;
+use cache::trace;
 use client::{
     connect_to_server,
     connect_with_retry,
+    first_notice,
+    max_client_requests,
+    RequestSlot,
     ServerConnection,
 };
-use cmdline::{Command, StatsFormat};
-use compiler::ColorMode;
+use cmdline::{Command, OciTarget, StatsFormat};
+use compiler::{ColorMode, CCompilerKind, CompilerKind, compiler_command, get_compiler_info, pkg};
+use config::{self, CONFIG};
+#[cfg(feature = "dist")]
+use dist;
 use futures::Future;
+use futures_cpupool::CpuPool;
 use jobserver::Client;
 use log::LogLevel::Trace;
 use mock_command::{
@@ -30,12 +40,12 @@ use mock_command::{
     ProcessCommandCreator,
     RunCommand,
 };
-use protocol::{Request, Response, CompileResponse, CompileFinished, Compile};
+use protocol::{Request, Response, CompileResponse, CompileFinished, Compile, ToolchainInfo};
 use serde_json;
 use server::{self, ServerInfo, ServerStartup};
 use std::env;
 use std::ffi::{OsStr,OsString};
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{
     self,
     Write,
@@ -44,9 +54,12 @@ use std::io::{
 use std::os::unix::process::ExitStatusExt;
 use std::path::{
     Path,
+    PathBuf,
 };
 use std::process;
+use std::time::Duration;
 use strip_ansi_escapes::Writer;
+use tempfile;
 use tokio_core::reactor::Core;
 use tokio_io::AsyncRead;
 use tokio_io::io::read_exact;
@@ -390,6 +403,97 @@ pub fn request_shutdown(mut conn: ServerConnection) -> Result<ServerInfo> {
     }
 }
 
+/// Send a `ListToolchains` request to the server, and return the packaged toolchains if successful.
+pub fn request_list_toolchains(mut conn: ServerConnection) -> Result<Vec<ToolchainInfo>> {
+    debug!("request_list_toolchains");
+    let response = conn.request(Request::ListToolchains).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::Toolchains(toolchains) = response {
+        Ok(toolchains)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `GetProvenance` request for `key` to the server, and return the result.
+pub fn request_get_provenance(mut conn: ServerConnection, key: &str) -> Result<::std::result::Result<Option<Provenance>, String>> {
+    debug!("request_get_provenance");
+    let response = conn.request(Request::GetProvenance(key.to_owned())).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::Provenance(provenance) = response {
+        Ok(provenance)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `PruneOlderThan` request to the server, and return the result.
+pub fn request_prune_older_than(mut conn: ServerConnection, age: Duration) -> Result<::std::result::Result<PruneStats, String>> {
+    debug!("request_prune_older_than");
+    let response = conn.request(Request::PruneOlderThan(age)).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::Pruned(stats) = response {
+        Ok(stats)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `PurgeStaleCompilers` request to the server, and return the result.
+pub fn request_purge_stale_compilers(mut conn: ServerConnection) -> Result<::std::result::Result<PruneStats, String>> {
+    debug!("request_purge_stale_compilers");
+    let response = conn.request(Request::PurgeStaleCompilers).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::Pruned(stats) = response {
+        Ok(stats)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `SetMaxSize` request to the server, and return the result.
+pub fn request_set_max_size(mut conn: ServerConnection, size: u64) -> Result<::std::result::Result<PruneStats, String>> {
+    debug!("request_set_max_size");
+    let response = conn.request(Request::SetMaxSize(size)).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::Pruned(stats) = response {
+        Ok(stats)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `RemoveLabel` request for `label` to the server, and return the result.
+pub fn request_remove_label(mut conn: ServerConnection, label: &str) -> Result<::std::result::Result<PruneStats, String>> {
+    debug!("request_remove_label");
+    let response = conn.request(Request::RemoveLabel(label.to_owned())).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::Pruned(stats) = response {
+        Ok(stats)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Ask the server to check the cache entries, removing corrupt ones if `repair`.
+pub fn request_verify_cache(mut conn: ServerConnection, repair: bool, rate: Option<u32>) -> Result<::std::result::Result<VerifyStats, String>> {
+    debug!("request_verify_cache");
+    let response = conn.request(Request::VerifyCache { repair, rate }).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::Verified(stats) = response {
+        Ok(stats)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
 /// Send a `Compile` request to the server, and return the server response if successful.
 fn request_compile<W, X, Y>(conn: &mut ServerConnection, exe: W, args: &Vec<X>, cwd: Y,
                             env_vars: Vec<(OsString, OsString)>) -> Result<CompileResponse>
@@ -476,7 +580,7 @@ fn handle_compile_finished(response: CompileFinished,
 ///
 /// If the server returned `UnhandledCompile`, run the compilation command
 /// locally using `creator` and return the result.
-fn handle_compile_response<T>(mut creator: T,
+fn handle_compile_response<T>(creator: T,
                               core: &mut Core,
                               conn: &mut ServerConnection,
                               response: CompileResponse,
@@ -519,7 +623,7 @@ fn handle_compile_response<T>(mut creator: T,
     };
 
     //TODO: possibly capture output here for testing.
-    let mut cmd = creator.new_command_sync(exe);
+    let mut cmd = compiler_command(&creator, exe);
     cmd.args(&cmdline)
         .current_dir(cwd);
     if log_enabled!(Trace) {
@@ -545,14 +649,99 @@ fn handle_compile_response<T>(mut creator: T,
     }
 }
 
+/// Other compiler wrappers which cache or distribute compilations themselves.
+const COMPILER_WRAPPERS: &[&str] = &["ccache", "distcc"];
+
+/// What to do when the compiler turns out to be another compiler wrapper.
+#[derive(Debug, PartialEq, Eq)]
+enum WrapperPolicy {
+    /// Warn, but compile through the wrapper anyway.
+    Warn,
+    /// Refuse to compile.
+    Error,
+    /// Compile with the real compiler behind the wrapper.
+    Unwrap,
+}
+
+/// Get the policy for compiling through other compiler wrappers.
+fn get_wrapper_policy() -> WrapperPolicy {
+    match env::var("SCCACHE_COMPILER_WRAPPER").as_ref().map(|s| s.as_str()) {
+        Ok("error") => WrapperPolicy::Error,
+        Ok("unwrap") => WrapperPolicy::Unwrap,
+        _ => WrapperPolicy::Warn,
+    }
+}
+
+/// Return the name of the compiler wrapper `exe` resolves to, if any, with a
+/// relative `exe` taken to be relative to `cwd`.
+///
+/// This catches both running a wrapper directly and the symlinks named
+/// after compilers that wrappers install, such as `/usr/lib/ccache/gcc`.
+pub fn compiler_wrapper_name(exe: &Path, cwd: &Path) -> Option<&'static str> {
+    let exe = cwd.join(exe);
+    let resolved = fs::canonicalize(&exe).unwrap_or(exe);
+    wrapper_named(&resolved)
+}
+
+/// Return the name of the compiler wrapper that `exe` is named after, if any.
+fn wrapper_named(exe: &Path) -> Option<&'static str> {
+    let stem = match exe.file_stem().and_then(|s| s.to_str()) {
+        Some(stem) => stem.to_owned(),
+        None => return None,
+    };
+    COMPILER_WRAPPERS.iter().find(|&&w| w == stem).map(|&w| w)
+}
+
+/// Find the compiler that the compiler wrapper at `exe` would run for `cmdline`.
+///
+/// If the wrapper was run directly, the compiler is the first entry in
+/// `cmdline`. Otherwise the wrapper is a symlink named after the compiler.
+/// Either way, a compiler given by name is the first executable with that name
+/// in `path` that isn't also a wrapper.
+pub fn unwrap_compiler(exe: &Path,
+                       cmdline: &[OsString],
+                       cwd: &Path,
+                       path: Option<OsString>) -> Option<(PathBuf, Vec<OsString>)> {
+    let exe_name = match exe.file_name() {
+        Some(name) => name.to_owned(),
+        None => return None,
+    };
+    let (compiler, args) = if wrapper_named(Path::new(&exe_name)).is_some() {
+        match cmdline.split_first() {
+            Some((compiler, args)) => (compiler.to_owned(), args.to_owned()),
+            None => return None,
+        }
+    } else {
+        (exe_name, cmdline.to_owned())
+    };
+    let is_wrapper = |c: &PathBuf| compiler_wrapper_name(c, cwd).is_some();
+    if Path::new(&compiler).components().count() > 1 {
+        return which_in(&compiler, None::<OsString>, cwd).ok()
+            .and_then(|c| if is_wrapper(&c) { None } else { Some((c, args)) })
+    }
+    let path = match path {
+        Some(path) => path,
+        None => return None,
+    };
+    env::split_paths(&path)
+        .filter_map(|dir| which_in(&compiler, Some(dir), cwd).ok())
+        .find(|c| !is_wrapper(c))
+        .map(|c| (c, args))
+}
+
 /// Send a `Compile` request to the sccache server `conn`, and handle the response.
 ///
 /// The first entry in `cmdline` will be looked up in `path` if it is not
 /// an absolute path.
+/// If that resolves to another compiler wrapper, such as ccache, then
+/// depending on `SCCACHE_COMPILER_WRAPPER` this warns, fails or compiles with
+/// the real compiler behind the wrapper instead.
+/// `slot`, if any, is released once the request has been sent.
 /// See `request_compile` and `handle_compile_response`.
 pub fn do_compile<T>(creator: T,
                      core: &mut Core,
                      mut conn: ServerConnection,
+                     slot: Option<RequestSlot>,
                      exe: &Path,
                      cmdline: Vec<OsString>,
                      cwd: &Path,
@@ -563,11 +752,304 @@ pub fn do_compile<T>(creator: T,
     where T: CommandCreatorSync,
 {
     trace!("do_compile");
-    let exe_path = which_in(exe, path, &cwd)?;
+    let exe_path = which_in(exe, path.clone(), &cwd)?;
+    let (exe_path, cmdline) = match compiler_wrapper_name(&exe_path, cwd) {
+        None => (exe_path, cmdline),
+        Some(wrapper) => match get_wrapper_policy() {
+            WrapperPolicy::Warn => {
+                if first_notice(get_port(), &format!("compiler-wrapper {}", exe_path.display())) {
+                    warn!("Compiler {:?} is {}, caching may not work", exe_path, wrapper);
+                    writeln!(stderr, "sccache: warning: compiler {:?} is {}, which will cache or distribute compilations itself; set SCCACHE_COMPILER_WRAPPER=unwrap to use the real compiler",
+                             exe_path, wrapper)?;
+                }
+                (exe_path, cmdline)
+            }
+            WrapperPolicy::Error => {
+                bail!("compiler {:?} is {}, refusing to compile through another compiler wrapper", exe_path, wrapper)
+            }
+            WrapperPolicy::Unwrap => match unwrap_compiler(&exe_path, &cmdline, cwd, path) {
+                Some((compiler, cmdline)) => {
+                    debug!("Unwrapped {} compiler {:?} to {:?}", wrapper, exe_path, compiler);
+                    (compiler, cmdline)
+                }
+                None => bail!("compiler {:?} is {}, and the compiler it wraps couldn't be found", exe_path, wrapper),
+            },
+        },
+    };
     let res = request_compile(&mut conn, &exe_path, &cmdline, &cwd, env_vars)?;
+    // The server has the request now, so let other clients send theirs.
+    drop(slot);
     handle_compile_response(creator, core, &mut conn, res, &exe_path, cmdline, cwd, stdout, stderr)
 }
 
+/// Print the configuration sccache resolved from its config file, environment
+/// and defaults as JSON, with secrets redacted.
+fn dump_config() -> Result<()> {
+    let core = Core::new()?;
+    let pool = CpuPool::new(1);
+    let config_path = config::config_file_path();
+    let config_file_loaded = config::read_config_file(&config_path).map(|c| c.is_some()).unwrap_or(false);
+    let active_cache = storage_from_config(&pool, &core.handle()).location();
+    let mut env = serde_json::Map::new();
+    for (k, v) in env::vars().filter(|&(ref k, _)| k.starts_with("SCCACHE_")) {
+        let secret = ["KEY", "TOKEN", "SECRET", "PASSWORD", "CONNECTION_STRING"].iter().any(|s| k.contains(s));
+        env.insert(k, serde_json::Value::String(if secret { "***".to_owned() } else { v }));
+    }
+    let mut dump = serde_json::Map::new();
+    dump.insert("config_file".to_owned(), serde_json::to_value(&config_path)?);
+    dump.insert("config_file_loaded".to_owned(), serde_json::Value::Bool(config_file_loaded));
+    dump.insert("active_cache".to_owned(), serde_json::Value::String(active_cache));
+    dump.insert("config".to_owned(), serde_json::to_value(&*CONFIG)?);
+    dump.insert("environment".to_owned(), serde_json::Value::Object(env));
+    let mut dump = serde_json::Value::Object(dump);
+    config::redact_secrets(&mut dump);
+    serde_json::to_writer_pretty(&mut io::stdout(), &dump)?;
+    println!();
+    Ok(())
+}
+
+/// The key written to and read back from caches by `check_config`.
+const CHECK_CONFIG_KEY: &str = "sccache-check-config";
+
+/// Check that an entry can be written to `storage`, read back and removed,
+/// or that it can be read from if it's read-only.
+fn check_storage(core: &mut Core, storage: &Storage) -> Result<String> {
+    if storage.is_read_only() {
+        core.run(storage.get(CHECK_CONFIG_KEY)).chain_err(|| "failed to read from cache")?;
+        return Ok(format!("{}: read from the cache (read-only)", storage.location()))
+    }
+    let mut entry = CacheWrite::new()?;
+    entry.put_object("check", &mut io::Cursor::new(b"ok"), None)?;
+    core.run(storage.put(CHECK_CONFIG_KEY, entry)).chain_err(|| "failed to write to cache")?;
+    match core.run(storage.get(CHECK_CONFIG_KEY)).chain_err(|| "failed to read from cache")? {
+        Cache::Hit(_) => {}
+        _ => bail!("{}: wrote an entry but couldn't read it back", storage.location()),
+    }
+    match core.run(storage.remove(CHECK_CONFIG_KEY)) {
+        Ok(()) => Ok(format!("{}: wrote, read back and removed an entry", storage.location())),
+        Err(e) => Ok(format!("{}: wrote and read back an entry, but couldn't remove it ({})", storage.location(), e)),
+    }
+}
+
+/// Check that the directory `dir` exists or can be created, and is writable.
+fn check_writable_dir(dir: &Path) -> Result<String> {
+    fs::create_dir_all(dir).chain_err(|| format!("couldn't create {}", dir.display()))?;
+    tempfile::NamedTempFile::new_in(dir).chain_err(|| format!("{} isn't writable", dir.display()))?;
+    Ok(format!("{} is writable", dir.display()))
+}
+
+/// Check that the dist scheduler at `addr` is reachable.
+#[cfg(feature = "dist")]
+fn check_scheduler(core: &mut Core, addr: ::std::net::IpAddr) -> Result<String> {
+    let client = dist::http::Client::new(
+        &core.handle(),
+        addr,
+        &CONFIG.dist.standby_scheduler_addrs,
+        &CONFIG.dist.cache_dir.join("client"),
+        CONFIG.dist.toolchain_cache_size,
+        &CONFIG.dist.custom_toolchains,
+        CONFIG.dist.toolchain_signing_key.as_ref().map(|p| &**p),
+        CONFIG.dist.inputs_compression,
+    );
+    let status = core.run(dist::Client::do_get_status(&client)).chain_err(|| format!("couldn't reach scheduler at {}", addr))?;
+    Ok(format!("scheduler at {} has {} build servers", addr, status.num_servers))
+}
+
+/// Print the job statistics gathered by the configured dist scheduler.
+#[cfg(feature = "dist")]
+fn dist_stats(core: &mut Core) -> Result<()> {
+    let addr = match CONFIG.dist.scheduler_addr {
+        Some(addr) => addr,
+        None => bail!("no dist scheduler configured"),
+    };
+    let client = dist::http::Client::new(
+        &core.handle(),
+        addr,
+        &CONFIG.dist.standby_scheduler_addrs,
+        &CONFIG.dist.cache_dir.join("client"),
+        CONFIG.dist.toolchain_cache_size,
+        &CONFIG.dist.custom_toolchains,
+        CONFIG.dist.toolchain_signing_key.as_ref().map(|p| &**p),
+        CONFIG.dist.inputs_compression,
+    );
+    let stats = core.run(dist::Client::do_get_stats(&client)).chain_err(|| format!("couldn't reach scheduler at {}", addr))?;
+    println!("Jobs the scheduler at {} couldn't assign: {}", addr, stats.alloc_failures);
+    let mut servers = stats.servers.iter().map(|&(ref id, ref s)| (id.0.to_string(), s)).collect::<Vec<_>>();
+    let mut clients = stats.clients.iter().map(|&(ref addr, ref s)| (addr.to_string(), s)).collect::<Vec<_>>();
+    servers.sort_by(|a, b| a.0.cmp(&b.0));
+    clients.sort_by(|a, b| a.0.cmp(&b.0));
+    for &(heading, ref rows) in &[("Build server", &servers), ("Client", &clients)] {
+        println!();
+        println!("{:<24} {:>10} {:>10} {:>16} {:>10} {:>14}",
+                 heading, "Jobs", "Completed", "Compile failures", "Failed", "Avg build (s)");
+        for &(ref name, stats) in rows.iter() {
+            let average = if stats.jobs_completed > 0 {
+                format!("{:.3}", stats.build_ms as f64 / stats.jobs_completed as f64 / 1000.0)
+            } else {
+                "-".to_owned()
+            };
+            println!("{:<24} {:>10} {:>10} {:>16} {:>10} {:>14}",
+                     name, stats.jobs_dispatched, stats.jobs_completed, stats.compile_failures, stats.jobs_failed, average);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "dist"))]
+fn dist_stats(_core: &mut Core) -> Result<()> {
+    bail!("sccache was built without distributed compilation support")
+}
+
+#[cfg(not(feature = "dist"))]
+fn check_scheduler(_core: &mut Core, _addr: ::std::net::IpAddr) -> Result<String> {
+    bail!("sccache was built without distributed compilation support")
+}
+
+/// Load the configuration and check each configured component, printing
+/// whether it works. Returns a failing exit status if any check fails.
+fn check_config() -> Result<i32> {
+    let mut core = Core::new()?;
+    let pool = CpuPool::new(1);
+    let mut failed = false;
+    {
+        let mut report = |component: &str, res: Result<String>| {
+            match res {
+                Ok(msg) => println!("PASS {}: {}", component, msg),
+                Err(e) => {
+                    failed = true;
+                    let msg = e.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(": ");
+                    println!("FAIL {}: {}", component, msg);
+                }
+            }
+        };
+
+        let config_path = config::config_file_path();
+        report("config file", config::read_config_file(&config_path).map(|conf| {
+            match conf {
+                Some(_) => format!("loaded {}", config_path.display()),
+                None => format!("no config file at {}, using defaults", config_path.display()),
+            }
+        }));
+        for cache_type in CONFIG.caches.iter() {
+            let res = storage_from_cache_type(cache_type, &pool, &core.handle())
+                .and_then(|storage| check_storage(&mut core, &*storage));
+            report("cache", res);
+        }
+        report("local disk cache", check_writable_dir(&CONFIG.fallback_cache.dir));
+        if let Some(addr) = CONFIG.dist.scheduler_addr {
+            report("dist scheduler", check_scheduler(&mut core, addr));
+        }
+    }
+    Ok(if failed { 1 } else { 0 })
+}
+
+/// The compilers `--compilers` looks for on `PATH` when it isn't given a list.
+const DEFAULT_COMPILERS: &[&str] = &["cc", "c++", "gcc", "g++", "clang", "clang++", "cl", "nvcc", "rustc"];
+
+/// Return the first line of `compiler --version`, if it prints one.
+fn compiler_version(compiler: &Path) -> Option<String> {
+    let output = process::Command::new(compiler).arg("--version").output().ok()?;
+    String::from_utf8_lossy(&output.stdout).lines()
+        .map(|l| l.trim().to_owned())
+        .find(|l| !l.is_empty())
+}
+
+/// Describe whether compilations with `compiler` can be distributed, and if
+/// not, why.
+fn distributable(compiler: &Path, packager: Box<pkg::CompilerPackager>) -> String {
+    if !cfg!(feature = "dist") {
+        return "no (sccache was built without distributed compilation support)".to_owned()
+    }
+    if CONFIG.dist.scheduler_addr.is_none() {
+        return "no (no dist scheduler is configured)".to_owned()
+    }
+    if CONFIG.dist.custom_toolchains.iter().any(|ct| ct.compiler_executable == compiler) {
+        return "yes (custom toolchain)".to_owned()
+    }
+    match pkg::dry_run(packager) {
+        Ok(_) => "yes".to_owned(),
+        Err(e) => format!("no ({})", e.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(": ")),
+    }
+}
+
+/// Detect each of `compilers`, or those of `DEFAULT_COMPILERS` that are on
+/// `PATH`, and print what sccache supports for them. Returns a failing exit
+/// status if any compiler that was asked for couldn't be handled.
+fn list_compilers(compilers: Option<Vec<String>>) -> Result<i32> {
+    let explicit = compilers.is_some();
+    let compilers = compilers.unwrap_or_else(|| {
+        DEFAULT_COMPILERS.iter().map(|c| (*c).to_owned()).collect()
+    });
+    let cwd = env::current_dir()?;
+    let env_vars = env::vars_os().collect::<Vec<_>>();
+    let jobserver = unsafe { Client::new() };
+    let mut core = Core::new()?;
+    let creator = ProcessCommandCreator::new(&core.handle(), &jobserver);
+    let pool = CpuPool::new(1);
+    let mut failed = false;
+    let mut found = 0;
+    for name in compilers {
+        let path = match which_in(&name, env::var_os("PATH"), &cwd) {
+            Ok(path) => path,
+            Err(_) => {
+                if explicit {
+                    println!("{}: not found", name);
+                    failed = true;
+                }
+                continue
+            }
+        };
+        found += 1;
+        println!("{}", path.display());
+        match core.run(get_compiler_info(&creator, &path, &env_vars, &pool)) {
+            Ok(c) => {
+                let kind = c.kind();
+                let version = match kind {
+                    // cl has no --version, it prints its banner on stderr instead.
+                    CompilerKind::C(CCompilerKind::MSVC) => None,
+                    _ => compiler_version(&path),
+                };
+                println!("    {:<20}{:?}", "Kind", kind);
+                println!("    {:<20}{}", "Version", version.as_ref().map_or("unknown", |v| &**v));
+                println!("    {:<20}{}", "Cacheable", "yes");
+                println!("    {:<20}{}", "Cache key from", match kind {
+                    CompilerKind::C(_) => "preprocessed source",
+                    CompilerKind::Rust => "source files listed in dep-info",
+                });
+                println!("    {:<20}{}", "Distributable", distributable(&path, c.get_toolchain_packager()));
+            }
+            Err(e) => {
+                failed |= explicit;
+                let reason = e.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(": ");
+                println!("    {:<20}{}", "Cacheable", format!("no ({})", reason));
+            }
+        }
+    }
+    if found == 0 && !explicit {
+        println!("None of {} were found on PATH", DEFAULT_COMPILERS.join(", "));
+    }
+    Ok(if failed { 1 } else { 0 })
+}
+
+/// Ask the server to remove cache entries unused for `age`, and report how
+/// many it removed.
+fn prune_older_than(age: Duration) -> Result<i32> {
+    let conn = connect_or_start_server(get_port())?;
+    let stats = request_prune_older_than(conn, age).chain_err(|| {
+        "couldn't prune cache entries on server"
+    })?;
+    match stats {
+        Ok(stats) => {
+            println!("Removed {} cache entries, freeing {} bytes", stats.entries, stats.bytes);
+            Ok(0)
+        }
+        Err(e) => {
+            println!("Couldn't prune cache entries: {}", e);
+            Ok(1)
+        }
+    }
+}
+
 /// Run `cmd` and return the process exit status.
 pub fn run_command(cmd: Command) -> Result<i32> {
     match cmd {
@@ -618,14 +1100,207 @@ pub fn run_command(cmd: Command) -> Result<i32> {
             let stats = request_shutdown(server)?;
             stats.print();
         }
+        Command::ListToolchains => {
+            trace!("Command::ListToolchains");
+            let conn = connect_or_start_server(get_port())?;
+            let toolchains = request_list_toolchains(conn).chain_err(|| {
+                "couldn't list toolchains on server"
+            })?;
+            if toolchains.is_empty() {
+                println!("No compilers have been used since the server started");
+            }
+            for tc in toolchains {
+                match tc.archive_id {
+                    Ok(archive_id) => println!("{} ({}): {}", tc.compiler.display(), tc.kind, archive_id),
+                    Err(e) => println!("{} ({}): failed to package toolchain: {}", tc.compiler.display(), tc.kind, e),
+                }
+            }
+        }
+        Command::ShowProvenance(key) => {
+            trace!("Command::ShowProvenance");
+            let conn = connect_or_start_server(get_port())?;
+            let provenance = request_get_provenance(conn, &key).chain_err(|| {
+                "couldn't get cache entry provenance from server"
+            })?;
+            match provenance {
+                Ok(Some(p)) => {
+                    println!("{:<20}{}", "Hostname", p.hostname.as_ref().map_or("unknown", |h| &**h));
+                    println!("{:<20}{}", "sccache version", p.sccache_version);
+                    println!("{:<20}{}", "Compiler", p.compiler.display());
+                    println!("{:<20}{}", "Compiler digest", p.compiler_digest);
+                    println!("{:<20}{}", "Stored at", p.timestamp);
+                    if let Some(ref label) = p.label {
+                        println!("{:<20}{}", "Label", label);
+                    }
+                }
+                Ok(None) => println!("The cache entry for {} was stored without provenance", key),
+                Err(e) => {
+                    println!("Couldn't read the cache entry for {}: {}", key, e);
+                    return Ok(1)
+                }
+            }
+        }
+        Command::PruneOlderThan(age) => {
+            trace!("Command::PruneOlderThan");
+            return prune_older_than(age)
+        }
+        Command::ClearLocalCache => {
+            trace!("Command::ClearLocalCache");
+            // Scripts run `ccache -C` to clear a local cache, which must not
+            // turn into wiping a cache shared with other machines.
+            if !CONFIG.caches.is_empty() {
+                println!("sccache is configured to use a cache other than the local disk cache, \
+                          which `-C` won't clear. To remove every entry from the configured cache, \
+                          run `sccache --prune-older-than 0s`.");
+                return Ok(1)
+            }
+            // Every entry is older than no time at all.
+            return prune_older_than(Duration::from_secs(0))
+        }
+        Command::ReplayTrace(path) => {
+            trace!("Command::ReplayTrace({:?})", path);
+            let events = trace::read_trace(&path)?;
+            let mut core = Core::new()?;
+            let pool = CpuPool::new(1);
+            let storage = storage_from_config(&pool, &core.handle());
+            println!("Replaying {} cache operations against {}", events.len(), storage.location());
+            trace::replay(&mut core, &*storage, &events).print();
+        }
+        Command::ExportOci { target, tag } => {
+            use tempdir::TempDir;
+
+            trace!("Command::ExportOci({:?}, {})", target, tag);
+            let mut core = Core::new()?;
+            let pool = CpuPool::new(1);
+            let storage = storage_from_config(&pool, &core.handle());
+            match target {
+                OciTarget::Dir(dir) => {
+                    let entries = oci::export(&mut core, &*storage, &dir, &tag)
+                        .chain_err(|| format!("failed to export {}", storage.location()))?;
+                    println!("Exported {} cache entries to {:?}, tagged {}", entries, dir, tag);
+                }
+                OciTarget::Registry(reference) => {
+                    let reference = oci::Reference::parse(&reference)?;
+                    let tempdir = TempDir::new("sccache")?;
+                    let entries = oci::export(&mut core, &*storage, tempdir.path(), reference.tag())
+                        .chain_err(|| format!("failed to export {}", storage.location()))?;
+                    oci::push(tempdir.path(), reference.tag(), &reference)
+                        .chain_err(|| format!("failed to push to {}", reference))?;
+                    println!("Exported {} cache entries to {}", entries, reference);
+                }
+            }
+        }
+        Command::ImportOci { target, tag } => {
+            use tempdir::TempDir;
+
+            trace!("Command::ImportOci({:?}, {:?})", target, tag);
+            let mut core = Core::new()?;
+            let pool = CpuPool::new(1);
+            let storage = storage_from_config(&pool, &core.handle());
+            // The image pulled is kept until it's imported.
+            let (dir, tag, _tempdir) = match target {
+                OciTarget::Dir(dir) => (dir, tag, None),
+                OciTarget::Registry(reference) => {
+                    let reference = oci::Reference::parse(&reference)?;
+                    let tempdir = TempDir::new("sccache")?;
+                    oci::pull(&reference, tempdir.path())
+                        .chain_err(|| format!("failed to pull {}", reference))?;
+                    (tempdir.path().to_owned(), Some(reference.tag().to_owned()), Some(tempdir))
+                }
+            };
+            let entries = oci::import(&mut core, &*storage, &dir, tag.as_ref().map(|t| &t[..]))
+                .chain_err(|| format!("failed to import into {}", storage.location()))?;
+            println!("Imported {} cache entries into {}", entries, storage.location());
+            // A running server only knows of the entries the local disk cache
+            // had when it started.
+            if storage.location().starts_with("Local disk") {
+                println!("A running server won't use them until it's restarted, stop it with `sccache --stop-server`");
+            }
+        }
+        Command::PurgeStaleCompilers => {
+            trace!("Command::PurgeStaleCompilers");
+            let conn = connect_or_start_server(get_port())?;
+            let stats = request_purge_stale_compilers(conn).chain_err(|| {
+                "couldn't purge cache entries on server"
+            })?;
+            match stats {
+                Ok(stats) => println!("Removed {} cache entries, freeing {} bytes", stats.entries, stats.bytes),
+                Err(e) => {
+                    println!("Couldn't purge cache entries: {}", e);
+                    return Ok(1)
+                }
+            }
+        }
+        Command::SetMaxSize(size) => {
+            trace!("Command::SetMaxSize({})", size);
+            let conn = connect_or_start_server(get_port())?;
+            let stats = request_set_max_size(conn, size).chain_err(|| {
+                "couldn't change the cache size on server"
+            })?;
+            match stats {
+                Ok(stats) => println!("Set the cache size to {} bytes, removing {} cache entries and freeing {} bytes",
+                                      size, stats.entries, stats.bytes),
+                Err(e) => {
+                    println!("Couldn't change the cache size: {}", e);
+                    return Ok(1)
+                }
+            }
+        }
+        Command::RemoveLabel(label) => {
+            trace!("Command::RemoveLabel({})", label);
+            let conn = connect_or_start_server(get_port())?;
+            let stats = request_remove_label(conn, &label).chain_err(|| {
+                "couldn't remove cache entries on server"
+            })?;
+            match stats {
+                Ok(stats) => println!("Removed {} cache entries labelled {}, freeing {} bytes", stats.entries, label, stats.bytes),
+                Err(e) => {
+                    println!("Couldn't remove cache entries: {}", e);
+                    return Ok(1)
+                }
+            }
+        }
+        Command::VerifyCache { repair, rate } => {
+            trace!("Command::VerifyCache {{ {}, {:?} }}", repair, rate);
+            let conn = connect_or_start_server(get_port())?;
+            let stats = request_verify_cache(conn, repair, rate).chain_err(|| {
+                "couldn't verify cache entries on server"
+            })?;
+            match stats {
+                Ok(stats) => {
+                    for key in stats.corrupt.iter() {
+                        println!("Corrupt: {}", key);
+                    }
+                    println!("Checked {} cache entries, {} corrupt", stats.checked, stats.corrupt.len());
+                    if repair {
+                        println!("Removed {} cache entries, freeing {} bytes", stats.removed.entries, stats.removed.bytes);
+                    }
+                    if stats.corrupt.len() as u64 > stats.removed.entries {
+                        return Ok(1)
+                    }
+                }
+                Err(e) => {
+                    println!("Couldn't verify cache entries: {}", e);
+                    return Ok(1)
+                }
+            }
+        }
         Command::Compile { exe, cmdline, cwd, env_vars } => {
             trace!("Command::Compile {{ {:?}, {:?}, {:?} }}", exe, cmdline, cwd);
             let jobserver = unsafe { Client::new() };
+            // Queue up here rather than flooding the server with connections.
+            let slot = match max_client_requests() {
+                Some(slots) => RequestSlot::acquire(get_port(), slots)
+                    .map_err(|e| warn!("Couldn't acquire a request slot, continuing anyway: {}", e))
+                    .ok(),
+                None => None,
+            };
             let conn = connect_or_start_server(get_port())?;
             let mut core = Core::new()?;
             let res = do_compile(ProcessCommandCreator::new(&core.handle(), &jobserver),
                                  &mut core,
                                  conn,
+                                 slot,
                                  exe.as_ref(),
                                  cmdline,
                                  &cwd,
@@ -637,6 +1312,23 @@ pub fn run_command(cmd: Command) -> Result<i32> {
                 "failed to execute compile"
             })
         }
+        Command::ListCompilers(compilers) => {
+            trace!("Command::ListCompilers({:?})", compilers);
+            return list_compilers(compilers)
+        }
+        Command::CheckConfig => {
+            trace!("Command::CheckConfig");
+            return check_config()
+        }
+        Command::DumpConfig => {
+            trace!("Command::DumpConfig");
+            dump_config()?;
+        }
+        Command::DistStats => {
+            trace!("Command::DistStats");
+            let mut core = Core::new()?;
+            dist_stats(&mut core)?;
+        }
         Command::ZeroStats => {
             trace!("Command::ZeroStats");
             let conn = connect_or_start_server(get_port())?;
@@ -13,13 +13,14 @@
 // limitations under the License.
 
 use compiler::{Cacheable, ColorMode, Compiler, CompilerArguments, CompileCommand, CompilerHasher, CompilerKind,
-               pkg::CompilerPackager, Compilation, HashResult};
+               pkg::CompilerPackager, Compilation, HashResult, external_cache_key};
+use config::{CONFIG, UnknownArgsPolicy};
 use dist;
-use futures::Future;
+use futures::{Future, future};
 use futures_cpupool::CpuPool;
 use mock_command::CommandCreatorSync;
 use std::borrow::Cow;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::fs::{self, File};
@@ -27,8 +28,11 @@ use std::hash::Hash;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::Mutex;
+use std::time::SystemTime;
 use tar;
 use util::{HashToDigest, Digest};
+use which::which_in;
 
 use errors::*;
 
@@ -59,6 +63,31 @@ pub enum Language {
     Cxx,
     ObjectiveC,
     ObjectiveCxx,
+    Cuda,
+}
+
+/// Where a compilation writes its object file.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OutputTarget {
+    /// A file, which is cached and restored on a hit.
+    File,
+    /// The compiler's stdout (`-o -` or `-o /dev/stdout`), which is cached and
+    /// replayed on a hit like any other compiler output.
+    Stdout,
+    /// Nowhere (`-o /dev/null`), as in runs that only generate dependencies.
+    Null,
+}
+
+impl OutputTarget {
+    pub fn of(path: &Path) -> OutputTarget {
+        if path == Path::new("-") || path == Path::new("/dev/stdout") {
+            OutputTarget::Stdout
+        } else if path == Path::new("/dev/null") {
+            OutputTarget::Null
+        } else {
+            OutputTarget::File
+        }
+    }
 }
 
 /// The results of parsing a compiler commandline.
@@ -77,10 +106,17 @@ pub struct ParsedArguments {
     pub preprocessor_args: Vec<OsString>,
     /// Commandline arguments for the preprocessor or the compiler.
     pub common_args: Vec<OsString>,
+    /// Commandline arguments for the preprocessor or the compiler that aren't
+    /// part of the hash key, which are unrecognized flags under
+    /// `UnknownArgsPolicy::Ignore`.
+    pub unhashed_args: Vec<OsString>,
     /// Whether or not the `-showIncludes` argument is passed on MSVC
     pub msvc_show_includes: bool,
     /// Whether the compilation is generating profiling or coverage data.
     pub profile_generate: bool,
+    /// Files the compiler reads that the preprocessor doesn't see, such as
+    /// profile data for `-fprofile-use`, whose contents are part of the hash key.
+    pub extra_hash_files: Vec<PathBuf>,
 }
 
 impl ParsedArguments {
@@ -90,6 +126,13 @@ impl ParsedArguments {
             .map(|s| s.to_string_lossy())
             .unwrap_or(Cow::Borrowed("Unknown filename"))
     }
+
+    /// Where the object file is written.
+    pub fn output_target(&self) -> OutputTarget {
+        self.outputs.get("obj")
+            .map(|o| OutputTarget::of(o))
+            .unwrap_or(OutputTarget::File)
+    }
 }
 
 impl Language {
@@ -106,12 +149,44 @@ impl Language {
         }
     }
 
+    /// Like `from_file_name`, but also recognizing the extensions in `extra`,
+    /// which maps them to the name of a language as given to `-x`. These may
+    /// contain dots, such as `cpp.in`.
+    pub fn from_file_name_with(file: &Path, extra: &BTreeMap<String, String>) -> Option<Self> {
+        if let Some(language) = Language::from_file_name(file) {
+            return Some(language)
+        }
+        let name = file.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let (extension, language) = extra.iter()
+            .find(|&(extension, _)| name.len() > extension.len() && name.ends_with(extension.as_str())
+                  && name[..name.len() - extension.len()].ends_with('.'))?;
+        let parsed = Language::from_name(language);
+        if parsed.is_none() {
+            warn!("Unknown language `{}` configured for source extension `{}`", language, extension);
+        }
+        parsed
+    }
+
+    /// Parse the name of a language as given to `-x`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "c" => Some(Language::C),
+            "c++" => Some(Language::Cxx),
+            "objective-c" => Some(Language::ObjectiveC),
+            "objective-c++" => Some(Language::ObjectiveCxx),
+            // nvcc calls it `cu`, clang `cuda`.
+            "cu" | "cuda" => Some(Language::Cuda),
+            _ => None,
+        }
+    }
+
     pub fn as_str(&self) -> &'static str {
         match *self {
             Language::C => "c",
             Language::Cxx => "c++",
             Language::ObjectiveC => "objc",
             Language::ObjectiveCxx => "objc++",
+            Language::Cuda => "cuda",
         }
     }
 }
@@ -135,6 +210,8 @@ pub enum CCompilerKind {
     Clang,
     /// Microsoft Visual C++
     MSVC,
+    /// NVIDIA CUDA compiler
+    NVCC,
 }
 
 /// An interface to a specific C compiler.
@@ -163,6 +240,14 @@ pub trait CCompilerImpl: Clone + fmt::Debug + Send + 'static {
                                 cwd: &Path,
                                 env_vars: &[(OsString, OsString)])
                                 -> Result<(CompileCommand, Option<dist::CompileCommand>, Cacheable)>;
+    /// The compiler that this compiler driver runs for the host code, if
+    /// any, which is part of the hash key since it's not in the arguments.
+    fn host_compiler(&self,
+                     _parsed_args: &ParsedArguments,
+                     _cwd: &Path,
+                     _env_vars: &[(OsString, OsString)]) -> Option<PathBuf> {
+        None
+    }
 }
 
 impl <I> CCompiler<I>
@@ -182,6 +267,7 @@ impl <I> CCompiler<I>
 
 impl<T: CommandCreatorSync, I: CCompilerImpl> Compiler<T> for CCompiler<I> {
     fn kind(&self) -> CompilerKind { CompilerKind::C(self.compiler.kind()) }
+    fn digest(&self) -> String { self.executable_digest.clone() }
     fn parse_arguments(&self,
                        arguments: &[OsString],
                        cwd: &Path) -> CompilerArguments<Box<CompilerHasher<T> + 'static>> {
@@ -195,10 +281,15 @@ impl<T: CommandCreatorSync, I: CCompilerImpl> Compiler<T> for CCompiler<I> {
                 }))
             }
             CompilerArguments::CannotCache(why) => CompilerArguments::CannotCache(why),
+            CompilerArguments::UnknownFlag(flag) => CompilerArguments::UnknownFlag(flag),
             CompilerArguments::NotCompilation => CompilerArguments::NotCompilation,
         }
     }
 
+    fn get_toolchain_packager(&self) -> Box<CompilerPackager> {
+        Box::new(CCompilerPackager { executable: self.executable.clone() })
+    }
+
     fn box_clone(&self) -> Box<Compiler<T>> {
         Box::new((*self).clone())
     }
@@ -213,11 +304,55 @@ impl<T, I> CompilerHasher<T> for CCompilerHasher<I>
                          cwd: PathBuf,
                          env_vars: Vec<(OsString, OsString)>,
                          may_dist: bool,
-                         _pool: &CpuPool)
+                         pool: &CpuPool)
                          -> SFuture<HashResult>
     {
         let me = *self;
         let CCompilerHasher { parsed_args, executable, executable_digest, compiler } = me;
+        // A build system that supplies its own key doesn't need the preprocessor
+        // output for hashing, only for distributed compilation.
+        if let (Some(key), false) = (ftry!(external_cache_key(&env_vars)), may_dist) {
+            let weak_toolchain_key = format!("{}-{}", executable.to_string_lossy(), executable_digest);
+            let toolchain_creator = Box::new(CCompilerPackager { executable: executable.clone() });
+            let source = parsed_args.input.clone();
+            let args_digest = args_digest(&parsed_args);
+            return f_ok(HashResult {
+                key: key,
+                compilation: Box::new(CCompilation {
+                    parsed_args: parsed_args,
+                    preprocessed_input: vec![],
+                    executable: executable.clone(),
+                    compiler: compiler,
+                    cwd,
+                    env_vars,
+                }),
+                weak_toolchain_key,
+                toolchain_creator,
+                executable,
+                compiler_digest: executable_digest,
+                source,
+                args_digest,
+            })
+        }
+        let extra_paths = extra_hash_paths(&parsed_args.extra_hash_files, &cwd);
+        let tool_hashes = future::join_all(hashed_tool_paths(&env_vars, &cwd).into_iter()
+                                           .map(|tool| hash_tool(tool, pool))
+                                           .collect::<Vec<_>>());
+        let host_hash: SFuture<Option<String>> = match compiler.host_compiler(&parsed_args, &cwd, &env_vars) {
+            Some(host) => {
+                let name = fs::canonicalize(&host).unwrap_or_else(|_| host.clone())
+                    .file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                Box::new(hash_tool(host, pool).map(move |digest| Some(format!("host:{}:{}", name, digest))))
+            }
+            None => f_ok(None),
+        };
+        let extra_hashes = hash_all(&extra_paths, pool).join3(tool_hashes, host_hash).map(|(mut hashes, tool_hashes, host_hash)| {
+            hashes.extend(tool_hashes);
+            hashes.extend(host_hash);
+            hashes
+        });
+        let input_link = input_link(&cwd.join(&parsed_args.input), &env_vars);
+        let key_cwd = CONFIG.cwd_in_key.key_cwd(&cwd);
         let result = compiler.preprocess(creator, &executable, &parsed_args, &cwd, &env_vars, may_dist);
         let out_pretty = parsed_args.output_pretty().into_owned();
         let env_vars = env_vars.to_vec();
@@ -241,40 +376,67 @@ impl<T, I> CompilerHasher<T> for CCompilerHasher<I>
                 }
                 e @ _ => Err(e),
             }
-        }).and_then(move |preprocessor_result| {
+        }).join(extra_hashes).and_then(move |(preprocessor_result, extra_hashes)| {
             trace!("[{}]: Preprocessor output is {} bytes",
                    parsed_args.output_pretty(),
                    preprocessor_result.stdout.len());
 
+            let mut extra_hashes = extra_hashes;
+            if let Some(target) = input_link {
+                debug!("[{}]: input is a symlink to {:?}", parsed_args.output_pretty(), target);
+                extra_hashes.push(format!("symlink:{}", target.to_string_lossy()));
+            }
+            if let Some(cwd) = key_cwd {
+                extra_hashes.push(format!("cwd:{}", cwd));
+            }
+            // Entries for compilations writing their object file to stdout or
+            // nowhere have no object file to restore, so they must not be
+            // shared with those writing it to a file.
+            match parsed_args.output_target() {
+                OutputTarget::File => {}
+                target => extra_hashes.push(format!("output:{:?}", target)),
+            }
             let key = {
                 hash_key(&executable_digest,
                          parsed_args.language,
                          &parsed_args.common_args,
                          &env_vars,
-                         &preprocessor_result.stdout)
+                         &preprocessor_result.stdout,
+                         &extra_hashes)
             };
             // A compiler binary may be a symlink to another and so has the same digest, but that means
             // the toolchain will not contain the correct path to invoke the compiler! Add the compiler
             // executable path to try and prevent this
             let weak_toolchain_key = format!("{}-{}", executable.to_string_lossy(), executable_digest);
             let toolchain_creator = Box::new(CCompilerPackager { executable: executable.clone() });
+            let source = parsed_args.input.clone();
+            let args_digest = args_digest(&parsed_args);
             Ok(HashResult {
                 key: key,
                 compilation: Box::new(CCompilation {
                     parsed_args: parsed_args,
                     preprocessed_input: preprocessor_result.stdout,
-                    executable: executable,
+                    executable: executable.clone(),
                     compiler: compiler,
                     cwd,
                     env_vars,
                 }),
                 weak_toolchain_key,
                 toolchain_creator,
+                executable,
+                compiler_digest: executable_digest,
+                source,
+                args_digest,
             })
         }))
     }
 
     fn color_mode(&self) -> ColorMode {
+        // An object file written to stdout must reach the client unaltered,
+        // rather than have what look like escape codes stripped from it.
+        if self.parsed_args.output_target() == OutputTarget::Stdout {
+            return ColorMode::On
+        }
         //TODO: actually implement this for C compilers
         ColorMode::Auto
     }
@@ -313,7 +475,8 @@ impl<I: CCompilerImpl> Compilation for CCompilation<I> {
             let mut file_header = tar::Header::new_ustar();
             // TODO: test this works
             if let Ok(metadata) = metadata_res {
-                // TODO: if the source file is a symlink, I think this does bad things
+                // `fs::metadata` follows symlinks, so a symlinked input is sent as the
+                // regular file it resolves to and needn't resolve on the build server.
                 file_header.set_metadata(&metadata);
             } else {
                 warn!("Couldn't get metadata of input file, falling back to some defaults");
@@ -337,7 +500,12 @@ impl<I: CCompilerImpl> Compilation for CCompilation<I> {
 
     fn outputs<'a>(&'a self) -> Box<Iterator<Item=(&'a str, &'a Path)> + 'a>
     {
-        Box::new(self.parsed_args.outputs.iter().map(|(k, v)| (*k, &**v)))
+        // An object file written to stdout arrives with the compiler's
+        // output, and one written to /dev/null isn't there to cache.
+        let skip_obj = self.parsed_args.output_target() != OutputTarget::File;
+        Box::new(self.parsed_args.outputs.iter()
+                 .filter(move |&(k, _)| !(skip_obj && *k == "obj"))
+                 .map(|(k, v)| (*k, &**v)))
     }
 }
 
@@ -348,24 +516,23 @@ struct CCompilerPackager {
 impl CompilerPackager for CCompilerPackager {
     #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
     fn write_pkg(self: Box<Self>, f: File) -> io::Result<()> {
-        use std::env;
         use std::os::unix::ffi::OsStrExt;
 
         info!("Packaging C compiler");
         // TODO: write our own, since this is GPL
-        let curdir = env::current_dir().unwrap();
-        env::set_current_dir("/tmp").unwrap();
-        let output = process::Command::new("icecc-create-env").arg(&self.executable).output().unwrap();
+        let tmpdir = Path::new("/tmp");
+        let output = process::Command::new("icecc-create-env").arg(&self.executable).current_dir(tmpdir).output()?;
         if !output.status.success() {
-            println!("{:?}\n\n\n===========\n\n\n{:?}", output.stdout, output.stderr);
-            panic!("failed to create toolchain")
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      format!("icecc-create-env failed to create toolchain: {}",
+                                              String::from_utf8_lossy(&output.stderr).trim())))
         }
-        let file_line = output.stdout.split(|&b| b == b'\n').find(|line| line.starts_with(b"creating ")).unwrap();
-        let filename = &file_line[b"creating ".len()..];
-        let filename = OsStr::from_bytes(filename);
-        io::copy(&mut File::open(filename).unwrap(), &mut {f}).unwrap();
-        fs::remove_file(filename).unwrap();
-        env::set_current_dir(curdir).unwrap();
+        let filename = match output.stdout.split(|&b| b == b'\n').find(|line| line.starts_with(b"creating ")) {
+            Some(file_line) => tmpdir.join(OsStr::from_bytes(&file_line[b"creating ".len()..])),
+            None => return Err(io::Error::new(io::ErrorKind::Other, "icecc-create-env didn't report the toolchain it created")),
+        };
+        io::copy(&mut File::open(&filename)?, &mut {f})?;
+        fs::remove_file(&filename)?;
         Ok(())
     }
 
@@ -383,7 +550,139 @@ lazy_static! {
     static ref CACHED_ENV_VARS: HashSet<&'static OsStr> = [
         "MACOSX_DEPLOYMENT_TARGET",
         "IPHONEOS_DEPLOYMENT_TARGET",
+        // Sets the value of `__DATE__` and `__TIME__` in gcc 7+.
+        "SOURCE_DATE_EPOCH",
+        // Flags nvcc adds to its command line.
+        "NVCC_APPEND_FLAGS",
+        "NVCC_PREPEND_FLAGS",
     ].iter().map(OsStr::new).collect();
+
+    /// Unrecognized flags already logged under `UnknownArgsPolicy::WarnOnce`.
+    static ref WARNED_UNKNOWN_FLAGS: Mutex<HashSet<OsString>> = Mutex::new(HashSet::new());
+
+    /// The digests of the tools in `SCCACHE_HASH_TOOLS`, with the mtime and
+    /// size they had when hashed, so that each is only hashed again once it
+    /// changes.
+    static ref TOOL_DIGESTS: Mutex<HashMap<PathBuf, (SystemTime, u64, String)>> = Mutex::new(HashMap::new());
+}
+
+/// Whether a compilation passing `flag`, which the argument parser doesn't
+/// recognize, can be cached under `policy`.
+pub fn unknown_flag_is_cacheable(flag: &OsStr, policy: UnknownArgsPolicy) -> bool {
+    match policy {
+        UnknownArgsPolicy::Hash |
+        UnknownArgsPolicy::Ignore => true,
+        UnknownArgsPolicy::Uncacheable => false,
+        UnknownArgsPolicy::WarnOnce => {
+            if WARNED_UNKNOWN_FLAGS.lock().unwrap().insert(flag.to_owned()) {
+                warn!("Not caching compilations passing unrecognized flag {:?}", flag);
+            }
+            false
+        }
+    }
+}
+
+/// A digest of the arguments of `parsed_args` that are part of the hash key.
+fn args_digest(parsed_args: &ParsedArguments) -> String {
+    let mut m = Digest::new();
+    m.update(parsed_args.language.as_str().as_bytes());
+    for arg in &parsed_args.common_args {
+        arg.hash(&mut HashToDigest { digest: &mut m });
+    }
+    m.finish()
+}
+
+/// Expand `files`, relative to `cwd`, into the list of files to hash.
+///
+/// Directories are replaced by the files they contain, in sorted order. Paths
+/// that don't exist are kept, so that their later creation changes the hash.
+fn extra_hash_paths(files: &[PathBuf], cwd: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![];
+    for f in files {
+        let path = cwd.join(f);
+        if path.is_dir() {
+            let mut entries = fs::read_dir(&path).map(|entries| {
+                entries.filter_map(|e| e.ok())
+                    .map(|e| e.path())
+                    .filter(|p| p.is_file())
+                    .collect::<Vec<_>>()
+            }).unwrap_or_default();
+            entries.sort();
+            paths.extend(entries);
+        } else {
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+/// A comma-separated list of auxiliary tools, such as `as,ld`, whose binaries are made
+/// part of the cache key, for platforms where object files depend on them.
+pub const HASH_TOOLS_VAR: &str = "SCCACHE_HASH_TOOLS";
+
+/// Find the tools listed in `SCCACHE_HASH_TOOLS` on the compile command's `PATH`.
+/// A tool that can't be found is left as its name, which `hash_all` hashes as missing.
+fn hashed_tool_paths(env_vars: &[(OsString, OsString)], cwd: &Path) -> Vec<PathBuf> {
+    let tools = match env_vars.iter().find(|&&(ref k, _)| k == HASH_TOOLS_VAR) {
+        Some(&(_, ref tools)) => tools.to_string_lossy().into_owned(),
+        None => return vec![],
+    };
+    let path = env_vars.iter()
+        .find(|&&(ref k, _)| k == "PATH")
+        .map(|&(_, ref v)| v.clone());
+    tools.split(',')
+        .map(|t| t.trim())
+        .filter(|t| !t.is_empty())
+        .map(|t| which_in(t, path.as_ref(), cwd).unwrap_or_else(|_| PathBuf::from(t)))
+        .collect()
+}
+
+/// Setting this to `0` or `false` makes the target of a symlinked input file part of the
+/// cache key, rather than only the contents it resolves to.
+pub const FOLLOW_SYMLINKS_VAR: &str = "SCCACHE_FOLLOW_SYMLINKS";
+
+/// Return the target of `input` if it's a symlink that should be hashed as a link.
+fn input_link(input: &Path, env_vars: &[(OsString, OsString)]) -> Option<PathBuf> {
+    let follow = env_vars.iter()
+        .find(|&&(ref k, _)| k == FOLLOW_SYMLINKS_VAR)
+        .map_or(true, |&(_, ref v)| v != "0" && v != "false");
+    if follow {
+        None
+    } else {
+        fs::read_link(input).ok()
+    }
+}
+
+/// Calculate the digests of `paths`. A missing file hashes as its path.
+fn hash_all(paths: &[PathBuf], pool: &CpuPool) -> SFuture<Vec<String>> {
+    Box::new(future::join_all(paths.iter().map(|p| {
+        if p.is_file() {
+            Digest::file(p.clone(), pool)
+        } else {
+            let mut m = Digest::new();
+            m.update(b"missing:");
+            m.update(p.to_string_lossy().as_bytes());
+            f_ok(m.finish())
+        }
+    }).collect::<Vec<_>>()))
+}
+
+/// Calculate the digest of the tool at `path`, as `hash_all` does, reusing
+/// the last one calculated while its mtime and size are the same.
+fn hash_tool(path: PathBuf, pool: &CpuPool) -> SFuture<String> {
+    let (mtime, size) = match fs::metadata(&path).and_then(|m| Ok((m.modified()?, m.len(), m.is_file()))) {
+        Ok((mtime, size, true)) => (mtime, size),
+        _ => return Box::new(hash_all(&[path], pool).map(|mut hashes| hashes.remove(0))),
+    };
+    if let Some(&(cached_mtime, cached_size, ref digest)) = TOOL_DIGESTS.lock().unwrap().get(&path) {
+        if (cached_mtime, cached_size) == (mtime, size) {
+            return f_ok(digest.clone())
+        }
+    }
+    Box::new(Digest::file(path.clone(), pool).map(move |digest| {
+        TOOL_DIGESTS.lock().unwrap().insert(path, (mtime, size, digest.clone()));
+        digest
+    }))
 }
 
 /// Compute the hash key of `compiler` compiling `preprocessor_output` with `args`.
@@ -391,7 +690,8 @@ pub fn hash_key(compiler_digest: &str,
                 language: Language,
                 arguments: &[OsString],
                 env_vars: &[(OsString, OsString)],
-                preprocessor_output: &[u8]) -> String
+                preprocessor_output: &[u8],
+                extra_hashes: &[String]) -> String
 {
     // If you change any of the inputs to the hash, you should change `CACHE_VERSION`.
     let mut m = Digest::new();
@@ -409,6 +709,9 @@ pub fn hash_key(compiler_digest: &str,
         }
     }
     m.update(preprocessor_output);
+    for h in extra_hashes {
+        m.update(h.as_bytes());
+    }
     m.finish()
 }
 
@@ -420,8 +723,8 @@ mod test {
     fn test_hash_key_executable_contents_differs() {
         let args = ovec!["a", "b", "c"];
         const PREPROCESSED : &'static [u8] = b"hello world";
-        assert_neq!(hash_key("abcd", Language::C, &args, &[], &PREPROCESSED),
-                    hash_key("wxyz", Language::C, &args, &[], &PREPROCESSED));
+        assert_neq!(hash_key("abcd", Language::C, &args, &[], &PREPROCESSED, &[]),
+                    hash_key("wxyz", Language::C, &args, &[], &PREPROCESSED, &[]));
     }
 
     #[test]
@@ -432,21 +735,21 @@ mod test {
         let ab = ovec!["a", "b"];
         let a = ovec!["a"];
         const PREPROCESSED: &'static [u8] = b"hello world";
-        assert_neq!(hash_key(digest, Language::C, &abc, &[], &PREPROCESSED),
-                    hash_key(digest, Language::C, &xyz, &[], &PREPROCESSED));
+        assert_neq!(hash_key(digest, Language::C, &abc, &[], &PREPROCESSED, &[]),
+                    hash_key(digest, Language::C, &xyz, &[], &PREPROCESSED, &[]));
 
-        assert_neq!(hash_key(digest, Language::C, &abc, &[], &PREPROCESSED),
-                    hash_key(digest, Language::C, &ab, &[], &PREPROCESSED));
+        assert_neq!(hash_key(digest, Language::C, &abc, &[], &PREPROCESSED, &[]),
+                    hash_key(digest, Language::C, &ab, &[], &PREPROCESSED, &[]));
 
-        assert_neq!(hash_key(digest, Language::C, &abc, &[], &PREPROCESSED),
-                    hash_key(digest, Language::C, &a, &[], &PREPROCESSED));
+        assert_neq!(hash_key(digest, Language::C, &abc, &[], &PREPROCESSED, &[]),
+                    hash_key(digest, Language::C, &a, &[], &PREPROCESSED, &[]));
     }
 
     #[test]
     fn test_hash_key_preprocessed_content_differs() {
         let args = ovec!["a", "b", "c"];
-        assert_neq!(hash_key("abcd", Language::C, &args, &[], &b"hello world"[..]),
-                    hash_key("abcd", Language::C, &args, &[], &b"goodbye"[..]));
+        assert_neq!(hash_key("abcd", Language::C, &args, &[], &b"hello world"[..], &[]),
+                    hash_key("abcd", Language::C, &args, &[], &b"goodbye"[..], &[]));
     }
 
     #[test]
@@ -455,13 +758,105 @@ mod test {
         let digest = "abcd";
         const PREPROCESSED: &'static [u8] = b"hello world";
         for var in CACHED_ENV_VARS.iter() {
-            let h1 = hash_key(digest, Language::C, &args, &[], &PREPROCESSED);
+            let h1 = hash_key(digest, Language::C, &args, &[], &PREPROCESSED, &[]);
             let vars = vec![(OsString::from(var), OsString::from("something"))];
-            let h2 = hash_key(digest, Language::C, &args, &vars, &PREPROCESSED);
+            let h2 = hash_key(digest, Language::C, &args, &vars, &PREPROCESSED, &[]);
             let vars = vec![(OsString::from(var), OsString::from("something else"))];
-            let h3 = hash_key(digest, Language::C, &args, &vars, &PREPROCESSED);
+            let h3 = hash_key(digest, Language::C, &args, &vars, &PREPROCESSED, &[]);
             assert_neq!(h1, h2);
             assert_neq!(h2, h3);
         }
     }
+
+    #[test]
+    fn test_hash_key_source_date_epoch() {
+        let args = ovec!["a", "b", "c"];
+        let digest = "abcd";
+        const PREPROCESSED: &'static [u8] = b"hello world";
+        let h1 = hash_key(digest, Language::C, &args, &[], &PREPROCESSED, &[]);
+        let vars = vec![(OsString::from("SOURCE_DATE_EPOCH"), OsString::from("1500000000"))];
+        let h2 = hash_key(digest, Language::C, &args, &vars, &PREPROCESSED, &[]);
+        let vars = vec![(OsString::from("SOURCE_DATE_EPOCH"), OsString::from("1600000000"))];
+        let h3 = hash_key(digest, Language::C, &args, &vars, &PREPROCESSED, &[]);
+        assert_neq!(h1, h2);
+        assert_neq!(h2, h3);
+        // Variables that aren't cached don't affect the key.
+        let vars = vec![(OsString::from("SOME_OTHER_VAR"), OsString::from("1600000000"))];
+        assert_eq!(h1, hash_key(digest, Language::C, &args, &vars, &PREPROCESSED, &[]));
+    }
+
+    #[test]
+    fn test_hash_key_extra_hashes_differ() {
+        let args = ovec!["a", "b", "c"];
+        let digest = "abcd";
+        const PREPROCESSED: &'static [u8] = b"hello world";
+        assert_neq!(hash_key(digest, Language::C, &args, &[], &PREPROCESSED, &[]),
+                    hash_key(digest, Language::C, &args, &[], &PREPROCESSED, &["1234".to_owned()]));
+        assert_neq!(hash_key(digest, Language::C, &args, &[], &PREPROCESSED, &["1234".to_owned()]),
+                    hash_key(digest, Language::C, &args, &[], &PREPROCESSED, &["5678".to_owned()]));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_input_link() {
+        use std::os::unix::fs::symlink;
+        use tempdir::TempDir;
+
+        let td = TempDir::new("sccache").unwrap();
+        let target = td.path().join("real.c");
+        File::create(&target).unwrap();
+        let link = td.path().join("link.c");
+        symlink(&target, &link).unwrap();
+        let no_follow = vec![(OsString::from(FOLLOW_SYMLINKS_VAR), OsString::from("0"))];
+        // By default symlinks are followed and only their contents are hashed.
+        assert_eq!(input_link(&link, &[]), None);
+        assert_eq!(input_link(&link, &no_follow), Some(target.clone()));
+        assert_eq!(input_link(&target, &no_follow), None);
+    }
+
+    #[test]
+    fn test_hashed_tool_paths() {
+        use tempdir::TempDir;
+
+        let td = TempDir::new("sccache").unwrap();
+        let tool = td.path().join("as");
+        File::create(&tool).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let env_vars = vec![(OsString::from(HASH_TOOLS_VAR), OsString::from("as, not-a-real-tool,")),
+                            (OsString::from("PATH"), td.path().as_os_str().to_owned())];
+        assert_eq!(hashed_tool_paths(&[], td.path()), Vec::<PathBuf>::new());
+        let paths = hashed_tool_paths(&env_vars, td.path());
+        assert_eq!(paths.len(), 2);
+        assert_eq!(paths[0], tool);
+        assert_eq!(paths[1], PathBuf::from("not-a-real-tool"));
+    }
+
+    #[test]
+    fn test_hash_tool_reuses_digest() {
+        use filetime::{FileTime, set_file_times};
+        use std::io::Write;
+        use tempdir::TempDir;
+
+        let td = TempDir::new("sccache").unwrap();
+        let pool = CpuPool::new(1);
+        let tool = td.path().join("as");
+        File::create(&tool).unwrap().write_all(b"one").unwrap();
+        let mtime = FileTime::from_seconds_since_1970(1_000_000_000, 0);
+        set_file_times(&tool, mtime, mtime).unwrap();
+        let digest = hash_tool(tool.clone(), &pool).wait().unwrap();
+        assert_eq!(digest, Digest::file(tool.clone(), &pool).wait().unwrap());
+        // Rewritten with the same size and mtime, it isn't hashed again.
+        File::create(&tool).unwrap().write_all(b"two").unwrap();
+        set_file_times(&tool, mtime, mtime).unwrap();
+        assert_eq!(hash_tool(tool.clone(), &pool).wait().unwrap(), digest);
+        // But it is once either changes.
+        File::create(&tool).unwrap().write_all(b"three").unwrap();
+        assert_neq!(hash_tool(tool.clone(), &pool).wait().unwrap(), digest);
+        assert_eq!(hash_tool(td.path().join("missing"), &pool).wait().unwrap(),
+                   hash_all(&[td.path().join("missing")], &pool).wait().unwrap()[0]);
+    }
 }
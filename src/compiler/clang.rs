@@ -21,6 +21,7 @@ use ::compiler::{
     CompileCommand,
     write_temp_file,
 };
+use config::CONFIG;
 use dist;
 use compiler::args::*;
 use compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments};
@@ -54,7 +55,8 @@ impl CCompilerImpl for Clang {
                        arguments: &[OsString],
                        cwd: &Path) -> CompilerArguments<ParsedArguments>
     {
-        gcc::parse_arguments(arguments, cwd, (&gcc::ARGS[..], &ARGS[..]))
+        gcc::parse_arguments(arguments, cwd, (&gcc::ARGS[..], &ARGS[..]), &CONFIG.source_extensions.clang,
+                             CONFIG.unknown_args)
     }
 
     fn preprocess<T>(&self,
@@ -81,14 +83,30 @@ impl CCompilerImpl for Clang {
     }
 }
 
-pub static ARGS: [(ArgInfo, gcc::GCCArgAttribute); 8] = [
+pub static ARGS: [(ArgInfo, gcc::GCCArgAttribute); 22] = [
     take_arg!("--serialize-diagnostics", String, Separated, PassThrough),
     take_arg!("--target", String, Separated, PassThrough),
     // TODO: should be extracted and reprocessed, though bear in mind some
     // flags are not valid under a -Xclang
     take_arg!("-Xclang", String, Separated, TooHard),
+    // Compilations using clang modules read and write a module cache that
+    // isn't part of the hash key or the cache entry, so they can't be cached.
     flag!("-fcxx-modules", TooHard),
+    flag!("-fimplicit-module-maps", TooHard),
+    take_arg!("-fmodule-file=", Path, Concatenated, TooHard),
+    take_arg!("-fmodule-map-file=", Path, Concatenated, TooHard),
     flag!("-fmodules", TooHard),
+    take_arg!("-fmodules-cache-path=", Path, Concatenated, TooHard),
+    flag!("-fmodules-ts", TooHard),
+    take_arg!("-foptimization-record-file=", Path, Concatenated, OptRecordFile),
+    flag!("-fprofile-instr-generate", ProfileGenerate),
+    take_arg!("-fprofile-instr-generate=", Path, Concatenated, ProfileGenerate),
+    flag!("-fprofile-instr-use", ProfileUse),
+    take_arg!("-fprofile-instr-use=", Path, Concatenated, ProfileUse),
+    flag!("-fsave-optimization-record", OptRecord),
+    take_arg!("-fsave-optimization-record=", String, Concatenated, OptRecord),
+    // The backend step of distributed ThinLTO reads the index file.
+    take_arg!("-fthinlto-index=", Path, Concatenated, ExtraHashFile),
     take_arg!("-gcc-toolchain", String, Separated, PassThrough),
     take_arg!("-include-pch", Path, CanBeSeparated, PreprocessorArgument),
     take_arg!("-target", String, Separated, PassThrough),
@@ -97,6 +115,7 @@ pub static ARGS: [(ArgInfo, gcc::GCCArgAttribute); 8] = [
 #[cfg(test)]
 mod test {
     use compiler::*;
+    use compiler::c::hash_key;
     use compiler::gcc;
     use futures::Future;
     use futures_cpupool::CpuPool;
@@ -158,5 +177,91 @@ mod test {
                    _parse_arguments(&stringvec!["-c", "foo.c", "-fcxx-modules", "-o", "foo.o"]));
         assert_eq!(CompilerArguments::CannotCache("-fmodules"),
                    _parse_arguments(&stringvec!["-c", "foo.c", "-fmodules", "-o", "foo.o"]));
+        assert_eq!(CompilerArguments::CannotCache("-fmodules"),
+                   _parse_arguments(&stringvec!["-c", "foo.c", "-fmodules", "-fmodules-cache-path=mcache", "-o", "foo.o"]));
+        assert_eq!(CompilerArguments::CannotCache("-fmodules-cache-path="),
+                   _parse_arguments(&stringvec!["-c", "foo.c", "-fmodules-cache-path=mcache", "-o", "foo.o"]));
+        assert_eq!(CompilerArguments::CannotCache("-fmodules-ts"),
+                   _parse_arguments(&stringvec!["-c", "foo.cpp", "-fmodules-ts", "-o", "foo.o"]));
+        assert_eq!(CompilerArguments::CannotCache("-fimplicit-module-maps"),
+                   _parse_arguments(&stringvec!["-c", "foo.c", "-fimplicit-module-maps", "-o", "foo.o"]));
+        assert_eq!(CompilerArguments::CannotCache("-fmodule-file="),
+                   _parse_arguments(&stringvec!["-c", "foo.c", "-fmodule-file=foo.pcm", "-o", "foo.o"]));
+    }
+
+    #[test]
+    fn test_parse_arguments_clang_module_map() {
+        let f = TestFixture::new();
+        let map = create_file(f.tempdir.path(), "module.modulemap", |mut f| {
+            f.write_all(b"module foo {\n  header \"foo.h\"\n  export *\n}\n")
+        }).unwrap();
+        let flag = format!("-fmodule-map-file={}", map.display());
+        assert_eq!(CompilerArguments::CannotCache("-fmodule-map-file="),
+                   _parse_arguments(&stringvec!["-c", "foo.c", flag, "-o", "foo.o"]));
+        assert_eq!(CompilerArguments::CannotCache("-fmodules"),
+                   _parse_arguments(&stringvec!["-c", "foo.c", "-fmodules", flag, "-o", "foo.o"]));
+    }
+
+    #[test]
+    fn test_parse_arguments_profile_instr() {
+        let a = parses!("-c", "foo.c", "-fprofile-instr-generate", "-o", "foo.o");
+        assert!(a.profile_generate);
+        assert!(a.extra_hash_files.is_empty());
+        assert_eq!(ovec!["-fprofile-instr-generate"], a.common_args);
+
+        let a = parses!("-c", "foo.c", "-fprofile-instr-use=foo.profdata", "-o", "foo.o");
+        assert!(!a.profile_generate);
+        assert_eq!(vec![PathBuf::from("foo.profdata")], a.extra_hash_files);
+        assert_eq!(ovec!["-fprofile-instr-use=foo.profdata"], a.common_args);
+    }
+
+    #[test]
+    fn test_parse_arguments_opt_record() {
+        let a = parses!("-c", "foo.c", "-fsave-optimization-record", "-o", "foo.o");
+        assert_map_contains!(a.outputs, ("obj", PathBuf::from("foo.o")),
+                             ("opt-record", PathBuf::from("foo.opt.yaml")));
+        assert_eq!(2, a.outputs.len());
+        assert_eq!(ovec!["-fsave-optimization-record"], a.common_args);
+
+        let a = parses!("-c", "foo.c", "-fsave-optimization-record=bitstream", "-o", "foo.o");
+        assert_map_contains!(a.outputs, ("opt-record", PathBuf::from("foo.opt.bitstream")));
+
+        let a = parses!("-c", "foo.c", "-foptimization-record-file=records/foo.yaml", "-o", "foo.o");
+        assert_map_contains!(a.outputs, ("opt-record", PathBuf::from("records/foo.yaml")));
+        assert_eq!(2, a.outputs.len());
+    }
+
+    #[test]
+    fn test_parse_arguments_lto() {
+        for flag in &["-flto", "-flto=thin", "-flto=full", "-fno-lto"] {
+            let a = parses!("-c", "foo.c", flag, "-o", "foo.o");
+            assert_eq!(ovec![*flag], a.common_args);
+            assert!(a.extra_hash_files.is_empty());
+            assert_eq!(1, a.outputs.len());
+        }
+
+        let a = parses!("-c", "foo.c", "-fthinlto-index=foo.o.thinlto.bc", "-o", "foo.o");
+        assert_eq!(vec![PathBuf::from("foo.o.thinlto.bc")], a.extra_hash_files);
+        assert_eq!(ovec!["-fthinlto-index=foo.o.thinlto.bc"], a.common_args);
+    }
+
+    #[test]
+    fn test_hash_key_lto() {
+        let key = |flags: &[&str]| {
+            let mut args = stringvec!["-c"];
+            args.extend(flags.iter().map(|s| s.to_string()));
+            args.push("foo.c".to_owned());
+            let a = match _parse_arguments(&args) {
+                CompilerArguments::Ok(a) => a,
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            };
+            hash_key("abcd", Language::C, &a.common_args, &[], &b"int x;"[..], &[])
+        };
+        let keys = vec![key(&[]), key(&["-flto"]), key(&["-flto=thin"]), key(&["-flto=full"])];
+        for (i, a) in keys.iter().enumerate() {
+            for b in &keys[i + 1..] {
+                assert_neq!(a, b);
+            }
+        }
     }
 }
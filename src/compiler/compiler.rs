@@ -14,16 +14,22 @@
 
 use cache::{
     Cache,
+    CacheRead,
     CacheWrite,
+    Provenance,
     Storage,
 };
 use compiler::msvc;
 use compiler::c::{CCompiler, CCompilerKind};
 use compiler::clang::Clang;
 use compiler::gcc::GCC;
+use compiler::miss_log;
+use compiler::shell;
 use compiler::msvc::MSVC;
+use compiler::nvcc::NVCC;
 use compiler::pkg::CompilerPackager;
 use compiler::rust::Rust;
+use config::{CONFIG, UnknownOutputsPolicy};
 use dist;
 use futures::{Future, IntoFuture};
 use futures_cpupool::CpuPool;
@@ -33,8 +39,9 @@ use mock_command::{
     RunCommand,
     exit_status,
 };
+use regex::Regex;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsString;
 use std::fmt;
 #[cfg(unix)]
@@ -48,10 +55,13 @@ use std::sync::Arc;
 use std::time::{
     Duration,
     Instant,
+    SystemTime,
+    UNIX_EPOCH,
 };
 use tempdir::TempDir;
 use tempfile::NamedTempFile;
-use util::{fmt_duration_as_secs, run_input_output};
+use strip_ansi_escapes;
+use util::{fmt_duration_as_secs, hostname, run_input_output, Digest};
 use tokio_core::reactor::{Handle, Timeout};
 
 use errors::*;
@@ -68,7 +78,7 @@ impl CompileCommand {
     pub fn execute<T>(self, creator: &T) -> SFuture<process::Output>
         where T: CommandCreatorSync
     {
-        let mut cmd = creator.clone().new_command_sync(self.executable);
+        let mut cmd = shell::compiler_command(creator, self.executable);
         cmd.args(&self.arguments)
             .env_clear()
             .envs(self.env_vars)
@@ -86,16 +96,43 @@ pub enum CompilerKind {
     Rust,
 }
 
+impl CompilerKind {
+    /// A short lowercase name for this kind of compiler, for labelling stats.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CompilerKind::C(CCompilerKind::GCC) => "gcc",
+            CompilerKind::C(CCompilerKind::Clang) => "clang",
+            CompilerKind::C(CCompilerKind::MSVC) => "msvc",
+            CompilerKind::C(CCompilerKind::NVCC) => "nvcc",
+            CompilerKind::Rust => "rust",
+        }
+    }
+}
+
 /// An interface to a compiler for argument parsing.
 pub trait Compiler<T>: Send + 'static
     where T: CommandCreatorSync,
 {
     /// Return the kind of compiler.
     fn kind(&self) -> CompilerKind;
+    /// Return a digest identifying the version of the compiler, as recorded
+    /// in the `Provenance` of the cache entries it produces.
+    fn digest(&self) -> String;
     /// Determine whether `arguments` are supported by this compiler.
     fn parse_arguments(&self,
                        arguments: &[OsString],
                        cwd: &Path) -> CompilerArguments<Box<CompilerHasher<T> + 'static>>;
+    /// Return a packager for this compiler's toolchain, for distributed compilation.
+    fn get_toolchain_packager(&self) -> Box<CompilerPackager>;
+    /// If `arguments` only query information about the compiler, such as
+    /// `rustc --print cfg`, return a key that identifies the query's output,
+    /// which can then be reused for as long as the compiler doesn't change.
+    fn query_key(&self,
+                 _arguments: &[OsString],
+                 _cwd: &Path,
+                 _env_vars: &[(OsString, OsString)]) -> Option<String> {
+        None
+    }
     fn box_clone(&self) -> Box<Compiler<T>>;
 }
 
@@ -134,23 +171,67 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
                              cache_control: CacheControl,
                              pool: CpuPool,
                              handle: Handle)
-                             -> SFuture<(CompileResult, process::Output)>
+                             -> SFuture<(CompileResult, DistStats, process::Output)>
     {
         let out_pretty = self.output_pretty().into_owned();
         debug!("[{}]: get_cached_or_compile: {:?}", out_pretty, arguments);
         let start = Instant::now();
-        let result = self.generate_hash_key(&creator, cwd.clone(), env_vars, dist_client.may_dist(), &pool);
+        let external_key = ftry!(external_cache_key(&env_vars));
+        let label = cache_label(&env_vars);
+        let shell_env = shell::env_digest(&creator, &env_vars);
+        let result = self.generate_hash_key(&creator, cwd.clone(), env_vars, dist_client.may_dist(), &pool)
+            .join(shell_env);
         Box::new(result.then(move |res| -> SFuture<_> {
             debug!("[{}]: generate_hash_key took {}", out_pretty, fmt_duration_as_secs(&start.elapsed()));
-            let (key, compilation, weak_toolchain_key, toolchain_creator) = match res {
+            let (key, compilation, weak_toolchain_key, toolchain_creator, provenance, logged, local_only) = match res {
                 Err(Error(ErrorKind::ProcessError(output), _)) => {
-                    return f_ok((CompileResult::Error, output));
+                    return f_ok((CompileResult::Error, DistStats::default(), output));
                 }
                 Err(e) => return f_err(e),
-                Ok(HashResult { key, compilation, weak_toolchain_key, toolchain_creator }) =>
-                    (key, compilation, weak_toolchain_key, toolchain_creator),
+                Ok((HashResult { key, compilation, weak_toolchain_key, toolchain_creator, executable, compiler_digest, source, args_digest }, shell_env)) => {
+                    // Compilers run through the compiler shell also depend on
+                    // the environment it sets up.
+                    let key = match shell_env {
+                        Some(shell_env) => {
+                            let mut m = Digest::new();
+                            m.update(key.as_bytes());
+                            m.update(shell_env.as_bytes());
+                            m.finish()
+                        }
+                        None => key,
+                    };
+                    let source = cwd.join(source);
+                    let local_only = dist_client.may_dist() && dist::is_local_only(&source);
+                    let logged = if miss_log::enabled() {
+                        Some(miss_log::Compile {
+                            compiler: executable.clone(),
+                            compiler_digest: compiler_digest.clone(),
+                            args_digest,
+                            source,
+                        })
+                    } else {
+                        None
+                    };
+                    let provenance = Provenance {
+                        hostname: hostname(),
+                        sccache_version: env!("CARGO_PKG_VERSION").to_owned(),
+                        compiler: executable,
+                        compiler_digest,
+                        timestamp: 0,
+                        label,
+                    };
+                    (key, compilation, weak_toolchain_key, toolchain_creator, provenance, logged, local_only)
+                }
+            };
+            let key = match external_key {
+                Some(external_key) => {
+                    debug!("[{}]: Using external cache key {}", out_pretty, external_key);
+                    external_storage_key(&external_key, &provenance.compiler_digest)
+                }
+                None => key,
             };
             trace!("[{}]: Hash key: {}", out_pretty, key);
+            let logged = logged.map(|logged| (logged, key.clone()));
             // If `ForceRecache` is enabled, we won't check the cache.
             let start = Instant::now();
             let cache_status = if cache_control == CacheControl::ForceRecache {
@@ -160,21 +241,25 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
             };
 
             // Set a maximum time limit for the cache to respond before we forge
-            // ahead ourselves with a compilation.
-            let timeout = Duration::new(60, 0);
-            let timeout = Timeout::new(timeout, &handle).into_future().flatten();
-
+            // ahead ourselves with a compilation. This covers waiting for a
+            // turn at the storage too, which the storage's own timeout doesn't.
             let cache_status = cache_status.map(Some);
-            let timeout = timeout.map(|_| None).chain_err(|| "timeout error");
-            let cache_status = cache_status.select(timeout).then(|r| {
-                match r {
-                    Ok((e, _other)) => Ok(e),
-                    Err((e, _other)) => Err(e),
+            let cache_status: SFuture<_> = match CONFIG.server.storage_timeout() {
+                Some(timeout) => {
+                    let timeout = Timeout::new(timeout, &handle).into_future().flatten();
+                    let timeout = timeout.map(|_| None).chain_err(|| "timeout error");
+                    Box::new(cache_status.select(timeout).then(|r| {
+                        match r {
+                            Ok((e, _other)) => Ok(e),
+                            Err((e, _other)) => Err(e),
+                        }
+                    }))
                 }
-            });
+                None => Box::new(cache_status),
+            };
 
             // Check the result of the cache lookup.
-            Box::new(cache_status.then(move |result| {
+            let result = cache_status.then(move |result| {
                 let duration = start.elapsed();
                 let outputs = compilation.outputs()
                     .map(|(key, path)| (key.to_string(), cwd.join(path)))
@@ -182,38 +267,49 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
 
                 let miss_type = match result {
                     Ok(Some(Cache::Hit(mut entry))) => {
-                        debug!("[{}]: Cache hit in {}", out_pretty, fmt_duration_as_secs(&duration));
+                        debug!("[{}]: Cache hit in {} for key {}", out_pretty, fmt_duration_as_secs(&duration), key);
                         let mut stdout = Vec::new();
                         let mut stderr = Vec::new();
                         drop(entry.get_object("stdout", &mut stdout));
                         drop(entry.get_object("stderr", &mut stderr));
-                        let write = pool.spawn_fn(move ||{
-                            for (key, path) in &outputs {
-                                let dir = match path.parent() {
-                                    Some(d) => d,
-                                    None => bail!("Output file without a parent directory!"),
-                                };
-                                // Write the cache entry to a tempfile and then atomically
-                                // move it to its final location so that other rustc invocations
-                                // happening in parallel don't see a partially-written file.
-                                let mut tmp = NamedTempFile::new_in(dir)?;
-                                let mode = entry.get_object(&key, &mut tmp)?;
-                                tmp.persist(path)?;
-                                if let Some(mode) = mode {
-                                    set_file_mode(&path, mode)?;
+                        if cache_control == CacheControl::RecompileOnWarnings && !stderr.is_empty() {
+                            debug!("[{}]: Cache hit has warnings, recompiling", out_pretty);
+                            MissType::Warnings
+                        } else {
+                            let mut outputs = outputs;
+                            add_extra_outputs(&mut outputs, &mut entry);
+                            let write = pool.spawn_fn(move ||{
+                                // Write every output to a tempfile first and then atomically
+                                // move them to their final locations in `restore_order`, so
+                                // that other rustc invocations happening in parallel, and
+                                // build tools waiting for the primary output, never see a
+                                // partially-restored result.
+                                let mut tmps = vec![];
+                                for (key, path) in restore_order(&outputs) {
+                                    let dir = match path.parent() {
+                                        Some(d) => d,
+                                        None => bail!("Output file without a parent directory!"),
+                                    };
+                                    let mut tmp = NamedTempFile::new_in(dir)?;
+                                    let mode = entry.get_object(&key, &mut tmp)?;
+                                    set_file_mode(tmp.path(), restored_file_mode(mode, umask()))?;
+                                    tmps.push((tmp, path));
                                 }
-                            }
-                            Ok(())
-                        });
-                        let output = process::Output {
-                            status: exit_status(0),
-                            stdout: stdout,
-                            stderr: stderr,
-                        };
-                        let result = CompileResult::CacheHit(duration);
-                        return Box::new(write.map(|_| {
-                            (result, output)
-                        })) as SFuture<_>
+                                for (tmp, path) in tmps {
+                                    tmp.persist(path)?;
+                                }
+                                Ok(())
+                            });
+                            let output = process::Output {
+                                status: exit_status(0),
+                                stdout: stdout,
+                                stderr: stderr,
+                            };
+                            let result = CompileResult::CacheHit(duration);
+                            return Box::new(write.map(|_| {
+                                (result, DistStats::default(), output)
+                            })) as SFuture<_>
+                        }
                     }
                     Ok(Some(Cache::Miss)) => {
                         debug!("[{}]: Cache miss in {}", out_pretty, fmt_duration_as_secs(&duration));
@@ -223,7 +319,7 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
                         debug!("[{}]: Cache recache in {}", out_pretty, fmt_duration_as_secs(&duration));
                         MissType::ForcedRecache
                     }
-                    Ok(None) => {
+                    Ok(None) | Err(Error(ErrorKind::StorageTimeout(..), _)) => {
                         debug!("[{}]: Cache timed out {}", out_pretty, fmt_duration_as_secs(&duration));
                         MissType::TimedOut
                     }
@@ -238,25 +334,62 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
 
                 // Cache miss, so compile it.
                 let start = Instant::now();
+                let dist_client = if local_only {
+                    debug!("[{}]: Compiling locally, the source is local only", out_pretty);
+                    Arc::new(dist::NoopClient) as Arc<dist::Client>
+                } else {
+                    dist_client
+                };
+                let unknown_outputs = CONFIG.unknown_outputs;
+                let siblings = match (unknown_outputs, outputs.get("obj")) {
+                    (UnknownOutputsPolicy::Ignore, _) | (_, None) => None,
+                    (_, Some(obj)) => Some(output_siblings(obj, &outputs)),
+                };
                 let compile = dist_or_local_compile(dist_client, creator, cwd, compilation, weak_toolchain_key, toolchain_creator, out_pretty.clone());
 
-                Box::new(compile.and_then(move |(cacheable, compiler_result)| {
+                Box::new(compile.and_then(move |(cacheable, dist_stats, compiler_result)| {
+                    let dist_stats = DistStats { local_only, ..dist_stats };
+                    let mut outputs = outputs;
                     let duration = start.elapsed();
                     if !compiler_result.status.success() {
                         debug!("[{}]: Compiled but failed, not storing in cache",
                                out_pretty);
-                        return f_ok((CompileResult::CompileFailed, compiler_result))
+                        return f_ok((CompileResult::CompileFailed, dist_stats, compiler_result))
                             as SFuture<_>
                     }
                     if cacheable != Cacheable::Yes {
                         // Not cacheable
                         debug!("[{}]: Compiled but not cacheable",
                                out_pretty);
-                        return f_ok((CompileResult::NotCacheable, compiler_result))
+                        return f_ok((CompileResult::NotCacheable, dist_stats, compiler_result))
+                    }
+                    if let Some(siblings) = siblings {
+                        let unknown = match unknown_outputs_of(&outputs, &siblings) {
+                            Some(unknown) => unknown,
+                            None => {
+                                debug!("[{}]: Compiled but may have rewritten {:?}, not caching",
+                                       out_pretty, siblings);
+                                return f_ok((CompileResult::NotCacheable, dist_stats, compiler_result))
+                            }
+                        };
+                        if !unknown.is_empty() {
+                            if unknown_outputs == UnknownOutputsPolicy::Uncacheable {
+                                debug!("[{}]: Compiled but wrote unknown outputs {:?}, not caching",
+                                       out_pretty, unknown);
+                                return f_ok((CompileResult::NotCacheable, dist_stats, compiler_result))
+                            }
+                            debug!("[{}]: Caching unknown outputs {:?}", out_pretty, unknown);
+                            for path in unknown {
+                                if let Some(name) = path.file_name().and_then(|n| n.to_str()).map(str::to_owned) {
+                                    outputs.insert(format!("{}{}", EXTRA_OUTPUT_PREFIX, name), path);
+                                }
+                            }
+                        }
                     }
                     debug!("[{}]: Compiled in {}, storing in cache", out_pretty, fmt_duration_as_secs(&duration));
+                    let compression = storage.compression();
                     let write = pool.spawn_fn(move || -> Result<_> {
-                        let mut entry = CacheWrite::new();
+                        let mut entry = CacheWrite::with_compression(compression)?;
                         for (key, path) in &outputs {
                             let mut f = File::open(&path)?;
                             let mode = get_file_mode(&path)?;
@@ -277,6 +410,9 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
                             let mut stderr = &compiler_result.stderr[..];
                             entry.put_object("stderr", &mut stderr, None)?;
                         }
+                        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+                            .map(|d| d.as_secs()).unwrap_or(0);
+                        entry.put_provenance(&Provenance { timestamp, ..provenance })?;
 
                         // Try to finish storing the newly-written cache
                         // entry. We'll get the result back elsewhere.
@@ -292,11 +428,17 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
                                 })
                             });
                         let future = Box::new(future);
-                        Ok((CompileResult::CacheMiss(miss_type, duration, future), compiler_result))
+                        Ok((CompileResult::CacheMiss(miss_type, duration, future), dist_stats, compiler_result))
                     }).chain_err(move || {
                         format!("failed to store `{}` to cache", o)
                     }))
                 }))
+            });
+            Box::new(result.map(move |(result, dist_stats, output)| {
+                if let Some((logged, key)) = logged {
+                    miss_log::record(&logged, &key, &result);
+                }
+                (result, dist_stats, output)
             }))
         }))
     }
@@ -318,14 +460,14 @@ fn dist_or_local_compile<T>(_dist_client: Arc<dist::Client>,
                             _weak_toolchain_key: String,
                             _toolchain_creator: Box<CompilerPackager>,
                             out_pretty: String)
-                            -> SFuture<(Cacheable, process::Output)>
+                            -> SFuture<(Cacheable, DistStats, process::Output)>
         where T: CommandCreatorSync {
     debug!("[{}]: Compiling locally", out_pretty);
 
     let mut path_transformer = dist::PathTransformer::new();
     let (compile_cmd, _dist_compile_cmd, cacheable) = compilation.generate_compile_commands(&mut path_transformer).unwrap();
     Box::new(compile_cmd.execute(&creator)
-        .map(move |o| (cacheable, o)))
+        .map(move |o| (cacheable, DistStats::default(), o)))
 }
 
 #[cfg(feature = "dist")]
@@ -336,18 +478,25 @@ fn dist_or_local_compile<T>(dist_client: Arc<dist::Client>,
                             weak_toolchain_key: String,
                             toolchain_creator: Box<CompilerPackager>,
                             out_pretty: String)
-                            -> SFuture<(Cacheable, process::Output)>
+                            -> SFuture<(Cacheable, DistStats, process::Output)>
         where T: CommandCreatorSync {
     use boxfnonce::BoxFnOnce;
     use futures::future;
+    use std::cell::Cell;
+    use std::rc::Rc;
 
-    debug!("[{}]: Attempting distributed compilation", out_pretty);
+    let trace_id = dist::TraceId::new();
+    debug!("[{}]: Attempting distributed compilation with trace id {}", out_pretty, trace_id);
+    let trace_id2 = trace_id.clone();
     let compile_out_pretty = out_pretty.clone();
     let compile_out_pretty2 = out_pretty.clone();
     let compile_out_pretty3 = out_pretty.clone();
+    let compile_out_pretty4 = out_pretty.clone();
     let mut path_transformer = dist::PathTransformer::new();
     let (compile_cmd, dist_compile_cmd, cacheable) = compilation.generate_compile_commands(&mut path_transformer).unwrap();
     let local_executable = compile_cmd.executable.clone();
+    let job_retries = Rc::new(Cell::new(0));
+    let job_retries2 = job_retries.clone();
     // TODO: the number of map_errs is subideal, but there's no futures-based carrier trait AFAIK
     Box::new(future::result(dist_compile_cmd.ok_or_else(|| "Could not create distributed compile command".into()))
         .and_then(move |dist_compile_cmd| {
@@ -359,7 +508,18 @@ fn dist_or_local_compile<T>(dist_client: Arc<dist::Client>,
             compilation.into_dist_inputs_creator(&mut path_transformer)
                 .map(|dist_inputs_creator| (path_transformer, dist_compile_cmd, dist_inputs_creator, dist_output_paths))
         })
-        .and_then(move |(path_transformer, mut dist_compile_cmd, dist_inputs_creator, dist_output_paths)| {
+        .and_then(move |(path_transformer, mut dist_compile_cmd, mut dist_inputs_creator, dist_output_paths)| {
+            // Keep the inputs around if the job may need to be sent again
+            // when it's retried, or saved if the compiler crashes on the
+            // build server.
+            let inputs = if CONFIG.dist.job_retries > 0 || CONFIG.dist.crash_reproducers_dir.is_some() {
+                let mut inputs = vec![];
+                dist_inputs_creator(&mut inputs);
+                Some(Rc::new(inputs))
+            } else {
+                None
+            };
+            let mut dist_inputs_creator = Some(dist_inputs_creator);
             debug!("[{}]: Identifying toolchain", compile_out_pretty2);
             let toolchain_creator_cb = BoxFnOnce::from(move |f| toolchain_creator.write_pkg(f));
             // TODO: put on a thread
@@ -368,52 +528,109 @@ fn dist_or_local_compile<T>(dist_client: Arc<dist::Client>,
             if let Some(dist_compile_executable) = maybe_dist_compile_executable {
                 dist_compile_cmd.executable = dist_compile_executable;
             }
-
-            debug!("[{}]: Requesting allocation", compile_out_pretty2);
-            Box::new(dist_client.do_alloc_job(dist_toolchain.clone()).map_err(Into::into)
-                .and_then(move |jares| {
-                    debug!("[{}]: Sending compile", compile_out_pretty2);
-                    let alloc = match jares {
-                        dist::AllocJobResult::Success { job_alloc, need_toolchain: true } =>
-                            Box::new(dist_client.do_submit_toolchain(job_alloc, dist_toolchain)
-                                .map(move |res| {
-                                    match res {
-                                        dist::SubmitToolchainResult::Success => job_alloc,
-                                        dist::SubmitToolchainResult::JobNotFound |
-                                        dist::SubmitToolchainResult::CannotCache => panic!(),
-                                    }
-                                }).map_err(Into::into)),
-                        dist::AllocJobResult::Success { job_alloc, need_toolchain: false } => f_ok(job_alloc),
-                        dist::AllocJobResult::Fail { msg: _ } => panic!("failed to allocate"),
-                    };
-                    alloc
-                        .and_then(move |job_alloc| {
-                            dist_client.do_run_job(job_alloc, dist_compile_cmd, dist_output_paths, dist_inputs_creator)
-                                .map_err(Into::into)
-                        })
-                })
-                .map(move |jres| {
-                    let jc = match jres {
-                        dist::RunJobResult::Complete(jc) => jc,
-                        dist::RunJobResult::JobNotFound => panic!(),
-                    };
+            let reproducer = (trace_id.clone(), dist_toolchain.clone(), dist_compile_cmd.clone(), inputs.clone());
+
+            Box::new(dist::run_with_retries(CONFIG.dist.job_retries, job_retries, move |failed_servers: &[dist::ServerId]| {
+                let write_inputs: Box<FnMut(&mut Write)> = match inputs {
+                    Some(ref inputs) => {
+                        let inputs = inputs.clone();
+                        Box::new(move |w: &mut Write| w.write_all(&inputs).unwrap())
+                    },
+                    // Without retries the job only runs once
+                    None => dist_inputs_creator.take().expect("unbuffered inputs were sent twice"),
+                };
+                run_dist_job(dist_client.clone(), trace_id.clone(), dist_toolchain.clone(), dist_compile_cmd.clone(),
+                             dist_output_paths.clone(), write_inputs, failed_servers, compile_out_pretty2.clone())
+            })
+                .and_then(move |jc| -> Result<_> {
+                    if jc.output.crashed() {
+                        if let (Some(dir), (trace_id, toolchain, command, Some(inputs))) = (CONFIG.dist.crash_reproducers_dir.as_ref(), reproducer) {
+                            match dist::write_crash_reproducer(dir, &trace_id, &toolchain, &command, &inputs, &jc.output) {
+                                Ok(path) => warn!("[{}]: Saved a reproducer for the crash in {}", compile_out_pretty4, path.display()),
+                                Err(e) => warn!("[{}]: Failed to save a reproducer for the crash: {}", compile_out_pretty4, e),
+                            }
+                        }
+                        bail!("the compiler crashed on the build server");
+                    }
                     info!("fetched {:?}", jc.outputs.iter().map(|&(ref p, ref bs)| (p, bs.len())).collect::<Vec<_>>());
                     for (path, bytes) in jc.outputs {
                         File::create(path_transformer.to_local(&path)).unwrap().write_all(&bytes).unwrap();
                     }
-                    jc.output.into()
+                    Ok(jc.output.into())
                 })
             )
         })
         // Something failed, do a local compilation
         .or_else(move |e| {
-            info!("[{}]: Could not perform distributed compile, falling back to local: {}", compile_out_pretty3, e);
+            info!("[{}]: Could not perform distributed compile (trace id {}), falling back to local: {}",
+                  compile_out_pretty3, trace_id2, e);
             compile_cmd.execute(&creator)
         })
-        .map(move |o| (cacheable, o))
+        .map(move |o| (cacheable, DistStats { job_retries: job_retries2.get(), local_only: false }, o))
     )
 }
 
+/// Allocate a job for `command` on a build server other than `failed_servers`
+/// through the scheduler, submitting the toolchain if the server needs it,
+/// and run it there. Errors come with the server the job was allocated on,
+/// if any.
+#[cfg(feature = "dist")]
+fn run_dist_job(dist_client: Arc<dist::Client>,
+                trace_id: dist::TraceId,
+                dist_toolchain: dist::Toolchain,
+                command: dist::CompileCommand,
+                output_paths: Vec<String>,
+                write_inputs: Box<FnMut(&mut Write)>,
+                failed_servers: &[dist::ServerId],
+                out_pretty: String)
+                -> Box<Future<Item=dist::JobComplete, Error=(Option<dist::ServerId>, Error)>> {
+    use futures::future;
+
+    debug!("[{}]: Requesting allocation", out_pretty);
+    if !failed_servers.is_empty() {
+        debug!("[{}]: Avoiding build servers {:?}", out_pretty, failed_servers);
+    }
+    Box::new(dist_client.do_alloc_job(&trace_id, dist_toolchain.clone(), failed_servers)
+        .map_err(|e| (None, e))
+        .and_then(move |jares| {
+            debug!("[{}]: Sending compile", out_pretty);
+            let (job_alloc, need_toolchain) = match jares {
+                dist::AllocJobResult::Success { job_alloc, need_toolchain } => (job_alloc, need_toolchain),
+                dist::AllocJobResult::Fail { msg } =>
+                    return Box::new(future::err((None, format!("Failed to allocate a job: {}", msg).into())))
+                        as Box<Future<Item=_, Error=_>>,
+            };
+            let server_id = job_alloc.server_id;
+            let alloc = if need_toolchain {
+                Box::new(dist_client.do_submit_toolchain(&trace_id, job_alloc, dist_toolchain)
+                    .and_then(move |res| -> Result<_> {
+                        match res {
+                            dist::SubmitToolchainResult::Success => Ok(job_alloc),
+                            dist::SubmitToolchainResult::InvalidSignature =>
+                                bail!("Server rejected the toolchain signature"),
+                            dist::SubmitToolchainResult::JobNotFound =>
+                                bail!(ErrorKind::DistRetryable("the build server lost the job".to_owned())),
+                            dist::SubmitToolchainResult::CannotCache => panic!(),
+                        }
+                    })) as SFuture<_>
+            } else {
+                f_ok(job_alloc)
+            };
+            Box::new(alloc
+                .and_then(move |job_alloc| {
+                    debug!("[{}]: Running job {} on {:?}", out_pretty, job_alloc.job_id, job_alloc.server_id);
+                    dist_client.do_run_job(&trace_id, job_alloc, command, output_paths, write_inputs)
+                })
+                .and_then(|jres| -> Result<_> {
+                    match jres {
+                        dist::RunJobResult::Complete(jc) => Ok(jc),
+                        dist::RunJobResult::JobNotFound =>
+                            bail!(ErrorKind::DistRetryable("the build server lost the job".to_owned())),
+                    }
+                })
+                .map_err(move |e| (Some(server_id), e)))
+        }))
+}
 
 impl<T: CommandCreatorSync> Clone for Box<CompilerHasher<T>> {
     fn clone(&self) -> Box<CompilerHasher<T>> { self.box_clone() }
@@ -447,6 +664,14 @@ pub struct HashResult {
     pub compilation: Box<Compilation + 'static>,
     /// A weak key that may be used to identify the toolchain
     pub weak_toolchain_key: String,
+    /// The compiler executable, recorded in the provenance of cache entries
+    pub executable: PathBuf,
+    /// A digest identifying the compiler version, recorded in the provenance of cache entries
+    pub compiler_digest: String,
+    /// The source file being compiled.
+    pub source: PathBuf,
+    /// A digest of the commandline arguments that are part of the key, to tell why keys change.
+    pub args_digest: String,
     /// A object that may be used to package the toolchain into a file
     pub toolchain_creator: Box<CompilerPackager>,
 }
@@ -459,6 +684,9 @@ pub enum CompilerArguments<T>
     Ok(T),
     /// Cannot cache this compilation.
     CannotCache(&'static str),
+    /// Cannot cache this compilation because of a flag the parser doesn't
+    /// recognize, according to the `UnknownArgsPolicy`.
+    UnknownFlag(OsString),
     /// This commandline is not a compile.
     NotCompilation,
 }
@@ -474,6 +702,8 @@ pub enum MissType {
     TimedOut,
     /// Error reading from cache
     CacheReadError,
+    /// The cached result had warnings, and recompiling was requested in that case.
+    Warnings,
 }
 
 /// Information about a successful cache write.
@@ -482,6 +712,15 @@ pub struct CacheWriteInfo {
     pub duration: Duration,
 }
 
+/// What happened with distributed compilation during a compile.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DistStats {
+    /// How many times the compile was retried on another build server.
+    pub job_retries: u64,
+    /// Whether the compile was kept off build servers by `local_only`.
+    pub local_only: bool,
+}
+
 /// The result of a compilation or cache retrieval.
 pub enum CompileResult {
     /// An error made the compilation not possible.
@@ -568,6 +807,151 @@ fn set_file_mode(_path: &Path, _mode: u32) -> Result<()>
     Ok(())
 }
 
+#[cfg(unix)]
+lazy_static! {
+    /// The server's umask, which the compilers it runs inherit.
+    static ref UMASK: u32 = read_umask();
+}
+
+/// Read the process's umask without changing it, if the OS allows that.
+#[cfg(target_os = "linux")]
+fn read_umask() -> u32 {
+    let status = File::open("/proc/self/status").and_then(|mut f| {
+        let mut s = String::new();
+        f.read_to_string(&mut s).map(|_| s)
+    });
+    let umask = status.ok().and_then(|s| {
+        s.lines()
+            .find(|l| l.starts_with("Umask:"))
+            .and_then(|l| u32::from_str_radix(l["Umask:".len()..].trim(), 8).ok())
+    });
+    match umask {
+        Some(umask) => umask,
+        None => swap_umask(),
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn read_umask() -> u32 {
+    swap_umask()
+}
+
+/// Read the umask the only portable way, by setting it and setting it back.
+/// Files created by other threads in between get the wrong mode, so this
+/// must happen before any threads start; see `init_umask`.
+#[cfg(unix)]
+fn swap_umask() -> u32 {
+    unsafe {
+        let umask = ::libc::umask(0o022);
+        ::libc::umask(umask);
+        umask as u32
+    }
+}
+
+/// Read the server's umask now, while it's still single-threaded.
+#[cfg(unix)]
+pub fn init_umask() {
+    umask();
+}
+
+#[cfg(windows)]
+pub fn init_umask() {}
+
+#[cfg(unix)]
+fn umask() -> u32 {
+    *UMASK
+}
+
+#[cfg(windows)]
+fn umask() -> u32 {
+    0
+}
+
+/// The mode to restore an output stored with `mode` with, which is the mode
+/// a fresh compile would have given it: compilers create files readable and
+/// writable by everyone, executable too if the stored file was, less the
+/// bits in `umask`. This keeps hits of entries stored by a server with a
+/// different umask from getting its permissions, and restores outputs
+/// stored without a mode as a compiler would create them rather than as
+/// private temporary files.
+fn restored_file_mode(mode: Option<u32>, umask: u32) -> u32 {
+    let created = match mode {
+        Some(mode) if mode & 0o111 != 0 => 0o777,
+        _ => 0o666,
+    };
+    created & !umask
+}
+
+/// The prefix of the names of the outputs cached by `unknown_outputs =
+/// "capture"` in a cache entry, before their file names.
+const EXTRA_OUTPUT_PREFIX: &str = "extra:";
+
+/// The suffixes of the files compilers write next to the object file under
+/// flags the argument parsers may not know about, such as `foo.su` for
+/// `foo.o` under `-fstack-usage`. Only these are looked for, so sources and
+/// other files that happen to share the object file's stem are never taken
+/// for outputs.
+const UNKNOWN_OUTPUT_SUFFIXES: &[&str] = &["ci", "dwo", "gcno", "opt.bitstream", "opt.yaml", "su"];
+
+/// The files next to `obj` with one of `UNKNOWN_OUTPUT_SUFFIXES` that exist
+/// and aren't among the known `outputs`.
+fn output_siblings(obj: &Path, outputs: &HashMap<String, PathBuf>) -> Vec<PathBuf> {
+    let (dir, stem) = match (obj.parent(), obj.file_stem().and_then(|s| s.to_str())) {
+        (Some(dir), Some(stem)) => (dir, stem),
+        _ => return vec![],
+    };
+    let known = outputs.values().collect::<HashSet<_>>();
+    UNKNOWN_OUTPUT_SUFFIXES.iter()
+        .map(|suffix| dir.join(format!("{}.{}", stem, suffix)))
+        .filter(|path| !known.contains(path) && path.is_file())
+        .collect()
+}
+
+/// The files written next to the object file by a compile that aren't among
+/// its `outputs`, given the `siblings` the object file had before it, or
+/// `None` if some of those are still there. Whether the compile rewrote
+/// those can't be told, so the compile can't be cached with or without them.
+fn unknown_outputs_of(outputs: &HashMap<String, PathBuf>, siblings: &[PathBuf]) -> Option<Vec<PathBuf>> {
+    let after = match outputs.get("obj") {
+        Some(obj) => output_siblings(obj, outputs),
+        None => return Some(vec![]),
+    };
+    if after.iter().any(|path| siblings.contains(path)) {
+        return None
+    }
+    Some(after)
+}
+
+/// Add the outputs cached by `unknown_outputs = "capture"` in `entry` to
+/// `outputs`, to be restored next to the object file.
+fn add_extra_outputs(outputs: &mut HashMap<String, PathBuf>, entry: &mut CacheRead) {
+    let dir = match outputs.get("obj").and_then(|obj| obj.parent()) {
+        Some(dir) => dir.to_owned(),
+        None => return,
+    };
+    for name in entry.object_names() {
+        if !name.starts_with(EXTRA_OUTPUT_PREFIX) {
+            continue
+        }
+        let file_name = PathBuf::from(&name[EXTRA_OUTPUT_PREFIX.len()..]);
+        // Only ever restore files right next to the object file.
+        if file_name.file_name() != Some(file_name.as_os_str()) {
+            continue
+        }
+        outputs.insert(name, dir.join(file_name));
+    }
+}
+
+/// The order in which to move the outputs of a cache hit into place: the
+/// secondary outputs (depfiles, `.dwo`, `.gcno` and so on) sorted by name,
+/// then the primary `obj` output, so that its appearance means the others
+/// are already there, as after a fresh compile.
+fn restore_order<'a>(outputs: &'a HashMap<String, PathBuf>) -> Vec<(&'a String, &'a PathBuf)> {
+    let mut outputs = outputs.iter().collect::<Vec<_>>();
+    outputs.sort_by_key(|&(key, _)| (key == "obj", key));
+    outputs
+}
+
 /// Can this result be stored in cache?
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Cacheable {
@@ -575,6 +959,53 @@ pub enum Cacheable {
     No,
 }
 
+/// The environment variable a build system can set to supply its own cache key
+/// for a compilation, instead of having sccache hash the inputs.
+pub const EXTERNAL_CACHE_KEY_VAR: &str = "SCCACHE_CACHE_KEY";
+
+/// Return the externally-computed cache key in `env_vars`, if any.
+///
+/// The key is trusted to cover the inputs of the compilation, but not the
+/// compiler; see `external_storage_key`.
+pub fn external_cache_key(env_vars: &[(OsString, OsString)]) -> Result<Option<String>> {
+    let key = match env_vars.iter().find(|&&(ref k, _)| k == EXTERNAL_CACHE_KEY_VAR) {
+        Some(&(_, ref v)) => v,
+        None => return Ok(None),
+    };
+    match key.to_str() {
+        Some(key) if key.len() >= 2 && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') => {
+            Ok(Some(key.to_owned()))
+        }
+        _ => bail!("{} must be at least two characters from [A-Za-z0-9_-], got {:?}",
+                   EXTERNAL_CACHE_KEY_VAR, key),
+    }
+}
+
+/// The key the result of a compilation with the external cache key `key`,
+/// by the compiler with `compiler_digest`, is stored under. Hashing with a
+/// prefix of its own gives keys that look like any other but can't collide
+/// with them, and that change along with the compiler.
+pub fn external_storage_key(key: &str, compiler_digest: &str) -> String {
+    let mut m = Digest::new();
+    m.update(b"sccache-external-key:");
+    m.update(compiler_digest.as_bytes());
+    m.update(b":");
+    m.update(key.as_bytes());
+    m.finish()
+}
+
+/// The environment variable that attaches a label to the cache entries a
+/// compile command stores, so they can later be removed together.
+pub const CACHE_LABEL_VAR: &str = "SCCACHE_CACHE_LABEL";
+
+/// Return the label in `env_vars` to store cache entries with, if any.
+pub fn cache_label(env_vars: &[(OsString, OsString)]) -> Option<String> {
+    env_vars.iter()
+        .find(|&&(ref k, _)| k == CACHE_LABEL_VAR)
+        .map(|&(_, ref v)| v.to_string_lossy().into_owned())
+        .and_then(|v| if v.is_empty() { None } else { Some(v) })
+}
+
 /// Control of caching behavior.
 #[derive(Debug, PartialEq)]
 pub enum CacheControl {
@@ -582,6 +1013,93 @@ pub enum CacheControl {
     Default,
     /// Ignore existing cache entries, force recompilation.
     ForceRecache,
+    /// Recompile instead of replaying a cached result with compiler warnings.
+    RecompileOnWarnings,
+}
+
+/// The environment variable that selects a `HitStderr` policy for a compile command.
+pub const HIT_STDERR_VAR: &str = "SCCACHE_HIT_STDERR";
+
+/// What to replay of the stored stderr of a compilation on a cache hit.
+/// The stderr of a fresh compilation is always passed on as-is.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HitStderr {
+    /// Replay it exactly, the default.
+    Full,
+    /// Drop warnings, with their notes and source excerpts, and keep the rest.
+    SuppressWarnings,
+    /// Don't replay anything.
+    None,
+}
+
+impl HitStderr {
+    /// Read the policy from `SCCACHE_HIT_STDERR` in `env_vars`.
+    pub fn from_env(env_vars: &[(OsString, OsString)]) -> HitStderr {
+        let val = match env_vars.iter().find(|&&(ref k, _)| k == HIT_STDERR_VAR) {
+            Some(&(_, ref val)) => val,
+            None => return HitStderr::Full,
+        };
+        match val.to_str() {
+            Some("full") => HitStderr::Full,
+            Some("suppress-warnings") => HitStderr::SuppressWarnings,
+            Some("none") => HitStderr::None,
+            _ => {
+                warn!("Invalid {} {:?}, expected full, suppress-warnings or none", HIT_STDERR_VAR, val);
+                HitStderr::Full
+            }
+        }
+    }
+
+    /// Apply this policy to the stderr of a cache hit.
+    pub fn apply(self, stderr: Vec<u8>) -> Vec<u8> {
+        match self {
+            HitStderr::Full => stderr,
+            HitStderr::SuppressWarnings => suppress_warnings(&stderr),
+            HitStderr::None => vec![],
+        }
+    }
+}
+
+/// Remove the warnings from compiler diagnostics in `stderr`.
+///
+/// A diagnostic starts at a line like `foo.c:1:2: warning: ...` (gcc and
+/// clang), `foo.c(1): warning C4101: ...` (MSVC) or `warning: ...` (rustc),
+/// and runs until the next one. Context lines such as `In function 'main':`
+/// that precede it are kept or dropped along with it.
+fn suppress_warnings(stderr: &[u8]) -> Vec<u8> {
+    let diagnostic = Regex::new(r"^(.*?: )?(warning|error|fatal error)(\[[^\]]*\]| [A-Z]+\d+)?: ").unwrap();
+    let context = Regex::new(r"(^(.*: )?In (\w+ )*(function|constructor|destructor|instantiation)\b|^In file included from |^\s+from .*[:,]$|: At (top level|global scope):$)").unwrap();
+    let summary = Regex::new(r"^\d+ warnings? generated\.$").unwrap();
+    let mut out = vec![];
+    let mut pending = vec![];
+    let mut keep = true;
+    let mut start = 0;
+    while start < stderr.len() {
+        let end = stderr[start..].iter().position(|&b| b == b'\n').map_or(stderr.len(), |i| start + i + 1);
+        let line = &stderr[start..end];
+        start = end;
+        let plain = strip_ansi_escapes::strip(line).unwrap_or_else(|_| line.to_vec());
+        let plain = String::from_utf8_lossy(&plain);
+        let plain = plain.trim_right();
+        if context.is_match(plain) {
+            pending.extend_from_slice(line);
+            continue
+        }
+        if let Some(caps) = diagnostic.captures(plain) {
+            keep = &caps[2] != "warning";
+        } else if summary.is_match(plain) {
+            keep = false;
+        }
+        if keep {
+            out.extend_from_slice(&pending);
+            out.extend_from_slice(line);
+        }
+        pending.clear();
+    }
+    if keep {
+        out.extend_from_slice(&pending);
+    }
+    out
 }
 
 /// Creates a future that will write `contents` to `path` inside of a temporary
@@ -625,7 +1143,7 @@ fn detect_compiler<T>(creator: &T,
     let is_rustc = if filename.to_string_lossy().to_lowercase() == "rustc" {
         // Sanity check that it's really rustc.
         let executable = executable.to_path_buf();
-        let child = creator.clone().new_command_sync(&executable)
+        let child = shell::compiler_command(creator, &executable)
             .stdout(Stdio::piped())
             .stderr(Stdio::null())
             .args(&["--version"])
@@ -671,7 +1189,10 @@ fn detect_c_compiler<T>(creator: T,
 {
     trace!("detect_c_compiler");
 
-    let test = b"#if defined(_MSC_VER) && defined(__clang__)
+    // nvcc also defines the macros of the host compiler it uses.
+    let test = b"#if defined(__NVCC__)
+nvcc
+#elif defined(_MSC_VER) && defined(__clang__)
 msvc-clang
 #elif defined(_MSC_VER)
 msvc
@@ -683,7 +1204,7 @@ gcc
 ".to_vec();
     let write = write_temp_file(&pool, "testfile.c".as_ref(), test);
 
-    let mut cmd = creator.clone().new_command_sync(&executable);
+    let mut cmd = shell::compiler_command(&creator, &executable);
     cmd.stdout(Stdio::piped())
        .stderr(Stdio::null())
        .envs(env.iter().map(|s| (&s.0, &s.1)));
@@ -713,6 +1234,10 @@ gcc
                 debug!("Found clang");
                 return Box::new(CCompiler::new(Clang, executable, &pool)
                                 .map(|c| Some(Box::new(c) as Box<Compiler<T>>)));
+            } else if line == "nvcc" {
+                debug!("Found nvcc");
+                return Box::new(CCompiler::new(NVCC, executable, &pool)
+                                .map(|c| Some(Box::new(c) as Box<Compiler<T>>)));
             } else if line == "msvc" || line == "msvc-clang" {
                 let is_clang = line == "msvc-clang";
                 debug!("Found MSVC (is clang: {})", is_clang);
@@ -793,6 +1318,16 @@ mod test {
         assert_eq!(CompilerKind::C(CCompilerKind::Clang), c.kind());
     }
 
+    #[test]
+    fn test_detect_compiler_kind_nvcc() {
+        let f = TestFixture::new();
+        let creator = new_creator();
+        let pool = CpuPool::new(1);
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "nvcc\n", "")));
+        let c = detect_compiler(&creator, &f.bins[0], &[], &pool).wait().unwrap().unwrap();
+        assert_eq!(CompilerKind::C(CCompilerKind::NVCC), c.kind());
+    }
+
     #[test]
     fn test_detect_compiler_kind_msvc() {
         use env_logger;
@@ -876,6 +1411,7 @@ mod test {
         let dist_client = Arc::new(dist::NoopClient);
         let storage = DiskCache::new(&f.tempdir.path().join("cache"),
                                      u64::MAX,
+                                     None,
                                      &pool);
         let storage: Arc<Storage> = Arc::new(storage);
         // Pretend to be GCC.
@@ -904,7 +1440,7 @@ mod test {
             o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
         };
         let hasher2 = hasher.clone();
-        let (cached, res) = hasher.get_cached_or_compile(dist_client.clone(),
+        let (cached, _, res) = hasher.get_cached_or_compile(dist_client.clone(),
                                                          creator.clone(),
                                                          storage.clone(),
                                                          arguments.clone(),
@@ -930,7 +1466,7 @@ mod test {
         // The preprocessor invocation.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
         // There should be no actual compiler invocation.
-        let (cached, res) = hasher2.get_cached_or_compile(dist_client.clone(),
+        let (cached, _, res) = hasher2.get_cached_or_compile(dist_client.clone(),
                                                           creator.clone(),
                                                           storage.clone(),
                                                           arguments,
@@ -947,6 +1483,77 @@ mod test {
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
     }
 
+    #[test]
+    fn test_compiler_get_cached_or_compile_special_outputs() {
+        use env_logger;
+        drop(env_logger::init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let dist_client = Arc::new(dist::NoopClient);
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"),
+                                     u64::MAX,
+                                     None,
+                                     &pool);
+        let storage: Arc<Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(&creator,
+                                  &f.bins[0],
+                                  &[],
+                                  &pool).wait().unwrap();
+        let cwd = f.tempdir.path();
+        // The object file written to stdout, and nothing for /dev/null.
+        for &(output, stdout) in &[("-", &b"\x1b[0m object file"[..]), ("/dev/null", &b""[..])] {
+            let arguments = ovec!["-c", "foo.c", "-o", output];
+            let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+                CompilerArguments::Ok(h) => h,
+                o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+            };
+            assert_eq!(ColorMode::On == hasher.color_mode(), output == "-");
+            let hasher2 = hasher.clone();
+            // The preprocessor invocation.
+            next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+            // The compiler invocation.
+            next_command(&creator, Ok(MockChild::new(exit_status(0), stdout, "")));
+            let (cached, _, res) = hasher.get_cached_or_compile(dist_client.clone(),
+                                                             creator.clone(),
+                                                             storage.clone(),
+                                                             arguments.clone(),
+                                                             cwd.to_path_buf(),
+                                                             vec![],
+                                                             CacheControl::Default,
+                                                             pool.clone(),
+                                                             handle.clone()).wait().unwrap();
+            match cached {
+                CompileResult::CacheMiss(MissType::Normal, _, f) => {
+                    // wait on cache write future so we don't race with it!
+                    f.wait().unwrap();
+                }
+                _ => assert!(false, "Unexpected compile result: {:?}", cached),
+            }
+            assert_eq!(stdout, res.stdout.as_slice());
+            // Now compile again, which should be a cache hit that writes no
+            // object file.
+            next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+            let (cached, _, res) = hasher2.get_cached_or_compile(dist_client.clone(),
+                                                              creator.clone(),
+                                                              storage.clone(),
+                                                              arguments,
+                                                              cwd.to_path_buf(),
+                                                              vec![],
+                                                              CacheControl::Default,
+                                                              pool.clone(),
+                                                              handle.clone()).wait().unwrap();
+            assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+            assert_eq!(exit_status(0), res.status);
+            assert_eq!(stdout, res.stdout.as_slice());
+            assert!(!cwd.join("-").exists());
+        }
+    }
+
     #[test]
     fn test_compiler_get_cached_or_compile_cached() {
         use env_logger;
@@ -959,6 +1566,7 @@ mod test {
         let dist_client = Arc::new(dist::NoopClient);
         let storage = DiskCache::new(&f.tempdir.path().join("cache"),
                                      u64::MAX,
+                                     None,
                                      &pool);
         let storage: Arc<Storage> = Arc::new(storage);
         // Pretend to be GCC.
@@ -987,7 +1595,7 @@ mod test {
             o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
         };
         let hasher2 = hasher.clone();
-        let (cached, res) = hasher.get_cached_or_compile(dist_client.clone(),
+        let (cached, _, res) = hasher.get_cached_or_compile(dist_client.clone(),
                                                          creator.clone(),
                                                          storage.clone(),
                                                          arguments.clone(),
@@ -1014,7 +1622,7 @@ mod test {
         // The preprocessor invocation.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
         // There should be no actual compiler invocation.
-        let (cached, res) = hasher2.get_cached_or_compile(dist_client.clone(),
+        let (cached, _, res) = hasher2.get_cached_or_compile(dist_client.clone(),
                                                           creator,
                                                           storage,
                                                           arguments,
@@ -1031,6 +1639,585 @@ mod test {
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
     }
 
+    #[test]
+    fn test_external_cache_key() {
+        let env = |v: &str| vec![(OsString::from(EXTERNAL_CACHE_KEY_VAR), OsString::from(v))];
+        assert_eq!(None, external_cache_key(&[]).unwrap());
+        assert_eq!(Some("abc-123_def".to_owned()), external_cache_key(&env("abc-123_def")).unwrap());
+        assert!(external_cache_key(&env("")).is_err());
+        assert!(external_cache_key(&env("a")).is_err());
+        assert!(external_cache_key(&env("../etc/passwd")).is_err());
+        let key = external_storage_key("abc-123_def", "compiler");
+        assert_neq!(key, "abc-123_def");
+        assert_neq!(key, external_storage_key("abc-123_def", "upgraded compiler"));
+        assert_neq!(key, external_storage_key("abc-123_ghi", "compiler"));
+    }
+
+    #[test]
+    fn test_cache_label() {
+        let env = |v: &str| vec![(OsString::from(CACHE_LABEL_VAR), OsString::from(v))];
+        assert_eq!(None, cache_label(&[]));
+        assert_eq!(None, cache_label(&env("")));
+        assert_eq!(Some("pre-refactor".to_owned()), cache_label(&env("pre-refactor")));
+    }
+
+    #[test]
+    fn test_cwd_in_key() {
+        use config::CwdInKey;
+
+        let td = TempDir::new("sccache").unwrap();
+        let dir = td.path().join("dir");
+        fs::create_dir(&dir).unwrap();
+        let dotted = dir.join("..").join("dir");
+        assert_eq!(CwdInKey::Raw.key_cwd(&dotted), Some(dotted.to_string_lossy().into_owned()));
+        assert_eq!(CwdInKey::Normalized.key_cwd(&dotted),
+                   Some(dir.canonicalize().unwrap().to_string_lossy().into_owned()));
+        assert_eq!(CwdInKey::None.key_cwd(&dotted), None);
+    }
+
+    #[test]
+    fn test_cwd_in_key_hash() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(&creator,
+                                  &f.bins[0],
+                                  &[],
+                                  &pool).wait().unwrap();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(h) => h,
+            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let key = |cwd: &str| {
+            // The preprocessor invocation.
+            next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+            hasher.clone().generate_hash_key(&creator, f.tempdir.path().join(cwd), vec![], false, &pool)
+                .wait().unwrap().key
+        };
+        // By default, compiling in another directory is the same compilation.
+        assert_eq!(key("a"), key("b"));
+    }
+
+    #[test]
+    fn test_hit_stderr() {
+        let env = |val: &str| vec![(OsString::from(HIT_STDERR_VAR), OsString::from(val))];
+        assert_eq!(HitStderr::from_env(&[]), HitStderr::Full);
+        assert_eq!(HitStderr::from_env(&env("full")), HitStderr::Full);
+        assert_eq!(HitStderr::from_env(&env("suppress-warnings")), HitStderr::SuppressWarnings);
+        assert_eq!(HitStderr::from_env(&env("none")), HitStderr::None);
+        assert_eq!(HitStderr::from_env(&env("bogus")), HitStderr::Full);
+
+        let gcc = b"foo.c: In function 'main':\n\
+                    foo.c:3:7: warning: unused variable 'x' [-Wunused-variable]\n\
+                    \x20   3 |   int x;\n\
+                    \x20     |       ^\n\
+                    foo.c:5:1: error: expected ';' before '}' token\n\
+                    \x20   5 | }\n\
+                    foo.c:6:3: note: in expansion of macro 'FOO'\n".to_vec();
+        assert_eq!(HitStderr::Full.apply(gcc.clone()), gcc);
+        assert_eq!(HitStderr::None.apply(gcc.clone()), Vec::<u8>::new());
+        assert_eq!(String::from_utf8(HitStderr::SuppressWarnings.apply(gcc)).unwrap(),
+                   "foo.c:5:1: error: expected ';' before '}' token\n\
+                    \x20   5 | }\n\
+                    foo.c:6:3: note: in expansion of macro 'FOO'\n");
+
+        let clang = b"In file included from foo.c:1:\n\
+                      ./foo.h:1:5: \x1b[0;1;35mwarning: \x1b[0mfoo [-Wfoo]\x1b[0m\n\
+                      int x\n\
+                      1 warning generated.\n".to_vec();
+        assert_eq!(HitStderr::SuppressWarnings.apply(clang), Vec::<u8>::new());
+
+        let rustc = b"warning: unused variable: `x`\n \
+                      --> src/lib.rs:1:5\n\
+                      \n\
+                      error[E0308]: mismatched types\n \
+                      --> src/lib.rs:2:5\n\
+                      \n".to_vec();
+        assert_eq!(String::from_utf8(HitStderr::SuppressWarnings.apply(rustc)).unwrap(),
+                   "error[E0308]: mismatched types\n --> src/lib.rs:2:5\n\n");
+
+        let msvc = b"foo.c\nfoo.c(3): warning C4101: 'x': unreferenced local variable\n".to_vec();
+        assert_eq!(HitStderr::SuppressWarnings.apply(msvc), b"foo.c\n".to_vec());
+    }
+
+    #[test]
+    fn test_restored_file_mode() {
+        assert_eq!(0o644, restored_file_mode(Some(0o644), 0o022));
+        assert_eq!(0o644, restored_file_mode(Some(0o664), 0o022));
+        assert_eq!(0o664, restored_file_mode(Some(0o600), 0o002));
+        assert_eq!(0o755, restored_file_mode(Some(0o775), 0o022));
+        assert_eq!(0o700, restored_file_mode(Some(0o755), 0o077));
+        assert_eq!(0o644, restored_file_mode(None, 0o022));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_compiler_get_cached_or_compile_file_mode() {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let dist_client = Arc::new(dist::NoopClient);
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"),
+                                     u64::MAX,
+                                     None,
+                                     &pool);
+        let storage: Arc<Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(&creator,
+                                  &f.bins[0],
+                                  &[],
+                                  &pool).wait().unwrap();
+        // The preprocessor invocation.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        // The compiler invocation, creating an executable output the way a
+        // compiler would, subject to the umask.
+        let obj = f.tempdir.path().join("foo.o");
+        let o = obj.clone();
+        next_command_calls(&creator, move |_| {
+            let mut f = fs::OpenOptions::new().write(true).create(true).mode(0o777).open(&o)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(h) => h,
+            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let hasher2 = hasher.clone();
+        let (cached, _, _) = hasher.get_cached_or_compile(dist_client.clone(),
+                                                       creator.clone(),
+                                                       storage.clone(),
+                                                       arguments.clone(),
+                                                       cwd.to_path_buf(),
+                                                       vec![],
+                                                       CacheControl::Default,
+                                                       pool.clone(),
+                                                       handle.clone()).wait().unwrap();
+        match cached {
+            CompileResult::CacheMiss(MissType::Normal, _, f) => {
+                // wait on cache write future so we don't race with it!
+                f.wait().unwrap();
+            }
+            _ => assert!(false, "Unexpected compile result: {:?}", cached),
+        }
+        let fresh_mode = fs::metadata(&obj).unwrap().permissions().mode() & 0o777;
+        assert_eq!(0o777 & !umask(), fresh_mode);
+        fs::remove_file(&obj).unwrap();
+        // The preprocessor invocation.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        // There should be no actual compiler invocation.
+        let (cached, _, _) = hasher2.get_cached_or_compile(dist_client.clone(),
+                                                        creator,
+                                                        storage,
+                                                        arguments,
+                                                        cwd.to_path_buf(),
+                                                        vec![],
+                                                        CacheControl::Default,
+                                                        pool,
+                                                        handle).wait().unwrap();
+        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        assert_eq!(fresh_mode, fs::metadata(&obj).unwrap().permissions().mode() & 0o777);
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_opt_record() {
+        use std::io::Read;
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let dist_client = Arc::new(dist::NoopClient);
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"),
+                                     u64::MAX,
+                                     None,
+                                     &pool);
+        let storage: Arc<Storage> = Arc::new(storage);
+        // Pretend to be clang.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "clang", "")));
+        let c = get_compiler_info(&creator,
+                                  &f.bins[0],
+                                  &[],
+                                  &pool).wait().unwrap();
+        // The preprocessor invocation.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        // The compiler invocation, writing the optimization record too.
+        let obj = f.tempdir.path().join("foo.o");
+        let record = f.tempdir.path().join("foo.opt.yaml");
+        let (o, r) = (obj.clone(), record.clone());
+        next_command_calls(&creator, move |_| {
+            File::create(&o)?.write_all(b"file contents")?;
+            File::create(&r)?.write_all(b"--- !Missed\n")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-fsave-optimization-record", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(h) => h,
+            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let hasher2 = hasher.clone();
+        let (cached, _, _) = hasher.get_cached_or_compile(dist_client.clone(),
+                                                       creator.clone(),
+                                                       storage.clone(),
+                                                       arguments.clone(),
+                                                       cwd.to_path_buf(),
+                                                       vec![],
+                                                       CacheControl::Default,
+                                                       pool.clone(),
+                                                       handle.clone()).wait().unwrap();
+        match cached {
+            CompileResult::CacheMiss(MissType::Normal, _, f) => {
+                // wait on cache write future so we don't race with it!
+                f.wait().unwrap();
+            }
+            _ => assert!(false, "Unexpected compile result: {:?}", cached),
+        }
+        fs::remove_file(&obj).unwrap();
+        fs::remove_file(&record).unwrap();
+        // The preprocessor invocation.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        // There should be no actual compiler invocation.
+        let (cached, _, _) = hasher2.get_cached_or_compile(dist_client.clone(),
+                                                        creator,
+                                                        storage,
+                                                        arguments,
+                                                        cwd.to_path_buf(),
+                                                        vec![],
+                                                        CacheControl::Default,
+                                                        pool,
+                                                        handle).wait().unwrap();
+        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        let mut contents = String::new();
+        File::open(&record).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!("--- !Missed\n", contents);
+    }
+
+    #[test]
+    fn test_unknown_outputs() {
+        use std::io::Cursor;
+        let f = TestFixture::new();
+        let obj = f.touch("foo.o").unwrap();
+        f.touch("foo.c").unwrap();
+        f.touch("bar.su").unwrap();
+        let mut outputs = HashMap::new();
+        outputs.insert("obj".to_owned(), obj.clone());
+        outputs.insert("dwo".to_owned(), obj.with_extension("dwo"));
+        f.touch("foo.dwo").unwrap();
+        let siblings = output_siblings(&obj, &outputs);
+        assert!(siblings.is_empty());
+        // A compile writing a file the outputs don't know about.
+        let record = f.touch("foo.opt.yaml").unwrap();
+        assert_eq!(Some(vec![record.clone()]), unknown_outputs_of(&outputs, &siblings));
+        // One that was there before may or may not have been rewritten.
+        let siblings = output_siblings(&obj, &outputs);
+        assert_eq!(None, unknown_outputs_of(&outputs, &siblings));
+
+        let mut entry = CacheWrite::new().unwrap();
+        for name in &["obj", "extra:foo.opt.yaml", "extra:../escape", "stdout"] {
+            entry.put_object(name, &mut Cursor::new(&b""[..]), None).unwrap();
+        }
+        let mut entry = CacheRead::from(Cursor::new(entry.finish().unwrap())).unwrap();
+        let mut outputs = HashMap::new();
+        outputs.insert("obj".to_owned(), obj.clone());
+        add_extra_outputs(&mut outputs, &mut entry);
+        assert_eq!(2, outputs.len());
+        assert_eq!(Some(&record), outputs.get("extra:foo.opt.yaml"));
+    }
+
+    #[test]
+    fn test_restore_order() {
+        let outputs = vec!["obj", "gcno", "d", "dwo"].into_iter()
+            .map(|k| (k.to_owned(), PathBuf::from(format!("foo.{}", k))))
+            .collect::<HashMap<_, _>>();
+        let order = restore_order(&outputs).into_iter().map(|(k, _)| &**k).collect::<Vec<_>>();
+        assert_eq!(order, vec!["d", "dwo", "gcno", "obj"]);
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_label() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let dist_client = Arc::new(dist::NoopClient);
+        let storage: Arc<Storage> = Arc::new(DiskCache::new(&f.tempdir.path().join("cache"),
+                                                              u64::MAX,
+                                                              None,
+                                                              &pool));
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(&creator,
+                                  &f.bins[0],
+                                  &[],
+                                  &pool).wait().unwrap();
+        // The external key skips preprocessing, so only the compiler runs.
+        let o = f.tempdir.path().join("foo.o");
+        next_command_calls(&creator, move |_| {
+            let mut f = File::create(&o)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let labelled_key = external_storage_key("labelledkey", &Digest::file(f.bins[0].clone(), &pool).wait().unwrap());
+        let env_vars = vec![(OsString::from(EXTERNAL_CACHE_KEY_VAR), OsString::from("labelledkey")),
+                            (OsString::from(CACHE_LABEL_VAR), OsString::from("pre-refactor"))];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(h) => h,
+            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let (cached, _, _res) = hasher.get_cached_or_compile(dist_client.clone(),
+                                                          creator.clone(),
+                                                          storage.clone(),
+                                                          arguments.clone(),
+                                                          f.tempdir.path().to_path_buf(),
+                                                          env_vars,
+                                                          CacheControl::Default,
+                                                          pool.clone(),
+                                                          handle.clone()).wait().unwrap();
+        match cached {
+            CompileResult::CacheMiss(MissType::Normal, _, f) => {
+                // wait on cache write future so we don't race with it!
+                f.wait().unwrap();
+            }
+            _ => assert!(false, "Unexpected compile result: {:?}", cached),
+        }
+        match storage.get(&labelled_key).wait().unwrap() {
+            Cache::Hit(mut entry) => {
+                assert_eq!(entry.get_provenance().unwrap().unwrap().label, Some("pre-refactor".to_owned()));
+            }
+            _ => panic!("Expected a cache entry under the external key"),
+        }
+        assert_eq!(storage.remove_label("post-refactor").wait().unwrap().entries, 0);
+        assert_eq!(storage.remove_label("pre-refactor").wait().unwrap().entries, 1);
+        match storage.get(&labelled_key).wait().unwrap() {
+            Cache::Miss => {}
+            _ => panic!("Expected the labelled entry to be removed"),
+        }
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_external_key() {
+        use env_logger;
+        drop(env_logger::init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let dist_client = Arc::new(dist::NoopClient);
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"),
+                                     u64::MAX,
+                                     None,
+                                     &pool);
+        let storage: Arc<Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(&creator,
+                                  &f.bins[0],
+                                  &[],
+                                  &pool).wait().unwrap();
+        // The compiler invocation, with no preprocessor invocation before it.
+        let obj = f.tempdir.path().join("foo.o");
+        let o = obj.clone();
+        next_command_calls(&creator, move |_| {
+            // Pretend to compile something.
+            let mut f = File::create(&o)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let env_vars = vec![(OsString::from(EXTERNAL_CACHE_KEY_VAR), OsString::from("buildsystemkey"))];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(h) => h,
+            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let hasher2 = hasher.clone();
+        let (cached, _, _res) = hasher.get_cached_or_compile(dist_client.clone(),
+                                                          creator.clone(),
+                                                          storage.clone(),
+                                                          arguments.clone(),
+                                                          cwd.to_path_buf(),
+                                                          env_vars.clone(),
+                                                          CacheControl::Default,
+                                                          pool.clone(),
+                                                          handle.clone()).wait().unwrap();
+        match cached {
+            CompileResult::CacheMiss(MissType::Normal, _, f) => {
+                // wait on cache write future so we don't race with it!
+                f.wait().unwrap();
+            }
+            _ => assert!(false, "Unexpected compile result: {:?}", cached),
+        }
+        assert_eq!(0, creator.lock().unwrap().children.len());
+        let key = external_storage_key("buildsystemkey", &Digest::file(f.bins[0].clone(), &pool).wait().unwrap());
+        match storage.get(&key).wait().unwrap() {
+            Cache::Hit(_) => {}
+            _ => panic!("Expected a cache entry under the external key"),
+        }
+        fs::remove_file(&obj).unwrap();
+        // The second time should be a hit without running anything.
+        let (cached, _, _res) = hasher2.get_cached_or_compile(dist_client.clone(),
+                                                           creator.clone(),
+                                                           storage,
+                                                           arguments,
+                                                           cwd.to_path_buf(),
+                                                           env_vars,
+                                                           CacheControl::Default,
+                                                           pool,
+                                                           handle).wait().unwrap();
+        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_werror_not_cached() {
+        use env_logger;
+        drop(env_logger::init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let dist_client = Arc::new(dist::NoopClient);
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"),
+                                     u64::MAX,
+                                     None,
+                                     &pool);
+        let storage: Arc<Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(&creator,
+                                  &f.bins[0],
+                                  &[],
+                                  &pool).wait().unwrap();
+        const COMPILER_STDERR: &'static [u8] = b"error: unused variable 'x' [-Werror=unused-variable]";
+        // Compile twice, a warning turned into an error must fail both times.
+        for _ in 0..2 {
+            // The preprocessor invocation.
+            next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+            // The compiler invocation.
+            next_command(&creator, Ok(MockChild::new(exit_status(1), "", COMPILER_STDERR)));
+        }
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-Werror", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(h) => h,
+            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        for _ in 0..2 {
+            let (cached, _, res) = hasher.clone().get_cached_or_compile(dist_client.clone(),
+                                                                     creator.clone(),
+                                                                     storage.clone(),
+                                                                     arguments.clone(),
+                                                                     cwd.to_path_buf(),
+                                                                     vec![],
+                                                                     CacheControl::Default,
+                                                                     pool.clone(),
+                                                                     handle.clone()).wait().unwrap();
+            assert_eq!(CompileResult::CompileFailed, cached);
+            assert!(!res.status.success());
+            assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
+            assert_eq!(Some(0), storage.current_entries());
+        }
+        // Ensure that we ran all processes.
+        assert_eq!(0, creator.lock().unwrap().children.len());
+    }
+
+    #[test]
+    /// Test that a cache hit with warnings is recompiled with
+    /// `CacheControl::RecompileOnWarnings`.
+    fn test_compiler_get_cached_or_compile_recompile_on_warnings() {
+        use env_logger;
+        drop(env_logger::init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let dist_client = Arc::new(dist::NoopClient);
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"),
+                                     u64::MAX,
+                                     None,
+                                     &pool);
+        let storage: Arc<Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(&creator,
+                                  &f.bins[0],
+                                  &[],
+                                  &pool).wait().unwrap();
+        const COMPILER_STDOUT: &'static [u8] = b"compiler stdout";
+        const COMPILER_STDERR: &'static [u8] = b"warning: something";
+        let obj = f.tempdir.path().join("foo.o");
+        // Compile twice, the second time should recompile despite the cache entry.
+        for _ in 0..2 {
+            // The preprocessor invocation.
+            next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+            // The compiler invocation.
+            let o = obj.clone();
+            next_command_calls(&creator, move |_| {
+                // Pretend to compile something.
+                let mut f = File::create(&o)?;
+                f.write_all(b"file contents")?;
+                Ok(MockChild::new(exit_status(0), COMPILER_STDOUT, COMPILER_STDERR))
+            });
+        }
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(h) => h,
+            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let hasher2 = hasher.clone();
+        let (cached, _, _res) = hasher.get_cached_or_compile(dist_client.clone(),
+                                                          creator.clone(),
+                                                          storage.clone(),
+                                                          arguments.clone(),
+                                                          cwd.to_path_buf(),
+                                                          vec![],
+                                                          CacheControl::RecompileOnWarnings,
+                                                          pool.clone(),
+                                                          handle.clone()).wait().unwrap();
+        match cached {
+            CompileResult::CacheMiss(MissType::Normal, _, f) => {
+                // wait on cache write future so we don't race with it!
+                f.wait().unwrap();
+            }
+            _ => assert!(false, "Unexpected compile result: {:?}", cached),
+        }
+        fs::remove_file(&obj).unwrap();
+        let (cached, _, res) = hasher2.get_cached_or_compile(dist_client.clone(),
+                                                          creator,
+                                                          storage,
+                                                          arguments,
+                                                          cwd.to_path_buf(),
+                                                          vec![],
+                                                          CacheControl::RecompileOnWarnings,
+                                                          pool,
+                                                          handle).wait().unwrap();
+        // Ensure that the object file was created.
+        assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
+        match cached {
+            CompileResult::CacheMiss(MissType::Warnings, _, f) => {
+                // wait on cache write future so we don't race with it!
+                f.wait().unwrap();
+            }
+            _ => assert!(false, "Unexpected compile result: {:?}", cached),
+        }
+        assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
+    }
+
     #[test]
     /// Test that a cache read that results in an error is treated as a cache
     /// miss.
@@ -1072,7 +2259,7 @@ mod test {
         };
         // The cache will return an error.
         storage.next_get(f_err("Some Error"));
-        let (cached, res) = hasher.get_cached_or_compile(dist_client.clone(),
+        let (cached, _, res) = hasher.get_cached_or_compile(dist_client.clone(),
                                                          creator.clone(),
                                                          storage.clone(),
                                                          arguments.clone(),
@@ -1108,6 +2295,7 @@ mod test {
         let dist_client = Arc::new(dist::NoopClient);
         let storage = DiskCache::new(&f.tempdir.path().join("cache"),
                                      u64::MAX,
+                                     None,
                                      &pool);
         let storage: Arc<Storage> = Arc::new(storage);
         // Pretend to be GCC.
@@ -1140,7 +2328,7 @@ mod test {
             o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
         };
         let hasher2 = hasher.clone();
-        let (cached, res) = hasher.get_cached_or_compile(dist_client.clone(),
+        let (cached, _, res) = hasher.get_cached_or_compile(dist_client.clone(),
                                                          creator.clone(),
                                                          storage.clone(),
                                                          arguments.clone(),
@@ -1163,7 +2351,7 @@ mod test {
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
         // Now compile again, but force recaching.
         fs::remove_file(&obj).unwrap();
-        let (cached, res) = hasher2.get_cached_or_compile(dist_client.clone(),
+        let (cached, _, res) = hasher2.get_cached_or_compile(dist_client.clone(),
                                                           creator,
                                                           storage,
                                                           arguments,
@@ -1198,6 +2386,7 @@ mod test {
         let dist_client = Arc::new(dist::NoopClient);
         let storage = DiskCache::new(&f.tempdir.path().join("cache"),
                                      u64::MAX,
+                                     None,
                                      &pool);
         let storage: Arc<Storage> = Arc::new(storage);
         // Pretend to be GCC.
@@ -1215,7 +2404,7 @@ mod test {
             CompilerArguments::Ok(h) => h,
             o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
         };
-        let (cached, res) = hasher.get_cached_or_compile(dist_client.clone(),
+        let (cached, _, res) = hasher.get_cached_or_compile(dist_client.clone(),
                                                          creator,
                                                          storage,
                                                          arguments,
@@ -18,13 +18,15 @@ use ::compiler::{
     CompileCommand,
 };
 use compiler::args::*;
-use compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments};
+use compiler::c::{self, CCompilerImpl, CCompilerKind, Language, OutputTarget, ParsedArguments};
+use compiler::shell;
 use log::LogLevel::Trace;
 use mock_command::{
     CommandCreatorSync,
     RunCommand,
 };
-use std::collections::HashMap;
+use config::{CONFIG, UnknownArgsPolicy};
+use std::collections::{BTreeMap, HashMap};
 use dist;
 use std::io::Read;
 use std::ffi::OsString;
@@ -45,7 +47,7 @@ impl CCompilerImpl for GCC {
                        arguments: &[OsString],
                        cwd: &Path) -> CompilerArguments<ParsedArguments>
     {
-        parse_arguments(arguments, cwd, &ARGS[..])
+        parse_arguments(arguments, cwd, &ARGS[..], &CONFIG.source_extensions.gcc, CONFIG.unknown_args)
     }
 
     fn preprocess<T>(&self,
@@ -84,14 +86,22 @@ pub enum GCCArgAttribute {
     Language,
     SplitDwarf,
     ProfileGenerate,
+    ProfileUse,
+    /// An input file that doesn't show up in the preprocessor output.
+    ExtraHashFile,
     TestCoverage,
     Coverage,
+    /// Write an optimization record next to the object file, in the format
+    /// given as the value, if any.
+    OptRecord,
+    /// Write an optimization record to the path given as the value.
+    OptRecordFile,
 }
 
 use self::GCCArgAttribute::*;
 
 // Mostly taken from https://github.com/ccache/ccache/blob/master/src/compopt.c#L32-L84
-pub static ARGS: [(ArgInfo, GCCArgAttribute); 65] = [
+pub static ARGS: [(ArgInfo, GCCArgAttribute); 67] = [
     flag!("-", TooHard),
     flag!("--coverage", Coverage),
     take_arg!("--param", String, Separated, PassThrough),
@@ -129,7 +139,9 @@ pub static ARGS: [(ArgInfo, GCCArgAttribute); 65] = [
     flag!("-fplugin=libcc1plugin", TooHard),
     flag!("-fprofile-arcs", ProfileGenerate),
     flag!("-fprofile-generate", ProfileGenerate),
-    flag!("-fprofile-use", TooHard),
+    take_arg!("-fprofile-generate=", Path, Concatenated, ProfileGenerate),
+    flag!("-fprofile-use", ProfileUse),
+    take_arg!("-fprofile-use=", Path, Concatenated, ProfileUse),
     flag!("-frepo", TooHard),
     flag!("-fsyntax-only", TooHard),
     flag!("-ftest-coverage", TestCoverage),
@@ -172,6 +184,8 @@ pub fn parse_arguments<S>(
     arguments: &[OsString],
     cwd: &Path,
     arg_info: S,
+    source_extensions: &BTreeMap<String, String>,
+    unknown_args: UnknownArgsPolicy,
 ) -> CompilerArguments<ParsedArguments>
 where
     S: SearchableArgInfo<Info = (ArgInfo, GCCArgAttribute)>,
@@ -180,6 +194,7 @@ where
     let mut input_arg = None;
     let mut dep_target = None;
     let mut common_args = vec!();
+    let mut unhashed_args = vec!();
     let mut preprocessor_args = vec!();
     let mut compilation = false;
     let mut multiple_input = false;
@@ -188,6 +203,10 @@ where
     let mut language = None;
     let mut profile_generate = false;
     let mut outputs_gcno = false;
+    let mut profile_use = false;
+    let mut extra_hash_files = vec![];
+    let mut opt_record_format = None;
+    let mut opt_record_file = None;
 
     // Custom iterator to expand `@` arguments which stand for reading a file
     // and interpreting it as a list of more arguments.
@@ -210,7 +229,23 @@ where
             Some(SplitDwarf) => split_dwarf = true,
             Some(DoCompilation) => compilation = true,
             Some(ProfileGenerate) => profile_generate = true,
+            Some(ProfileUse) => {
+                match item.arg.get_value() {
+                    Some(path) => extra_hash_files.push(path.unwrap_path()),
+                    None => profile_use = true,
+                }
+            }
+            Some(ExtraHashFile) => {
+                if let Some(path) = item.arg.get_value() {
+                    extra_hash_files.push(path.unwrap_path());
+                }
+            }
             Some(TestCoverage) => outputs_gcno = true,
+            Some(OptRecord) => {
+                opt_record_format = Some(item.arg.get_value().map(OsString::from)
+                                         .unwrap_or_else(|| "yaml".into()));
+            }
+            Some(OptRecordFile) => opt_record_file = item.arg.get_value().map(|p| p.unwrap_path()),
             Some(Coverage) => {
                 outputs_gcno = true;
                 profile_generate = true;
@@ -223,12 +258,9 @@ where
             Some(Language) => {
                 let lang = item.arg.get_value().map(OsString::from);
                 let lang = lang.as_ref().map(|a| a.to_string_lossy());
-                language = match lang.as_ref().map(|a| a.as_ref()) {
-                    Some("c") => Some(Language::C),
-                    Some("c++") => Some(Language::Cxx),
-                    Some("objective-c") => Some(Language::ObjectiveC),
-                    Some("objective-c++") => Some(Language::ObjectiveCxx),
-                    _ => return CompilerArguments::CannotCache("-x"),
+                language = match lang.as_ref().and_then(|a| Language::from_name(a)) {
+                    Some(l) => Some(l),
+                    None => return CompilerArguments::CannotCache("-x"),
                 };
             }
             None => {
@@ -239,7 +271,11 @@ where
                         }
                         input_arg = Some(val.clone());
                     }
-                    Argument::UnknownFlag(_) => {}
+                    Argument::UnknownFlag(ref flag) => {
+                        if !c::unknown_flag_is_cacheable(flag, unknown_args) {
+                            return CompilerArguments::UnknownFlag(flag.clone());
+                        }
+                    }
                     _ => unreachable!(),
                 }
             }
@@ -247,8 +283,12 @@ where
         let args = match item.data {
             Some(SplitDwarf) |
             Some(ProfileGenerate) |
+            Some(ProfileUse) |
+            Some(ExtraHashFile) |
             Some(TestCoverage) |
             Some(Coverage) |
+            Some(OptRecord) |
+            Some(OptRecordFile) |
             Some(PassThrough) => Some(&mut common_args),
             Some(PreprocessorArgument) |
             Some(NeedDepTarget) => Some(&mut preprocessor_args),
@@ -260,7 +300,13 @@ where
             None => {
                 match item.arg {
                     Argument::Raw(_) => None,
-                    Argument::UnknownFlag(_) => Some(&mut common_args),
+                    // This includes code generation flags like -fPIC and -fno-pic,
+                    // which must be part of the hash key. The compiler's default
+                    // PIC mode is covered by the compiler digest.
+                    Argument::UnknownFlag(_) => match unknown_args {
+                        UnknownArgsPolicy::Ignore => Some(&mut unhashed_args),
+                        _ => Some(&mut common_args),
+                    },
                     _ => unreachable!(),
                 }
             }
@@ -291,18 +337,25 @@ where
         None => return CompilerArguments::CannotCache("no input file"),
     };
     if language == None {
-        language = Language::from_file_name(Path::new(&input));
+        language = Language::from_file_name_with(Path::new(&input), source_extensions);
     }
     let language = match language {
         Some(l) => l,
-        None => return CompilerArguments::CannotCache("unknown source language"),
+        None => {
+            debug!("Not caching compilation of {:?}: unrecognized source extension", input);
+            return CompilerArguments::CannotCache("unknown source language")
+        }
     };
     let mut outputs = HashMap::new();
     let output = match output_arg {
-        // We can't cache compilation that doesn't go to a file
-        None => Path::new(&input).with_extension("o"),
+        // Without `-o` the object is written to the current directory, not
+        // next to the input.
+        None => Path::new(Path::new(&input).file_name().unwrap_or(&input)).with_extension("o"),
         Some(o) =>  PathBuf::from(o),
     };
+    // Like the other side outputs, gcc and clang name the split DWARF file
+    // after the object file, not the input: `-o obj/foo.o` writes
+    // `obj/foo.dwo`.
     if split_dwarf {
         let dwo = output.with_extension("dwo");
         outputs.insert("dwo", dwo);
@@ -312,6 +365,23 @@ where
         outputs.insert("gcno", gcno);
         profile_generate = true;
     }
+    // Clang writes the record to `foo.opt.yaml` for `foo.o` unless told
+    // otherwise, and naming the file implies writing it.
+    match (opt_record_file, opt_record_format) {
+        (Some(path), _) => { outputs.insert("opt-record", path); }
+        (None, Some(format)) => {
+            let mut ext = OsString::from("opt.");
+            ext.push(format);
+            outputs.insert("opt-record", output.with_extension(ext));
+        }
+        (None, None) => {}
+    }
+    if profile_use {
+        // Without a path, gcc reads the profile next to the object file and
+        // clang reads `default.profdata` from the working directory.
+        extra_hash_files.push(output.with_extension("gcda"));
+        extra_hash_files.push(PathBuf::from("default.profdata"));
+    }
     if need_explicit_dep_target {
         preprocessor_args.push("-MT".into());
         preprocessor_args.push(dep_target.unwrap_or(output.clone().into_os_string()));
@@ -325,8 +395,10 @@ where
         outputs: outputs,
         preprocessor_args: preprocessor_args,
         common_args: common_args,
+        unhashed_args,
         msvc_show_includes: false,
         profile_generate,
+        extra_hash_files,
     })
 }
 
@@ -345,8 +417,9 @@ pub fn preprocess<T>(creator: &T,
         Language::Cxx => "c++",
         Language::ObjectiveC => "objective-c",
         Language::ObjectiveCxx => "objective-c++",
+        Language::Cuda => "cuda",
     };
-    let mut cmd = creator.clone().new_command_sync(executable);
+    let mut cmd = shell::compiler_command(creator, executable);
     cmd.arg("-x").arg(language)
         .arg("-E");
     // When performing distributed compilation, line number info is important for error
@@ -359,6 +432,7 @@ pub fn preprocess<T>(creator: &T,
     cmd.arg(&parsed_args.input)
         .args(&parsed_args.preprocessor_args)
         .args(&parsed_args.common_args)
+        .args(&parsed_args.unhashed_args)
         .env_clear()
         .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)))
         .current_dir(cwd);
@@ -392,6 +466,7 @@ pub fn generate_compile_commands(path_transformer: &mut dist::PathTransformer,
         Language::Cxx => "c++",
         Language::ObjectiveC => "objective-c",
         Language::ObjectiveCxx => "objective-c++",
+        Language::Cuda => "cuda",
     };
     let mut arguments: Vec<OsString> = vec![
         "-x".into(), language.into(),
@@ -401,6 +476,7 @@ pub fn generate_compile_commands(path_transformer: &mut dist::PathTransformer,
     ];
     arguments.extend(parsed_args.preprocessor_args.clone());
     arguments.extend(parsed_args.common_args.clone());
+    arguments.extend(parsed_args.unhashed_args.clone());
     let command = CompileCommand {
         executable: executable.to_owned(),
         arguments: arguments,
@@ -409,12 +485,21 @@ pub fn generate_compile_commands(path_transformer: &mut dist::PathTransformer,
     };
 
     let dist_command = (|| {
+        // Files like profile data aren't sent to the build server.
+        if !parsed_args.extra_hash_files.is_empty() {
+            return None
+        }
+        // Compilations whose object file goes to stdout or nowhere run locally.
+        if parsed_args.output_target() != OutputTarget::File {
+            return None
+        }
         // https://gcc.gnu.org/onlinedocs/gcc-4.9.0/gcc/Overall-Options.html
         let language = match parsed_args.language {
             Language::C => "cpp-output",
             Language::Cxx => "c++-cpp-output",
             Language::ObjectiveC => "objective-c-cpp-output",
             Language::ObjectiveCxx => "objective-c++-cpp-output",
+            Language::Cuda => "cuda-cpp-output",
         };
         let mut arguments: Vec<String> = vec![
             "-x".into(), language.into(),
@@ -424,6 +509,7 @@ pub fn generate_compile_commands(path_transformer: &mut dist::PathTransformer,
         ];
         // We could do preprocessor_args here, but skip for consistency with msvc
         arguments.extend(dist::osstrings_to_strings(&parsed_args.common_args)?);
+        arguments.extend(dist::osstrings_to_strings(&parsed_args.unhashed_args)?);
         Some(dist::CompileCommand {
             executable: path_transformer.to_dist(&executable)?,
             arguments: arguments,
@@ -507,12 +593,13 @@ impl<'a> Iterator for ExpandIncludeFile<'a> {
 }
 
 #[cfg(test)]
-mod test {
+pub mod test {
     use std::fs::File;
     use std::io::Write;
 
     use super::*;
     use compiler::*;
+    use compiler::c::hash_key;
     use futures::Future;
     use mock_command::*;
     use test::utils::*;
@@ -520,7 +607,7 @@ mod test {
 
     fn _parse_arguments(arguments: &[String]) -> CompilerArguments<ParsedArguments> {
         let args = arguments.iter().map(OsString::from).collect::<Vec<_>>();
-        parse_arguments(&args, ".".as_ref(), &ARGS[..])
+        parse_arguments(&args, ".".as_ref(), &ARGS[..], &BTreeMap::new(), UnknownArgsPolicy::Hash)
     }
 
     #[test]
@@ -550,6 +637,56 @@ mod test {
         assert!(!msvc_show_includes);
     }
 
+    #[test]
+    fn test_parse_arguments_source_extensions() {
+        let parse = |input: &str, extensions: &BTreeMap<String, String>| {
+            match parse_arguments(&ovec!["-c", input, "-o", "foo.o"], ".".as_ref(), &ARGS[..], extensions, UnknownArgsPolicy::Hash) {
+                CompilerArguments::Ok(ParsedArguments { language, .. }) => Some(language),
+                CompilerArguments::CannotCache("unknown source language") => None,
+                o => panic!("Got unexpected parse result: {:?}", o),
+            }
+        };
+        let none = BTreeMap::new();
+        assert_eq!(parse("foo.ino", &none), None);
+        assert_eq!(parse("foo.cpp.in", &none), None);
+        let extensions = vec![
+            ("ino".to_owned(), "c++".to_owned()),
+            ("cpp.in".to_owned(), "c++".to_owned()),
+            ("bogus".to_owned(), "fortran".to_owned()),
+        ].into_iter().collect();
+        assert_eq!(parse("foo.ino", &extensions), Some(Language::Cxx));
+        assert_eq!(parse("foo.cpp.in", &extensions), Some(Language::Cxx));
+        assert_eq!(parse("foocpp.in", &extensions), None);
+        assert_eq!(parse("foo.bogus", &extensions), None);
+        // Extra extensions don't replace the built-in ones.
+        assert_eq!(parse("foo.c", &extensions), Some(Language::C));
+    }
+
+    #[test]
+    fn test_parse_arguments_unknown_args() {
+        let parse = |policy| {
+            let args = ovec!["-c", "foo.c", "-fno-such-flag", "-o", "foo.o"];
+            parse_arguments(&args, ".".as_ref(), &ARGS[..], &BTreeMap::new(), policy)
+        };
+        match parse(UnknownArgsPolicy::Hash) {
+            CompilerArguments::Ok(ParsedArguments { common_args, unhashed_args, .. }) => {
+                assert_eq!(common_args, ovec!["-fno-such-flag"]);
+                assert!(unhashed_args.is_empty());
+            }
+            o => panic!("Got unexpected parse result: {:?}", o),
+        }
+        match parse(UnknownArgsPolicy::Ignore) {
+            CompilerArguments::Ok(ParsedArguments { common_args, unhashed_args, .. }) => {
+                assert!(common_args.is_empty());
+                assert_eq!(unhashed_args, ovec!["-fno-such-flag"]);
+            }
+            o => panic!("Got unexpected parse result: {:?}", o),
+        }
+        for &policy in &[UnknownArgsPolicy::Uncacheable, UnknownArgsPolicy::WarnOnce] {
+            assert_eq!(parse(policy), CompilerArguments::UnknownFlag("-fno-such-flag".into()));
+        }
+    }
+
     #[test]
     fn test_parse_arguments_default_name() {
         let args = stringvec!["-c", "foo.c"];
@@ -606,6 +743,23 @@ mod test {
         assert!(!msvc_show_includes);
     }
 
+    #[test]
+    fn test_parse_arguments_split_dwarf_paths() {
+        let outputs = |args: Vec<String>| match _parse_arguments(&args) {
+            CompilerArguments::Ok(args) => args.outputs,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        let outputs1 = outputs(stringvec!["-gsplit-dwarf", "-c", "src/foo.cpp", "-o", "obj/foo.o"]);
+        assert_map_contains!(outputs1,
+                             ("obj", PathBuf::from("obj/foo.o")),
+                             ("dwo", PathBuf::from("obj/foo.dwo")));
+        // Without `-o` both land in the current directory.
+        let outputs2 = outputs(stringvec!["-gsplit-dwarf", "-c", "src/foo.cpp"]);
+        assert_map_contains!(outputs2,
+                             ("obj", PathBuf::from("foo.o")),
+                             ("dwo", PathBuf::from("foo.dwo")));
+    }
+
     #[test]
     fn test_parse_arguments_coverage_outputs_gcno() {
         let args = stringvec!["--coverage", "-c", "foo.cpp", "-o", "foo.o"];
@@ -618,6 +772,7 @@ mod test {
             msvc_show_includes,
             common_args,
             profile_generate,
+            extra_hash_files: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -648,6 +803,7 @@ mod test {
             msvc_show_includes,
             common_args,
             profile_generate,
+            extra_hash_files: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -678,6 +834,7 @@ mod test {
             msvc_show_includes,
             common_args,
             profile_generate,
+            extra_hash_files: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -882,9 +1039,35 @@ mod test {
     }
 
     #[test]
-    fn test_parse_arguments_pgo() {
-        assert_eq!(CompilerArguments::CannotCache("-fprofile-use"),
-                   _parse_arguments(&stringvec!["-c", "foo.c", "-fprofile-use", "-o", "foo.o"]));
+    fn test_parse_arguments_profile_use() {
+        let args = stringvec!["-c", "foo.c", "-fprofile-use", "-o", "foo.o"];
+        let ParsedArguments { common_args, extra_hash_files, .. } = match _parse_arguments(&args) {
+            CompilerArguments::Ok(args) => args,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(ovec!["-fprofile-use"], common_args);
+        assert_eq!(vec![PathBuf::from("foo.gcda"), PathBuf::from("default.profdata")],
+                   extra_hash_files);
+
+        let args = stringvec!["-c", "foo.c", "-fprofile-use=prof/foo.gcda", "-o", "foo.o"];
+        let ParsedArguments { common_args, extra_hash_files, .. } = match _parse_arguments(&args) {
+            CompilerArguments::Ok(args) => args,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(ovec!["-fprofile-use=prof/foo.gcda"], common_args);
+        assert_eq!(vec![PathBuf::from("prof/foo.gcda")], extra_hash_files);
+    }
+
+    #[test]
+    fn test_parse_arguments_profile_generate_dir() {
+        let args = stringvec!["-c", "foo.c", "-fprofile-generate=prof", "-o", "foo.o"];
+        let ParsedArguments { common_args, profile_generate, extra_hash_files, .. } = match _parse_arguments(&args) {
+            CompilerArguments::Ok(args) => args,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(ovec!["-fprofile-generate=prof"], common_args);
+        assert!(profile_generate);
+        assert!(extra_hash_files.is_empty());
     }
 
     #[test]
@@ -924,6 +1107,76 @@ mod test {
         assert!(!msvc_show_includes);
     }
 
+    #[test]
+    fn test_parse_arguments_pic_flags() {
+        for flag in &["-fPIC", "-fpic", "-fno-pic", "-fPIE", "-fno-PIE"] {
+            let args = stringvec!["-c", *flag, "foo.c"];
+            let ParsedArguments { preprocessor_args, common_args, .. } = match _parse_arguments(&args) {
+                CompilerArguments::Ok(args) => args,
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            };
+            assert!(preprocessor_args.is_empty());
+            assert_eq!(ovec![*flag], common_args);
+        }
+    }
+
+    /// Assert that compiling with each of `flag_sets` has a cache key of its
+    /// own, with `key` giving the cache key for a set of flags.
+    pub fn assert_distinct_keys<F>(flag_sets: &[&[&str]], key: F)
+        where F: Fn(&[&str]) -> String,
+    {
+        let keys = flag_sets.iter().map(|flags| key(flags)).collect::<Vec<_>>();
+        for (i, a) in keys.iter().enumerate() {
+            for (j, b) in keys.iter().enumerate().skip(i + 1) {
+                assert!(a != b, "{:?} and {:?} have the same key", flag_sets[i], flag_sets[j]);
+            }
+        }
+    }
+
+    /// The arguments of compiling `foo.c` with `flags`.
+    fn parse_flags(flags: &[&str]) -> ParsedArguments {
+        let mut args = stringvec!["-c"];
+        args.extend(flags.iter().map(|s| s.to_string()));
+        args.push("foo.c".to_owned());
+        match _parse_arguments(&args) {
+            CompilerArguments::Ok(args) => args,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        }
+    }
+
+    /// The cache key of compiling `foo.c` with `flags`.
+    fn flags_key(flags: &[&str]) -> String {
+        let parsed = parse_flags(flags);
+        hash_key("abcd", Language::C, &parsed.common_args, &[], &b"int x;"[..], &[])
+    }
+
+    #[test]
+    fn test_hash_key_pic_flags() {
+        assert_distinct_keys(&[&[], &["-fPIC"], &["-fpic"], &["-fno-pic"]], flags_key);
+    }
+
+    #[test]
+    fn test_hash_key_debug_flags() {
+        // The last of conflicting debug levels wins, so their order matters too.
+        assert_distinct_keys(&[&[], &["-g"], &["-g0"], &["-g1"], &["-g2"], &["-g3"],
+                               &["-ggdb"], &["-ggdb3"], &["-gdwarf-4"], &["-gline-tables-only"],
+                               &["-g", "-g0"], &["-g0", "-g"]],
+                             flags_key);
+    }
+
+    #[test]
+    fn test_hash_key_werror_flags() {
+        let flag_sets: &[&[&str]] = &[&["-Wall"],
+                                      &["-Wall", "-Werror"],
+                                      &["-Wall", "-Werror", "-Wno-error=unused-variable"],
+                                      &["-Wall", "-Werror=unused-variable"],
+                                      &["-Wall", "-Werror", "-w"]];
+        for flags in flag_sets {
+            assert!(parse_flags(flags).preprocessor_args.is_empty());
+        }
+        assert_distinct_keys(flag_sets, flags_key);
+    }
+
     #[test]
     fn test_compile_simple() {
         let creator = new_creator();
@@ -935,8 +1188,10 @@ mod test {
             outputs: vec![("obj", "foo.o".into())].into_iter().collect(),
             preprocessor_args: vec!(),
             common_args: vec!(),
+            unhashed_args: vec!(),
             msvc_show_includes: false,
             profile_generate: false,
+            extra_hash_files: vec!(),
         };
         let compiler = &f.bins[0];
         // Compiler invocation.
@@ -0,0 +1,217 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A log of whether each cacheable compilation hit in the cache, and why it
+//! missed if it did, as one JSON object per line.
+
+use compiler::{CompileResult, MissType};
+use serde_json;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// How a compilation fared in the cache.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogResult {
+    Hit,
+    Miss,
+    Uncacheable,
+}
+
+/// Why a compilation missed in the cache.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MissReason {
+    /// The source file hasn't been compiled since the server started.
+    FirstSeen,
+    /// The compiler changed since the source file was last compiled.
+    VersionSkew,
+    /// The arguments that are part of the cache key changed.
+    FlagChange,
+    /// The source file or a file it includes changed.
+    InputChange,
+    /// Nothing changed, but the entry is no longer in the cache.
+    Cold,
+    /// `SCCACHE_RECACHE` was set.
+    Forced,
+    /// The cache didn't answer in time.
+    Timeout,
+    /// The entry couldn't be read.
+    ReadError,
+    /// The entry had warnings, and `SCCACHE_RECOMPILE_WARNINGS` was set.
+    Warnings,
+}
+
+/// A compilation to log, as identified when hashing it.
+pub struct Compile {
+    pub compiler: PathBuf,
+    pub compiler_digest: String,
+    pub args_digest: String,
+    pub source: PathBuf,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    key: &'a str,
+    result: LogResult,
+    reason: Option<MissReason>,
+    compiler: &'a Path,
+    source: &'a Path,
+}
+
+/// What a source file was last compiled with.
+struct Seen {
+    key: String,
+    compiler_digest: String,
+    args_digest: String,
+}
+
+/// Writes the log, remembering each source file's last compilation to tell
+/// why the next one missed.
+pub struct MissLog<W> {
+    out: W,
+    seen: HashMap<PathBuf, Seen>,
+}
+
+impl<W: Write> MissLog<W> {
+    pub fn new(out: W) -> MissLog<W> {
+        MissLog { out, seen: HashMap::new() }
+    }
+
+    /// Why `compile` missed in the cache under `key`, if it missed for no
+    /// more specific reason than the key not being there.
+    fn reason(&self, compile: &Compile, key: &str) -> MissReason {
+        match self.seen.get(&compile.source) {
+            None => MissReason::FirstSeen,
+            Some(seen) if seen.compiler_digest != compile.compiler_digest => MissReason::VersionSkew,
+            Some(seen) if seen.args_digest != compile.args_digest => MissReason::FlagChange,
+            Some(seen) if seen.key == key => MissReason::Cold,
+            Some(_) => MissReason::InputChange,
+        }
+    }
+
+    /// Log the `result` of `compile` under `key`. Compilations that failed
+    /// aren't logged.
+    pub fn record(&mut self, compile: &Compile, key: &str, result: &CompileResult) -> io::Result<()> {
+        let (result, reason) = match *result {
+            CompileResult::CacheHit(_) => (LogResult::Hit, None),
+            CompileResult::CacheMiss(ref miss_type, _, _) => {
+                let reason = match *miss_type {
+                    MissType::Normal => self.reason(compile, key),
+                    MissType::ForcedRecache => MissReason::Forced,
+                    MissType::TimedOut => MissReason::Timeout,
+                    MissType::CacheReadError => MissReason::ReadError,
+                    MissType::Warnings => MissReason::Warnings,
+                };
+                (LogResult::Miss, Some(reason))
+            }
+            CompileResult::NotCacheable => (LogResult::Uncacheable, None),
+            CompileResult::Error |
+            CompileResult::CompileFailed => return Ok(()),
+        };
+        let record = Record {
+            key,
+            result,
+            reason,
+            compiler: &compile.compiler,
+            source: &compile.source,
+        };
+        let mut line = serde_json::to_vec(&record).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        line.push(b'\n');
+        self.out.write_all(&line)?;
+        self.seen.insert(compile.source.clone(), Seen {
+            key: key.to_owned(),
+            compiler_digest: compile.compiler_digest.clone(),
+            args_digest: compile.args_digest.clone(),
+        });
+        Ok(())
+    }
+}
+
+lazy_static! {
+    /// The log at the path in `SCCACHE_MISS_LOG`, if it's set.
+    static ref MISS_LOG: Option<Mutex<MissLog<File>>> = {
+        env::var_os("SCCACHE_MISS_LOG").and_then(|path| {
+            match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(f) => Some(Mutex::new(MissLog::new(f))),
+                Err(e) => {
+                    warn!("Couldn't open the miss log {:?}: {}", path, e);
+                    None
+                }
+            }
+        })
+    };
+}
+
+/// Whether compilations are being logged.
+pub fn enabled() -> bool {
+    MISS_LOG.is_some()
+}
+
+/// Log the `result` of `compile` under `key`, if compilations are being logged.
+pub fn record(compile: &Compile, key: &str, result: &CompileResult) {
+    if let Some(ref log) = *MISS_LOG {
+        if let Err(e) = log.lock().unwrap().record(compile, key, result) {
+            warn!("Couldn't write to the miss log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future;
+    use std::time::Duration;
+
+    fn compile(compiler_digest: &str, args_digest: &str) -> Compile {
+        Compile {
+            compiler: "/usr/bin/cc".into(),
+            compiler_digest: compiler_digest.to_owned(),
+            args_digest: args_digest.to_owned(),
+            source: "/src/foo.c".into(),
+        }
+    }
+
+    fn miss() -> CompileResult {
+        CompileResult::CacheMiss(MissType::Normal, Duration::from_secs(0), Box::new(future::empty()))
+    }
+
+    #[test]
+    fn test_miss_reasons() {
+        let mut log = MissLog::new(vec![]);
+        log.record(&compile("cc1", "a1"), "k1", &miss()).unwrap();
+        log.record(&compile("cc1", "a1"), "k1", &CompileResult::CacheHit(Duration::from_secs(0))).unwrap();
+        log.record(&compile("cc1", "a1"), "k1", &miss()).unwrap();
+        log.record(&compile("cc1", "a1"), "k2", &miss()).unwrap();
+        log.record(&compile("cc1", "a2"), "k3", &miss()).unwrap();
+        log.record(&compile("cc2", "a2"), "k4", &miss()).unwrap();
+        log.record(&compile("cc2", "a2"), "k4", &CompileResult::CompileFailed).unwrap();
+        log.record(&compile("cc2", "a2"), "k4", &CompileResult::NotCacheable).unwrap();
+        let lines = String::from_utf8(log.out).unwrap();
+        let lines = lines.lines().collect::<Vec<_>>();
+        assert_eq!(lines, vec![
+            r#"{"key":"k1","result":"miss","reason":"first-seen","compiler":"/usr/bin/cc","source":"/src/foo.c"}"#,
+            r#"{"key":"k1","result":"hit","reason":null,"compiler":"/usr/bin/cc","source":"/src/foo.c"}"#,
+            r#"{"key":"k1","result":"miss","reason":"cold","compiler":"/usr/bin/cc","source":"/src/foo.c"}"#,
+            r#"{"key":"k2","result":"miss","reason":"input-change","compiler":"/usr/bin/cc","source":"/src/foo.c"}"#,
+            r#"{"key":"k3","result":"miss","reason":"flag-change","compiler":"/usr/bin/cc","source":"/src/foo.c"}"#,
+            r#"{"key":"k4","result":"miss","reason":"version-skew","compiler":"/usr/bin/cc","source":"/src/foo.c"}"#,
+            r#"{"key":"k4","result":"uncacheable","reason":null,"compiler":"/usr/bin/cc","source":"/src/foo.c"}"#,
+        ]);
+    }
+}
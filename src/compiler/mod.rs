@@ -18,8 +18,14 @@ mod c;
 mod clang;
 mod compiler;
 mod gcc;
+mod miss_log;
 mod msvc;
-mod pkg;
+mod nvcc;
+pub mod pkg;
 mod rust;
+mod shell;
 
+pub use compiler::c::CCompilerKind;
 pub use compiler::compiler::*;
+pub use compiler::rust::is_rustc_path;
+pub use compiler::shell::compiler_command;
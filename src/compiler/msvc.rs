@@ -21,7 +21,8 @@ use ::compiler::{
     write_temp_file,
 };
 use compiler::args::*;
-use compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments};
+use compiler::c::{self, CCompilerImpl, CCompilerKind, Language, ParsedArguments};
+use compiler::shell;
 use dist;
 use local_encoding::{Encoding, Encoder};
 use log::LogLevel::Debug;
@@ -31,7 +32,8 @@ use mock_command::{
     CommandCreatorSync,
     RunCommand,
 };
-use std::collections::{HashMap,HashSet};
+use config::{CONFIG, UnknownArgsPolicy};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::io::{
@@ -61,7 +63,7 @@ impl CCompilerImpl for MSVC {
                        arguments: &[OsString],
                        cwd: &Path) -> CompilerArguments<ParsedArguments>
     {
-        parse_arguments(arguments, cwd, self.is_clang)
+        parse_arguments(arguments, cwd, self.is_clang, &CONFIG.source_extensions.msvc, CONFIG.unknown_args)
     }
 
     fn preprocess<T>(&self,
@@ -92,6 +94,47 @@ fn from_local_codepage(bytes: &Vec<u8>) -> io::Result<String> {
     Encoding::OEM.to_string(bytes)
 }
 
+/// Find the prefix of the `-showIncludes` line for the `test.h` in `dir` in
+/// `output`.
+///
+/// Localized toolchains translate the prefix (`Note: including file: ` in
+/// English, `Hinweis: Einlesen der Datei: ` in German, and so on), so rather
+/// than looking for any particular text this goes by the structure of the
+/// line: the prefix is everything up to the space after which the rest of
+/// the line names the header.
+fn find_showincludes_prefix(output: &str, dir: &Path) -> Option<String> {
+    for line in output.lines() {
+        let line = line.trim_right();
+        if !line.ends_with("test.h") {
+            continue
+        }
+        for (i, c) in line.char_indices().rev() {
+            if c != ' ' {
+                continue
+            }
+            let path = dir.join(&line[i + 1..]);
+            // See if the rest of this line is a full pathname.
+            if path.exists() {
+                // Everything from the beginning of the line
+                // to this index is the prefix.
+                return Some(line[..i+1].to_owned());
+            }
+        }
+    }
+    None
+}
+
+/// Return the path named by `line` if it's a line of `-showIncludes` output
+/// with the `prefix` detected by `detect_showincludes_prefix`. Nested
+/// includes indent the path by an extra space for each level.
+fn parse_showincludes_line<'a>(line: &'a str, prefix: &str) -> Option<&'a str> {
+    if !prefix.is_empty() && line.starts_with(prefix) {
+        Some(line[prefix.len()..].trim())
+    } else {
+        None
+    }
+}
+
 /// Detect the prefix included in the output of MSVC's -showIncludes output.
 pub fn detect_showincludes_prefix<T>(creator: &T,
                                      exe: &OsStr,
@@ -105,7 +148,7 @@ pub fn detect_showincludes_prefix<T>(creator: &T,
                                 b"#include \"test.h\"\n".to_vec());
 
     let exe = exe.to_os_string();
-    let mut creator = creator.clone();
+    let creator = creator.clone();
     let pool = pool.clone();
     let write2 = write.and_then(move |(tempdir, input)| {
         let header = tempdir.path().join("test.h");
@@ -118,7 +161,7 @@ pub fn detect_showincludes_prefix<T>(creator: &T,
         })
     });
     let output = write2.and_then(move |(tempdir, input)| {
-        let mut cmd = creator.new_command_sync(&exe);
+        let mut cmd = shell::compiler_command(&creator, &exe);
         cmd.args(&["-nologo", "-showIncludes", "-c", "-Fonul", "-I."])
             .arg(&input)
             .current_dir(&tempdir.path())
@@ -146,22 +189,8 @@ pub fn detect_showincludes_prefix<T>(creator: &T,
         let process::Output { stdout: stdout_bytes, .. } = output;
         let stdout = from_local_codepage(&stdout_bytes)
             .chain_err(|| "Failed to convert compiler stdout while detecting showIncludes prefix")?;
-        for line in stdout.lines() {
-            if !line.ends_with("test.h") {
-                continue
-            }
-            for (i, c) in line.char_indices().rev() {
-                if c != ' ' {
-                    continue
-                }
-                let path = tempdir.path().join(&line[i + 1..]);
-                // See if the rest of this line is a full pathname.
-                if path.exists() {
-                    // Everything from the beginning of the line
-                    // to this index is the prefix.
-                    return Ok(line[..i+1].to_owned());
-                }
-            }
+        if let Some(prefix) = find_showincludes_prefix(&stdout, tempdir.path()) {
+            return Ok(prefix)
         }
         drop(tempdir);
 
@@ -235,10 +264,13 @@ static ARGS: [(ArgInfo, MSVCArgAttribute); 22] = [
     take_arg!("@", Path, Concatenated, TooHard),
 ];
 
-pub fn parse_arguments(arguments: &[OsString], cwd: &Path, is_clang: bool) -> CompilerArguments<ParsedArguments> {
+pub fn parse_arguments(arguments: &[OsString], cwd: &Path, is_clang: bool,
+                       source_extensions: &BTreeMap<String, String>,
+                       unknown_args: UnknownArgsPolicy) -> CompilerArguments<ParsedArguments> {
     let mut output_arg = None;
     let mut input_arg = None;
     let mut common_args = vec!();
+    let mut unhashed_args = vec!();
     let mut preprocessor_args = vec!();
     let mut compilation = false;
     let mut debug_info = false;
@@ -295,7 +327,15 @@ pub fn parse_arguments(arguments: &[OsString], cwd: &Path, is_clang: bool) -> Co
                         }
                         input_arg = Some(val.clone());
                     }
-                    Argument::UnknownFlag(ref flag) => common_args.push(flag.clone()),
+                    Argument::UnknownFlag(ref flag) => {
+                        if !c::unknown_flag_is_cacheable(flag, unknown_args) {
+                            return CompilerArguments::UnknownFlag(flag.clone());
+                        }
+                        match unknown_args {
+                            UnknownArgsPolicy::Ignore => unhashed_args.push(flag.clone()),
+                            _ => common_args.push(flag.clone()),
+                        }
+                    }
                     _ => unreachable!(),
                 }
             }
@@ -316,8 +356,12 @@ pub fn parse_arguments(arguments: &[OsString], cwd: &Path, is_clang: bool) -> Co
         let args = match item.data {
             Some(SplitDwarf) |
             Some(ProfileGenerate) |
+            Some(ProfileUse) |
+            Some(ExtraHashFile) |
             Some(TestCoverage) |
             Some(Coverage) |
+            Some(OptRecord) |
+            Some(OptRecordFile) |
             Some(DoCompilation) |
             Some(Language) |
             Some(Output) |
@@ -359,9 +403,12 @@ pub fn parse_arguments(arguments: &[OsString], cwd: &Path, is_clang: bool) -> Co
     }
     let (input, language) = match input_arg {
         Some(i) => {
-            match Language::from_file_name(Path::new(&i)) {
+            match Language::from_file_name_with(Path::new(&i), source_extensions) {
                 Some(l) => (i.to_owned(), l),
-                None => return CompilerArguments::CannotCache("unknown source language"),
+                None => {
+                    debug!("Not caching compilation of {:?}: unrecognized source extension", i);
+                    return CompilerArguments::CannotCache("unknown source language")
+                }
             }
         }
         // We can't cache compilation without an input.
@@ -398,8 +445,10 @@ pub fn parse_arguments(arguments: &[OsString], cwd: &Path, is_clang: bool) -> Co
         outputs: outputs,
         preprocessor_args: preprocessor_args,
         common_args: common_args,
+        unhashed_args,
         msvc_show_includes: show_includes,
         profile_generate: false,
+        extra_hash_files: vec!(),
     })
 }
 
@@ -454,12 +503,13 @@ pub fn preprocess<T>(creator: &T,
                      -> SFuture<process::Output>
     where T: CommandCreatorSync
 {
-    let mut cmd = creator.clone().new_command_sync(executable);
+    let mut cmd = shell::compiler_command(creator, executable);
     cmd.arg("-E")
         .arg(&parsed_args.input)
         .arg("-nologo")
         .args(&parsed_args.preprocessor_args)
         .args(&parsed_args.common_args)
+        .args(&parsed_args.unhashed_args)
         .env_clear()
         .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)))
         .current_dir(&cwd);
@@ -490,8 +540,8 @@ pub fn preprocess<T>(creator: &T,
             let mut deps = HashSet::new();
             let mut stderr_bytes = vec!();
             for line in stderr.lines() {
-                if line.starts_with(&includes_prefix) {
-                    let dep = normpath(line[includes_prefix.len()..].trim());
+                if let Some(dep) = parse_showincludes_line(line, &includes_prefix) {
+                    let dep = normpath(dep);
                     trace!("included: {}", dep);
                     if deps.insert(dep.clone()) && !dep.contains(' ') {
                         write!(f, "{} ", dep)?;
@@ -559,6 +609,7 @@ fn generate_compile_commands(path_transformer: &mut dist::PathTransformer,
     ];
     arguments.extend(parsed_args.preprocessor_args.clone());
     arguments.extend(parsed_args.common_args.clone());
+    arguments.extend(parsed_args.unhashed_args.clone());
 
     let command = CompileCommand {
         executable: executable.to_owned(),
@@ -583,6 +634,7 @@ fn generate_compile_commands(path_transformer: &mut dist::PathTransformer,
         // to misidentification of flags like -DYNAMICBASE (though in that specific
         // case we're safe as it only applies to link time, which sccache avoids).
         arguments.extend(dist::osstrings_to_strings(&parsed_args.common_args)?);
+        arguments.extend(dist::osstrings_to_strings(&parsed_args.unhashed_args)?);
 
         Some(dist::CompileCommand {
             executable: path_transformer.to_dist(&executable)?,
@@ -604,11 +656,12 @@ mod test {
     use futures::Future;
     use futures_cpupool::CpuPool;
     use mock_command::*;
+    use std::io::Read;
     use super::*;
     use test::utils::*;
 
     fn parse_arguments(arguments: &[OsString]) -> CompilerArguments<ParsedArguments> {
-        super::parse_arguments(arguments, &env::current_dir().unwrap(), false)
+        super::parse_arguments(arguments, &env::current_dir().unwrap(), false, &BTreeMap::new(), UnknownArgsPolicy::Hash)
     }
 
     #[test]
@@ -628,6 +681,50 @@ mod test {
         assert_eq!("blah: ", detect_showincludes_prefix(&creator, "cl.exe".as_ref(), Vec::new(), &pool).wait().unwrap());
     }
 
+    #[test]
+    fn test_detect_showincludes_prefix_localized() {
+        let f = TestFixture::new();
+        let header = f.touch("test.h").unwrap();
+        let header = header.to_str().unwrap();
+        for prefix in &["Hinweis: Einlesen der Datei: ",
+                        "\u{30e1}\u{30e2}: \u{30a4}\u{30f3}\u{30af}\u{30eb}\u{30fc}\u{30c9} \u{30d5}\u{30a1}\u{30a4}\u{30eb}:  "] {
+            let output = format!("test.c\r\n{}{}\r\n", prefix, header);
+            assert_eq!(Some(prefix.to_string()), find_showincludes_prefix(&output, f.tempdir.path()));
+        }
+        assert_eq!(None, find_showincludes_prefix("test.c\r\n", f.tempdir.path()));
+    }
+
+    #[test]
+    fn test_preprocess_localized_showincludes() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let parsed_args = ParsedArguments {
+            input: "foo.c".into(),
+            language: Language::C,
+            depfile: Some("foo.d".into()),
+            outputs: vec![("obj", "foo.obj".into())].into_iter().collect(),
+            preprocessor_args: vec!(),
+            common_args: vec!(),
+            unhashed_args: vec!(),
+            msvc_show_includes: false,
+            profile_generate: false,
+            extra_hash_files: vec!(),
+        };
+        let compiler = &f.bins[0];
+        for prefix in &["Hinweis: Einlesen der Datei: ",
+                        "\u{30e1}\u{30e2}: \u{30a4}\u{30f3}\u{30af}\u{30eb}\u{30fc}\u{30c9} \u{30d5}\u{30a1}\u{30a4}\u{30eb}:  "] {
+            let stderr = format!("foo.c\r\n{0}bar.h\r\n{0} baz.h\r\nwarning\r\n", prefix);
+            next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessed", &stderr)));
+            let output = preprocess(&creator, &compiler, &parsed_args, f.tempdir.path(), &[], false, prefix)
+                .wait().unwrap();
+            assert_eq!("foo.c\nwarning\n", String::from_utf8(output.stderr).unwrap());
+            let mut depfile = String::new();
+            File::open(f.tempdir.path().join("foo.d")).unwrap().read_to_string(&mut depfile).unwrap();
+            assert_eq!("foo.obj: foo.c bar.h baz.h \nfoo.c:\nbar.h:\nbaz.h:\n", depfile);
+        }
+        assert_eq!(0, creator.lock().unwrap().children.len());
+    }
+
     #[test]
     fn test_parse_arguments_simple() {
         let args = ovec!["-c", "foo.c", "-Fofoo.obj"];
@@ -845,8 +942,10 @@ mod test {
             outputs: vec![("obj", "foo.obj".into())].into_iter().collect(),
             preprocessor_args: vec!(),
             common_args: vec!(),
+            unhashed_args: vec!(),
             msvc_show_includes: false,
             profile_generate: false,
+            extra_hash_files: vec!(),
         };
         let compiler = &f.bins[0];
         // Compiler invocation.
@@ -876,8 +975,10 @@ mod test {
                           ("pdb", pdb.into())].into_iter().collect(),
             preprocessor_args: vec!(),
             common_args: vec!(),
+            unhashed_args: vec!(),
             msvc_show_includes: false,
             profile_generate: false,
+            extra_hash_files: vec!(),
         };
         let compiler = &f.bins[0];
         // Compiler invocation.
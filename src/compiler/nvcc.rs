@@ -0,0 +1,616 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The NVIDIA CUDA compiler driver. nvcc splits each compilation into host
+//! and device steps that it runs with temporary files of its own, so only its
+//! own command line is hashed: the preprocessed source, which includes every
+//! header, and the flags, where the device architectures (`-gencode`,
+//! `-arch`, `-code`) and the options passed to the device tools (`-Xptxas`)
+//! are normalized to a single spelling. The host compiler nvcc runs, given by
+//! `-ccbin` or found in `PATH`, is hashed too.
+
+use ::compiler::{
+    gcc,
+    Cacheable,
+    CompilerArguments,
+    CompileCommand,
+};
+use compiler::args::*;
+use compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments};
+use compiler::gcc::GCCArgAttribute::*;
+use compiler::shell;
+use config::{CONFIG, UnknownArgsPolicy};
+use dist;
+use log::LogLevel::Trace;
+use mock_command::{
+    CommandCreatorSync,
+    RunCommand,
+};
+use futures::Future;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::process;
+use util::run_input_output;
+use which::which_in;
+
+use errors::*;
+
+/// A unit struct on which to implement `CCompilerImpl`.
+#[derive(Clone, Debug)]
+pub struct NVCC;
+
+impl CCompilerImpl for NVCC {
+    fn kind(&self) -> CCompilerKind { CCompilerKind::NVCC }
+    fn parse_arguments(&self,
+                       arguments: &[OsString],
+                       cwd: &Path) -> CompilerArguments<ParsedArguments>
+    {
+        let mut extensions = CONFIG.source_extensions.nvcc.clone();
+        extensions.entry("cu".to_owned()).or_insert_with(|| "cu".to_owned());
+        parse_arguments(arguments, cwd, &extensions, CONFIG.unknown_args)
+    }
+
+    fn preprocess<T>(&self,
+                     creator: &T,
+                     executable: &Path,
+                     parsed_args: &ParsedArguments,
+                     cwd: &Path,
+                     env_vars: &[(OsString, OsString)],
+                     may_dist: bool)
+                     -> SFuture<process::Output> where T: CommandCreatorSync
+    {
+        preprocess(creator, executable, parsed_args, cwd, env_vars, may_dist)
+    }
+
+    fn generate_compile_commands(&self,
+                                path_transformer: &mut dist::PathTransformer,
+                                executable: &Path,
+                                parsed_args: &ParsedArguments,
+                                cwd: &Path,
+                                env_vars: &[(OsString, OsString)])
+                                -> Result<(CompileCommand, Option<dist::CompileCommand>, Cacheable)>
+    {
+        generate_compile_commands(path_transformer, executable, parsed_args, cwd, env_vars)
+    }
+
+    fn host_compiler(&self,
+                     parsed_args: &ParsedArguments,
+                     cwd: &Path,
+                     env_vars: &[(OsString, OsString)]) -> Option<PathBuf>
+    {
+        Some(host_compiler(parsed_args, cwd, env_vars))
+    }
+}
+
+/// nvcc's flags, on top of and overriding gcc's. nvcc takes the values of
+/// options either separately or after a `=`.
+pub static ARGS: [(ArgInfo, gcc::GCCArgAttribute); 58] = [
+    flag!("--compile", DoCompilation),
+    take_arg!("--compiler-bindir", Path, CanBeSeparated('='), PassThrough),
+    take_arg!("--compiler-options", String, CanBeSeparated('='), PassThrough),
+    flag!("--cubin", DoCompilation),
+    flag!("--cuda", TooHard),
+    take_arg!("--define-macro", String, CanBeSeparated('='), PreprocessorArgument),
+    take_arg!("--dependency-output", Path, CanBeSeparated('='), PreprocessorArgument),
+    take_arg!("--dependency-target-name", String, CanBeSeparated('='), DepTarget),
+    flag!("--device-c", DoCompilation),
+    flag!("--device-w", DoCompilation),
+    flag!("--dryrun", TooHard),
+    flag!("--fatbin", DoCompilation),
+    take_arg!("--generate-code", String, CanBeSeparated('='), PassThrough),
+    flag!("--generate-dependencies", TooHard),
+    flag!("--generate-dependencies-with-compile", NeedDepTarget),
+    flag!("--generate-nonsystem-dependencies", TooHard),
+    flag!("--generate-nonsystem-dependencies-with-compile", NeedDepTarget),
+    take_arg!("--gpu-architecture", String, CanBeSeparated('='), PassThrough),
+    take_arg!("--gpu-code", String, CanBeSeparated('='), PassThrough),
+    take_arg!("--include-path", Path, CanBeSeparated('='), PreprocessorArgument),
+    flag!("--keep", TooHard),
+    take_arg!("--keep-dir", Path, CanBeSeparated('='), TooHard),
+    take_arg!("--linker-options", String, CanBeSeparated('='), PassThrough),
+    take_arg!("--maxrregcount", String, CanBeSeparated('='), PassThrough),
+    take_arg!("--nvlink-options", String, CanBeSeparated('='), PassThrough),
+    take_arg!("--output-file", Path, CanBeSeparated('='), Output),
+    take_arg!("--pre-include", Path, CanBeSeparated('='), PreprocessorArgument),
+    flag!("--preprocess", TooHard),
+    flag!("--ptx", DoCompilation),
+    take_arg!("--ptxas-options", String, CanBeSeparated('='), PassThrough),
+    take_arg!("--relocatable-device-code", String, CanBeSeparated('='), PassThrough),
+    take_arg!("--std", String, CanBeSeparated('='), PassThrough),
+    take_arg!("--system-include", Path, CanBeSeparated('='), PreprocessorArgument),
+    take_arg!("--threads", String, CanBeSeparated('='), PassThrough),
+    take_arg!("--undefine-macro", String, CanBeSeparated('='), PreprocessorArgument),
+    take_arg!("--x", String, CanBeSeparated('='), Language),
+    flag!("-G", PassThrough),
+    take_arg!("-Xcompiler", String, CanBeSeparated('='), PassThrough),
+    take_arg!("-Xlinker", String, CanBeSeparated('='), PassThrough),
+    take_arg!("-Xnvlink", String, CanBeSeparated('='), PassThrough),
+    take_arg!("-Xptxas", String, CanBeSeparated('='), PassThrough),
+    take_arg!("-arch", String, CanBeSeparated('='), PassThrough),
+    take_arg!("-ccbin", Path, CanBeSeparated('='), PassThrough),
+    take_arg!("-code", String, CanBeSeparated('='), PassThrough),
+    flag!("-cubin", DoCompilation),
+    flag!("-cuda", TooHard),
+    flag!("-dc", DoCompilation),
+    flag!("-dryrun", TooHard),
+    flag!("-dw", DoCompilation),
+    flag!("-fatbin", DoCompilation),
+    take_arg!("-gencode", String, CanBeSeparated('='), PassThrough),
+    flag!("-keep", TooHard),
+    take_arg!("-keep-dir", Path, CanBeSeparated('='), TooHard),
+    take_arg!("-maxrregcount", String, CanBeSeparated('='), PassThrough),
+    flag!("-ptx", DoCompilation),
+    take_arg!("-rdc", String, CanBeSeparated('='), PassThrough),
+    take_arg!("-std", String, CanBeSeparated('='), PassThrough),
+    take_arg!("-t", String, CanBeSeparated('='), PassThrough),
+];
+
+/// The short spelling of nvcc's long options that are kept in the arguments,
+/// so that compilations spelling them differently share cache entries.
+static ALIASES: [(&'static str, &'static str); 19] = [
+    ("--compiler-bindir", "-ccbin"),
+    ("--compiler-options", "-Xcompiler"),
+    ("--define-macro", "-D"),
+    ("--dependency-output", "-MF"),
+    ("--generate-code", "-gencode"),
+    ("--generate-dependencies-with-compile", "-MD"),
+    ("--generate-nonsystem-dependencies-with-compile", "-MMD"),
+    ("--gpu-architecture", "-arch"),
+    ("--gpu-code", "-code"),
+    ("--include-path", "-I"),
+    ("--linker-options", "-Xlinker"),
+    ("--maxrregcount", "-maxrregcount"),
+    ("--nvlink-options", "-Xnvlink"),
+    ("--pre-include", "-include"),
+    ("--ptxas-options", "-Xptxas"),
+    ("--relocatable-device-code", "-rdc"),
+    ("--std", "-std"),
+    ("--system-include", "-isystem"),
+    ("--undefine-macro", "-U"),
+];
+
+/// The flag selecting the compilation phase `arg` asks for, in its short
+/// spelling, if it's one whose output can be cached.
+fn phase_flag(arg: &OsStr) -> Option<&'static str> {
+    match arg.to_str() {
+        Some("-c") | Some("--compile") => Some("-c"),
+        Some("-dc") | Some("--device-c") => Some("-dc"),
+        Some("-dw") | Some("--device-w") => Some("-dw"),
+        Some("-ptx") | Some("--ptx") => Some("-ptx"),
+        Some("-cubin") | Some("--cubin") => Some("-cubin"),
+        Some("-fatbin") | Some("--fatbin") => Some("-fatbin"),
+        _ => None,
+    }
+}
+
+/// The extension of the file nvcc writes for `phase` when not given `-o`,
+/// for the phases that don't write an object file.
+fn phase_extension(phase: &str) -> Option<&'static str> {
+    match phase {
+        "-ptx" => Some("ptx"),
+        "-cubin" => Some("cubin"),
+        "-fatbin" => Some("fatbin"),
+        _ => None,
+    }
+}
+
+/// The name of `language` as given to nvcc's `-x`.
+fn language_name(language: Language) -> Option<&'static str> {
+    match language {
+        Language::C => Some("c"),
+        Language::Cxx => Some("c++"),
+        Language::Cuda => Some("cu"),
+        Language::ObjectiveC | Language::ObjectiveCxx => None,
+    }
+}
+
+/// Rewrite the long options in `args` to their short spelling, joining the
+/// two-letter ones to their values as gcc's parser does.
+fn normalize_aliases(args: Vec<OsString>) -> Vec<OsString> {
+    let mut normalized = Vec::with_capacity(args.len());
+    let mut it = args.into_iter();
+    while let Some(arg) = it.next() {
+        let short = arg.to_str().and_then(|a| ALIASES.iter().find(|&&(long, _)| long == a)).map(|&(_, short)| short);
+        match short {
+            Some(short) if short.len() == 2 => {
+                let mut joined = OsString::from(short);
+                if let Some(value) = it.next() {
+                    joined.push(value);
+                }
+                normalized.push(joined);
+            }
+            Some(short) => normalized.push(short.into()),
+            None => normalized.push(arg),
+        }
+    }
+    normalized
+}
+
+/// Parse `arguments` as for gcc, with nvcc's flags, determining whether they
+/// are supported.
+pub fn parse_arguments(arguments: &[OsString],
+                       cwd: &Path,
+                       source_extensions: &BTreeMap<String, String>,
+                       unknown_args: UnknownArgsPolicy)
+                       -> CompilerArguments<ParsedArguments>
+{
+    let mut phases = arguments.iter().filter_map(|a| phase_flag(a)).collect::<Vec<_>>();
+    phases.dedup();
+    let explicit_output = arguments.iter().any(|a| {
+        a == "-o" || a.to_str().map_or(false, |a| a.starts_with("--output-file"))
+    });
+    let mut parsed = match gcc::parse_arguments(arguments, cwd, (&gcc::ARGS[..], &ARGS[..]),
+                                                source_extensions, unknown_args) {
+        CompilerArguments::Ok(parsed) => parsed,
+        other => return other,
+    };
+    if language_name(parsed.language).is_none() {
+        return CompilerArguments::CannotCache("unsupported source language")
+    }
+    let phase = match phases.len() {
+        1 => phases[0],
+        _ => return CompilerArguments::CannotCache("multiple compilation phases"),
+    };
+    if let (Some(extension), false) = (phase_extension(phase), explicit_output) {
+        let output = parsed.outputs["obj"].with_extension(extension);
+        // Named after the object file, it may also be the dependency target.
+        if let Some(object) = parsed.outputs.insert("obj", output.clone()) {
+            for arg in parsed.preprocessor_args.iter_mut() {
+                if *arg == object.as_os_str() {
+                    *arg = output.clone().into_os_string();
+                }
+            }
+        }
+    }
+    // The phase decides what's written, so it's part of the hash key.
+    parsed.common_args.push(phase.into());
+    parsed.preprocessor_args = normalize_aliases(parsed.preprocessor_args);
+    parsed.common_args = normalize_aliases(parsed.common_args);
+    CompilerArguments::Ok(parsed)
+}
+
+/// The host compiler nvcc uses when not given `-ccbin`, or given a directory.
+#[cfg(windows)]
+const DEFAULT_HOST_COMPILER: &'static str = "cl";
+#[cfg(not(windows))]
+const DEFAULT_HOST_COMPILER: &'static str = "gcc";
+
+/// The value of the `-ccbin` argument in `args`, if any.
+fn ccbin(args: &[OsString]) -> Option<OsString> {
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if arg == "-ccbin" {
+            return it.next().cloned()
+        }
+        if let Some(value) = arg.to_str().and_then(|a| if a.starts_with("-ccbin=") { Some(&a[7..]) } else { None }) {
+            return Some(value.into())
+        }
+    }
+    None
+}
+
+/// Find the host compiler nvcc would run for `parsed_args`: the one given
+/// by `-ccbin`, which may be a path, a directory containing it or a name
+/// to look up in `PATH`, or else the default one in `PATH`. A compiler that
+/// can't be found is left as its name, which hashes as missing.
+fn host_compiler(parsed_args: &ParsedArguments,
+                 cwd: &Path,
+                 env_vars: &[(OsString, OsString)]) -> PathBuf
+{
+    let path = env_vars.iter()
+        .find(|&&(ref k, _)| k == "PATH")
+        .map(|&(_, ref v)| v.clone());
+    let ccbin = ccbin(&parsed_args.common_args);
+    let found = match ccbin {
+        Some(ref ccbin) if cwd.join(ccbin).is_dir() => {
+            which_in(DEFAULT_HOST_COMPILER, Some(cwd.join(ccbin)), cwd)
+        }
+        Some(ref ccbin) if Path::new(ccbin).components().count() > 1 => {
+            Ok(cwd.join(ccbin))
+        }
+        Some(ref ccbin) => which_in(ccbin, path.as_ref(), cwd),
+        None => which_in(DEFAULT_HOST_COMPILER, path.as_ref(), cwd),
+    };
+    found.unwrap_or_else(|_| PathBuf::from(ccbin.unwrap_or_else(|| DEFAULT_HOST_COMPILER.into())))
+}
+
+/// Split the arguments generating dependencies off `args`, with `-MD` and
+/// `-MMD` replaced by `-M` and `-MM`, which generate them without compiling.
+fn split_dependency_args(args: &[OsString]) -> (Vec<OsString>, Vec<OsString>) {
+    let mut dep_args = vec![];
+    let mut other_args = vec![];
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.to_str() {
+            Some("-MD") => dep_args.push("-M".into()),
+            Some("-MMD") => dep_args.push("-MM".into()),
+            Some("-MP") => dep_args.push(arg.clone()),
+            Some("-MF") | Some("-MT") | Some("-MQ") => {
+                dep_args.push(arg.clone());
+                dep_args.extend(it.next().cloned());
+            }
+            _ => other_args.push(arg.clone()),
+        }
+    }
+    (dep_args, other_args)
+}
+
+pub fn preprocess<T>(creator: &T,
+                     executable: &Path,
+                     parsed_args: &ParsedArguments,
+                     cwd: &Path,
+                     env_vars: &[(OsString, OsString)],
+                     _may_dist: bool)
+                     -> SFuture<process::Output>
+    where T: CommandCreatorSync
+{
+    trace!("preprocess");
+    let language = match language_name(parsed_args.language) {
+        Some(language) => language,
+        None => return f_err("Unsupported source language for nvcc"),
+    };
+    let (mut dep_args, preprocessor_args) = split_dependency_args(&parsed_args.preprocessor_args);
+    let common_args = parsed_args.common_args.iter()
+        .filter(|a| phase_flag(a).is_none())
+        .collect::<Vec<_>>();
+    let relocatable = parsed_args.common_args.iter().any(|a| a == "-dc")
+        && !parsed_args.common_args.iter().any(|a| a == "-rdc");
+    let new_command = || {
+        let mut cmd = shell::compiler_command(creator, executable);
+        cmd.args(&preprocessor_args)
+            .args(&common_args)
+            .args(&parsed_args.unhashed_args);
+        // `-dc` implies `-rdc=true`, which defines `__CUDACC_RDC__`.
+        if relocatable {
+            cmd.arg("-rdc=true");
+        }
+        cmd.arg("-x").arg(language)
+            .arg(&parsed_args.input)
+            .env_clear()
+            .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)))
+            .current_dir(cwd);
+        cmd
+    };
+    // nvcc won't generate dependencies while preprocessing, so do that in a
+    // run of its own, writing them where `-MD` would.
+    let deps = if dep_args.iter().any(|a| a == "-M" || a == "-MM") {
+        if !dep_args.iter().any(|a| a == "-MF") {
+            if let Some(object) = parsed_args.outputs.get("obj") {
+                dep_args.push("-MF".into());
+                dep_args.push(object.with_extension("d").into());
+            }
+        }
+        let mut deps = new_command();
+        deps.args(&dep_args);
+        if log_enabled!(Trace) {
+            trace!("dependencies: {:?}", deps);
+        }
+        Some(run_input_output(deps, None))
+    } else {
+        None
+    };
+    // nvcc only takes `-E` after the other flags.
+    let mut cmd = new_command();
+    cmd.arg("-E");
+    if log_enabled!(Trace) {
+        trace!("preprocess: {:?}", cmd);
+    }
+    let preprocess = run_input_output(cmd, None);
+    match deps {
+        Some(deps) => Box::new(deps.join(preprocess).map(|(_, output)| output)),
+        None => preprocess,
+    }
+}
+
+pub fn generate_compile_commands(_path_transformer: &mut dist::PathTransformer,
+                                executable: &Path,
+                                parsed_args: &ParsedArguments,
+                                cwd: &Path,
+                                env_vars: &[(OsString, OsString)])
+                                -> Result<(CompileCommand, Option<dist::CompileCommand>, Cacheable)>
+{
+    trace!("compile");
+
+    let out_file = match parsed_args.outputs.get("obj") {
+        Some(obj) => obj,
+        None => {
+            return Err("Missing object file output".into())
+        }
+    };
+    let language = match language_name(parsed_args.language) {
+        Some(language) => language,
+        None => bail!("Unsupported source language for nvcc"),
+    };
+    // The compilation phase is among the common arguments.
+    let mut arguments: Vec<OsString> = vec![
+        "-x".into(), language.into(),
+        parsed_args.input.clone().into(),
+        "-o".into(), out_file.into(),
+    ];
+    arguments.extend(parsed_args.preprocessor_args.clone());
+    arguments.extend(parsed_args.common_args.clone());
+    arguments.extend(parsed_args.unhashed_args.clone());
+    let command = CompileCommand {
+        executable: executable.to_owned(),
+        arguments: arguments,
+        env_vars: env_vars.to_owned(),
+        cwd: cwd.to_owned(),
+    };
+
+    // nvcc compilations aren't distributed, their toolchains can't be packaged.
+    Ok((command, None, Cacheable::Yes))
+}
+
+#[cfg(test)]
+mod test {
+    use compiler::*;
+    use compiler::c::hash_key;
+    use futures::Future;
+    use mock_command::*;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use super::*;
+    use test::utils::*;
+
+    fn _parse_arguments(arguments: &[String]) -> CompilerArguments<ParsedArguments> {
+        let arguments = arguments.iter().map(OsString::from).collect::<Vec<_>>();
+        let extensions = vec![("cu".to_owned(), "cu".to_owned())].into_iter().collect();
+        parse_arguments(&arguments, ".".as_ref(), &extensions, UnknownArgsPolicy::Hash)
+    }
+
+    macro_rules! parses {
+        ( $( $s:expr ),* ) => {
+            match _parse_arguments(&[ $( $s.to_string(), )* ]) {
+                CompilerArguments::Ok(a) => a,
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_arguments_simple() {
+        let a = parses!("-c", "foo.cu", "-o", "foo.o");
+        assert_eq!(Some("foo.cu"), a.input.to_str());
+        assert_eq!(Language::Cuda, a.language);
+        assert_map_contains!(a.outputs, ("obj", PathBuf::from("foo.o")));
+        assert_eq!(1, a.outputs.len());
+        assert!(a.preprocessor_args.is_empty());
+        assert_eq!(ovec!["-c"], a.common_args);
+
+        let a = parses!("-c", "foo.cpp", "-o", "foo.o");
+        assert_eq!(Language::Cxx, a.language);
+        assert_eq!(CompilerArguments::CannotCache("unsupported source language"),
+                   _parse_arguments(&stringvec!["-c", "foo.m", "-o", "foo.o"]));
+    }
+
+    #[test]
+    fn test_parse_arguments_gencode() {
+        let a = parses!("-c", "foo.cu", "-gencode=arch=compute_70,code=sm_70",
+                        "-gencode", "arch=compute_80,code=sm_80", "-o", "foo.o");
+        assert_eq!(ovec!["-gencode", "arch=compute_70,code=sm_70", "-gencode", "arch=compute_80,code=sm_80", "-c"],
+                   a.common_args);
+        // Different spellings of the same flags are the same arguments.
+        let a = parses!("--compile", "foo.cu", "--generate-code=arch=compute_70,code=sm_70",
+                        "--ptxas-options=-v", "--include-path", "inc", "-o", "foo.o");
+        let b = parses!("-c", "foo.cu", "-gencode", "arch=compute_70,code=sm_70", "-Xptxas", "-v", "-Iinc",
+                        "-o", "foo.o");
+        assert_eq!(b.common_args, a.common_args);
+        assert_eq!(b.preprocessor_args, a.preprocessor_args);
+        assert_eq!(ovec!["-Iinc"], a.preprocessor_args);
+        // nvcc's `-G` doesn't take a value, unlike gcc's.
+        let a = parses!("-c", "foo.cu", "-G", "-o", "foo.o");
+        assert_eq!(ovec!["-G", "-c"], a.common_args);
+    }
+
+    #[test]
+    fn test_parse_arguments_phases() {
+        let a = parses!("-dc", "foo.cu", "-o", "foo.o");
+        assert_eq!(ovec!["-dc"], a.common_args);
+        assert_map_contains!(a.outputs, ("obj", PathBuf::from("foo.o")));
+        let a = parses!("--fatbin", "foo.cu");
+        assert_eq!(ovec!["-fatbin"], a.common_args);
+        assert_map_contains!(a.outputs, ("obj", PathBuf::from("foo.fatbin")));
+        let a = parses!("-ptx", "foo.cu", "-o", "kernels.ptx");
+        assert_map_contains!(a.outputs, ("obj", PathBuf::from("kernels.ptx")));
+        assert_eq!(CompilerArguments::CannotCache("multiple compilation phases"),
+                   _parse_arguments(&stringvec!["-c", "-ptx", "foo.cu"]));
+        assert_eq!(CompilerArguments::NotCompilation,
+                   _parse_arguments(&stringvec!["-dlink", "foo.o", "-o", "link.o"]));
+        for flag in &["-keep", "--dryrun", "-E", "--generate-dependencies"] {
+            assert_eq!(CompilerArguments::CannotCache(*flag),
+                       _parse_arguments(&stringvec!["-c", flag, "foo.cu", "-o", "foo.o"]));
+        }
+    }
+
+    #[test]
+    fn test_parse_arguments_dependencies() {
+        let a = parses!("-c", "foo.cu", "--generate-dependencies-with-compile", "--dependency-output", "foo.d",
+                        "-o", "foo.o");
+        assert_eq!(ovec!["-MD", "-MF", "foo.d", "-MT", "foo.o"], a.preprocessor_args);
+        let a = parses!("-fatbin", "foo.cu", "-MMD");
+        assert_eq!(ovec!["-MMD", "-MT", "foo.fatbin"], a.preprocessor_args);
+    }
+
+    #[test]
+    fn test_hash_key_phase() {
+        let key = |phase: &str| {
+            let a = parses!(phase, "foo.cu", "-o", "foo.o");
+            hash_key("abcd", Language::Cuda, &a.common_args, &[], &b"__global__ void f() {}"[..], &[])
+        };
+        assert_neq!(key("-c"), key("-dc"));
+        assert_neq!(key("-c"), key("-fatbin"));
+    }
+
+    #[test]
+    fn test_preprocess_dependencies() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let a = parses!("-dc", "foo.cu", "-MD", "-Iinc", "-gencode", "arch=compute_70,code=sm_70", "-o", "foo.o");
+        let runs = Arc::new(Mutex::new(vec![]));
+        for _ in 0..2 {
+            let runs = runs.clone();
+            next_command_calls(&creator, move |args| {
+                runs.lock().unwrap().push(args.to_vec());
+                Ok(MockChild::new(exit_status(0), "preprocessed", ""))
+            });
+        }
+        let output = preprocess(&creator, &f.bins[0], &a, f.tempdir.path(), &[], false).wait().unwrap();
+        assert_eq!(b"preprocessed", &output.stdout[..]);
+        let runs = runs.lock().unwrap();
+        assert_eq!(2, runs.len());
+        // Dependencies first, without compiling.
+        assert_eq!(ovec!["-Iinc", "-gencode", "arch=compute_70,code=sm_70", "-rdc=true", "-x", "cu", "foo.cu",
+                         "-M", "-MT", "foo.o", "-MF", "foo.d"],
+                   runs[0]);
+        assert_eq!(ovec!["-Iinc", "-gencode", "arch=compute_70,code=sm_70", "-rdc=true", "-x", "cu", "foo.cu",
+                         "-E"],
+                   runs[1]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_host_compiler() {
+        let f = TestFixture::new();
+        let dir = f.tempdir.path();
+        let gcc = mk_bin(&dir.join("bin"), "gcc").unwrap();
+        let gxx = mk_bin(&dir.join("bin"), "g++-9").unwrap();
+        let other_gcc = mk_bin(&dir.join("other"), "gcc").unwrap();
+        let env_vars = vec![(OsString::from("PATH"), dir.join("bin").into_os_string())];
+        let host = |a: &ParsedArguments| host_compiler(a, dir, &env_vars);
+
+        assert_eq!(gcc, host(&parses!("-c", "foo.cu")));
+        assert_eq!(gxx, host(&parses!("-c", "foo.cu", "-ccbin", "g++-9")));
+        assert_eq!(gxx, host(&parses!("-c", "foo.cu", "--compiler-bindir", "g++-9")));
+        assert_eq!(other_gcc, host(&parses!("-c", "foo.cu", "-ccbin", "other")));
+        assert_eq!(other_gcc, host(&parses!("-c", "foo.cu", "-ccbin=other/gcc")));
+        assert_eq!(PathBuf::from("clang"), host(&parses!("-c", "foo.cu", "-ccbin", "clang")));
+    }
+
+    #[test]
+    fn test_compile_phase() {
+        let a = parses!("-fatbin", "foo.cu", "-gencode", "arch=compute_70,code=sm_70");
+        let mut path_transformer = dist::PathTransformer::new();
+        let (command, dist_command, cacheable) = generate_compile_commands(&mut path_transformer,
+                                                                           "nvcc".as_ref(),
+                                                                           &a,
+                                                                           ".".as_ref(),
+                                                                           &[]).unwrap();
+        assert_eq!(ovec!["-x", "cu", "foo.cu", "-o", "foo.fatbin", "-gencode", "arch=compute_70,code=sm_70",
+                         "-fatbin"],
+                   command.arguments);
+        assert!(dist_command.is_none());
+        assert_eq!(Cacheable::Yes, cacheable);
+    }
+}
@@ -12,9 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use dist;
 use std::io;
 use std::fs::File;
+use tempfile;
 
-pub trait CompilerPackager {
+use errors::*;
+
+pub trait CompilerPackager: Send {
     fn write_pkg(self: Box<Self>, f: File) -> io::Result<()>;
 }
+
+/// Package a toolchain with `packager` without caching or submitting it, and
+/// return the id the archive would be submitted under.
+pub fn dry_run(packager: Box<CompilerPackager>) -> Result<String> {
+    let tmpfile = tempfile::NamedTempFile::new()?;
+    packager.write_pkg(tmpfile.reopen()?).chain_err(|| "failed to package toolchain")?;
+    dist::toolchain_archive_id(tmpfile.path())
+}
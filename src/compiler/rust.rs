@@ -15,6 +15,8 @@
 use compiler::{Cacheable, ColorMode, Compiler, CompilerArguments, CompileCommand, CompilerHasher, CompilerKind,
                pkg::CompilerPackager, Compilation, HashResult};
 use compiler::args::*;
+use config::CONFIG;
+use compiler::shell;
 use dist;
 use futures::{Future, future};
 use futures_cpupool::CpuPool;
@@ -23,7 +25,7 @@ use mock_command::{CommandCreatorSync, RunCommand};
 use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::env::consts::DLL_EXTENSION;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::fs::{self, File};
 use std::hash::Hash;
@@ -31,6 +33,7 @@ use std::io::{self, Read};
 use std::iter;
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::sync::{Once, ONCE_INIT};
 use std::time::Instant;
 use tempdir::TempDir;
 use util::{fmt_duration_as_secs, run_input_output, Digest};
@@ -73,6 +76,8 @@ pub struct RustHasher {
 pub struct ParsedArguments {
     /// The full commandline, with arguments and their values as pairs.
     arguments: Vec<(OsString, Option<OsString>)>,
+    /// The crate root source file.
+    input: PathBuf,
     /// The location of compiler outputs.
     output_dir: PathBuf,
     /// Paths to extern crates used in the compile.
@@ -83,6 +88,10 @@ pub struct ParsedArguments {
     crate_name: String,
     /// If dependency info is being emitted, the name of the dep info file.
     dep_info: Option<PathBuf>,
+    /// If metadata is being emitted, the name of the rmeta file.
+    metadata: Option<PathBuf>,
+    /// Whether `link` is among the `--emit` types.
+    emit_link: bool,
     /// The value of any `--color` option passed on the commandline.
     color_mode: ColorMode,
 }
@@ -111,12 +120,31 @@ lazy_static! {
     static ref ALLOWED_EMIT: HashSet<&'static str> = [
         "link",
         "dep-info",
+        "metadata",
     ].iter().map(|s| *s).collect();
 }
 
 /// Version number for cache key.
 const CACHE_VERSION: &[u8] = b"2";
 
+/// Whether `arg` is `-C incremental=...`, which enables incremental compilation.
+fn is_incremental(&(ref arg, ref val): &(OsString, Option<OsString>)) -> bool {
+    (arg == "-C" || arg == "--codegen") &&
+        val.as_ref().and_then(|v| v.to_str()).map_or(false, |v| v.starts_with("incremental="))
+}
+
+/// Log, the first time it happens, that compilations generating code aren't
+/// cached because incremental compilation is enabled, which would otherwise
+/// only be counted in the stats.
+fn warn_incremental() {
+    static WARNED: Once = ONCE_INIT;
+    WARNED.call_once(|| {
+        warn!("Not caching rustc compilations with incremental compilation enabled, only ones \
+               emitting just metadata such as `cargo check`'s. Set CARGO_INCREMENTAL=0 to cache \
+               `cargo build`.");
+    });
+}
+
 /// Calculate the SHA-1 digest of each file in `files` on background threads
 /// in `pool`.
 fn hash_all(files: Vec<String>, pool: &CpuPool) -> SFuture<Vec<String>>
@@ -146,7 +174,7 @@ fn hash_source_files<T>(creator: &T,
     // Get the full list of source files from rustc's dep-info.
     let temp_dir = ftry!(TempDir::new("sccache").chain_err(|| "Failed to create temp dir"));
     let dep_file = temp_dir.path().join("deps.d");
-    let mut cmd = creator.clone().new_command_sync(executable);
+    let mut cmd = shell::compiler_command(creator, executable);
     cmd.args(&arguments)
         .args(&["--emit", "dep-info"])
         .arg("-o")
@@ -220,7 +248,7 @@ fn get_compiler_outputs<T>(creator: &T,
                            env_vars: &[(OsString, OsString)]) -> SFuture<Vec<String>>
     where T: CommandCreatorSync,
 {
-    let mut cmd = creator.clone().new_command_sync(executable);
+    let mut cmd = shell::compiler_command(creator, executable);
     cmd.args(&arguments)
         .args(&["--print", "file-names"])
         .env_clear()
@@ -239,10 +267,10 @@ fn get_compiler_outputs<T>(creator: &T,
 impl Rust {
     /// Create a new Rust compiler instance, calculating the hashes of
     /// all the shared libraries in its sysroot.
-    pub fn new<T>(mut creator: T, executable: PathBuf, pool: CpuPool) -> SFuture<Rust>
+    pub fn new<T>(creator: T, executable: PathBuf, pool: CpuPool) -> SFuture<Rust>
         where T: CommandCreatorSync,
     {
-        let mut cmd = creator.new_command_sync(&executable);
+        let mut cmd = shell::compiler_command(&creator, &executable);
         cmd.stdout(Stdio::piped())
             .stderr(Stdio::null())
             .arg("--print=sysroot");
@@ -282,6 +310,15 @@ impl<T> Compiler<T> for Rust
     where T: CommandCreatorSync,
 {
     fn kind(&self) -> CompilerKind { CompilerKind::Rust }
+    /// The `weak_toolchain_key` that `generate_hash_key` records.
+    fn digest(&self) -> String {
+        let mut m = Digest::new();
+        m.update(CACHE_VERSION);
+        for d in &self.compiler_shlibs_digests {
+            m.update(d.as_bytes());
+        }
+        m.finish()
+    }
     /// Parse `arguments` as rustc command-line arguments, determine if
     /// we can cache the result of compilation. This is only intended to
     /// cover a subset of rustc invocations, primarily focused on those
@@ -308,10 +345,53 @@ impl<T> Compiler<T> for Rust
             }
             CompilerArguments::NotCompilation => CompilerArguments::NotCompilation,
             CompilerArguments::CannotCache(why) => CompilerArguments::CannotCache(why),
+            CompilerArguments::UnknownFlag(flag) => CompilerArguments::UnknownFlag(flag),
         }
     }
 
 
+    fn get_toolchain_packager(&self) -> Box<CompilerPackager> {
+        Box::new(RustCompilerPackager { sysroot: self.sysroot.clone() })
+    }
+
+    /// `--print` queries and `--version` are pure functions of the compiler,
+    /// the arguments, the working directory (for custom target specs) and
+    /// `RUST*` variables such as `RUSTC_BOOTSTRAP`. Queries that name a
+    /// source file depend on its contents, so they aren't cached.
+    fn query_key(&self,
+                 arguments: &[OsString],
+                 cwd: &Path,
+                 env_vars: &[(OsString, OsString)]) -> Option<String> {
+        let is_query = |arg: &OsString| {
+            arg == "--print" || arg == "-V" || arg == "--version" || arg == "-vV" ||
+                arg.starts_with("--print=")
+        };
+        let is_source = |arg: &OsString| Path::new(arg).extension().map_or(false, |e| e == "rs");
+        if !arguments.iter().any(is_query) || arguments.iter().any(is_source) {
+            return None
+        }
+        let mut m = Digest::new();
+        m.update(self.executable.to_string_lossy().as_bytes());
+        for d in &self.compiler_shlibs_digests {
+            m.update(d.as_bytes());
+        }
+        m.update(cwd.to_string_lossy().as_bytes());
+        for arg in arguments {
+            m.update(&[0]);
+            arg.hash(&mut HashToDigest { digest: &mut m });
+        }
+        let mut env_vars = env_vars.iter()
+            .filter(|&&(ref k, _)| k.starts_with("RUST"))
+            .collect::<Vec<_>>();
+        env_vars.sort();
+        for &&(ref var, ref val) in &env_vars {
+            var.hash(&mut HashToDigest { digest: &mut m });
+            m.update(&b"="[..]);
+            val.hash(&mut HashToDigest { digest: &mut m });
+        }
+        Some(m.finish())
+    }
+
     fn box_clone(&self) -> Box<Compiler<T>> {
         Box::new((*self).clone())
     }
@@ -372,8 +452,31 @@ static ARGS: [(ArgInfo, RustArgAttribute); 33] = [
     take_arg!("-o", Path, CanBeSeparated, TooHard),
 ];
 
+/// Whether `arg` names a rustc executable rather than being an argument to one.
+///
+/// Cargo runs `$RUSTC_WRAPPER $RUSTC_WORKSPACE_WRAPPER $RUSTC ...`, so when a
+/// workspace wrapper is set, the command sccache sees is the workspace
+/// wrapper with the path to rustc as its first argument.
+pub fn is_rustc_path(arg: &OsStr) -> bool {
+    let path = Path::new(arg);
+    !arg.to_string_lossy().starts_with('-') &&
+        path.file_stem().map_or(false, |s| s.to_string_lossy().to_lowercase() == "rustc") &&
+        path.extension().map_or(true, |e| e.to_string_lossy().to_lowercase() == "exe")
+}
+
 fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<ParsedArguments>
 {
+    // If the first argument is another rustc, the executable that was
+    // detected as rustc is really a wrapper around it, whose effect on the
+    // compilation can't be known.
+    if let Some(first) = arguments.first() {
+        if is_rustc_path(first) {
+            warn!("rustc was run with another rustc, {:?}, as its first argument, so it is probably \
+                   a wrapper; not caching. sccache should be RUSTC_WRAPPER, with any other wrapper \
+                   in RUSTC_WORKSPACE_WRAPPER.", first);
+            return CompilerArguments::CannotCache("rustc wrapper chain");
+        }
+    }
     let mut args = vec![];
 
     let mut emit: Option<HashSet<String>> = None;
@@ -385,6 +488,8 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     let mut static_lib_names = vec![];
     let mut static_link_paths: Vec<PathBuf> = vec![];
     let mut color_mode = ColorMode::Auto;
+    let mut incremental = false;
+    let mut unlinkable_crate_type = false;
 
     for item in ArgsIter::new(arguments.iter().map(|s| s.clone()), &ARGS[..]) {
         let arg = item.arg.to_os_string();
@@ -452,11 +557,11 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
                 emit = value.map(|a| a.split(",").map(&str::to_owned).collect());
             }
             Some(CrateType) => {
-                // We can't cache non-rlib/staticlib crates, because rustc invokes the
+                // We can't cache linking non-rlib/staticlib crates, because rustc invokes the
                 // system linker to link them, and we don't know about all the linker inputs.
                 if let Some(v) = value {
                     if v.split(",").any(|t| t != "lib" && t != "rlib" && t != "staticlib") {
-                        return CompilerArguments::CannotCache("crate-type");
+                        unlinkable_crate_type = true;
                     }
                 }
             }
@@ -478,15 +583,7 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
                     if let (Some(name), Some(val)) = (name, val) {
                         match name {
                             "extra-filename" => extra_filename = Some(val.to_owned()),
-                            // Incremental compilation makes a mess of sccache's entire world
-                            // view. It produces additional compiler outputs that we don't cache,
-                            // and just letting rustc do its work in incremental mode is likely
-                            // to be faster than trying to fetch a result from cache anyway, so
-                            // don't bother caching compiles where it's enabled currently.
-                            // Longer-term we would like to figure out better integration between
-                            // sccache and rustc in the incremental scenario:
-                            // https://github.com/mozilla/sccache/issues/236
-                            "incremental" => return CompilerArguments::CannotCache("incremental"),
+                            "incremental" => incremental = true,
                             _ => {},
                         }
                     }
@@ -528,26 +625,44 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
             };
         }
     };
-    // We don't actually save the input value, but there needs to be one.
     req!(input);
-    drop(input);
     req!(output_dir);
     req!(emit);
     req!(crate_name);
     // We won't cache invocations that are not producing
-    // binary output.
-    if !emit.is_empty() && !emit.contains("link") {
+    // binary output or metadata.
+    let emit_link = emit.contains("link");
+    if !emit.is_empty() && !emit_link && !emit.contains("metadata") {
         return CompilerArguments::NotCompilation;
     }
     // We won't cache invocations that are outputting anything but
-    // linker output and dep-info.
+    // linker output, metadata and dep-info.
     if emit.iter().any(|e| !ALLOWED_EMIT.contains(e.as_str())) {
         return CompilerArguments::CannotCache("unsupported --emit");
     }
+    if emit_link && unlinkable_crate_type {
+        return CompilerArguments::CannotCache("crate-type");
+    }
+    // Incremental compilation makes a mess of sccache's entire world
+    // view. It produces additional compiler outputs that we don't cache,
+    // and just letting rustc do its work in incremental mode is likely
+    // to be faster than trying to fetch a result from cache anyway, so
+    // don't bother caching compiles generating code where it's enabled.
+    // Longer-term we would like to figure out better integration between
+    // sccache and rustc in the incremental scenario:
+    // https://github.com/mozilla/sccache/issues/236
+    // Metadata-only compiles, as run by `cargo check`, are still cached:
+    // rustc leaves the incremental directory out of the crate's hash, so
+    // the rmeta file doesn't depend on it, and a cache hit only leaves
+    // rustc's own incremental state to be rebuilt by a later compile.
+    if incremental && emit_link {
+        warn_incremental();
+        return CompilerArguments::CannotCache("incremental");
+    }
     // Figure out the dep-info filename, if emitting dep-info.
     let dep_info = if emit.contains("dep-info") {
         let mut dep_info = crate_name.clone();
-        if let Some(extra_filename) = extra_filename {
+        if let Some(ref extra_filename) = extra_filename {
             dep_info.push_str(&extra_filename[..]);
         }
         dep_info.push_str(".d");
@@ -555,6 +670,12 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     } else {
         None
     };
+    // rustc names the rmeta file like an rlib, whatever the crate type.
+    let metadata = if emit.contains("metadata") {
+        Some(format!("lib{}{}.rmeta", crate_name, extra_filename.as_ref().map_or("", |s| &s[..])))
+    } else {
+        None
+    };
     // Locate all static libs specified on the commandline.
     let staticlibs = static_lib_names.into_iter().filter_map(|name| {
         for path in static_link_paths.iter() {
@@ -577,11 +698,14 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     externs.sort();
     CompilerArguments::Ok(ParsedArguments {
         arguments: args,
+        input: input.into(),
         output_dir: output_dir.into(),
         externs: externs,
         staticlibs: staticlibs,
         crate_name: crate_name.to_string(),
         dep_info: dep_info.map(|s| s.into()),
+        metadata: metadata.map(|s| s.into()),
+        emit_link,
         color_mode,
     })
 }
@@ -598,12 +722,13 @@ impl<T> CompilerHasher<T> for RustHasher
                          -> SFuture<HashResult>
     {
         let me = *self;
-        let RustHasher { executable, sysroot, compiler_shlibs_digests, parsed_args: ParsedArguments { arguments, output_dir, externs, staticlibs, crate_name, dep_info, color_mode: _ } } = me;
+        let RustHasher { executable, sysroot, compiler_shlibs_digests, parsed_args: ParsedArguments { arguments, input, output_dir, externs, staticlibs, crate_name, dep_info, metadata, emit_link, color_mode: _ } } = me;
         trace!("[{}]: generate_hash_key", crate_name);
-        // `filtered_arguments` omits --emit and --out-dir arguments.
+        // `filtered_arguments` omits --emit, --out-dir and -C incremental arguments.
         // It's used for invoking rustc with `--emit=dep-info` to get the list of
         // source files for this crate.
         let filtered_arguments = arguments.iter()
+            .filter(|a| !is_incremental(a))
             .filter_map(|&(ref arg, ref val)| {
                 if arg == "--emit" || arg == "--out-dir" {
                     None
@@ -627,6 +752,7 @@ impl<T> CompilerHasher<T> for RustHasher
                                         .map(|s| s.to_string_lossy().into_owned())
                                         .collect(),
                                         &pool);
+        let key_cwd = CONFIG.cwd_in_key.key_cwd(&cwd);
         let creator = creator.clone();
         let hashes = source_hashes.join3(extern_hashes, staticlib_hashes);
         Box::new(hashes.and_then(move |(source_hashes, extern_hashes, staticlib_hashes)|
@@ -647,8 +773,11 @@ impl<T> CompilerHasher<T> for RustHasher
             // A few argument types are not passed in a deterministic order
             // by cargo: --extern, -L, --cfg. We'll filter those out, sort them,
             // and append them to the rest of the arguments.
+            // The incremental directory, only passed to metadata-only compiles
+            // here, doesn't change their output, so it's left out.
             let args = {
                 let (mut sortables, rest): (Vec<_>, Vec<_>) = arguments.iter()
+                    .filter(|a| !is_incremental(a))
                     .partition(|&&(ref arg, _)| arg == "--extern" || arg == "-L" || arg == "--cfg");
                 sortables.sort();
                 rest.into_iter()
@@ -662,6 +791,11 @@ impl<T> CompilerHasher<T> for RustHasher
                     })
             };
             args.hash(&mut HashToDigest { digest: &mut m });
+            let args_digest = {
+                let mut m = Digest::new();
+                args.hash(&mut HashToDigest { digest: &mut m });
+                m.finish()
+            };
             // 4. The digest of all source files (this includes src file from cmdline).
             // 5. The digest of all files listed on the commandline (self.externs).
             // 6. The digest of all static libraries listed on the commandline (self.staticlibs).
@@ -684,13 +818,25 @@ impl<T> CompilerHasher<T> for RustHasher
                     val.hash(&mut HashToDigest { digest: &mut m });
                 }
             }
+            // 8. The working directory, which rustc embeds in debug information.
+            if let Some(cwd) = key_cwd {
+                m.update(b"cwd:");
+                m.update(cwd.as_bytes());
+            }
             // Turn arguments into a simple Vec<OsString> for compilation.
             let arguments: Vec<OsString> = arguments.into_iter()
                 .flat_map(|(arg, val)| Some(arg).into_iter().chain(val))
                 // Always request color output, the client will strip colors if needed.
                 .chain(iter::once("--color=always".into()))
                 .collect();
-            Box::new(get_compiler_outputs(&creator, &executable, &arguments, &cwd, &env_vars).map(move |outputs| {
+            // `--print file-names` lists what linking writes, so there's
+            // nothing to ask when only metadata is emitted.
+            let outputs = if emit_link {
+                get_compiler_outputs(&creator, &executable, &arguments, &cwd, &env_vars)
+            } else {
+                f_ok(vec![])
+            };
+            Box::new(outputs.map(move |outputs| {
                 let output_dir = PathBuf::from(output_dir);
                 // Convert output files into a map of basename -> full path.
                 let mut outputs = outputs.into_iter()
@@ -703,11 +849,15 @@ impl<T> CompilerHasher<T> for RustHasher
                     let p = output_dir.join(&dep_info);
                     outputs.insert(dep_info.to_string_lossy().into_owned(), p);
                 }
+                if let Some(metadata) = metadata {
+                    let p = output_dir.join(&metadata);
+                    outputs.insert(metadata.to_string_lossy().into_owned(), p);
+                }
                 let toolchain_creator = Box::new(RustCompilerPackager { sysroot: sysroot.clone() });
                 HashResult {
                     key: m.finish(),
                     compilation: Box::new(RustCompilation {
-                        executable: executable,
+                        executable: executable.clone(),
                         sysroot: sysroot,
                         arguments: arguments,
                         outputs: outputs,
@@ -715,8 +865,13 @@ impl<T> CompilerHasher<T> for RustHasher
                         cwd,
                         env_vars,
                     }),
+                    // The weak toolchain key covers rustc and its shared libraries
+                    compiler_digest: weak_toolchain_key.clone(),
                     weak_toolchain_key,
                     toolchain_creator,
+                    executable,
+                    source: input,
+                    args_digest,
                 }
             }))
         }))
@@ -764,10 +919,11 @@ impl CompilerPackager for RustCompilerPackager {
         use tar;
 
         info!("Packaging Rust compiler");
+        let sysroot_path = self.sysroot.strip_prefix("/")
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, format!("sysroot {:?} is not absolute", self.sysroot)))?;
         let mut builder = tar::Builder::new(f);
-        builder.append_dir_all(&self.sysroot.strip_prefix("/").unwrap(), &self.sysroot).unwrap();
-        builder.finish().unwrap();
-        Ok(())
+        builder.append_dir_all(sysroot_path, &self.sysroot)?;
+        builder.finish()
     }
 
     #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
@@ -856,6 +1012,22 @@ mod test {
         assert_eq!(h.externs, ovec!["/foo/target/debug/deps/liblibc-89a24418d48d484a.rlib", "/foo/target/debug/deps/liblog-2f7366be74992849.rlib"]);
     }
 
+    #[test]
+    fn test_parse_arguments_wrapper_chain() {
+        assert!(is_rustc_path(OsStr::new("rustc")));
+        assert!(is_rustc_path(OsStr::new("/home/me/.cargo/bin/rustc")));
+        assert!(is_rustc_path(OsStr::new("rustc.exe")));
+        assert!(!is_rustc_path(OsStr::new("rustc.rs")));
+        assert!(!is_rustc_path(OsStr::new("src/lib.rs")));
+        assert!(!is_rustc_path(OsStr::new("--crate-name")));
+        // `$RUSTC_WORKSPACE_WRAPPER $RUSTC ...` where the workspace wrapper
+        // was itself detected as rustc.
+        let r = fails!("/usr/bin/rustc", "--crate-name", "foo", "src/lib.rs",
+                       "--emit=dep-info,link", "--out-dir", "/out");
+        assert_eq!(r, CompilerArguments::CannotCache("rustc wrapper chain"));
+        parses!("--crate-name", "rustc", "src/rustc.rs", "--emit=dep-info,link", "--out-dir", "/out");
+    }
+
     #[test]
     fn test_parse_arguments_incremental() {
         parses!("--emit", "link", "foo.rs", "--out-dir", "out", "--crate-name", "foo");
@@ -864,6 +1036,58 @@ mod test {
         assert_eq!(r, CompilerArguments::CannotCache("incremental"))
     }
 
+    /// The arguments of `cargo check`.
+    const CARGO_CHECK: &[&str] = &[
+        "--crate-name", "foo", "src/lib.rs", "--color", "never", "--crate-type", "lib",
+        "--emit=dep-info,metadata", "-C", "debuginfo=2", "-C", "metadata=0123", "-C", "extra-filename=-0123",
+        "--out-dir", "/target/debug/deps", "-L", "dependency=/target/debug/deps",
+    ];
+
+    /// The arguments of a pipelined `cargo build`.
+    const CARGO_BUILD: &[&str] = &[
+        "--crate-name", "foo", "src/lib.rs", "--color", "never", "--crate-type", "lib",
+        "--emit=dep-info,metadata,link", "-C", "debuginfo=2", "-C", "metadata=0123", "-C", "extra-filename=-0123",
+        "--out-dir", "/target/debug/deps", "-L", "dependency=/target/debug/deps",
+    ];
+
+    /// `args`, with incremental compilation in `incremental` if given, as cargo
+    /// passes it when `CARGO_INCREMENTAL=1`.
+    fn cargo_args(args: &[&str], incremental: Option<&str>) -> Vec<String> {
+        let mut args = args.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        if let Some(dir) = incremental {
+            args.push("-C".to_owned());
+            args.push(format!("incremental={}", dir));
+        }
+        args
+    }
+
+    #[test]
+    fn test_parse_arguments_incremental_check() {
+        let h = match _parse_arguments(&cargo_args(CARGO_CHECK, Some("/target/debug/incremental"))) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(h.metadata, Some("libfoo-0123.rmeta".into()));
+        assert_eq!(h.dep_info, Some("foo-0123.d".into()));
+        assert!(!h.emit_link);
+        // Checking a binary doesn't link it.
+        parses!("--crate-name", "foo", "src/main.rs", "--crate-type", "bin", "--emit=dep-info,metadata",
+                "--out-dir", "out", "-C", "incremental=/inc");
+    }
+
+    #[test]
+    fn test_parse_arguments_incremental_build() {
+        assert_eq!(_parse_arguments(&cargo_args(CARGO_BUILD, Some("/target/debug/incremental"))),
+                   CompilerArguments::CannotCache("incremental"));
+        // Without incremental compilation, both the rlib and rmeta files are cached.
+        let h = match _parse_arguments(&cargo_args(CARGO_BUILD, None)) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(h.metadata, Some("libfoo-0123.rmeta".into()));
+        assert!(h.emit_link);
+    }
+
     #[test]
     fn test_parse_arguments_dep_info_no_extra_filename() {
         let h = parses!("--crate-name", "foo", "src/lib.rs",
@@ -1064,11 +1288,14 @@ c:/foo/bar.rs:
                                 ("b".into(), None),
                                 ("--extern".into(), Some("abc".into())),
                                 ],
+                input: "foo.rs".into(),
                 output_dir: "foo/".into(),
                 externs: vec!["bar.rlib".into()],
                 staticlibs: vec![f.tempdir.path().join("libbaz.a")],
                 crate_name: "foo".into(),
                 dep_info: None,
+                metadata: None,
+                emit_link: true,
                 color_mode: ColorMode::Auto,
             }
         });
@@ -1169,6 +1396,78 @@ c:/foo/bar.rs:
                             &mk_files));
     }
 
+    #[test]
+    fn test_hashes_cwd() {
+        let args = ovec!["--emit", "link", "foo.rs", "--out-dir", "out", "--crate-name", "foo"];
+        // Each call compiles in a directory of its own, which by default isn't
+        // part of the key.
+        assert_eq!(hash_key(&args, &vec![], nothing), hash_key(&args, &vec![], nothing));
+    }
+
+    #[test]
+    fn test_hashes_debuginfo() {
+        use compiler::gcc::test::assert_distinct_keys;
+
+        assert_distinct_keys(&[&[], &["-g"], &["-C", "debuginfo=0"], &["-C", "debuginfo=1"],
+                               &["-C", "debuginfo=2"], &["-g", "-C", "debuginfo=0"]],
+                             |flags| {
+            let mut args = ovec!["--emit", "link", "foo.rs", "--out-dir", "out", "--crate-name", "foo"];
+            args.extend(flags.iter().map(OsString::from));
+            hash_key(&args, &vec![], nothing)
+        });
+    }
+
+    #[test]
+    fn test_hashes_incremental_check() {
+        let key = |incremental| {
+            let args = cargo_args(CARGO_CHECK, incremental).iter().map(OsString::from).collect::<Vec<_>>();
+            hash_key(&args, &vec![], nothing)
+        };
+        // The incremental directory isn't part of the key.
+        assert_eq!(key(None), key(Some("/a/incremental")));
+        assert_eq!(key(Some("/a/incremental")), key(Some("/b/incremental")));
+    }
+
+    #[test]
+    fn test_generate_hash_key_incremental_check() {
+        let f = TestFixture::new();
+        f.touch("foo.rs").unwrap();
+        let args = ovec!["--crate-name", "foo", "foo.rs", "--crate-type", "lib", "--emit=dep-info,metadata",
+                         "-C", "extra-filename=-0123", "--out-dir", "out", "-C", "incremental=inc"];
+        let parsed_args = match parse_arguments(&args, f.tempdir.path()) {
+            CompilerArguments::Ok(parsed_args) => parsed_args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        let hasher = Box::new(RustHasher {
+            executable: "rustc".into(),
+            sysroot: f.tempdir.path().join("sysroot"),
+            compiler_shlibs_digests: vec![],
+            parsed_args: parsed_args,
+        });
+        let creator = new_creator();
+        let pool = CpuPool::new(1);
+        // Only the dep-info run: nothing is linked, so no `--print file-names`.
+        let dep_info_args = Arc::new(Mutex::new(vec![]));
+        {
+            let dep_info_args = dep_info_args.clone();
+            next_command_calls(&creator, move |args| {
+                *dep_info_args.lock().unwrap() = args.to_vec();
+                let dep_info_path = args.iter().skip_while(|a| *a != "-o").nth(1).unwrap();
+                let mut f = File::create(dep_info_path)?;
+                writeln!(f, "blah: foo.rs")?;
+                writeln!(f, "foo.rs:")?;
+                Ok(MockChild::new(exit_status(0), "", ""))
+            });
+        }
+        let res = hasher.generate_hash_key(&creator, f.tempdir.path().to_owned(), vec![], false, &pool)
+            .wait().unwrap();
+        assert!(!dep_info_args.lock().unwrap().iter().any(|a| a == "incremental=inc"));
+        let mut out = res.compilation.outputs().map(|(k, _)| k.to_owned()).collect::<Vec<_>>();
+        out.sort();
+        assert_eq!(out, vec!["foo-0123.d", "libfoo-0123.rmeta"]);
+        assert_eq!(0, creator.lock().unwrap().children.len());
+    }
+
     #[test]
     fn test_equal_hashes_link_paths() {
         assert_eq!(hash_key(&ovec!["--emit", "link", "-L", "x=x", "foo.rs", "--out-dir", "out",
@@ -1186,4 +1485,27 @@ c:/foo/bar.rs:
                                    "foo.rs", "--out-dir", "out", "--crate-name", "foo"], &vec![],
                             nothing));
     }
+
+    #[test]
+    fn test_query_key() {
+        let rust = Rust {
+            executable: "rustc".into(),
+            sysroot: "/sysroot".into(),
+            compiler_shlibs_digests: vec!["abc".to_owned()],
+        };
+        let key = |args: Vec<OsString>, env_vars: Vec<(OsString, OsString)>| {
+            Compiler::<MockCommandCreator>::query_key(&rust, &args, ".".as_ref(), &env_vars)
+        };
+        let cfg = key(ovec!["-", "--crate-name", "___", "--print=file-names", "--print=cfg"], vec![]);
+        assert!(cfg.is_some());
+        assert_eq!(cfg, key(ovec!["-", "--crate-name", "___", "--print=file-names", "--print=cfg"],
+                            vec![("HOME".into(), "/home".into())]));
+        assert_neq!(cfg, key(ovec!["-", "--crate-name", "___", "--print=file-names", "--print=cfg"],
+                             vec![("RUSTC_BOOTSTRAP".into(), "1".into())]));
+        assert_neq!(cfg, key(ovec!["-", "--crate-name", "___", "--print=file-names", "--print=cfg",
+                                   "--target", "x86_64-unknown-linux-musl"], vec![]));
+        assert!(key(ovec!["-vV"], vec![]).is_some());
+        assert_eq!(key(ovec!["--print", "cfg", "foo.rs"], vec![]), None);
+        assert_eq!(key(ovec!["--emit", "link", "foo.rs", "--crate-name", "foo"], vec![]), None);
+    }
 }
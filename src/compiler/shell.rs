@@ -0,0 +1,213 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running compilers through the configured compiler shell, such as one
+//! loading environment modules in its profile scripts, and the environment
+//! they get from it. That isn't part of the compile command's environment, so
+//! it's hashed separately.
+
+use config::CONFIG;
+use futures::Future;
+use mock_command::{CommandCreatorSync, RunCommand};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::hash::Hash;
+use std::sync::Mutex;
+use util::{run_input_output, Digest, HashToDigest};
+
+use errors::*;
+
+lazy_static! {
+    /// The digest of the shell's environment for each compile command
+    /// environment, by a digest of that.
+    static ref ENV_DIGESTS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// A command running the compiler `program`, through the configured compiler
+/// shell if there is one.
+pub fn compiler_command<T, S>(creator: &T, program: S) -> T::Cmd
+    where T: CommandCreatorSync,
+          S: AsRef<OsStr>,
+{
+    command_through_shell(creator, program, &CONFIG.server.compiler_shell)
+}
+
+/// A command running `program`, or if `shell` isn't empty, running that shell
+/// with its arguments to `exec` `program` with the command's arguments.
+fn command_through_shell<T, S>(creator: &T, program: S, shell: &[String]) -> T::Cmd
+    where T: CommandCreatorSync,
+          S: AsRef<OsStr>,
+{
+    match shell.split_first() {
+        None => creator.clone().new_command_sync(program),
+        Some((shell, shell_args)) => {
+            let mut cmd = creator.clone().new_command_sync(shell);
+            cmd.args(shell_args)
+                .arg("-c")
+                .arg("exec \"$0\" \"$@\"")
+                .arg(program);
+            cmd
+        }
+    }
+}
+
+/// Parse the output of `env`. Lines without a `=` continue the value of the
+/// variable before them.
+fn parse_env(output: &[u8]) -> BTreeMap<String, String> {
+    let mut vars = BTreeMap::new();
+    let mut last = None;
+    for line in String::from_utf8_lossy(output).lines() {
+        match line.find('=') {
+            Some(i) if i > 0 => {
+                let name = line[..i].to_owned();
+                vars.insert(name.clone(), line[i + 1..].to_owned());
+                last = Some(name);
+            }
+            _ => if let Some(value) = last.as_ref().and_then(|name| vars.get_mut(name)) {
+                value.push('\n');
+                value.push_str(line);
+            },
+        }
+    }
+    vars
+}
+
+/// The variables in `vars` that the shell added or changed, given it was run
+/// with `env_vars`. Those passed through unchanged are already part of the
+/// compile command's hash key, and `env` lists things like `PWD` and `SHLVL`
+/// that only say how it was run.
+fn shell_vars(mut vars: BTreeMap<String, String>, env_vars: &[(OsString, OsString)]) -> BTreeMap<String, String> {
+    for &(ref var, ref val) in env_vars {
+        if let (Some(var), Some(val)) = (var.to_str(), val.to_str()) {
+            if vars.get(var).map(|v| &v[..]) == Some(val) {
+                vars.remove(var);
+            }
+        }
+    }
+    vars
+}
+
+/// The variables set differently in `a` and `b`.
+fn volatile_vars(a: &BTreeMap<String, String>, b: &BTreeMap<String, String>) -> Vec<String> {
+    let mut names = a.keys().chain(b.keys())
+        .filter(|name| a.get(*name) != b.get(*name))
+        .cloned()
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// A digest of `vars`, leaving out the `volatile` ones.
+fn digest_vars(vars: &BTreeMap<String, String>, volatile: &[String]) -> String {
+    let mut m = Digest::new();
+    for (name, value) in vars {
+        if !volatile.contains(name) {
+            m.update(name.as_bytes());
+            m.update(b"=");
+            m.update(value.as_bytes());
+            m.update(b"\0");
+        }
+    }
+    m.finish()
+}
+
+/// A digest of the variables that the configured compiler shell adds to or
+/// changes in `env_vars` for compilers, or `None` if compilers don't run
+/// through a shell. The shell is run twice the first time, so variables it sets
+/// differently each time can be logged and left out, rather than making
+/// every compilation a cache miss.
+pub fn env_digest<T>(creator: &T, env_vars: &[(OsString, OsString)]) -> SFuture<Option<String>>
+    where T: CommandCreatorSync
+{
+    if CONFIG.server.compiler_shell.is_empty() {
+        return f_ok(None)
+    }
+    let env_key = {
+        let mut m = Digest::new();
+        for &(ref var, ref val) in env_vars {
+            var.hash(&mut HashToDigest { digest: &mut m });
+            val.hash(&mut HashToDigest { digest: &mut m });
+        }
+        m.finish()
+    };
+    if let Some(digest) = ENV_DIGESTS.lock().unwrap().get(&env_key) {
+        return f_ok(Some(digest.clone()))
+    }
+    let run_env = {
+        let creator = creator.clone();
+        let env_vars = env_vars.to_owned();
+        move || {
+            let mut cmd = compiler_command(&creator, "env");
+            cmd.env_clear()
+                .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)));
+            let env_vars = env_vars.clone();
+            run_input_output(cmd, None).map(move |output| shell_vars(parse_env(&output.stdout), &env_vars))
+        }
+    };
+    let first = run_env();
+    first.and_then(move |first| {
+        run_env().map(move |second| {
+            let volatile = volatile_vars(&first, &second);
+            if !volatile.is_empty() {
+                warn!("The compiler shell's environment changes from run to run, \
+                       leaving {} out of the hash key", volatile.join(", "));
+            }
+            let digest = digest_vars(&first, &volatile);
+            ENV_DIGESTS.lock().unwrap().insert(env_key, digest.clone());
+            Some(digest)
+        })
+    }).chain_err(|| "failed to run the compiler shell")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mock_command::*;
+    use test::utils::*;
+
+    #[test]
+    fn test_command_through_shell() {
+        let creator = new_creator();
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "", "")));
+        let cmd = command_through_shell(&creator, "cc", &["bash".to_owned(), "-l".to_owned()]);
+        assert_eq!(
+            vec!["-l", "-c", "exec \"$0\" \"$@\"", "cc"],
+            cmd.args.iter().map(|a| a.to_str().unwrap()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_shell_vars() {
+        let vars = parse_env(b"HOME=/home/me\nPATH=/opt/gcc/bin:/usr/bin\nMODULES=gcc/8\n");
+        let env_vars = vec![
+            (OsString::from("HOME"), OsString::from("/home/me")),
+            (OsString::from("PATH"), OsString::from("/usr/bin")),
+        ];
+        let vars = shell_vars(vars, &env_vars);
+        assert_eq!(vec!["MODULES", "PATH"], vars.keys().map(|k| &k[..]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_volatile_vars() {
+        let first = parse_env(b"HOME=/home/me\nMODULES=gcc/8\nTMP=/tmp/abc\nMULTI=a\nb\n");
+        assert_eq!(first.get("MULTI").map(|v| &v[..]), Some("a\nb"));
+        let second = parse_env(b"HOME=/home/me\nMODULES=gcc/8\nTMP=/tmp/xyz\nMULTI=a\nb\nNEW=1\n");
+        let volatile = volatile_vars(&first, &second);
+        assert_eq!(volatile, vec!["NEW".to_owned(), "TMP".to_owned()]);
+        assert_eq!(digest_vars(&first, &volatile), digest_vars(&second, &volatile));
+        let third = parse_env(b"HOME=/home/me\nMODULES=gcc/9\nTMP=/tmp/abc\nMULTI=a\nb\n");
+        assert_neq!(digest_vars(&first, &volatile), digest_vars(&third, &volatile));
+    }
+}
@@ -115,8 +115,13 @@ impl ClientToolchains {
             return Ok((tc, Some(compiler_path)))
         }
         if let Some(archive_id) = self.weak_to_strong(weak_key) {
-            debug!("Using cached toolchain {} -> {}", weak_key, archive_id);
-            return Ok((Toolchain { archive_id }, None))
+            let tc = Toolchain { archive_id };
+            // The weak map outlives restarts, but the archive may have been evicted since.
+            if self.cache.lock().unwrap().contains_toolchain(&tc) {
+                debug!("Using cached toolchain {} -> {}", weak_key, tc.archive_id);
+                return Ok((tc, None))
+            }
+            debug!("Cached toolchain {} -> {} was evicted, recreating it", weak_key, tc.archive_id);
         }
         debug!("Weak key {} appears to be new", weak_key);
         // Only permit one toolchain creation at a time. Not an issue if there are multiple attempts
@@ -171,11 +176,18 @@ impl TcCache {
         self.inner.contains_key(make_lru_key_path(&tc.archive_id))
     }
 
+    /// Add the toolchain `tc` written by `with`, as long as it hashes to its
+    /// archive id. Until then it's kept out of the cache, so that a toolchain
+    /// that doesn't match is never found or run.
     pub fn insert_with<F: FnOnce(File) -> io::Result<()>>(&mut self, tc: &Toolchain, with: F) -> Result<()> {
-        self.inner.insert_with(make_lru_key_path(&tc.archive_id), with).map_err(|e| -> Error { e.into() })?;
-        let verified_archive_id = file_key(self.get(tc)?)?;
-        // TODO: remove created toolchain?
-        if verified_archive_id == tc.archive_id { Ok(()) } else { Err("written file does not match expected hash key".into()) }
+        let tmpfile = tempfile::NamedTempFile::new()?;
+        with(tmpfile.reopen()?)?;
+        let verified_archive_id = path_key(tmpfile.path())?;
+        if verified_archive_id != tc.archive_id {
+            // Dropping the temporary file deletes it.
+            return Err("written file does not match expected hash key".into())
+        }
+        self.inner.insert_file(make_lru_key_path(&tc.archive_id), tmpfile.path()).map_err(|e| -> Error { e.into() })
     }
 
     pub fn get(&mut self, tc: &Toolchain) -> LruResult<Box<ReadSeek>> {
@@ -189,6 +201,11 @@ impl TcCache {
     }
 }
 
+/// Return the id of the toolchain archive at `path`.
+pub fn toolchain_archive_id<P: AsRef<OsStr>>(path: P) -> Result<String> {
+    path_key(path)
+}
+
 fn path_key<P: AsRef<OsStr>>(path: P) -> Result<String> {
     file_key(File::open(path.as_ref())?)
 }
@@ -214,3 +231,28 @@ fn hash_reader<R: Read + Send + 'static>(rdr: R) -> Result<String> {
     }
     Ok(util::hex(m.finish().as_ref()))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_insert_with_rejects_mismatched_toolchain() {
+        let td = TempDir::new("sccache").unwrap();
+        let mut cache = TcCache::new(td.path(), 1 << 20).unwrap();
+        let tc = Toolchain { archive_id: hash_reader(Cursor::new(b"toolchain".to_vec())).unwrap() };
+        let write = |bytes: &'static [u8]| move |mut f: File| f.write_all(bytes);
+        assert!(cache.insert_with(&tc, write(b"tampered")).is_err());
+        assert!(!cache.contains_toolchain(&tc));
+        // A second submit isn't let through by the first one's leftovers.
+        assert!(cache.insert_with(&tc, write(b"tampered again")).is_err());
+        assert!(!cache.contains_toolchain(&tc));
+        cache.insert_with(&tc, write(b"toolchain")).unwrap();
+        assert!(cache.contains_toolchain(&tc));
+        let mut contents = vec![];
+        cache.get(&tc).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(&b"toolchain"[..], &contents[..]);
+    }
+}
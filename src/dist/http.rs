@@ -12,37 +12,48 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use base64;
 use bincode;
 use boxfnonce::BoxFnOnce;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use config;
-use futures::{Future, Stream};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use futures::{future, Future, Stream};
+use futures::future::Loop;
 use num_cpus;
 use reqwest;
 use rouille;
 use serde;
 use std;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::{self, Read, Write};
 use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
 use std::time::Duration;
 use super::cache;
 use super::{
-    ServerId, JobId, Toolchain, CompileCommand,
+    ServerId, JobId, Toolchain, CompileCommand, TraceId,
     ToolchainReader, InputsReader,
 
     AllocJobResult, JobAlloc,
     AssignJobResult,
     HeartbeatServerResult,
     RunJobResult,
+    StatsResult,
     StatusResult,
     SubmitToolchainResult,
     UpdateJobStatusResult, JobStatus,
 
     SchedulerIncoming, SchedulerOutgoing,
     ServerIncoming, ServerOutgoing,
+
+    ToolchainSigner,
 };
 use tokio_core;
 
@@ -51,6 +62,17 @@ use errors::*;
 const SCHEDULER_PORT: u16 = 10500;
 const SERVER_PORT: u16 = 10501;
 
+/// Header carrying the base64 encoded detached signature of a submitted toolchain.
+const TOOLCHAIN_SIGNATURE_HEADER: &str = "X-Sccache-Toolchain-Signature";
+/// Header naming the compression applied to the inputs of a job.
+const INPUTS_ENCODING_HEADER: &str = "X-Sccache-Inputs-Encoding";
+/// Header on build server responses listing the inputs compression it supports.
+/// Clients only compress inputs for servers that have sent this, so older servers
+/// keep receiving them uncompressed.
+const ACCEPT_INPUTS_ENCODING_HEADER: &str = "X-Sccache-Accept-Inputs-Encoding";
+/// Header carrying the trace id of the compile a request is for.
+const TRACE_ID_HEADER: &str = "X-Sccache-Trace-Id";
+
 // TODO: move this into the config module
 struct Cfg;
 
@@ -140,12 +162,14 @@ pub fn bincode_response<T>(content: &T) -> rouille::Response where T: serde::Ser
 pub struct ErrJson<'a> {
     description: &'a str,
     cause: Option<Box<ErrJson<'a>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace_id: Option<&'a str>,
 }
 
 impl<'a> ErrJson<'a> {
     pub fn from_err<E: ?Sized + std::error::Error>(err: &'a E) -> ErrJson<'a> {
         let cause = err.cause().map(ErrJson::from_err).map(Box::new);
-        ErrJson { description: err.description(), cause }
+        ErrJson { description: err.description(), cause, trace_id: None }
     }
 }
 macro_rules! try_or_500 {
@@ -158,6 +182,31 @@ macro_rules! try_or_500 {
             },
         }
     );
+    ($result:expr, $trace_id:expr) => (
+        match $result {
+            Ok(r) => r,
+            Err(err) => {
+                let trace_id: &TraceId = $trace_id;
+                error!("[{}]: {}", trace_id, err);
+                let json = ErrJson { trace_id: Some(&trace_id.0), ..ErrJson::from_err(&err) };
+                return rouille::Response::json(&json).with_status_code(500)
+            },
+        }
+    );
+}
+
+/// The trace id a request was sent with, or a new one for clients that don't send it.
+fn trace_id_input(request: &rouille::Request) -> TraceId {
+    request.header(TRACE_ID_HEADER)
+        .map(|id| TraceId(id.to_owned()))
+        .unwrap_or_else(TraceId::new)
+}
+
+/// Headers to send the trace id `trace_id` with a request.
+fn trace_id_headers(trace_id: &TraceId) -> reqwest::header::Headers {
+    let mut headers = reqwest::header::Headers::new();
+    headers.set_raw(TRACE_ID_HEADER, trace_id.0.clone());
+    headers
 }
 
 // Note that content-length is necessary due to https://github.com/tiny-http/tiny-http/issues/147
@@ -201,23 +250,43 @@ fn bincode_req<T: serde::de::DeserializeOwned + 'static>(req: &mut reqwest::Requ
 }
 
 fn bincode_req_fut<T: serde::de::DeserializeOwned + 'static>(req: &mut reqwest::unstable::async::RequestBuilder) -> SFuture<T> {
-    Box::new(req.send().map_err(Into::into)
-        .and_then(|res| {
-            let status = res.status();
-            res.into_body().concat2()
-                .map(move |b| (status, b)).map_err(Into::into)
-        })
-        .and_then(|(status, body)| {
+    Box::new(bincode_req_headers_fut(req).map(|(r, _headers)| r))
+}
+
+/// Like `bincode_req_fut`, but also return the response headers.
+fn bincode_req_headers_fut<T: serde::de::DeserializeOwned + 'static>(req: &mut reqwest::unstable::async::RequestBuilder) -> SFuture<(T, reqwest::header::Headers)> {
+    Box::new(req.send()
+        .map_err(|e| Error::with_chain(e, ErrorKind::DistRetryable("the request failed".to_owned())))
+        .and_then(bincode_res_fut))
+}
+
+/// Read and deserialize the body of `res`, returning it along with the response headers.
+fn bincode_res_fut<T: serde::de::DeserializeOwned + 'static>(res: reqwest::unstable::async::Response) -> SFuture<(T, reqwest::header::Headers)> {
+    let status = res.status();
+    let headers = res.headers().clone();
+    Box::new(res.into_body().concat2()
+        .map_err(|e| Error::with_chain(e, ErrorKind::DistRetryable("the connection was lost".to_owned())))
+        .and_then(move |body| {
+            if status == reqwest::StatusCode::ServiceUnavailable {
+                return f_err(ErrorKind::DistRetryable(format!("Error {}: {}", status.as_u16(), String::from_utf8_lossy(&body))))
+            }
             if !status.is_success() {
                 return f_err(format!("Error {}: {}", status.as_u16(), String::from_utf8_lossy(&body)))
             }
             match bincode::deserialize(&body) {
-                Ok(r) => f_ok(r),
+                Ok(r) => f_ok((r, headers)),
                 Err(e) => f_err(e),
             }
         }))
 }
 
+/// Whether a build server's response `headers` say it accepts gzip compressed inputs.
+fn accepts_gzip_inputs(headers: &reqwest::header::Headers) -> bool {
+    headers.get_raw(ACCEPT_INPUTS_ENCODING_HEADER)
+        .and_then(|v| v.one())
+        .map_or(false, |v| v.split(|&b| b == b',').any(|e| e == b"gzip"))
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 struct HeartbeatServerHttpRequest {
@@ -226,8 +295,17 @@ struct HeartbeatServerHttpRequest {
 }
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
+struct UpdateJobStatusHttpRequest {
+    port: u16,
+    status: JobStatus,
+}
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct AllocJobHttpRequest {
     pub toolchain: Toolchain,
+    // Missing from requests by older clients.
+    #[serde(default)]
+    pub exclude_servers: Vec<ServerId>,
 }
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -263,10 +341,13 @@ impl<S: SchedulerIncoming + 'static> Scheduler<S> {
             trace!("Req {}: {:?}", request_id, request);
             let response = (|| router!(request,
                 (POST) (/api/v1/scheduler/alloc_job) => {
-                    let toolchain = try_or_400!(bincode_input(request));
-                    trace!("Req {}: alloc_job: {:?}", request_id, toolchain);
+                    let trace_id = trace_id_input(request);
+                    let alloc_job = try_or_400!(bincode_input(request));
+                    trace!("Req {} [{}]: alloc_job: {:?}", request_id, trace_id, alloc_job);
+                    let AllocJobHttpRequest { toolchain, exclude_servers } = alloc_job;
 
-                    let res: AllocJobResult = try_or_500!(handler.handle_alloc_job(&requester, toolchain));
+                    let client = request.remote_addr().ip();
+                    let res: AllocJobResult = try_or_500!(handler.handle_alloc_job(&requester, &trace_id, client, toolchain, &exclude_servers), &trace_id);
                     bincode_response(&res)
                 },
                 (POST) (/api/v1/scheduler/heartbeat_server) => {
@@ -278,10 +359,23 @@ impl<S: SchedulerIncoming + 'static> Scheduler<S> {
                     let HeartbeatServerResult = handler.handle_heartbeat_server(server_id, num_cpus).unwrap();
                     rouille::Response::empty_204()
                 },
+                (POST) (/api/v1/scheduler/job_status/{job_id: JobId}) => {
+                    let update = try_or_400!(bincode_input(request));
+                    trace!("Req {}: job_status: {} {:?}", request_id, job_id, update);
+                    let UpdateJobStatusHttpRequest { port, status } = update;
+                    let server_id = ServerId(SocketAddr::new(request.remote_addr().ip(), port));
+
+                    let UpdateJobStatusResult = handler.handle_update_job_status(server_id, job_id, status).unwrap();
+                    rouille::Response::empty_204()
+                },
                 (GET) (/api/v1/scheduler/status) => {
                     let res: StatusResult = handler.handle_status().unwrap();
                     bincode_response(&res)
                 },
+                (GET) (/api/v1/scheduler/stats) => {
+                    let res: StatsResult = handler.handle_stats().unwrap();
+                    bincode_response(&res)
+                },
                 _ => {
                     warn!("Unknown request {:?}", request);
                     rouille::Response::empty_404()
@@ -301,40 +395,47 @@ struct SchedulerRequester {
 }
 
 impl SchedulerOutgoing for SchedulerRequester {
-    fn do_assign_job(&self, server_id: ServerId, job_id: JobId, tc: Toolchain) -> Result<AssignJobResult> {
+    fn do_assign_job(&self, trace_id: &TraceId, server_id: ServerId, job_id: JobId, tc: Toolchain) -> Result<AssignJobResult> {
         let url = format!("http://{}/api/v1/distserver/assign_job/{}", server_id.addr(), job_id);
-        bincode_req(self.client.post(&url).bincode(&tc)?)
+        bincode_req(self.client.post(&url).headers(trace_id_headers(trace_id)).bincode(&tc)?)
     }
 }
 
 pub struct Server<S> {
-    scheduler_addr: SocketAddr,
+    scheduler_addrs: Vec<SocketAddr>,
     handler: S,
 }
 
 impl<S: ServerIncoming + 'static> Server<S> {
-    pub fn new(scheduler_addr: IpAddr, handler: S) -> Self {
+    /// Create a build server which registers with each of `scheduler_addrs`.
+    pub fn new(scheduler_addrs: &[IpAddr], handler: S) -> Self {
         Self {
-            scheduler_addr: Cfg::scheduler_connect_addr(scheduler_addr),
+            scheduler_addrs: scheduler_addrs.iter().map(|&addr| Cfg::scheduler_connect_addr(addr)).collect(),
             handler,
         }
     }
 
     pub fn start(self) -> ! {
-        let Self { scheduler_addr, handler } = self;
-        let requester = ServerRequester { _client: reqwest::Client::new(), _scheduler_addr: scheduler_addr };
+        let Self { scheduler_addrs, handler } = self;
         let addr = Cfg::server_listen_addr();
+        let requester = ServerRequester { client: reqwest::Client::new(), job_schedulers: Mutex::new(HashMap::new()), port: addr.port() };
 
         // TODO: detect if this panics
         thread::spawn(move || {
-            let url = format!("http://{}:{}/api/v1/scheduler/heartbeat_server", scheduler_addr.ip(), scheduler_addr.port());
+            let urls: Vec<_> = scheduler_addrs.iter()
+                .map(|addr| format!("http://{}:{}/api/v1/scheduler/heartbeat_server", addr.ip(), addr.port()))
+                .collect();
             let req = HeartbeatServerHttpRequest { num_cpus: num_cpus::get(), port: addr.port() };
             let client = reqwest::Client::new();
             loop {
-                match client.post(&url).bincode(&req).unwrap().send() {
-                    Ok(ref res) if res.status().is_success() => (),
-                    Ok(res) => error!("Response {} from server when heartbeating {:?}", res.status(), req),
-                    Err(e) => error!("Failed to send heartbeat to server: {}", e),
+                // Heartbeat to every scheduler, so that a standby scheduler can
+                // take over without waiting for servers to register with it
+                for url in urls.iter() {
+                    match client.post(url).bincode(&req).unwrap().send() {
+                        Ok(ref res) if res.status().is_success() => (),
+                        Ok(res) => error!("Response {} from {} when heartbeating {:?}", res.status(), url, req),
+                        Err(e) => error!("Failed to send heartbeat to {}: {}", url, e),
+                    }
                 }
                 thread::sleep(Duration::from_secs(30))
             }
@@ -346,32 +447,50 @@ impl<S: ServerIncoming + 'static> Server<S> {
             trace!("Req {}: {:?}", request_id, request);
             let response = (|| router!(request,
                 (POST) (/api/v1/distserver/assign_job/{job_id: JobId}) => {
+                    let trace_id = trace_id_input(request);
                     let toolchain = try_or_400!(bincode_input(request));
-                    trace!("Req {}: assign_job: {:?}", request_id, toolchain);
+                    trace!("Req {} [{}]: assign_job: {:?}", request_id, trace_id, toolchain);
 
-                    let res: AssignJobResult = try_or_500!(handler.handle_assign_job(job_id, toolchain));
+                    let res: AssignJobResult = try_or_500!(handler.handle_assign_job(&trace_id, job_id, toolchain), &trace_id);
+                    // Only the scheduler that assigned the job is told how it went
+                    let scheduler_addr = Cfg::scheduler_connect_addr(request.remote_addr().ip());
+                    requester.job_schedulers.lock().unwrap().insert(job_id, scheduler_addr);
                     bincode_response(&res)
                 },
                 (POST) (/api/v1/distserver/submit_toolchain/{job_id: JobId}) => {
+                    let trace_id = trace_id_input(request);
+                    trace!("Req {} [{}]: submit_toolchain: {}", request_id, trace_id, job_id);
+                    let signature = match request.header(TOOLCHAIN_SIGNATURE_HEADER) {
+                        Some(sig) => Some(try_or_400!(base64::decode(sig))),
+                        None => None,
+                    };
                     let mut body = request.data().unwrap();
                     let toolchain_rdr = ToolchainReader(Box::new(body));
 
-                    let res: SubmitToolchainResult = try_or_500!(handler.handle_submit_toolchain(&requester, job_id, toolchain_rdr));
+                    let res: SubmitToolchainResult = try_or_500!(handler.handle_submit_toolchain(&requester, &trace_id, job_id, toolchain_rdr, signature), &trace_id);
                     bincode_response(&res)
                 },
                 (POST) (/api/v1/distserver/run_job) => {
+                    let trace_id = trace_id_input(request);
                     let mut body = request.data().unwrap();
                     let bincode_length = body.read_u32::<BigEndian>().unwrap() as u64;
 
                     let mut bincode_reader = body.take(bincode_length);
                     let runjob = bincode::deserialize_from(&mut bincode_reader, bincode::Infinite).unwrap();
-                    trace!("Req {}: run_job: {:?}", request_id, runjob);
+                    trace!("Req {} [{}]: run_job: {:?}", request_id, trace_id, runjob);
                     let RunJobHttpRequest { job_id, command, outputs } = runjob;
                     let body = bincode_reader.into_inner();
-                    let inputs_rdr = InputsReader(Box::new(body));
+                    let inputs_rdr = match request.header(INPUTS_ENCODING_HEADER) {
+                        None => InputsReader(Box::new(body)),
+                        Some("gzip") => InputsReader(Box::new(GzDecoder::new(body))),
+                        Some(encoding) => {
+                            return rouille::Response::text(format!("Unsupported inputs encoding: {}", encoding))
+                                .with_status_code(400)
+                        }
+                    };
                     let outputs = outputs.into_iter().collect();
 
-                    let res: RunJobResult = try_or_500!(handler.handle_run_job(&requester, job_id, command, outputs, inputs_rdr));
+                    let res: RunJobResult = try_or_500!(handler.handle_run_job(&requester, &trace_id, job_id, command, outputs, inputs_rdr), &trace_id);
                     bincode_response(&res)
                 },
                 _ => {
@@ -379,6 +498,7 @@ impl<S: ServerIncoming + 'static> Server<S> {
                     rouille::Response::empty_404()
                 },
             ))();
+            let response = response.with_additional_header(ACCEPT_INPUTS_ENCODING_HEADER, "gzip");
             trace!("Res {}: {:?}", request_id, response);
             response
         }).unwrap();
@@ -389,59 +509,188 @@ impl<S: ServerIncoming + 'static> Server<S> {
 }
 
 struct ServerRequester {
-    _client: reqwest::Client,
-    _scheduler_addr: SocketAddr,
+    client: reqwest::Client,
+    // The scheduler which assigned each job that hasn't finished yet
+    job_schedulers: Mutex<HashMap<JobId, SocketAddr>>,
+    port: u16,
 }
 
 impl ServerOutgoing for ServerRequester {
-    fn do_update_job_status(&self, _job_id: JobId, _status: JobStatus) -> Result<UpdateJobStatusResult> {
-        // TODO
+    fn do_update_job_status(&self, job_id: JobId, status: JobStatus) -> Result<UpdateJobStatusResult> {
+        // Schedulers only count finished jobs, so there's nothing to send
+        // until the job has completed or failed
+        match status {
+            JobStatus::Complete { .. } | JobStatus::Failed => (),
+            JobStatus::Pending | JobStatus::Started => return Ok(UpdateJobStatusResult),
+        }
+        let addr = match self.job_schedulers.lock().unwrap().remove(&job_id) {
+            Some(addr) => addr,
+            None => {
+                warn!("Not updating the status of job {}, no scheduler assigned it", job_id);
+                return Ok(UpdateJobStatusResult)
+            },
+        };
+        // Status updates only feed statistics, so send them in the background
+        // rather than holding up the build result, and don't fail the job if
+        // the scheduler is unreachable
+        let url = format!("http://{}/api/v1/scheduler/job_status/{}", addr, job_id);
+        let req = UpdateJobStatusHttpRequest { port: self.port, status };
+        let client = self.client.clone();
+        thread::spawn(move || {
+            let res = client.post(&url).bincode(&req).and_then(|b| b.send().map_err(Into::into));
+            match res {
+                Ok(ref res) if res.status().is_success() => (),
+                Ok(res) => warn!("Response {} from {} when updating the status of job {}", res.status(), url, job_id),
+                Err(e) => warn!("Failed to update the status of job {} at {}: {}", job_id, url, e),
+            }
+        });
         Ok(UpdateJobStatusResult)
     }
 }
 
 pub struct Client {
-    scheduler_addr: SocketAddr,
+    // The primary scheduler followed by the standby schedulers
+    scheduler_addrs: Arc<Vec<SocketAddr>>,
+    // Index into `scheduler_addrs` of the scheduler that last responded
+    current_scheduler: Arc<AtomicUsize>,
     client: reqwest::unstable::async::Client,
     tc_cache: cache::ClientToolchains,
+    tc_signer: Option<ToolchainSigner>,
+    inputs_compression: config::InputsCompression,
+    // Build servers which have said they accept gzip compressed inputs
+    gzip_servers: Arc<Mutex<HashSet<ServerId>>>,
 }
 
 impl Client {
-    pub fn new(handle: &tokio_core::reactor::Handle, scheduler_addr: IpAddr, cache_dir: &Path, cache_size: u64, custom_toolchains: &[config::CustomToolchain]) -> Self {
+    pub fn new(handle: &tokio_core::reactor::Handle, scheduler_addr: IpAddr, standby_scheduler_addrs: &[IpAddr], cache_dir: &Path, cache_size: u64, custom_toolchains: &[config::CustomToolchain], signing_key: Option<&Path>, inputs_compression: config::InputsCompression) -> Self {
+        let tc_signer = signing_key.and_then(|path| {
+            ToolchainSigner::from_pkcs8_file(path)
+                .map_err(|e| error!("Not signing toolchains: {}", e))
+                .ok()
+        });
         Self {
-            scheduler_addr: Cfg::scheduler_connect_addr(scheduler_addr),
+            scheduler_addrs: Arc::new(std::iter::once(&scheduler_addr).chain(standby_scheduler_addrs)
+                .map(|&addr| Cfg::scheduler_connect_addr(addr))
+                .collect()),
+            current_scheduler: Arc::new(AtomicUsize::new(0)),
             client: reqwest::unstable::async::Client::new(handle),
             tc_cache: cache::ClientToolchains::new(cache_dir, cache_size, custom_toolchains),
+            tc_signer,
+            inputs_compression,
+            gzip_servers: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Remember whether the build server `server_id` accepts compressed inputs, from its response `headers`.
+    fn record_inputs_encoding(gzip_servers: &Mutex<HashSet<ServerId>>, server_id: ServerId, headers: &reqwest::header::Headers) {
+        let mut gzip_servers = gzip_servers.lock().unwrap();
+        if accepts_gzip_inputs(headers) {
+            gzip_servers.insert(server_id);
+        } else {
+            gzip_servers.remove(&server_id);
         }
     }
 }
 
 impl super::Client for Client {
-    fn do_alloc_job(&self, tc: Toolchain) -> SFuture<AllocJobResult> {
-        let url = format!("http://{}/api/v1/scheduler/alloc_job", self.scheduler_addr);
-        Box::new(f_res(self.client.post(&url).bincode(&tc).map(bincode_req_fut)).and_then(|r| r))
+    fn do_alloc_job(&self, trace_id: &TraceId, tc: Toolchain, exclude_servers: &[ServerId]) -> SFuture<AllocJobResult> {
+        let req = AllocJobHttpRequest { toolchain: tc, exclude_servers: exclude_servers.to_vec() };
+        let body = ftry!(bincode::serialize(&req, bincode::Infinite));
+        let headers = trace_id_headers(trace_id);
+        let client = self.client.clone();
+        let scheduler_addrs = self.scheduler_addrs.clone();
+        let current_scheduler = self.current_scheduler.clone();
+        let first = current_scheduler.load(Ordering::SeqCst);
+        // Start with the scheduler that last responded, and only move on to the
+        // next one if it can't be reached at all. A scheduler that responds
+        // with an error may already have assigned the job to a build server,
+        // so asking another one could run the job twice.
+        Box::new(future::loop_fn(0, move |attempt| {
+            let index = (first + attempt) % scheduler_addrs.len();
+            let url = format!("http://{}/api/v1/scheduler/alloc_job", scheduler_addrs[index]);
+            let num_schedulers = scheduler_addrs.len();
+            let current_scheduler = current_scheduler.clone();
+            client.post(&url).headers(headers.clone()).bytes(body.clone()).send().then(move |res| match res {
+                Ok(res) => {
+                    if current_scheduler.swap(index, Ordering::SeqCst) != index {
+                        info!("Failed over to scheduler {}", url);
+                    }
+                    Ok(Loop::Break(res))
+                },
+                Err(e) => {
+                    if attempt + 1 >= num_schedulers {
+                        return Err(e).chain_err(|| "no scheduler could be reached")
+                    }
+                    warn!("Failed to reach scheduler {}: {}", url, e);
+                    Ok(Loop::Continue(attempt + 1))
+                },
+            })
+        }).and_then(bincode_res_fut).map(|(r, _headers)| r))
     }
-    fn do_submit_toolchain(&self, job_alloc: JobAlloc, tc: Toolchain) -> SFuture<SubmitToolchainResult> {
+    fn do_submit_toolchain(&self, trace_id: &TraceId, job_alloc: JobAlloc, tc: Toolchain) -> SFuture<SubmitToolchainResult> {
         let url = format!("http://{}/api/v1/distserver/submit_toolchain/{}", job_alloc.server_id.addr(), job_alloc.job_id);
         if let Some(toolchain_bytes) = self.tc_cache.get_toolchain(&tc) {
-            bincode_req_fut(self.client.post(&url).bytes(toolchain_bytes))
+            let mut req = self.client.post(&url);
+            let mut headers = trace_id_headers(trace_id);
+            if let Some(ref signer) = self.tc_signer {
+                headers.set_raw(TOOLCHAIN_SIGNATURE_HEADER, base64::encode(&signer.sign(&tc)));
+            }
+            req.headers(headers);
+            let gzip_servers = self.gzip_servers.clone();
+            let server_id = job_alloc.server_id;
+            Box::new(bincode_req_headers_fut(req.bytes(toolchain_bytes)).map(move |(res, headers)| {
+                Client::record_inputs_encoding(&gzip_servers, server_id, &headers);
+                res
+            }))
         } else {
             f_err("couldn't find toolchain locally")
         }
     }
-    fn do_run_job(&self, job_alloc: JobAlloc, command: CompileCommand, outputs: Vec<String>, mut write_inputs: Box<FnMut(&mut Write)>) -> SFuture<RunJobResult> {
+    fn do_run_job(&self, trace_id: &TraceId, job_alloc: JobAlloc, command: CompileCommand, outputs: Vec<String>, mut write_inputs: Box<FnMut(&mut Write)>) -> SFuture<RunJobResult> {
         let url = format!("http://{}/api/v1/distserver/run_job", job_alloc.server_id.addr());
         let bincode = bincode::serialize(&RunJobHttpRequest { job_id: job_alloc.job_id, command, outputs }, bincode::Infinite).unwrap();
         let bincode_length = bincode.len();
         let mut inputs = vec![];
         write_inputs(&mut inputs);
 
+        let mut req = self.client.post(&url);
+        let mut headers = trace_id_headers(trace_id);
+        let gzip = self.inputs_compression == config::InputsCompression::Gzip &&
+            self.gzip_servers.lock().unwrap().contains(&job_alloc.server_id);
+        if gzip {
+            let raw_len = inputs.len();
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(&inputs).unwrap();
+            inputs = encoder.finish().unwrap();
+            debug!("[{}]: Compressed inputs for job {} from {} to {} bytes ({:.1}x)",
+                   trace_id, job_alloc.job_id, raw_len, inputs.len(), raw_len as f64 / inputs.len() as f64);
+            headers.set_raw(INPUTS_ENCODING_HEADER, "gzip");
+        }
+        req.headers(headers);
+
         let mut body = vec![];
         body.write_u32::<BigEndian>(bincode_length as u32).unwrap();
         body.write(&bincode).unwrap();
         body.write(&inputs).unwrap();
 
-        bincode_req_fut(self.client.post(&url).bytes(body))
+        let gzip_servers = self.gzip_servers.clone();
+        let server_id = job_alloc.server_id;
+        Box::new(bincode_req_headers_fut(req.bytes(body)).map(move |(res, headers)| {
+            Client::record_inputs_encoding(&gzip_servers, server_id, &headers);
+            res
+        }))
+    }
+
+    fn do_get_status(&self) -> SFuture<StatusResult> {
+        let scheduler_addr = self.scheduler_addrs[self.current_scheduler.load(Ordering::SeqCst)];
+        let url = format!("http://{}/api/v1/scheduler/status", scheduler_addr);
+        bincode_req_fut(&mut self.client.get(&url))
+    }
+
+    fn do_get_stats(&self) -> SFuture<StatsResult> {
+        let scheduler_addr = self.scheduler_addrs[self.current_scheduler.load(Ordering::SeqCst)];
+        let url = format!("http://{}/api/v1/scheduler/stats", scheduler_addr);
+        bincode_req_fut(&mut self.client.get(&url))
     }
 
     fn put_toolchain(&self, compiler_path: &Path, weak_key: &str, create: BoxFnOnce<(fs::File,), io::Result<()>>) -> Result<(Toolchain, Option<String>)> {
@@ -14,16 +14,27 @@
 
 use boxfnonce::BoxFnOnce;
 use compiler;
-pub use dist::cache::TcCache;
+use config::CONFIG;
+use futures::{future, Future};
+use futures::future::Loop;
+pub use dist::cache::{TcCache, toolchain_archive_id};
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair};
+use serde_json;
+use std::cell::Cell;
 use std::fmt;
-use std::fs;
+use std::fs::{self, File};
 use std::io::{self, Read, Write};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::ffi::OsString;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::rc::Rc;
 use std::str::FromStr;
 use std::sync::Mutex;
+use untrusted;
+use util::glob_matches;
+use uuid::Uuid;
 
 use errors::*;
 
@@ -168,6 +179,55 @@ pub fn try_compile_command_to_dist(command: compiler::CompileCommand) -> Option<
     })
 }
 
+// Toolchain signing
+//
+// A toolchain is identified by its archive id, which is a hash of the archive
+// contents that the server checks when storing the archive. Signing the archive
+// id is therefore enough to tie a signature to the archive contents.
+
+/// Signs toolchains before they are submitted to a server.
+pub struct ToolchainSigner {
+    key_pair: Ed25519KeyPair,
+}
+
+impl ToolchainSigner {
+    /// Load an Ed25519 signing key in PKCS#8 format from `path`.
+    pub fn from_pkcs8_file(path: &Path) -> Result<Self> {
+        let mut pkcs8 = vec![];
+        File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut pkcs8))
+            .chain_err(|| format!("failed to read toolchain signing key {:?}", path))?;
+        let key_pair = Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8))
+            .map_err(|_| -> Error { format!("invalid toolchain signing key {:?}", path).into() })?;
+        Ok(ToolchainSigner { key_pair })
+    }
+
+    /// Produce a detached signature for `tc`.
+    pub fn sign(&self, tc: &Toolchain) -> Vec<u8> {
+        self.key_pair.sign(tc.archive_id.as_bytes()).as_ref().to_owned()
+    }
+}
+
+/// Check that `sig` is a valid signature of `tc` made with the key matching `public_key`.
+pub fn verify_toolchain_signature(public_key: &[u8], tc: &Toolchain, sig: &[u8]) -> Result<()> {
+    signature::verify(&signature::ED25519,
+                      untrusted::Input::from(public_key),
+                      untrusted::Input::from(tc.archive_id.as_bytes()),
+                      untrusted::Input::from(sig))
+        .map_err(|_| "toolchain signature verification failed".into())
+}
+
+/// Generate a new toolchain signing key, returning the PKCS#8 encoded
+/// key pair and the raw public key.
+pub fn generate_toolchain_signing_key() -> Result<(Vec<u8>, Vec<u8>)> {
+    let rng = SystemRandom::new();
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
+        .map_err(|_| -> Error { "failed to generate toolchain signing key".into() })?;
+    let key_pair = Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8))
+        .map_err(|_| -> Error { "failed to parse generated toolchain signing key".into() })?;
+    Ok((pkcs8.to_vec(), key_pair.public_key_bytes().to_owned()))
+}
+
 // TODO: Clone by assuming immutable/no GC for now
 // TODO: make fields non-public?
 // TODO: make archive_id validate that it's just a bunch of hex chars
@@ -193,6 +253,22 @@ impl FromStr for JobId {
         u64::from_str(s).map(JobId)
     }
 }
+/// Identifies a single distributed compile across the client, scheduler and
+/// build server, so their logs for it can be matched up.
+#[derive(Hash, Eq, PartialEq)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TraceId(pub String);
+impl TraceId {
+    pub fn new() -> Self {
+        TraceId(Uuid::new_v4().simple().to_string())
+    }
+}
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
 #[derive(Hash, Eq, PartialEq)]
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -225,6 +301,28 @@ impl From<process::Output> for ProcessOutput {
         ProcessOutput { code: o.status.code(), stdout: o.stdout, stderr: o.stderr }
     }
 }
+impl ProcessOutput {
+    /// Whether the process exited successfully.
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+    /// Whether the process looks like a compiler that crashed, rather than one
+    /// that failed on bad input: it was killed by a signal, or reported an
+    /// internal error.
+    pub fn crashed(&self) -> bool {
+        const CRASH_MARKERS: &[&[u8]] = &[
+            // gcc
+            b"internal compiler error",
+            // clang and rustc on LLVM assertions
+            b"PLEASE submit a bug report",
+            // msvc
+            b"fatal error C1001",
+        ];
+        self.code.is_none() || CRASH_MARKERS.iter().any(|marker| {
+            self.stderr.windows(marker.len()).any(|w| w == *marker)
+        })
+    }
+}
 #[cfg(unix)]
 use std::os::unix::process::ExitStatusExt;
 #[cfg(windows)]
@@ -245,6 +343,77 @@ impl From<ProcessOutput> for process::Output {
     }
 }
 
+/// What's needed to rerun a compile that crashed on a build server.
+#[derive(Serialize)]
+struct CrashReproducer<'a> {
+    trace_id: &'a TraceId,
+    toolchain: &'a Toolchain,
+    command: &'a CompileCommand,
+    code: Option<i32>,
+}
+
+/// Save the compile `command` that crashed on a build server with `output`,
+/// along with the `inputs` archive it was sent and the id of its toolchain,
+/// in a directory for `trace_id` under `dir`. Return that directory.
+pub fn write_crash_reproducer(dir: &Path, trace_id: &TraceId, toolchain: &Toolchain, command: &CompileCommand,
+                              inputs: &[u8], output: &ProcessOutput) -> Result<PathBuf> {
+    let dir = dir.join(&trace_id.0);
+    fs::create_dir_all(&dir).chain_err(|| format!("failed to create {}", dir.display()))?;
+    let reproducer = CrashReproducer { trace_id, toolchain, command, code: output.code };
+    let file = File::create(dir.join("command.json"))?;
+    serde_json::to_writer_pretty(file, &reproducer).chain_err(|| "failed to write command.json")?;
+    for &(name, contents) in &[("inputs.tar", inputs), ("stdout", &output.stdout[..]), ("stderr", &output.stderr[..])] {
+        File::create(dir.join(name))?.write_all(contents)?;
+    }
+    Ok(dir)
+}
+
+/// Whether a distributed compile that failed with `e` may succeed if run
+/// again on another build server, as when the server went away or was too
+/// busy, rather than failing because of the compile itself.
+pub fn is_retryable(e: &Error) -> bool {
+    match *e.kind() {
+        ErrorKind::DistRetryable(_) => true,
+        _ => false,
+    }
+}
+
+/// Run a distributed job with `run`, and again up to `job_retries` times for
+/// as long as it fails in a way another build server might not, counting each
+/// retry in `retries`. `run` is passed the servers that have already failed
+/// the job, so that it can ask the scheduler for another one, and fails with
+/// the server it ran the job on if it got as far as being allocated one.
+pub fn run_with_retries<T, F>(job_retries: u32, retries: Rc<Cell<u64>>, mut run: F) -> SFuture<T>
+    where T: 'static,
+          F: FnMut(&[ServerId]) -> Box<Future<Item=T, Error=(Option<ServerId>, Error)>> + 'static
+{
+    Box::new(future::loop_fn(vec![], move |mut failed_servers: Vec<ServerId>| {
+        let retries = retries.clone();
+        run(&failed_servers).then(move |res| match res {
+            Ok(t) => Ok(Loop::Break(t)),
+            Err((server_id, e)) => {
+                if retries.get() >= job_retries as u64 || !is_retryable(&e) {
+                    return Err(e)
+                }
+                warn!("Retrying distributed compile on another build server: {}", e);
+                retries.set(retries.get() + 1);
+                failed_servers.extend(server_id);
+                Ok(Loop::Continue(failed_servers))
+            },
+        })
+    }))
+}
+
+/// Whether the source file at `path` matches one of the `local_only` globs,
+/// so it mustn't be sent to a build server. Only the main source file is
+/// checked: headers are sent as part of the preprocessed output of whichever
+/// sources include them, even if they match the globs themselves.
+pub fn is_local_only(path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    let path = if cfg!(windows) { path.replace('\\', "/") } else { path.into_owned() };
+    CONFIG.dist.local_only.iter().any(|pattern| glob_matches(pattern, &path))
+}
+
 // TODO: standardise on compressed or not for inputs and toolchain
 
 // TODO: make fields not public
@@ -274,10 +443,16 @@ pub struct AssignJobResult {
 
 // JobStatus
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub enum JobStatus {
     Pending,
     Started,
-    Complete,
+    /// The build ran for `build_ms` milliseconds, and the compiler exited
+    /// successfully if `succeeded`.
+    Complete { build_ms: u64, succeeded: bool },
+    /// The build couldn't be run.
+    Failed,
 }
 #[derive(Clone)]
 pub struct UpdateJobStatusResult;
@@ -310,6 +485,35 @@ pub struct StatusResult {
     pub num_servers: usize,
 }
 
+// Stats
+
+/// What a scheduler has seen of the jobs of one build server or client.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobStats {
+    /// The number of jobs assigned.
+    pub jobs_dispatched: u64,
+    /// The number of jobs whose build ran, successfully or not.
+    pub jobs_completed: u64,
+    /// The number of completed jobs whose compiler failed.
+    pub compile_failures: u64,
+    /// The number of jobs whose build couldn't be run.
+    pub jobs_failed: u64,
+    /// The total time spent running builds, in milliseconds.
+    pub build_ms: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatsResult {
+    /// The number of jobs that couldn't be assigned to any server.
+    pub alloc_failures: u64,
+    /// Stats for each build server.
+    pub servers: Vec<(ServerId, JobStats)>,
+    /// Stats for each client, by address.
+    pub clients: Vec<(IpAddr, JobStats)>,
+}
+
 // SubmitToolchain
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -318,6 +522,7 @@ pub enum SubmitToolchainResult {
     Success,
     JobNotFound,
     CannotCache,
+    InvalidSignature,
 }
 
 ///////////////////
@@ -350,7 +555,7 @@ type ExtResult<T, E> = ::std::result::Result<T, E>;
 
 pub trait SchedulerOutgoing {
     // To Server
-    fn do_assign_job(&self, server_id: ServerId, job_id: JobId, tc: Toolchain) -> Result<AssignJobResult>;
+    fn do_assign_job(&self, trace_id: &TraceId, server_id: ServerId, job_id: JobId, tc: Toolchain) -> Result<AssignJobResult>;
 }
 
 pub trait ServerOutgoing {
@@ -361,21 +566,25 @@ pub trait ServerOutgoing {
 pub trait SchedulerIncoming: Send + Sync {
     type Error: ::std::error::Error;
     // From Client
-    fn handle_alloc_job(&self, requester: &SchedulerOutgoing, tc: Toolchain) -> ExtResult<AllocJobResult, Self::Error>;
+    fn handle_alloc_job(&self, requester: &SchedulerOutgoing, trace_id: &TraceId, client: IpAddr, tc: Toolchain, exclude_servers: &[ServerId]) -> ExtResult<AllocJobResult, Self::Error>;
     // From Server
     fn handle_heartbeat_server(&self, server_id: ServerId, num_cpus: usize) -> ExtResult<HeartbeatServerResult, Self::Error>;
+    // From Server
+    fn handle_update_job_status(&self, server_id: ServerId, job_id: JobId, status: JobStatus) -> ExtResult<UpdateJobStatusResult, Self::Error>;
     // From anyone
     fn handle_status(&self) -> ExtResult<StatusResult, Self::Error>;
+    // From anyone
+    fn handle_stats(&self) -> ExtResult<StatsResult, Self::Error>;
 }
 
 pub trait ServerIncoming: Send + Sync {
     type Error: ::std::error::Error;
     // From Scheduler
-    fn handle_assign_job(&self, job_id: JobId, tc: Toolchain) -> ExtResult<AssignJobResult, Self::Error>;
+    fn handle_assign_job(&self, trace_id: &TraceId, job_id: JobId, tc: Toolchain) -> ExtResult<AssignJobResult, Self::Error>;
     // From Client
-    fn handle_submit_toolchain(&self, requester: &ServerOutgoing, job_id: JobId, tc_rdr: ToolchainReader) -> ExtResult<SubmitToolchainResult, Self::Error>;
+    fn handle_submit_toolchain(&self, requester: &ServerOutgoing, trace_id: &TraceId, job_id: JobId, tc_rdr: ToolchainReader, signature: Option<Vec<u8>>) -> ExtResult<SubmitToolchainResult, Self::Error>;
     // From Client
-    fn handle_run_job(&self, requester: &ServerOutgoing, job_id: JobId, command: CompileCommand, outputs: Vec<String>, inputs_rdr: InputsReader) -> ExtResult<RunJobResult, Self::Error>;
+    fn handle_run_job(&self, requester: &ServerOutgoing, trace_id: &TraceId, job_id: JobId, command: CompileCommand, outputs: Vec<String>, inputs_rdr: InputsReader) -> ExtResult<RunJobResult, Self::Error>;
 }
 
 pub trait BuilderIncoming: Send + Sync {
@@ -388,13 +597,18 @@ pub trait BuilderIncoming: Send + Sync {
 
 pub trait Client {
     // To Scheduler
-    fn do_alloc_job(&self, tc: Toolchain) -> SFuture<AllocJobResult>;
+    // Ask for a job on any build server but those in `exclude_servers`
+    fn do_alloc_job(&self, trace_id: &TraceId, tc: Toolchain, exclude_servers: &[ServerId]) -> SFuture<AllocJobResult>;
     // To Server
-    fn do_submit_toolchain(&self, job_alloc: JobAlloc, tc: Toolchain) -> SFuture<SubmitToolchainResult>;
+    fn do_submit_toolchain(&self, trace_id: &TraceId, job_alloc: JobAlloc, tc: Toolchain) -> SFuture<SubmitToolchainResult>;
     // To Server
     // TODO: ideally Box<FnOnce or FnBox
     // BoxFnOnce library doesn't work due to incorrect lifetime inference - https://github.com/rust-lang/rust/issues/28796#issuecomment-410071058
-    fn do_run_job(&self, job_alloc: JobAlloc, command: CompileCommand, outputs: Vec<String>, write_inputs: Box<FnMut(&mut Write)>) -> SFuture<RunJobResult>;
+    fn do_run_job(&self, trace_id: &TraceId, job_alloc: JobAlloc, command: CompileCommand, outputs: Vec<String>, write_inputs: Box<FnMut(&mut Write)>) -> SFuture<RunJobResult>;
+    // To Scheduler
+    fn do_get_status(&self) -> SFuture<StatusResult>;
+    // To Scheduler
+    fn do_get_stats(&self) -> SFuture<StatsResult>;
     fn put_toolchain(&self, compiler_path: &Path, weak_key: &str, create: BoxFnOnce<(fs::File,), io::Result<()>>) -> Result<(Toolchain, Option<String>)>;
     fn may_dist(&self) -> bool;
 }
@@ -404,15 +618,21 @@ pub trait Client {
 pub struct NoopClient;
 
 impl Client for NoopClient {
-    fn do_alloc_job(&self, _tc: Toolchain) -> SFuture<AllocJobResult> {
+    fn do_alloc_job(&self, _trace_id: &TraceId, _tc: Toolchain, _exclude_servers: &[ServerId]) -> SFuture<AllocJobResult> {
         f_ok(AllocJobResult::Fail { msg: "Using NoopClient".to_string() })
     }
-    fn do_submit_toolchain(&self, _job_alloc: JobAlloc, _tc: Toolchain) -> SFuture<SubmitToolchainResult> {
+    fn do_submit_toolchain(&self, _trace_id: &TraceId, _job_alloc: JobAlloc, _tc: Toolchain) -> SFuture<SubmitToolchainResult> {
         panic!("NoopClient");
     }
-    fn do_run_job(&self, _job_alloc: JobAlloc, _command: CompileCommand, _outputs: Vec<String>, _write_inputs: Box<FnMut(&mut Write)>) -> SFuture<RunJobResult> {
+    fn do_run_job(&self, _trace_id: &TraceId, _job_alloc: JobAlloc, _command: CompileCommand, _outputs: Vec<String>, _write_inputs: Box<FnMut(&mut Write)>) -> SFuture<RunJobResult> {
         panic!("NoopClient");
     }
+    fn do_get_status(&self) -> SFuture<StatusResult> {
+        f_err("distributed compilation not configured")
+    }
+    fn do_get_stats(&self) -> SFuture<StatsResult> {
+        f_err("distributed compilation not configured")
+    }
 
     fn put_toolchain(&self, _compiler_path: &Path, _weak_key: &str, _create: BoxFnOnce<(fs::File,), io::Result<()>>) -> Result<(Toolchain, Option<String>)> {
         bail!("NoopClient");
@@ -0,0 +1,161 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::*;
+use super::cache::ClientToolchains;
+use boxfnonce::BoxFnOnce;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use test::utils::*;
+
+#[test]
+fn test_toolchain_signature() {
+    let f = TestFixture::new();
+    let (pkcs8, public_key) = generate_toolchain_signing_key().unwrap();
+    let key_path = f.tempdir.path().join("toolchain.key");
+    File::create(&key_path).unwrap().write_all(&pkcs8).unwrap();
+    let signer = ToolchainSigner::from_pkcs8_file(&key_path).unwrap();
+
+    let tc = Toolchain { archive_id: "abcd1234".to_owned() };
+    let sig = signer.sign(&tc);
+    assert!(verify_toolchain_signature(&public_key, &tc, &sig).is_ok());
+
+    let other_tc = Toolchain { archive_id: "1234abcd".to_owned() };
+    assert!(verify_toolchain_signature(&public_key, &other_tc, &sig).is_err());
+    let (_, other_public_key) = generate_toolchain_signing_key().unwrap();
+    assert!(verify_toolchain_signature(&other_public_key, &tc, &sig).is_err());
+}
+
+#[test]
+fn test_client_toolchains_reuse() {
+    let f = TestFixture::new();
+    let cache_dir = f.tempdir.path().join("client");
+    let created = Arc::new(AtomicUsize::new(0));
+    let put = |tcs: &ClientToolchains, weak_key: &str, contents: &'static [u8]| {
+        let created = created.clone();
+        let (tc, _) = tcs.put_toolchain(Path::new("/usr/bin/cc"), weak_key, BoxFnOnce::from(move |mut file: fs::File| {
+            created.fetch_add(1, Ordering::SeqCst);
+            file.write_all(contents)
+        })).unwrap();
+        tc
+    };
+    // Room for one 10 byte archive but not two.
+    let tc1 = {
+        let tcs = ClientToolchains::new(&cache_dir, 15, &[]);
+        put(&tcs, "weak1", b"toolchain1")
+    };
+    assert_eq!(created.load(Ordering::SeqCst), 1);
+    // A new client (as after a restart) reuses the archive without packaging it again.
+    let tcs = ClientToolchains::new(&cache_dir, 15, &[]);
+    assert_eq!(put(&tcs, "weak1", b"toolchain1"), tc1);
+    assert_eq!(created.load(Ordering::SeqCst), 1);
+    // Once the archive is evicted it's packaged again, under the same id.
+    put(&tcs, "weak2", b"toolchain2");
+    assert_eq!(put(&tcs, "weak1", b"toolchain1"), tc1);
+    assert_eq!(created.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn test_trace_id() {
+    let a = TraceId::new();
+    let b = TraceId::new();
+    assert_ne!(a, b);
+    assert_eq!(a.to_string().len(), 32);
+    assert!(a.to_string().chars().all(|c| c.is_digit(16)));
+}
+
+#[test]
+fn test_is_retryable() {
+    let gone: Error = io::Error::new(io::ErrorKind::ConnectionReset, "reset").into();
+    assert!(!is_retryable(&gone));
+    assert!(is_retryable(&Error::with_chain(gone, ErrorKind::DistRetryable("build server went away".to_owned()))));
+    assert!(is_retryable(&ErrorKind::DistRetryable("Error 503: busy".to_owned()).into()));
+    assert!(!is_retryable(&"Failed to allocate a job: no servers".into()));
+    assert!(!is_retryable(&"the compiler crashed on the build server".into()));
+}
+
+#[test]
+fn test_run_with_retries() {
+    use futures::{future, Future};
+    use std::cell::{Cell, RefCell};
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+
+    fn retryable() -> Error { ErrorKind::DistRetryable("the build server lost the job".to_owned()).into() }
+    fn compile_error() -> Error { "the compiler crashed on the build server".into() }
+
+    let server = |port| ServerId(SocketAddr::new("10.0.0.1".parse().unwrap(), port));
+    // Run a job which fails on the servers in `fail_on` with `err`.
+    let run = |job_retries, fail_on: Vec<ServerId>, err: fn() -> Error| {
+        let retries = Rc::new(Cell::new(0));
+        let calls = Rc::new(RefCell::new(vec![]));
+        let calls2 = calls.clone();
+        let mut servers = fail_on.into_iter();
+        let res = run_with_retries(job_retries, retries.clone(), move |failed: &[ServerId]| {
+            calls2.borrow_mut().push(failed.to_vec());
+            let res = match servers.next() {
+                Some(server_id) => Err((Some(server_id), err())),
+                None => Ok("done"),
+            };
+            Box::new(future::result(res)) as Box<Future<Item=_, Error=_>>
+        }).wait();
+        let calls = calls.borrow().clone();
+        (res.ok(), retries.get(), calls)
+    };
+
+    assert_eq!(run(0, vec![], retryable), (Some("done"), 0, vec![vec![]]));
+    assert_eq!(run(0, vec![server(1)], retryable), (None, 0, vec![vec![]]));
+    // Each retry avoids the servers that already failed
+    assert_eq!(run(2, vec![server(1), server(2)], retryable),
+               (Some("done"), 2, vec![vec![], vec![server(1)], vec![server(1), server(2)]]));
+    assert_eq!(run(1, vec![server(1), server(2)], retryable),
+               (None, 1, vec![vec![], vec![server(1)]]));
+    assert_eq!(run(2, vec![server(1)], compile_error), (None, 0, vec![vec![]]));
+}
+
+#[test]
+fn test_crash_reproducer() {
+    let output = |code, stderr: &str| ProcessOutput { code, stdout: b"out".to_vec(), stderr: stderr.as_bytes().to_vec() };
+    assert!(!output(Some(0), "").crashed());
+    assert!(!output(Some(1), "foo.c:1:1: error: expected ';'").crashed());
+    assert!(output(None, "").crashed());
+    assert!(output(Some(4), "foo.c:1:1: internal compiler error: Segmentation fault").crashed());
+
+    let f = TestFixture::new();
+    let trace_id = TraceId("abcd".to_owned());
+    let tc = Toolchain { archive_id: "1234".to_owned() };
+    let command = CompileCommand {
+        executable: "/usr/bin/cc".to_owned(),
+        arguments: vec!["-c".to_owned(), "foo.c".to_owned()],
+        env_vars: vec![],
+        cwd: "/build".to_owned(),
+    };
+    let crash = output(None, "Segmentation fault");
+    let dir = write_crash_reproducer(f.tempdir.path(), &trace_id, &tc, &command, b"inputs", &crash).unwrap();
+    assert_eq!(dir, f.tempdir.path().join("abcd"));
+    let read = |name: &str| {
+        let mut contents = String::new();
+        File::open(dir.join(name)).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    };
+    assert_eq!(read("inputs.tar"), "inputs");
+    assert_eq!(read("stdout"), "out");
+    assert_eq!(read("stderr"), "Segmentation fault");
+    let command = read("command.json");
+    assert!(command.contains("\"archive_id\": \"1234\""));
+    assert!(command.contains("\"/usr/bin/cc\""));
+}
@@ -19,6 +19,7 @@ use std::convert;
 use std::error;
 use std::io;
 use std::process;
+use std::time::Duration;
 
 use bincode;
 use futures::Future;
@@ -63,6 +64,18 @@ error_chain! {
             display("didn't get a successful HTTP status, got `{}`", status)
         }
         ProcessError(output: process::Output)
+        DistRetryable(reason: String) {
+            description("transient distributed compilation failure")
+            display("transient distributed compilation failure: {}", reason)
+        }
+        WriteSuppressed(reason: String) {
+            description("cache write suppressed")
+            display("cache write suppressed: {}", reason)
+        }
+        StorageTimeout(operation: &'static str, timeout: Duration) {
+            description("cache operation timed out")
+            display("cache {} timed out after {}s", operation, timeout.as_secs())
+        }
     }
 }
 
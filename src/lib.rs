@@ -33,6 +33,7 @@ extern crate env_logger;
 #[macro_use]
 extern crate error_chain;
 extern crate filetime;
+extern crate flate2;
 #[macro_use]
 extern crate futures;
 extern crate futures_cpupool;
@@ -86,6 +87,7 @@ extern crate tokio_proto;
 extern crate tokio_service;
 extern crate tokio_serde_bincode;
 extern crate toml;
+extern crate untrusted;
 #[cfg(feature = "gcs")]
 extern crate url;
 extern crate uuid;
@@ -93,6 +95,7 @@ extern crate uuid;
 extern crate winapi;
 extern crate which;
 extern crate zip;
+extern crate zstd;
 
 // To get macros in scope, this has to be first.
 #[cfg(test)]
@@ -102,6 +105,8 @@ mod test;
 #[macro_use]
 pub mod errors;
 
+#[cfg(feature = "admin")]
+mod admin;
 #[cfg(feature = "azure")]
 mod azure;
 mod cache;
@@ -112,6 +117,8 @@ mod compiler;
 mod config;
 pub mod dist;
 mod jobserver;
+#[cfg(feature = "metrics")]
+mod metrics;
 mod mock_command;
 mod protocol;
 pub mod server;
@@ -0,0 +1,207 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An HTTP endpoint serving the server's stats as Prometheus metrics, in the
+//! Prometheus text format at `/metrics`. The stats are fetched with the same
+//! `Request` the stats command sends, on a socket of their own.
+
+use futures::{future, Future, Stream};
+use hyper::{self, Method, StatusCode};
+use hyper::header::ContentType;
+use hyper::server::{Http, Request as HttpRequest, Response as HttpResponse};
+use server::ServerInfo;
+use std::env;
+use std::fmt::Write;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+use std::time::Duration;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Handle;
+use tokio_service::Service;
+
+use errors::*;
+
+/// Read the address to serve metrics on from `SCCACHE_METRICS_ADDR`,
+/// returning `None` if it isn't set.
+pub fn addr_from_env() -> Result<Option<SocketAddr>> {
+    match env::var("SCCACHE_METRICS_ADDR") {
+        Ok(addr) => parse_addr(&addr).map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Parse `addr` as a socket address, or as a port on the loopback address.
+fn parse_addr(addr: &str) -> Result<SocketAddr> {
+    if let Ok(port) = addr.parse::<u16>() {
+        return Ok(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), port))
+    }
+    addr.parse().chain_err(|| format!("Invalid SCCACHE_METRICS_ADDR `{}`", addr))
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+/// Write the `HELP` and `TYPE` lines introducing the metric `name`.
+fn describe(out: &mut String, name: &str, kind: &str, help: &str) {
+    drop(writeln!(out, "# HELP {} {}", name, help));
+    drop(writeln!(out, "# TYPE {} {}", name, kind));
+}
+
+/// Render `info` in the Prometheus text format.
+fn render(info: &ServerInfo) -> String {
+    let stats = &info.stats;
+    let mut out = String::new();
+
+    describe(&mut out, "sccache_compile_requests_total", "counter", "Client compile requests.");
+    drop(writeln!(out, "sccache_compile_requests_total {}", stats.compile_requests));
+    describe(&mut out, "sccache_compile_fails_total", "counter", "Compilations that failed.");
+    drop(writeln!(out, "sccache_compile_fails_total {}", stats.compile_fails));
+
+    describe(&mut out, "sccache_cache_results_total", "counter",
+             "Cache lookups of compile requests, by compiler and result.");
+    let mut compilers = stats.compilers.iter().collect::<Vec<_>>();
+    compilers.sort_by(|a, b| a.0.cmp(b.0));
+    for (compiler, counts) in compilers {
+        for &(result, count) in &[("hit", counts.hits), ("miss", counts.misses), ("error", counts.errors)] {
+            drop(writeln!(out, "sccache_cache_results_total{{compiler=\"{}\",result=\"{}\"}} {}",
+                          compiler, result, count));
+        }
+    }
+
+    describe(&mut out, "sccache_cache_read_duration_seconds_total", "counter",
+             "Time spent reading the cache, by result.");
+    drop(writeln!(out, "sccache_cache_read_duration_seconds_total{{result=\"hit\"}} {}",
+                  secs(stats.cache_read_hit_duration)));
+    drop(writeln!(out, "sccache_cache_read_duration_seconds_total{{result=\"miss\"}} {}",
+                  secs(stats.cache_read_miss_duration)));
+    describe(&mut out, "sccache_cache_writes_total", "counter", "Cache entries written.");
+    drop(writeln!(out, "sccache_cache_writes_total {}", stats.cache_writes));
+    describe(&mut out, "sccache_cache_write_duration_seconds_total", "counter", "Time spent writing the cache.");
+    drop(writeln!(out, "sccache_cache_write_duration_seconds_total {}", secs(stats.cache_write_duration)));
+
+    describe(&mut out, "sccache_cache_backend_errors_total", "counter",
+             "Failed cache backend operations, by operation.");
+    for &(operation, count) in &[("read", stats.cache_read_errors),
+                                 ("write", stats.cache_write_errors),
+                                 ("timeout", stats.cache_timeouts),
+                                 ("write_timeout", stats.cache_write_timeouts)] {
+        drop(writeln!(out, "sccache_cache_backend_errors_total{{operation=\"{}\"}} {}", operation, count));
+    }
+
+    for &(name, help, value) in &[("sccache_cache_size_bytes", "The size of the cache.", info.cache_size),
+                                  ("sccache_cache_max_size_bytes", "The maximum size of the cache.", info.max_cache_size),
+                                  ("sccache_cache_entries", "The number of entries in the cache.", info.cache_entries),
+                                  ("sccache_cache_max_entries", "The maximum number of entries in the cache.",
+                                   info.max_cache_entries)] {
+        if let Some(value) = value {
+            describe(&mut out, name, "gauge", help);
+            drop(writeln!(out, "{} {}", name, value));
+        }
+    }
+    out
+}
+
+fn text_response(status: StatusCode, body: String) -> HttpResponse {
+    HttpResponse::new()
+        .with_status(status)
+        .with_header(ContentType::plaintext())
+        .with_body(body)
+}
+
+struct MetricsService<F> {
+    get_info: Rc<F>,
+}
+
+impl<F> Service for MetricsService<F>
+    where F: Fn() -> ServerInfo + 'static,
+{
+    type Request = HttpRequest;
+    type Response = HttpResponse;
+    type Error = hyper::Error;
+    type Future = Box<Future<Item = HttpResponse, Error = hyper::Error>>;
+
+    fn call(&self, req: HttpRequest) -> Self::Future {
+        match (req.method(), req.path()) {
+            (&Method::Get, "/metrics") => {}
+            (method, "/metrics") => {
+                let message = format!("{} is not allowed on /metrics\n", method);
+                return Box::new(future::ok(text_response(StatusCode::MethodNotAllowed, message)))
+            }
+            (_, path) => {
+                let message = format!("No such endpoint {}\n", path);
+                return Box::new(future::ok(text_response(StatusCode::NotFound, message)))
+            }
+        }
+        Box::new(future::ok(text_response(StatusCode::Ok, render(&(self.get_info)()))))
+    }
+}
+
+/// Serve metrics on `addr` on `handle`, getting the stats from `get_info`.
+/// Return the address they're served on, which has the actual port if
+/// `addr` asked for any free one.
+pub fn start<F>(addr: SocketAddr, handle: &Handle, get_info: F) -> Result<SocketAddr>
+    where F: Fn() -> ServerInfo + 'static,
+{
+    let listener = TcpListener::bind(&addr, handle)
+        .chain_err(|| format!("Failed to bind the metrics endpoint to {}", addr))?;
+    let addr = listener.local_addr()?;
+    info!("Serving metrics on http://{}/metrics", addr);
+    let get_info = Rc::new(get_info);
+    let http = Http::new();
+    let handle2 = handle.clone();
+    let server = listener.incoming().for_each(move |(sock, addr)| {
+        http.bind_connection(&handle2, sock, addr, MetricsService {
+            get_info: get_info.clone(),
+        });
+        Ok(())
+    });
+    handle.spawn(server.map_err(|e| error!("Metrics endpoint stopped accepting connections: {}", e)));
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use server::{CompilerCacheStats, ServerStats};
+
+    #[test]
+    fn test_render() {
+        let mut stats = ServerStats::default();
+        stats.compile_requests = 3;
+        stats.cache_read_hit_duration = Duration::from_millis(1500);
+        stats.compilers.insert("gcc".to_owned(), CompilerCacheStats { hits: 2, misses: 1, errors: 0 });
+        let info = ServerInfo {
+            stats,
+            cache_location: "Local disk".to_owned(),
+            cache_size: Some(1024),
+            max_cache_size: Some(4096),
+            cache_entries: None,
+            max_cache_entries: None,
+            shadow: None,
+        };
+        let text = render(&info);
+        let lines = text.lines().collect::<Vec<_>>();
+        for line in &["sccache_compile_requests_total 3",
+                      "# TYPE sccache_cache_results_total counter",
+                      "sccache_cache_results_total{compiler=\"gcc\",result=\"hit\"} 2",
+                      "sccache_cache_results_total{compiler=\"gcc\",result=\"miss\"} 1",
+                      "sccache_cache_results_total{compiler=\"gcc\",result=\"error\"} 0",
+                      "sccache_cache_read_duration_seconds_total{result=\"hit\"} 1.5",
+                      "sccache_cache_size_bytes 1024"] {
+            assert!(lines.contains(line), "Missing `{}` in:\n{}", line, text);
+        }
+        assert!(!text.contains("sccache_cache_entries"));
+    }
+}
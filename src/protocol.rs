@@ -1,5 +1,8 @@
+use cache::{Provenance, PruneStats, VerifyStats};
 use compiler::ColorMode;
 use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::Duration;
 use server::ServerInfo;
 
 /// A client request.
@@ -11,6 +14,27 @@ pub enum Request {
     GetStats,
     /// Shut the server down gracefully.
     Shutdown,
+    /// Package the toolchains of the compilers the server has seen, without submitting them.
+    ListToolchains,
+    /// Look up the provenance of the cache entry with this hash key.
+    GetProvenance(String),
+    /// Remove cache entries that haven't been used in this long.
+    PruneOlderThan(Duration),
+    /// Remove cache entries produced by compilers that have since been removed or replaced.
+    PurgeStaleCompilers,
+    /// Change the maximum size of the cache, in bytes, until the server restarts.
+    SetMaxSize(u64),
+    /// Remove cache entries stored with this label.
+    RemoveLabel(String),
+    /// Check that every cache entry is intact.
+    VerifyCache {
+        /// Remove the entries that aren't.
+        repair: bool,
+        /// The most entries to check a second.
+        rate: Option<u32>,
+    },
+    /// Stop or resume storing compilation results in the cache.
+    SetReadOnly(bool),
     /// Execute a compile or fetch a cached compilation result.
     Compile(Compile),
 }
@@ -26,6 +50,29 @@ pub enum Response {
     ShuttingDown(ServerInfo),
     /// Second response for `Request::Compile`, containing the results of the compilation.
     CompileFinished(CompileFinished),
+    /// Response for `Request::ListToolchains`.
+    Toolchains(Vec<ToolchainInfo>),
+    /// Response for `Request::GetProvenance`, `None` if the entry was stored
+    /// without provenance, or why the entry couldn't be read.
+    Provenance(Result<Option<Provenance>, String>),
+    /// Response for `Request::PruneOlderThan`, `Request::PurgeStaleCompilers`,
+    /// `Request::SetMaxSize` and `Request::RemoveLabel`, or why pruning failed.
+    Pruned(Result<PruneStats, String>),
+    /// Response for `Request::VerifyCache`, or why verifying failed.
+    Verified(Result<VerifyStats, String>),
+    /// Response for `Request::SetReadOnly`, containing the new setting.
+    ReadOnly(bool),
+}
+
+/// The result of packaging a compiler's toolchain for distributed compilation.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ToolchainInfo {
+    /// The full path to the compiler executable.
+    pub compiler: PathBuf,
+    /// The kind of compiler.
+    pub kind: String,
+    /// The id the toolchain would be submitted under, or why packaging it failed.
+    pub archive_id: Result<String, String>,
 }
 
 /// Possible responses from the server for a `Compile` request.
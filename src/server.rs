@@ -12,19 +12,38 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(feature = "admin")]
+use admin;
 use cache::{
+    Cache,
     Storage,
-    storage_from_config,
+    configured_storage_from_config,
+    disk_storage_from_config,
+    shadow_storage_from_config,
 };
+use cache::disk::DiskCache;
+use cache::encrypted::encrypted_storage_from_config;
+use cache::limit::LimitedStorage;
+use cache::readonly::ReadOnlyStorage;
+use cache::shadow::ShadowStats;
+use cache::timeout::TimeoutStorage;
+use cache::trace::trace_storage_from_env;
+use cache::warm;
 use compiler::{
     CacheControl,
     Compiler,
     CompilerArguments,
     CompilerHasher,
+    CompilerKind,
     CompileResult,
+    compiler_command,
+    HitStderr,
+    init_umask,
+    is_rustc_path,
     MissType,
     get_compiler_info,
 };
+use compiler::pkg;
 use config::CONFIG;
 use dist;
 use filetime::FileTime;
@@ -34,13 +53,16 @@ use futures::task::{self, Task};
 use futures::{Stream, Sink, Async, AsyncSink, Poll, StartSend, Future};
 use futures_cpupool::CpuPool;
 use jobserver::Client;
+#[cfg(feature = "metrics")]
+use metrics;
 use mock_command::{
     CommandCreatorSync,
     ProcessCommandCreator,
+    RunCommand,
 };
 use number_prefix::{binary_prefix, Prefixed, Standalone};
-use protocol::{Compile, CompileFinished, CompileResponse, Request, Response};
-use std::cell::RefCell;
+use protocol::{Compile, CompileFinished, CompileResponse, Request, Response, ToolchainInfo};
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::env;
 use std::ffi::{OsStr, OsString};
@@ -51,8 +73,10 @@ use std::path::PathBuf;
 use std::process::{Output, ExitStatus};
 use std::rc::Rc;
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::u64;
+use tempdir::TempDir;
 use tokio_core::net::TcpListener;
 use tokio_core::reactor::{Handle, Core, Timeout};
 use tokio_io::codec::length_delimited::Framed;
@@ -62,13 +86,16 @@ use tokio_proto::streaming::pipeline::{Frame, ServerProto, Transport};
 use tokio_proto::streaming::{Body, Message};
 use tokio_serde_bincode::{ReadBincode, WriteBincode};
 use tokio_service::Service;
-use util; //::fmt_duration_as_secs;
+use util::{self, run_input_output}; //::fmt_duration_as_secs;
 
 use errors::*;
 
 /// If the server is idle for this many seconds, shut down.
 const DEFAULT_IDLE_TIMEOUT: u64 = 600;
 
+/// Stop uploading the local cache on shutdown after this many seconds.
+const UPLOAD_ON_SHUTDOWN_LIMIT_SECS: u64 = 300;
+
 /// Result of background server startup.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ServerStartup {
@@ -80,6 +107,37 @@ pub enum ServerStartup {
     Err { reason: String },
 }
 
+/// Get the length of the warmup window, in seconds, from the environment.
+///
+/// Cache hits and misses during the warmup window are counted separately
+/// so that cold-start misses can be excluded from hit-rate monitoring.
+fn get_warmup_secs() -> Option<u64> {
+    env::var("SCCACHE_WARMUP_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+}
+
+/// Whether the cache starts out read-only, from the environment.
+fn get_read_only() -> bool {
+    env::var("SCCACHE_READ_ONLY").ok().map_or(false, |v| v == "1")
+}
+
+/// Get the extra environment variables to run compilers with from the
+/// environment, or `None` to pass the client's environment through unchanged.
+fn get_compiler_env_allowlist() -> Option<Vec<String>> {
+    env::var("SCCACHE_COMPILER_ENV_ALLOWLIST")
+        .ok()
+        .map(|s| s.split(',').map(|v| v.trim().to_owned()).filter(|v| !v.is_empty()).collect())
+}
+
+/// Get the number of compile requests that make up the warmup window
+/// from the environment.
+fn get_warmup_requests() -> Option<u64> {
+    env::var("SCCACHE_WARMUP_REQUESTS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+}
+
 /// Get the time the server should idle for before shutting down.
 fn get_idle_timeout() -> u64 {
     // A value of 0 disables idle shutdown entirely.
@@ -133,9 +191,13 @@ fn get_signal(_status: ExitStatus) -> i32 {
 /// requests a shutdown.
 pub fn start_server(port: u16) -> Result<()> {
     info!("start_server: port: {}", port);
+    init_umask();
     let client = unsafe { Client::new() };
     let core = Core::new()?;
-    let pool = CpuPool::new(20);
+    let pool = CpuPool::new(CONFIG.server.cpu_threads.max(1));
+    // Caches that do blocking I/O get threads of their own, so slow cache
+    // operations don't hold up hashing, and vice versa.
+    let storage_pool = CpuPool::new(CONFIG.server.storage_concurrency.max(1));
     let dist_client: Arc<dist::Client> = match CONFIG.dist.scheduler_addr {
         #[cfg(feature = "dist")]
         Some(addr) => {
@@ -143,9 +205,12 @@ pub fn start_server(port: u16) -> Result<()> {
             Arc::new(dist::http::Client::new(
                 &core.handle(),
                 addr,
+                &CONFIG.dist.standby_scheduler_addrs,
                 &CONFIG.dist.cache_dir.join("client"),
                 CONFIG.dist.toolchain_cache_size,
                 &CONFIG.dist.custom_toolchains,
+                CONFIG.dist.toolchain_signing_key.as_ref().map(|p| &**p),
+                CONFIG.dist.inputs_compression,
             ))
         },
         #[cfg(not(feature = "dist"))]
@@ -158,15 +223,61 @@ pub fn start_server(port: u16) -> Result<()> {
             Arc::new(dist::NoopClient)
         },
     };
-    let storage = storage_from_config(&pool, &core.handle());
-    let res = SccacheServer::<ProcessCommandCreator>::new(port, pool, core, client, dist_client, storage);
+    let res = ephemeral_cache_from_env().and_then(|ephemeral| {
+        // Whether the server is using one of the configured caches rather
+        // than the local disk one, which could then be uploaded to it.
+        let mut remote = false;
+        let storage: Arc<Storage> = match ephemeral {
+            // A throwaway cache neither reads nor writes the configured caches.
+            Some((ref dir, _)) => {
+                info!("Using an ephemeral cache in {}", dir.path().display());
+                Arc::new(DiskCache::new(dir.path(), CONFIG.fallback_cache.size, None, &storage_pool))
+            }
+            None => {
+                let storage = match configured_storage_from_config(&storage_pool, &core.handle()) {
+                    Some(storage) => {
+                        remote = true;
+                        storage
+                    }
+                    None => {
+                        info!("No configured caches successful, falling back to default");
+                        disk_storage_from_config(&storage_pool)
+                    }
+                };
+                let storage = shadow_storage_from_config(storage, &storage_pool, &core.handle());
+                encrypted_storage_from_config(storage, &CONFIG.encryption)?
+            }
+        };
+        // Time operations out inside the limit, so a hung one gives its
+        // place up for the next. A blocking backend may still be working on
+        // it, so past timeouts more operations than the limit can be in
+        // flight in the backend, but no more than the storage pool has
+        // threads for are running at once.
+        let storage: Arc<Storage> = match CONFIG.server.storage_timeout() {
+            Some(timeout) => Arc::new(TimeoutStorage::new(storage, &core.handle(), timeout)),
+            None => storage,
+        };
+        let storage = Arc::new(LimitedStorage::new(storage, CONFIG.server.storage_concurrency));
+        let storage = trace_storage_from_env(storage);
+        let mut srv = SccacheServer::<ProcessCommandCreator>::new(port, pool, core, client, dist_client, storage)?;
+        if CONFIG.fallback_cache.upload_on_shutdown && remote {
+            srv.set_upload_on_shutdown(storage_pool, CONFIG.fallback_cache.upload_rate);
+        }
+        Ok((srv, ephemeral))
+    });
     let notify = env::var_os("SCCACHE_STARTUP_NOTIFY");
     match res {
-        Ok(srv) => {
+        Ok((srv, ephemeral)) => {
             let port = srv.port();
             info!("server started, listening on port {}", port);
+            srv.start_admin_api();
+            srv.start_metrics();
+            srv.check_compiler_changes();
             notify_server_startup(&notify, ServerStartup::Ok { port })?;
             srv.run(future::empty::<(), ()>())?;
+            if let Some((dir, true)) = ephemeral {
+                info!("Keeping the ephemeral cache in {}", dir.into_path().display());
+            }
             Ok(())
         }
         Err(e) => {
@@ -178,6 +289,20 @@ pub fn start_server(port: u16) -> Result<()> {
     }
 }
 
+/// The directory of a throwaway cache for this server alone, if
+/// `SCCACHE_EPHEMERAL` is set, and whether to keep it after the server exits
+/// rather than removing it.
+fn ephemeral_cache_from_env() -> Result<Option<(TempDir, bool)>> {
+    let keep = match env::var("SCCACHE_EPHEMERAL") {
+        Ok(ref v) if v == "1" => false,
+        Ok(ref v) if v == "keep" => true,
+        Ok(v) => bail!("Invalid SCCACHE_EPHEMERAL `{}`, expected 1 or keep", v),
+        Err(_) => return Ok(None),
+    };
+    let dir = TempDir::new("sccache-ephemeral").chain_err(|| "failed to create the ephemeral cache")?;
+    Ok(Some((dir, keep)))
+}
+
 pub struct SccacheServer<C: CommandCreatorSync> {
     core: Core,
     listener: TcpListener,
@@ -185,6 +310,9 @@ pub struct SccacheServer<C: CommandCreatorSync> {
     timeout: Duration,
     service: SccacheService<C>,
     wait: WaitUntilZero,
+    /// The pool to open the local cache on, to upload it to the remote one on
+    /// shutdown, and at most how many entries a second.
+    upload_on_shutdown: Option<(CpuPool, Option<u32>)>,
 }
 
 impl<C: CommandCreatorSync> SccacheServer<C> {
@@ -217,19 +345,40 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
             service: service,
             timeout: Duration::from_secs(get_idle_timeout()),
             wait: wait,
+            upload_on_shutdown: None,
         })
     }
 
+    /// Configures the warmup window during which cache hits and misses
+    /// are accounted separately.
+    #[allow(dead_code)]
+    pub fn set_warmup(&mut self, duration: Option<Duration>, requests: Option<u64>) {
+        self.service.warmup = Warmup::new(duration, requests);
+    }
+
     /// Configures how long this server will be idle before shutting down.
     #[allow(dead_code)]
     pub fn set_idle_timeout(&mut self, timeout: Duration) {
         self.timeout = timeout;
     }
 
+    /// Set whether this server's storage discards writes.
+    #[allow(dead_code)]
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.service.read_only.store(read_only, Ordering::SeqCst);
+    }
+
     /// Set the storage this server will use.
     #[allow(dead_code)]
     pub fn set_storage(&mut self, storage: Arc<Storage>) {
-        self.service.storage = storage;
+        self.service.storage = Arc::new(ReadOnlyStorage::new(storage, self.service.read_only.clone()));
+    }
+
+    /// Upload the entries of the local disk cache missing from this server's
+    /// storage to it, at most `rate` a second, when the server shuts down.
+    /// The local cache is only opened, on `pool`, then.
+    pub fn set_upload_on_shutdown(&mut self, pool: CpuPool, rate: Option<u32>) {
+        self.upload_on_shutdown = Some((pool, rate));
     }
 
     /// Returns a reference to a thread pool to run work on
@@ -244,6 +393,90 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         &self.service.creator
     }
 
+    /// Serve the admin HTTP API if `SCCACHE_ADMIN_ADDR` is set. Problems with
+    /// it are logged rather than stopping the server from starting.
+    #[cfg(feature = "admin")]
+    pub fn start_admin_api(&self) where C: 'static {
+        let config = match admin::config_from_env() {
+            Ok(Some(config)) => config,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Not serving the admin API: {}", e);
+                return
+            }
+        };
+        let service = self.service.clone();
+        let res = admin::start(config, &self.core.handle(), move |req| {
+            Box::new(service.call(Message::WithoutBody(req)).map(|res| res.into_inner()))
+        });
+        if let Err(e) = res {
+            warn!("Not serving the admin API: {}", e);
+        }
+    }
+
+    #[cfg(not(feature = "admin"))]
+    pub fn start_admin_api(&self) {
+        if env::var_os("SCCACHE_ADMIN_ADDR").is_some() {
+            warn!("SCCACHE_ADMIN_ADDR is set but sccache was built without the admin feature");
+        }
+    }
+
+    /// Log the compilers that have changed since some of the cache entries
+    /// were stored, checking in the background. Shutting down waits for the
+    /// check to finish.
+    pub fn check_compiler_changes(&self) where C: 'static {
+        let service = self.service.clone();
+        let check = self.service.storage.compiler_digests().and_then(move |digests| {
+            let compilers = digests.keys().cloned().collect::<Vec<_>>();
+            service.current_compiler_digests(compilers).map(move |current| (digests, current))
+        }).map(|(digests, current)| {
+            for (compiler, recorded) in digests {
+                let changed = match current.get(&compiler) {
+                    Some(digest) => recorded.iter().any(|d| d != digest),
+                    None => !compiler.exists(),
+                };
+                if changed {
+                    info!("Compiler {:?} has changed since some of the cache entries were stored, \
+                           they will no longer be hit. `sccache --purge-stale-compilers` removes \
+                           them from the local disk cache.", compiler);
+                }
+            }
+        }).map_err(|e| debug!("Not checking for compiler changes: {}", e));
+        self.core.handle().spawn(check);
+    }
+
+    /// Serve Prometheus metrics if `SCCACHE_METRICS_ADDR` is set. Problems
+    /// with it are logged rather than stopping the server from starting.
+    #[cfg(feature = "metrics")]
+    pub fn start_metrics(&self) where C: 'static {
+        let res = metrics::addr_from_env().and_then(|addr| match addr {
+            Some(addr) => self.serve_metrics(addr).map(|_| ()),
+            None => Ok(()),
+        });
+        if let Err(e) = res {
+            warn!("Not serving metrics: {}", e);
+        }
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    pub fn start_metrics(&self) {
+        if env::var_os("SCCACHE_METRICS_ADDR").is_some() {
+            warn!("SCCACHE_METRICS_ADDR is set but sccache was built without the metrics feature");
+        }
+    }
+
+    /// Serve Prometheus metrics on `addr`, returning the address they're
+    /// served on.
+    #[cfg(feature = "metrics")]
+    pub fn serve_metrics(&self, addr: SocketAddr) -> Result<SocketAddr> where C: 'static {
+        // Read the stats directly rather than calling the service, so
+        // scrapes neither count as activity keeping the server from going
+        // idle nor keep a clone of its `ActiveInfo` alive.
+        let stats = self.service.stats.clone();
+        let storage = self.service.storage.clone();
+        metrics::start(addr, &self.core.handle(), move || server_info(&stats.borrow(), &*storage))
+    }
+
     /// Returns the port that this server is bound to
     #[allow(dead_code)]
     pub fn port(&self) -> u16 {
@@ -264,7 +497,9 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
     fn _run<'a>(self, shutdown: Box<Future<Item = (), Error = ()> + 'a>)
                 -> io::Result<()>
     {
-        let SccacheServer { mut core, listener, rx, service, timeout, wait } = self;
+        let SccacheServer { mut core, listener, rx, service, timeout, wait, upload_on_shutdown } = self;
+        let storage = service.storage.clone();
+        let read_only = service.read_only.clone();
 
         // Create our "server future" which will simply handle all incoming
         // connections in separate tasks.
@@ -316,9 +551,42 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         })));
 
         let server = future::select_all(futures);
-        core.run(server)
+        let (_, _, rest) = core.run(server)
             .map_err(|p| p.0)?;
 
+        let upload = match upload_on_shutdown {
+            Some(_) if read_only.load(Ordering::SeqCst) => {
+                info!("not uploading the local cache, the cache is read-only");
+                None
+            }
+            Some((pool, rate)) => {
+                info!("uploading the local cache to {}", storage.location());
+                let limit = Duration::from_secs(UPLOAD_ON_SHUTDOWN_LIMIT_SECS);
+                let upload: SFuture<u64> = match encrypted_storage_from_config(disk_storage_from_config(&pool), &CONFIG.encryption) {
+                    Ok(local) => warm::upload_missing(local, storage, rate, limit, &handle),
+                    Err(e) => f_err(e),
+                };
+                Some(upload.then(|res| {
+                    match res {
+                        Ok(n) => info!("uploaded {} entries of the local cache", n),
+                        Err(e) => warn!("failed to upload the local cache: {}", e),
+                    }
+                    Ok::<_, io::Error>(())
+                }))
+            }
+            None => None,
+        };
+        match upload {
+            // Keep accepting connections while uploading, so that no new
+            // server can start and open the local cache meanwhile.
+            Some(upload) => {
+                let rest = future::select_all(rest).map(|_| ()).map_err(|p| p.0);
+                core.run(upload.select(rest))
+                    .map_err(|p| p.0)?;
+            }
+            None => drop(rest),
+        }
+
         info!("moving into the shutdown phase now, waiting at most 10 seconds \
               for all client requests to complete");
 
@@ -344,12 +612,25 @@ struct SccacheService<C: CommandCreatorSync> {
     /// Server statistics.
     stats: Rc<RefCell<ServerStats>>,
 
+    /// The cold start window during which stats are bucketed separately.
+    warmup: Warmup,
+
     /// Distributed sccache client
     dist_client: Arc<dist::Client>,
 
     /// Cache storage.
     storage: Arc<Storage>,
 
+    /// Whether `storage` discards writes, toggled with `Request::SetReadOnly`.
+    read_only: Arc<AtomicBool>,
+
+    /// If set, compilers are run with only the environment variables they
+    /// need and these.
+    env_allowlist: Option<Rc<Vec<String>>>,
+
+    /// The outputs of compiler queries such as `rustc --print cfg`, by `Compiler::query_key`.
+    query_outputs: Rc<RefCell<HashMap<String, Output>>>,
+
     /// A cache of known compiler info.
     compilers: Rc<RefCell<HashMap<PathBuf, Option<(Box<Compiler<C>>, FileTime)>>>>,
 
@@ -391,6 +672,24 @@ pub enum ServerMessage {
     Shutdown,
 }
 
+/// Describe `e` along with the errors that caused it, for sending to clients.
+fn error_chain_string(e: &Error) -> String {
+    e.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(": ")
+}
+
+/// Info and stats about the cache, given the server's `stats` and `storage`.
+fn server_info(stats: &ServerStats, storage: &Storage) -> ServerInfo {
+    ServerInfo {
+        stats: stats.clone(),
+        cache_location: storage.location(),
+        cache_size: storage.current_size(),
+        max_cache_size: storage.max_size(),
+        cache_entries: storage.current_entries(),
+        max_cache_entries: storage.max_entries(),
+        shadow: storage.shadow_stats(),
+    }
+}
+
 impl<C> Service for SccacheService<C>
     where C: CommandCreatorSync + 'static,
 {
@@ -411,6 +710,7 @@ impl<C> Service for SccacheService<C>
             Request::Compile(compile) => {
                 debug!("handle_client: compile");
                 self.stats.borrow_mut().compile_requests += 1;
+                self.warmup.requests_seen.set(self.warmup.requests_seen.get() + 1);
                 return self.handle_compile(compile)
             }
             Request::GetStats => {
@@ -422,6 +722,59 @@ impl<C> Service for SccacheService<C>
                 self.zero_stats();
                 Response::Stats(self.get_info())
             }
+            Request::ListToolchains => {
+                debug!("handle_client: list_toolchains");
+                return self.list_toolchains()
+            }
+            Request::GetProvenance(key) => {
+                debug!("handle_client: get_provenance");
+                return self.get_provenance(&key)
+            }
+            Request::PruneOlderThan(age) => {
+                debug!("handle_client: prune_older_than");
+                return Box::new(self.storage.prune_older_than(age).then(|res| {
+                    let res = res.map_err(|e| error_chain_string(&e));
+                    Ok(Message::WithoutBody(Response::Pruned(res)))
+                }))
+            }
+            Request::PurgeStaleCompilers => {
+                debug!("handle_client: purge_stale_compilers");
+                let me = self.clone();
+                let storage = self.storage.clone();
+                let purge = self.storage.compiler_digests().and_then(move |digests| {
+                    me.current_compiler_digests(digests.into_iter().map(|(compiler, _)| compiler).collect())
+                }).and_then(move |current| storage.purge_stale_compilers(current));
+                return Box::new(purge.then(|res| {
+                    let res = res.map_err(|e| error_chain_string(&e));
+                    Ok(Message::WithoutBody(Response::Pruned(res)))
+                }))
+            }
+            Request::SetMaxSize(size) => {
+                debug!("handle_client: set_max_size: {}", size);
+                return Box::new(self.storage.set_max_size(size).then(|res| {
+                    let res = res.map_err(|e| error_chain_string(&e));
+                    Ok(Message::WithoutBody(Response::Pruned(res)))
+                }))
+            }
+            Request::RemoveLabel(label) => {
+                debug!("handle_client: remove_label: {}", label);
+                return Box::new(self.storage.remove_label(&label).then(|res| {
+                    let res = res.map_err(|e| error_chain_string(&e));
+                    Ok(Message::WithoutBody(Response::Pruned(res)))
+                }))
+            }
+            Request::VerifyCache { repair, rate } => {
+                debug!("handle_client: verify_cache: repair: {}, rate: {:?}", repair, rate);
+                return Box::new(self.storage.verify(repair, rate).then(|res| {
+                    let res = res.map_err(|e| error_chain_string(&e));
+                    Ok(Message::WithoutBody(Response::Verified(res)))
+                }))
+            }
+            Request::SetReadOnly(read_only) => {
+                debug!("handle_client: set_read_only: {}", read_only);
+                self.read_only.store(read_only, Ordering::SeqCst);
+                Response::ReadOnly(read_only)
+            }
             Request::Shutdown => {
                 debug!("handle_client: shutdown");
                 let future = self.tx.clone().send(ServerMessage::Shutdown);
@@ -446,10 +799,16 @@ impl<C> SccacheService<C>
                pool: CpuPool,
                tx: mpsc::Sender<ServerMessage>,
                info: ActiveInfo) -> SccacheService<C> {
+        let read_only = Arc::new(AtomicBool::new(get_read_only()));
         SccacheService {
             stats: Rc::new(RefCell::new(ServerStats::default())),
+            warmup: Warmup::new(get_warmup_secs().map(Duration::from_secs),
+                                get_warmup_requests()),
             dist_client,
-            storage: storage,
+            storage: Arc::new(ReadOnlyStorage::new(storage, read_only.clone())),
+            read_only,
+            env_allowlist: get_compiler_env_allowlist().map(Rc::new),
+            query_outputs: Rc::new(RefCell::new(HashMap::new())),
             compilers: Rc::new(RefCell::new(HashMap::new())),
             pool: pool,
             creator: C::new(&handle, client),
@@ -461,12 +820,7 @@ impl<C> SccacheService<C>
 
     /// Get info and stats about the cache.
     fn get_info(&self) -> ServerInfo {
-        ServerInfo {
-            stats: self.stats.borrow().clone(),
-            cache_location: self.storage.location(),
-            cache_size: self.storage.current_size(),
-            max_cache_size: self.storage.max_size(),
-        }
+        server_info(&self.stats.borrow(), &*self.storage)
     }
 
     /// Zero stats about the cache.
@@ -474,6 +828,41 @@ impl<C> SccacheService<C>
         *self.stats.borrow_mut() = ServerStats::default();
     }
 
+    /// Package the toolchain of each compiler detected so far, on `pool`,
+    /// and report the resulting toolchain ids or packaging failures.
+    fn list_toolchains(&self) -> SFuture<SccacheResponse> {
+        let mut compilers = self.compilers.borrow().iter()
+            .filter_map(|(path, entry)| {
+                entry.as_ref().map(|&(ref c, _)| {
+                    (path.clone(), format!("{:?}", c.kind()), c.get_toolchain_packager())
+                })
+            })
+            .collect::<Vec<_>>();
+        compilers.sort_by(|a, b| a.0.cmp(&b.0));
+        let pool = self.pool.clone();
+        let toolchains = compilers.into_iter().map(move |(compiler, kind, packager)| {
+            pool.spawn_fn(move || -> Result<_> {
+                let archive_id = pkg::dry_run(packager).map_err(|e| error_chain_string(&e));
+                Ok(ToolchainInfo { compiler, kind, archive_id })
+            })
+        });
+        Box::new(future::join_all(toolchains).map(|toolchains| {
+            Message::WithoutBody(Response::Toolchains(toolchains))
+        }))
+    }
+
+    /// Look up the provenance of the cache entry stored under `key`.
+    fn get_provenance(&self, key: &str) -> SFuture<SccacheResponse> {
+        Box::new(self.storage.get(key).then(|res| {
+            let provenance = match res {
+                Ok(Cache::Hit(mut entry)) => entry.get_provenance(),
+                Ok(_) => Err("no cache entry with that key".into()),
+                Err(e) => Err(e),
+            };
+            let provenance = provenance.map_err(|e| error_chain_string(&e));
+            Ok(Message::WithoutBody(Response::Provenance(provenance)))
+        }))
+    }
 
     /// Handle a compile request from a client.
     ///
@@ -486,10 +875,14 @@ impl<C> SccacheService<C>
         let exe = compile.exe;
         let cmd = compile.args;
         let cwd = compile.cwd;
-        let env_vars = compile.env_vars;
+        let env_vars = match self.env_allowlist {
+            Some(ref allowlist) => util::scrub_compiler_env(compile.env_vars, allowlist),
+            None => compile.env_vars,
+        };
         let me = self.clone();
-        Box::new(self.compiler_info(exe.into(), &env_vars).map(move |info| {
-            me.check_compiler(info, cmd, cwd.into(), env_vars)
+        let exe = PathBuf::from(exe);
+        Box::new(self.compiler_info(exe.clone(), &env_vars).map(move |info| {
+            me.check_compiler(info, exe, cmd, cwd.into(), env_vars)
         }))
     }
 
@@ -505,6 +898,12 @@ impl<C> SccacheService<C>
         let result = match self.compilers.borrow().get(&path) {
             // It's a hit only if the mtime matches.
             Some(&Some((ref c, ref cached_mtime))) if *cached_mtime == mtime => Some(Some(c.clone())),
+            Some(&Some(_)) => {
+                info!("Compiler {:?} has changed since it was last used, its existing cache entries \
+                       will no longer be hit. `sccache --purge-stale-compilers` removes them from \
+                       the local disk cache.", path);
+                None
+            }
             // We cache non-results.
             Some(&None) => Some(None),
             _ => None,
@@ -531,10 +930,26 @@ impl<C> SccacheService<C>
         }
     }
 
+    /// The current digests of those of `compilers` that still exist and can
+    /// be detected.
+    fn current_compiler_digests(&self, compilers: Vec<PathBuf>) -> SFuture<HashMap<PathBuf, String>> {
+        // No client's environment is at hand, so detect them with the
+        // server's own.
+        let env = env::vars_os().collect::<Vec<_>>();
+        let digests = compilers.into_iter()
+            .filter(|compiler| compiler.exists())
+            .map(|compiler| self.compiler_info(compiler.clone(), &env).then(move |info| -> Result<_> {
+                Ok(info.ok().and_then(|info| info).map(|c| (compiler, c.digest())))
+            }))
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(digests).map(|digests| digests.into_iter().filter_map(|d| d).collect()))
+    }
+
     /// Check that we can handle and cache `cmd` when run with `compiler`.
     /// If so, run `start_compile_task` to execute it.
     fn check_compiler(&self,
                       compiler: Option<Box<Compiler<C>>>,
+                      exe: PathBuf,
                       cmd: Vec<OsString>,
                       cwd: PathBuf,
                       env_vars: Vec<(OsString, OsString)>) -> SccacheResponse
@@ -543,6 +958,11 @@ impl<C> SccacheService<C>
         match compiler {
             None => {
                 debug!("check_compiler: Unsupported compiler");
+                if cmd.first().map_or(false, |arg| is_rustc_path(arg)) {
+                    warn!("{:?} was run with rustc, {:?}, as its first argument, so it is probably a \
+                           RUSTC_WORKSPACE_WRAPPER. sccache can't know how it changes the compilation, \
+                           so the compilation won't be cached.", exe, cmd[0]);
+                }
                 stats.requests_unsupported_compiler += 1;
                 return Message::WithoutBody(
                     Response::Compile(CompileResponse::UnsupportedCompiler)
@@ -550,6 +970,14 @@ impl<C> SccacheService<C>
             }
             Some(c) => {
                 debug!("check_compiler: Supported compiler");
+                if let Some(key) = c.query_key(&cmd, &cwd, &env_vars) {
+                    debug!("check_compiler: Compiler query: {:?}", cmd);
+                    stats.requests_not_compile += 1;
+                    let (tx, rx) = Body::pair();
+                    self.start_query_task(key, exe, cmd, cwd, env_vars, tx);
+                    let res = CompileResponse::CompileStarted;
+                    return Message::WithBody(Response::Compile(res), rx)
+                }
                 // Now check that we can handle this compiler with
                 // the provided commandline.
                 match c.parse_arguments(&cmd, &cwd) {
@@ -557,7 +985,7 @@ impl<C> SccacheService<C>
                         debug!("parse_arguments: Ok: {:?}", cmd);
                         stats.requests_executed += 1;
                         let (tx, rx) = Body::pair();
-                        self.start_compile_task(hasher, cmd, cwd, env_vars, tx);
+                        self.start_compile_task(c.kind(), hasher, cmd, cwd, env_vars, tx);
                         let res = CompileResponse::CompileStarted;
                         return Message::WithBody(Response::Compile(res), rx)
                     }
@@ -566,6 +994,11 @@ impl<C> SccacheService<C>
                         debug!("parse_arguments: CannotCache({}): {:?}", why, cmd);
                         stats.requests_not_cacheable += 1;
                     }
+                    CompilerArguments::UnknownFlag(flag) => {
+                        debug!("parse_arguments: UnknownFlag({:?}): {:?}", flag, cmd);
+                        stats.requests_not_cacheable += 1;
+                        *stats.requests_unknown_flags.entry(flag.to_string_lossy().into_owned()).or_insert(0) += 1;
+                    }
                     CompilerArguments::NotCompilation => {
                         debug!("parse_arguments: NotCompilation: {:?}", cmd);
                         stats.requests_not_compile += 1;
@@ -578,23 +1011,89 @@ impl<C> SccacheService<C>
         Message::WithoutBody(Response::Compile(res))
     }
 
+    /// Answer the compiler query `arguments` from `query_outputs` if it has
+    /// been run before, otherwise run it and remember its output if it succeeds.
+    fn start_query_task(&self,
+                        key: String,
+                        exe: PathBuf,
+                        arguments: Vec<OsString>,
+                        cwd: PathBuf,
+                        env_vars: Vec<(OsString, OsString)>,
+                        tx: mpsc::Sender<Result<Response>>) {
+        let cached = self.query_outputs.borrow().get(&key).cloned();
+        let hit = cached.is_some();
+        let output = match cached {
+            Some(output) => {
+                trace!("Compiler query cache hit: {:?}", arguments);
+                f_ok(output)
+            }
+            None => {
+                let mut cmd = compiler_command(&self.creator, &exe);
+                cmd.args(&arguments)
+                    .env_clear()
+                    .envs(env_vars)
+                    .current_dir(&cwd);
+                let me = self.clone();
+                // Queries like the ones Cargo runs read the crate from an empty stdin.
+                Box::new(run_input_output(cmd, Some(vec![])).then(move |res| {
+                    match res {
+                        Ok(output) => {
+                            me.query_outputs.borrow_mut().insert(key, output.clone());
+                            Ok(output)
+                        }
+                        Err(Error(ErrorKind::ProcessError(output), _)) => Ok(output),
+                        Err(e) => Err(e),
+                    }
+                })) as SFuture<_>
+            }
+        };
+        let stats = self.stats.clone();
+        let task = output.then(move |res| {
+            if hit {
+                stats.borrow_mut().compiler_query_hits += 1;
+            }
+            let mut finish = CompileFinished::default();
+            match res {
+                Ok(Output { status, stdout, stderr }) => {
+                    match status.code() {
+                        Some(code) => finish.retcode = Some(code),
+                        None => finish.signal = Some(get_signal(status)),
+                    };
+                    finish.stdout = stdout;
+                    finish.stderr = stderr;
+                }
+                Err(e) => {
+                    error!("Failed to run compiler query: {}", e);
+                    finish.retcode = Some(-2);
+                    finish.stderr = format!("sccache: failed to run compiler query: {}\n", e).into_bytes();
+                }
+            }
+            tx.send(Ok(Response::CompileFinished(finish))).then(|_| Ok(()))
+        });
+        self.handle.spawn(task);
+    }
+
     /// Given compiler arguments `arguments`, look up
     /// a compile result in the cache or execute the compilation and store
     /// the result in the cache.
     fn start_compile_task(&self,
+                          kind: CompilerKind,
                           hasher: Box<CompilerHasher<C>>,
                           arguments: Vec<OsString>,
                           cwd: PathBuf,
                           env_vars: Vec<(OsString, OsString)>,
                           tx: mpsc::Sender<Result<Response>>) {
-        let force_recache = env_vars.iter().any(|&(ref k, ref _v)| {
-            k.as_os_str() == OsStr::new("SCCACHE_RECACHE")
+        let has_var = |name| env_vars.iter().any(|&(ref k, ref _v)| {
+            k.as_os_str() == OsStr::new(name)
         });
-        let cache_control = if force_recache {
+        let cache_control = if has_var("SCCACHE_RECACHE") {
             CacheControl::ForceRecache
+        } else if has_var("SCCACHE_RECOMPILE_WARNINGS") {
+            CacheControl::RecompileOnWarnings
         } else {
             CacheControl::Default
         };
+        let hit_stderr = HitStderr::from_env(&env_vars);
         let out_pretty = hasher.output_pretty().into_owned();
         let color_mode = hasher.color_mode();
         let result = hasher.get_cached_or_compile(self.dist_client.clone(),
@@ -607,19 +1106,36 @@ impl<C> SccacheService<C>
                                                   self.pool.clone(),
                                                   self.handle.clone());
         let me = self.clone();
+        let warmup = self.warmup.active();
         let task = result.then(move |result| {
             let mut cache_write = None;
             let mut stats = me.stats.borrow_mut();
             let mut res = CompileFinished::default();
             res.color_mode = color_mode;
             match result {
-                Ok((compiled, out)) => {
+                Ok((compiled, dist_stats, out)) => {
+                    stats.dist_job_retries += dist_stats.job_retries;
+                    if dist_stats.local_only {
+                        stats.dist_local_only_compiles += 1;
+                    }
+                    let hit = match compiled {
+                        CompileResult::CacheHit(_) => true,
+                        _ => false,
+                    };
                     match compiled {
                         CompileResult::Error => {
                             stats.cache_errors += 1;
+                            stats.for_compiler(&kind).errors += 1;
                         }
+                        CompileResult::CacheHit(_) if warmup => {
+                            stats.warmup_cache_hits += 1;
+                        },
                         CompileResult::CacheHit(duration) => {
                             stats.cache_hits += 1;
+                            stats.for_compiler(&kind).hits += 1;
+                            if !out.stderr.is_empty() {
+                                stats.cache_hits_with_warnings += 1;
+                            }
                             stats.cache_read_hit_duration += duration;
                         },
                         CompileResult::CacheMiss(miss_type, duration, future) => {
@@ -633,14 +1149,28 @@ impl<C> SccacheService<C>
                                 }
                                 MissType::CacheReadError => {
                                     stats.cache_errors += 1;
+                                    stats.for_compiler(&kind).errors += 1;
                                 }
+                                MissType::Warnings => {
+                                    stats.warnings_recompiles += 1;
+                                }
+                            }
+                            if warmup {
+                                stats.warmup_cache_misses += 1;
+                            } else {
+                                stats.cache_misses += 1;
+                                stats.for_compiler(&kind).misses += 1;
+                                stats.cache_read_miss_duration += duration;
                             }
-                            stats.cache_misses += 1;
-                            stats.cache_read_miss_duration += duration;
                             cache_write = Some(future);
                         }
                         CompileResult::NotCacheable => {
-                            stats.cache_misses += 1;
+                            if warmup {
+                                stats.warmup_cache_misses += 1;
+                            } else {
+                                stats.cache_misses += 1;
+                                stats.for_compiler(&kind).misses += 1;
+                            }
                             stats.non_cacheable_compilations += 1;
                         }
                         CompileResult::CompileFailed => {
@@ -654,7 +1184,7 @@ impl<C> SccacheService<C>
                         None => res.signal = Some(get_signal(status)),
                     };
                     res.stdout = stdout;
-                    res.stderr = stderr;
+                    res.stderr = if hit { hit_stderr.apply(stderr) } else { stderr };
                 }
                 Err(Error(ErrorKind::ProcessError(output), _)) => {
                     debug!("Compilation failed: {:?}", output);
@@ -678,6 +1208,7 @@ impl<C> SccacheService<C>
                         drop(writeln!(error, "sccache:  cause: {}", e));
                     }
                     stats.cache_errors += 1;
+                    stats.for_compiler(&kind).errors += 1;
                     //TODO: figure out a better way to communicate this?
                     res.retcode = Some(-2);
                     res.stderr = error.into_bytes();
@@ -688,10 +1219,21 @@ impl<C> SccacheService<C>
             let me = me.clone();
             let cache_write = cache_write.then(move |result| {
                 match result {
+                    Err(Error(ErrorKind::WriteSuppressed(reason), _)) => {
+                        debug!("Cache write suppressed: {}", reason);
+                        me.stats.borrow_mut().cache_writes_suppressed += 1;
+                    }
+                    Err(Error(ErrorKind::StorageTimeout(..), _)) => {
+                        me.stats.borrow_mut().cache_write_timeouts += 1;
+                    }
                     Err(e) => {
                         debug!("Error executing cache write: {}", e);
                         me.stats.borrow_mut().cache_write_errors += 1;
                     }
+                    Ok(Some(ref info)) if me.read_only.load(Ordering::SeqCst) => {
+                        debug!("[{}]: Cache write suppressed, the cache is read-only", info.object_file_pretty);
+                        me.stats.borrow_mut().cache_writes_suppressed += 1;
+                    }
                     //TODO: save cache stats!
                     Ok(Some(info)) => {
                         debug!("[{}]: Cache write finished in {}",
@@ -714,7 +1256,15 @@ impl<C> SccacheService<C>
 }
 
 /// Statistics about the server.
+///
+/// This is the `stats` object of the JSON `--show-stats --stats-format=json`
+/// prints, with a member of the same name for each field. Counts are
+/// numbers, durations are objects of whole `secs` and `nanos`, and the maps
+/// are objects keyed by flag or compiler name. Fields may be added but aren't
+/// renamed or removed, and missing ones read as zero, so the JSON of older
+/// servers still parses.
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct ServerStats {
     /// The count of client compile requests.
     pub compile_requests: u64,
@@ -724,16 +1274,27 @@ pub struct ServerStats {
     pub requests_not_compile: u64,
     /// The count of client requests that were not cacheable.
     pub requests_not_cacheable: u64,
+    /// The count of client requests that were not cacheable because of each
+    /// unrecognized flag.
+    pub requests_unknown_flags: HashMap<String, u64>,
+    /// The count of compiler queries answered from a previous run.
+    pub compiler_query_hits: u64,
     /// The count of client requests that were executed.
     pub requests_executed: u64,
     /// The count of errors handling compile requests.
     pub cache_errors: u64,
     /// The count of cache hits for handled compile requests.
     pub cache_hits: u64,
+    /// The count of cache hits that replayed compiler warnings.
+    pub cache_hits_with_warnings: u64,
+    /// The count of cache hits with warnings that were recompiled instead of replayed.
+    pub warnings_recompiles: u64,
     /// The count of cache misses for handled compile requests.
     pub cache_misses: u64,
     /// The count of cache misses because the cache took too long to respond.
     pub cache_timeouts: u64,
+    /// The count of cache writes given up on because they took too long.
+    pub cache_write_timeouts: u64,
     /// The count of errors reading cache entries.
     pub cache_read_errors: u64,
     /// The count of compilations which were successful but couldn't be cached.
@@ -744,6 +1305,8 @@ pub struct ServerStats {
     pub cache_write_errors: u64,
     /// The number of successful cache writes.
     pub cache_writes: u64,
+    /// The count of cache writes skipped to protect the disk.
+    pub cache_writes_suppressed: u64,
     /// The total time spent writing cache entries.
     pub cache_write_duration: Duration,
     /// The total time spent reading cache hits.
@@ -752,15 +1315,73 @@ pub struct ServerStats {
     pub cache_read_miss_duration: Duration,
     /// The count of compilation failures.
     pub compile_fails: u64,
+    /// The count of distributed compiles retried on another build server.
+    pub dist_job_retries: u64,
+    /// The count of compiles kept off build servers by `local_only`.
+    pub dist_local_only_compiles: u64,
+    /// The count of cache hits during the warmup window.
+    pub warmup_cache_hits: u64,
+    /// The count of cache misses during the warmup window.
+    pub warmup_cache_misses: u64,
+    /// The cache hits, misses and errors of each kind of compiler, by its name.
+    pub compilers: HashMap<String, CompilerCacheStats>,
 }
 
-/// Info and stats about the server.
+/// The cache hits, misses and errors of one kind of compiler. Like the
+/// overall counts, they leave out the warmup window.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CompilerCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub errors: u64,
+}
+
+/// The cold start window of a server.
+///
+/// While the window is open, cache hits and misses are recorded in the
+/// `warmup_*` stats instead of the regular counters.
+#[derive(Clone)]
+struct Warmup {
+    /// When the server started.
+    start: Instant,
+    /// How long after startup the window stays open.
+    duration: Option<Duration>,
+    /// How many compile requests the window covers.
+    requests: Option<u64>,
+    /// The count of compile requests seen since startup.
+    requests_seen: Rc<Cell<u64>>,
+}
+
+impl Warmup {
+    fn new(duration: Option<Duration>, requests: Option<u64>) -> Warmup {
+        Warmup {
+            start: Instant::now(),
+            duration,
+            requests,
+            requests_seen: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// Whether the warmup window is still open.
+    fn active(&self) -> bool {
+        let in_duration = self.duration.map(|d| self.start.elapsed() < d);
+        let in_requests = self.requests.map(|r| self.requests_seen.get() <= r);
+        in_duration.unwrap_or(false) || in_requests.unwrap_or(false)
+    }
+}
+
+/// Info and stats about the server, printed as JSON by
+/// `--show-stats --stats-format=json`. The sizes and entry counts are `null`
+/// when the cache doesn't know them.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ServerInfo {
     pub stats: ServerStats,
     pub cache_location: String,
     pub cache_size: Option<u64>,
     pub max_cache_size: Option<u64>,
+    pub cache_entries: Option<u64>,
+    pub max_cache_entries: Option<u64>,
+    pub shadow: Option<ShadowStats>,
 }
 
 impl Default for ServerStats {
@@ -770,25 +1391,41 @@ impl Default for ServerStats {
             requests_unsupported_compiler: u64::default(),
             requests_not_compile: u64::default(),
             requests_not_cacheable: u64::default(),
+            requests_unknown_flags: HashMap::new(),
+            compiler_query_hits: u64::default(),
             requests_executed: u64::default(),
             cache_errors: u64::default(),
             cache_hits: u64::default(),
+            cache_hits_with_warnings: u64::default(),
+            warnings_recompiles: u64::default(),
             cache_misses: u64::default(),
             cache_timeouts: u64::default(),
+            cache_write_timeouts: u64::default(),
             cache_read_errors: u64::default(),
             non_cacheable_compilations: u64::default(),
             forced_recaches: u64::default(),
             cache_write_errors: u64::default(),
+            cache_writes_suppressed: u64::default(),
             cache_writes: u64::default(),
             cache_write_duration: Duration::new(0, 0),
             cache_read_hit_duration: Duration::new(0, 0),
             cache_read_miss_duration: Duration::new(0, 0),
             compile_fails: u64::default(),
+            dist_job_retries: u64::default(),
+            dist_local_only_compiles: u64::default(),
+            warmup_cache_hits: u64::default(),
+            warmup_cache_misses: u64::default(),
+            compilers: HashMap::new(),
         }
     }
 }
 
 impl ServerStats {
+    /// The stats of the compiler of `kind`.
+    fn for_compiler(&mut self, kind: &CompilerKind) -> &mut CompilerCacheStats {
+        self.compilers.entry(kind.name().to_owned()).or_insert_with(Default::default)
+    }
+
     /// Print stats to stdout in a human-readable format.
     ///
     /// Return the formatted width of each of the (name, value) columns.
@@ -817,16 +1454,25 @@ impl ServerStats {
         set_stat!(stats_vec, self.compile_requests, "Compile requests");
         set_stat!(stats_vec, self.requests_executed, "Compile requests executed");
         set_stat!(stats_vec, self.cache_hits, "Cache hits");
+        set_stat!(stats_vec, self.cache_hits_with_warnings, "Cache hits with warnings");
         set_stat!(stats_vec, self.cache_misses, "Cache misses");
+        set_stat!(stats_vec, self.warmup_cache_hits, "Warmup cache hits");
+        set_stat!(stats_vec, self.warmup_cache_misses, "Warmup cache misses");
         set_stat!(stats_vec, self.cache_timeouts, "Cache timeouts");
         set_stat!(stats_vec, self.cache_read_errors, "Cache read errors");
         set_stat!(stats_vec, self.forced_recaches, "Forced recaches");
+        set_stat!(stats_vec, self.warnings_recompiles, "Recompiled hits with warnings");
         set_stat!(stats_vec, self.cache_write_errors, "Cache write errors");
+        set_stat!(stats_vec, self.cache_write_timeouts, "Cache write timeouts");
+        set_stat!(stats_vec, self.cache_writes_suppressed, "Suppressed cache writes");
         set_stat!(stats_vec, self.compile_fails, "Compilation failures");
+        set_stat!(stats_vec, self.dist_job_retries, "Retried distributed compiles");
+        set_stat!(stats_vec, self.dist_local_only_compiles, "Local-only compiles");
         set_stat!(stats_vec, self.cache_errors, "Cache errors");
         set_stat!(stats_vec, self.non_cacheable_compilations, "Non-cacheable compilations");
         set_stat!(stats_vec, self.requests_not_cacheable, "Non-cacheable calls");
         set_stat!(stats_vec, self.requests_not_compile, "Non-compilation calls");
+        set_stat!(stats_vec, self.compiler_query_hits, "Cached compiler queries");
         set_stat!(stats_vec, self.requests_unsupported_compiler, "Unsupported compiler calls");
         set_duration_stat!(stats_vec, self.cache_write_duration, self.cache_writes, "Average cache write");
         set_duration_stat!(stats_vec, self.cache_read_miss_duration, self.cache_misses, "Average cache read miss");
@@ -836,6 +1482,11 @@ impl ServerStats {
         for (name, stat, suffix_len) in stats_vec {
             println!("{:<name_width$} {:>stat_width$}", name, stat, name_width=name_width, stat_width=stat_width + suffix_len);
         }
+        let mut unknown_flags = self.requests_unknown_flags.iter().collect::<Vec<_>>();
+        unknown_flags.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (flag, count) in unknown_flags {
+            println!("Non-cacheable calls with unknown flag {}: {}", flag, count);
+        }
         (name_width, stat_width)
     }
 }
@@ -855,6 +1506,31 @@ impl ServerInfo {
                 println!("{:<name_width$} {:>stat_width$} {}", name, val, suffix, name_width=name_width, stat_width=stat_width);
             }
         }
+        for &(name, val) in &[("Cache entries", &self.cache_entries),
+                             ("Max cache entries", &self.max_cache_entries)] {
+            if let &Some(val) = val {
+                println!("{:<name_width$} {:>stat_width$}", name, val, name_width=name_width, stat_width=stat_width);
+            }
+        }
+        if let Some(ref shadow) = self.shadow {
+            println!("{:<name_width$} {}", "Shadow cache location", shadow.location, name_width=name_width);
+            for &(name, val) in &[("Shadow cache hits", shadow.hits),
+                                 ("Shadow cache misses", shadow.misses),
+                                 ("Shadow cache read errors", shadow.read_errors),
+                                 ("Shadow misses of primary hits", shadow.primary_only_hits),
+                                 ("Shadow cache writes", shadow.writes),
+                                 ("Shadow cache write errors", shadow.write_errors)] {
+                println!("{:<name_width$} {:>stat_width$}", name, val, name_width=name_width, stat_width=stat_width);
+            }
+            let lookups = shadow.hits + shadow.misses + shadow.read_errors;
+            for &(name, duration, count) in &[("Average shadow cache read", shadow.read_duration, lookups),
+                                             ("Average shadow cache write", shadow.write_duration, shadow.writes)] {
+                if count > 0 {
+                    let average = duration / count as u32;
+                    println!("{:<name_width$} {:>stat_width$}", name, util::fmt_duration_as_secs(&average), name_width=name_width, stat_width=stat_width);
+                }
+            }
+        }
     }
 }
 
@@ -3,7 +3,9 @@
 
 #[allow(unused_imports, deprecated)]
 use std::ascii::AsciiExt;
+use std::cmp;
 use std::fmt;
+use std::io::Read;
 
 use base64;
 use crypto::digest::Digest;
@@ -11,13 +13,16 @@ use crypto::hmac::Hmac;
 use crypto::mac::Mac;
 use crypto::sha1::Sha1;
 use futures::{Future, Stream};
-use hyper::{self, header};
+use futures::stream;
+use hyper;
+use hyper::header;
 use hyper::Method;
-use hyper::client::{Client, Request, HttpConnector};
+use hyper::client::{Client, Request, Response, HttpConnector};
 use hyper_tls::HttpsConnector;
 use simples3::credential::*;
 use time;
 use tokio_core::reactor::Handle;
+use util::{get_resumable, range_from};
 
 use errors::*;
 
@@ -31,6 +36,15 @@ pub enum Ssl {
     No,
 }
 
+/// How S3 encrypts uploaded objects at rest. KMS isn't here: S3 only takes
+/// requests for KMS-encrypted objects signed with SigV4, and these are
+/// signed with SigV2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerSideEncryption {
+    /// With keys managed by S3.
+    Aes256,
+}
+
 fn base_url(endpoint: &str, ssl: Ssl) -> String {
     format!("{}://{}/",
             match ssl {
@@ -51,11 +65,59 @@ fn signature(string_to_sign: &str, signing_key: &str) -> String {
     base64::encode_config::<Vec<u8>>(&s, base64::STANDARD)
 }
 
+/// Check that `result` is a successful response to a `verb` request.
+fn check_response(verb: &str, result: hyper::Result<Response>) -> Result<Response> {
+    match result {
+        Ok(res) => {
+            if res.status().is_success() {
+                trace!("{} succeeded", verb);
+                Ok(res)
+            } else {
+                trace!("{} failed with HTTP status: {}", verb, res.status());
+                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            }
+        }
+        Err(e) => {
+            trace!("{} failed with error: {:?}", verb, e);
+            Err(e.into())
+        }
+    }
+}
+
+/// Read the body of `res` as a string.
+fn read_body(res: Response) -> SFuture<String> {
+    Box::new(res.body().concat2().map_err(Into::into).map(|body| {
+        String::from_utf8_lossy(&body).into_owned()
+    }))
+}
+
+/// Get the text of the first `name` element in the XML document `body`.
+fn xml_element(body: &str, name: &str) -> Option<String> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let start = body.find(&open)? + open.len();
+    let end = body[start..].find(&close)? + start;
+    Some(body[start..end].to_owned())
+}
+
+/// The body of a request completing a multipart upload of parts with `etags`.
+fn complete_multipart_body(etags: &[String]) -> String {
+    let mut body = "<CompleteMultipartUpload>".to_owned();
+    for (i, etag) in etags.iter().enumerate() {
+        body.push_str(&format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", i + 1, etag));
+    }
+    body.push_str("</CompleteMultipartUpload>");
+    body
+}
+
 /// An S3 bucket.
+#[derive(Clone)]
 pub struct Bucket {
     name: String,
     base_url: String,
     client: Client<HttpsConnector<HttpConnector>>,
+    /// How to encrypt uploaded objects, if not as the bucket does by default.
+    sse: Option<ServerSideEncryption>,
 }
 
 impl fmt::Display for Bucket {
@@ -65,7 +127,7 @@ impl fmt::Display for Bucket {
 }
 
 impl Bucket {
-    pub fn new(name: &str, endpoint: &str, ssl: Ssl, handle: &Handle)
+    pub fn new(name: &str, endpoint: &str, ssl: Ssl, sse: Option<ServerSideEncryption>, handle: &Handle)
         -> Result<Bucket>
     {
         let base_url = base_url(&endpoint, ssl);
@@ -75,55 +137,171 @@ impl Bucket {
             client: Client::configure()
                         .connector(HttpsConnector::new(1, handle)?)
                         .build(handle),
+            sse: sse,
         })
     }
 
     pub fn get(&self, key: &str) -> SFuture<Vec<u8>> {
         let url = format!("{}{}", self.base_url, key);
         debug!("GET {}", url);
-        let url2 = url.clone();
-        Box::new(self.client.get(url.parse().unwrap()).chain_err(move || {
-            format!("failed GET: {}", url)
-        }).and_then(|res| {
-            if res.status().is_success() {
-                let content_length = res.headers().get::<header::ContentLength>()
-                    .map(|&header::ContentLength(len)| len);
-                Ok((res.body(), content_length))
-            } else {
-                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+        get_resumable(self.client.clone(), move |offset| {
+            let mut request = Request::new(Method::Get, url.parse().unwrap());
+            if offset > 0 {
+                request.headers_mut().set(range_from(offset));
             }
-        }).and_then(|(body, content_length)| {
-            body.fold(Vec::new(), |mut body, chunk| {
-                body.extend_from_slice(&chunk);
-                Ok::<_, hyper::Error>(body)
-            }).chain_err(|| {
-                "failed to read HTTP body"
-            }).and_then(move |bytes| {
-                if let Some(len) = content_length {
-                    if len != bytes.len() as u64 {
-                        bail!(format!("Bad HTTP body size read: {}, expected {}", bytes.len(), len));
-                    } else {
-                        info!("Read {} bytes from {}", bytes.len(), url2);
-                    }
-                }
-                Ok(bytes)
-            })
-        }))
+            request
+        })
     }
 
     pub fn put(&self, key: &str, content: Vec<u8>, creds: &AwsCredentials)
                -> SFuture<()> {
-        let url = format!("{}{}", self.base_url, key);
-        debug!("PUT {}", url);
-        let mut request = Request::new(Method::Put, url.parse().unwrap());
+        debug!("PUT {}{}", self.base_url, key);
+        let mut request = self.request(Method::Put, key, "application/octet-stream", true, creds);
+        request.headers_mut().set(header::ContentLength(content.len() as u64));
+        request.headers_mut().set(header::CacheControl(vec![
+            // Two weeks
+            header::CacheDirective::MaxAge(1296000)
+        ]));
+        request.set_body(content);
+
+        Box::new(self.client.request(request).then(|result| {
+            check_response("PUT", result).map(|_| ())
+        }))
+    }
+
+    /// Upload the `len` bytes of `content` to `key` as a multipart upload in
+    /// parts of `part_size` bytes, uploading up to `concurrency` parts at once.
+    /// Each part is only read from `content` when it's about to be uploaded,
+    /// so no more than `concurrency` parts are held in memory. If any part
+    /// fails the upload is aborted, so that S3 doesn't keep the uploaded parts.
+    pub fn put_multipart<R>(&self, key: &str, content: R, len: u64, creds: &AwsCredentials,
+                            part_size: usize, concurrency: usize) -> SFuture<()>
+        where R: Read + 'static,
+    {
+        debug!("POST {}{}?uploads", self.base_url, key);
+        let request = self.request(Method::Post, &format!("{}?uploads", key), "", true, creds);
+        let initiate = self.client.request(request)
+            .then(|result| check_response("POST", result))
+            .and_then(read_body)
+            .and_then(|body| {
+                xml_element(&body, "UploadId").ok_or_else(|| {
+                    format!("no UploadId in response: {}", body).into()
+                })
+            });
+
+        let bucket = self.clone();
+        let key = key.to_owned();
+        let creds = creds.clone();
+        Box::new(initiate.and_then(move |upload_id| {
+            debug!("Uploading {} bytes to {} in {} byte parts", len, key, part_size);
+            let mut content = content;
+            let part_len = part_size as u64;
+            let parts = (0..(len + part_len - 1) / part_len).map(move |i| -> Result<_> {
+                let mut part = vec![0; cmp::min(part_len, len - i * part_len) as usize];
+                content.read_exact(&mut part).chain_err(|| "failed to read part of cache entry")?;
+                Ok((i as usize, part))
+            });
+            let uploads = {
+                let (bucket, key, upload_id, creds) = (bucket.clone(), key.clone(), upload_id.clone(), creds.clone());
+                stream::iter_result(parts)
+                    .map(move |(i, part)| bucket.put_part(&key, &upload_id, i + 1, part, &creds))
+                    .buffered(concurrency)
+                    .collect()
+            };
+            let complete = {
+                let (bucket, key, upload_id, creds) = (bucket.clone(), key.clone(), upload_id.clone(), creds.clone());
+                uploads.and_then(move |etags| bucket.complete_multipart(&key, &upload_id, &etags, &creds))
+            };
+            complete.or_else(move |e| {
+                warn!("Multipart upload of {} failed, aborting: {}", key, e);
+                bucket.abort_multipart(&key, &upload_id, &creds).then(|_| Err(e))
+            })
+        }))
+    }
+
+    /// Upload `content` as part number `part_number` of the multipart upload
+    /// `upload_id`, returning the ETag of the part.
+    fn put_part(&self, key: &str, upload_id: &str, part_number: usize, content: Vec<u8>, creds: &AwsCredentials)
+                -> SFuture<String> {
+        let path = format!("{}?partNumber={}&uploadId={}", key, part_number, upload_id);
+        trace!("PUT {}{}", self.base_url, path);
+        let mut request = self.request(Method::Put, &path, "", false, creds);
+        request.headers_mut().set(header::ContentLength(content.len() as u64));
+        request.set_body(content);
+
+        Box::new(self.client.request(request).then(|result| -> Result<String> {
+            let res = check_response("PUT", result)?;
+            let etag = res.headers().get_raw("ETag")
+                .and_then(|etag| etag.one())
+                .map(|etag| String::from_utf8_lossy(etag).into_owned());
+            etag.ok_or_else(|| "no ETag in response to uploading a part".into())
+        }))
+    }
+
+    /// Complete the multipart upload `upload_id` of the parts with `etags`.
+    fn complete_multipart(&self, key: &str, upload_id: &str, etags: &[String], creds: &AwsCredentials)
+                          -> SFuture<()> {
+        let path = format!("{}?uploadId={}", key, upload_id);
+        debug!("POST {}{}", self.base_url, path);
+        let body = complete_multipart_body(etags);
+        let mut request = self.request(Method::Post, &path, "application/xml", false, creds);
+        request.headers_mut().set(header::ContentLength(body.len() as u64));
+        request.set_body(body);
+
+        Box::new(self.client.request(request)
+            .then(|result| check_response("POST", result))
+            .and_then(read_body)
+            .and_then(|body| -> Result<()> {
+                // Completing can fail after the response has started, in
+                // which case the error is in the body of a 200 response.
+                if body.contains("<Error>") {
+                    bail!("failed to complete multipart upload: {}", body)
+                }
+                Ok(())
+            }))
+    }
+
+    /// Delete the object at `key`. Deleting one that doesn't exist succeeds.
+    pub fn delete(&self, key: &str, creds: &AwsCredentials) -> SFuture<()> {
+        debug!("DELETE {}{}", self.base_url, key);
+        let request = self.request(Method::Delete, key, "", false, creds);
+        Box::new(self.client.request(request).then(|result| {
+            check_response("DELETE", result).map(|_| ())
+        }))
+    }
+
+    /// Abort the multipart upload `upload_id`, discarding any uploaded parts.
+    fn abort_multipart(&self, key: &str, upload_id: &str, creds: &AwsCredentials) -> SFuture<()> {
+        let path = format!("{}?uploadId={}", key, upload_id);
+        debug!("DELETE {}{}", self.base_url, path);
+        let request = self.request(Method::Delete, &path, "", false, creds);
+        Box::new(self.client.request(request).then(|result| {
+            check_response("DELETE", result).map(|_| ())
+        }))
+    }
+
+    /// Create a signed `method` request for `path`, which may include a query
+    /// naming a subresource, such as `?uploads`. If `creates_object`, the
+    /// request asks for the object to be encrypted as configured; S3 takes
+    /// that only when starting an upload, and decrypts objects when reading
+    /// them however they were encrypted.
+    fn request(&self, method: Method, path: &str, content_type: &str, creates_object: bool, creds: &AwsCredentials)
+               -> Request {
+        let url = format!("{}{}", self.base_url, path);
+        let verb = method.to_string();
+        let mut request = Request::new(method, url.parse().unwrap());
 
-        let content_type = "application/octet-stream";
         let date = time::now_utc().rfc822().to_string();
         let mut canonical_headers = String::new();
         let token = creds.token().as_ref().map(|s| s.as_str());
+        let sse = match self.sse {
+            Some(ServerSideEncryption::Aes256) if creates_object => Some("AES256"),
+            _ => None,
+        };
         // Keep the list of header values sorted!
         for (header, maybe_value) in vec![
             ("x-amz-security-token", token),
+            ("x-amz-server-side-encryption", sse),
             ] {
             if let Some(ref value) = maybe_value {
                 request.headers_mut()
@@ -131,34 +309,13 @@ impl Bucket {
                 canonical_headers.push_str(format!("{}:{}\n", header.to_ascii_lowercase(), value).as_ref());
             }
         }
-        let auth = self.auth("PUT", &date, key, "", &canonical_headers, content_type, creds);
+        let auth = self.auth(&verb, &date, path, "", &canonical_headers, content_type, creds);
         request.headers_mut().set_raw("Date", vec!(date.into_bytes()));
-        request.headers_mut().set(header::ContentType(content_type.parse().unwrap()));
-        request.headers_mut().set(header::ContentLength(content.len() as u64));
-        request.headers_mut().set(header::CacheControl(vec![
-            // Two weeks
-            header::CacheDirective::MaxAge(1296000)
-        ]));
+        if !content_type.is_empty() {
+            request.headers_mut().set(header::ContentType(content_type.parse().unwrap()));
+        }
         request.headers_mut().set_raw("Authorization", vec!(auth.into_bytes()));
-        request.set_body(content);
-
-        Box::new(self.client.request(request).then(|result| {
-            match result {
-                Ok(res) => {
-                    if res.status().is_success() {
-                        trace!("PUT succeeded");
-                        Ok(())
-                    } else {
-                        trace!("PUT failed with HTTP status: {}", res.status());
-                        Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
-                    }
-                }
-                Err(e) => {
-                    trace!("PUT failed with error: {:?}", e);
-                    Err(e.into())
-                }
-            }
-        }))
+        request
     }
 
     // http://docs.aws.amazon.com/AmazonS3/latest/dev/RESTAuthentication.html
@@ -175,3 +332,138 @@ impl Bucket {
         format!("AWS {}:{}", creds.aws_access_key_id(), signature)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{Duration, UTC};
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use test::utils::{MockRequest, mock_http_server};
+    use tokio_core::reactor::Core;
+
+    /// Start a mock S3 server for multipart uploads, failing uploads of part
+    /// `failing_part`, and return its address and the requests made to it.
+    fn mock_s3(failing_part: Option<usize>) -> (String, Arc<Mutex<Vec<MockRequest>>>) {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let addr = {
+            let requests = requests.clone();
+            mock_http_server(move |request| {
+                requests.lock().unwrap().push(request.clone());
+                match (&request.method[..], &request.path[..]) {
+                    ("POST", "/a/b/c/abc?uploads") => {
+                        (200, vec![], b"<InitiateMultipartUploadResult><UploadId>up1</UploadId>\
+                                        </InitiateMultipartUploadResult>".to_vec())
+                    }
+                    ("PUT", path) if path.starts_with("/a/b/c/abc?partNumber=") => {
+                        let part = path["/a/b/c/abc?partNumber=".len()..path.find('&').unwrap()].parse().unwrap();
+                        if Some(part) == failing_part {
+                            (500, vec![], vec![])
+                        } else {
+                            (200, vec![("ETag", format!("\"etag{}\"", part))], vec![])
+                        }
+                    }
+                    ("POST", "/a/b/c/abc?uploadId=up1") => {
+                        (200, vec![], b"<CompleteMultipartUploadResult></CompleteMultipartUploadResult>".to_vec())
+                    }
+                    ("DELETE", "/a/b/c/abc?uploadId=up1") => (204, vec![], vec![]),
+                    _ => (404, vec![], vec![]),
+                }
+            })
+        };
+        (addr, requests)
+    }
+
+    #[test]
+    fn test_put_multipart() {
+        let (addr, requests) = mock_s3(None);
+        let mut core = Core::new().unwrap();
+        let creds = AwsCredentials::new("key", "secret", None, UTC::now() + Duration::hours(1));
+        let bucket = Bucket::new("bucket", &addr, Ssl::No, None, &core.handle()).unwrap();
+        let content = (0..10u8).collect::<Vec<_>>();
+        core.run(bucket.put_multipart("a/b/c/abc", Cursor::new(content), 10, &creds, 4, 2)).unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(("POST", "/a/b/c/abc?uploads"), (&requests[0].method[..], &requests[0].path[..]));
+        let mut parts = requests.iter()
+            .filter(|r| r.method == "PUT")
+            .map(|r| (r.path.clone(), r.body.clone()))
+            .collect::<Vec<_>>();
+        parts.sort();
+        assert_eq!(vec![
+            ("/a/b/c/abc?partNumber=1&uploadId=up1".to_owned(), vec![0, 1, 2, 3]),
+            ("/a/b/c/abc?partNumber=2&uploadId=up1".to_owned(), vec![4, 5, 6, 7]),
+            ("/a/b/c/abc?partNumber=3&uploadId=up1".to_owned(), vec![8, 9]),
+        ], parts);
+        // Completed once all the parts are uploaded, with their ETags in order.
+        let complete = requests.last().unwrap();
+        assert_eq!(("POST", "/a/b/c/abc?uploadId=up1"), (&complete.method[..], &complete.path[..]));
+        let etags = vec!["\"etag1\"".to_owned(), "\"etag2\"".to_owned(), "\"etag3\"".to_owned()];
+        assert_eq!(complete_multipart_body(&etags).into_bytes(), complete.body);
+        assert_eq!(5, requests.len());
+    }
+
+    #[test]
+    fn test_put_multipart_aborts() {
+        let (addr, requests) = mock_s3(Some(2));
+        let mut core = Core::new().unwrap();
+        let creds = AwsCredentials::new("key", "secret", None, UTC::now() + Duration::hours(1));
+        let bucket = Bucket::new("bucket", &addr, Ssl::No, None, &core.handle()).unwrap();
+        let content = (0..10u8).collect::<Vec<_>>();
+        assert!(core.run(bucket.put_multipart("a/b/c/abc", Cursor::new(content), 10, &creds, 4, 1)).is_err());
+
+        let requests = requests.lock().unwrap();
+        let made = requests.iter().map(|r| (&r.method[..], &r.path[..])).collect::<Vec<_>>();
+        assert_eq!(vec![
+            ("POST", "/a/b/c/abc?uploads"),
+            ("PUT", "/a/b/c/abc?partNumber=1&uploadId=up1"),
+            ("PUT", "/a/b/c/abc?partNumber=2&uploadId=up1"),
+            ("DELETE", "/a/b/c/abc?uploadId=up1"),
+        ], made);
+    }
+
+    fn header<'a>(request: &'a Request, name: &str) -> Option<&'a [u8]> {
+        request.headers().get_raw(name).and_then(|v| v.one())
+    }
+
+    #[test]
+    fn test_server_side_encryption_headers() {
+        let core = Core::new().unwrap();
+        let creds = AwsCredentials::new("key", "secret", None, UTC::now() + Duration::hours(1));
+        let bucket = Bucket::new("bucket", "s3.amazonaws.com", Ssl::No, Some(ServerSideEncryption::Aes256),
+                                 &core.handle()).unwrap();
+        let request = bucket.request(Method::Put, "a/b/c/abc", "application/octet-stream", true, &creds);
+        assert_eq!(Some(&b"AES256"[..]), header(&request, "x-amz-server-side-encryption"));
+        // The header is signed.
+        let signed = bucket.auth("PUT", &String::from_utf8_lossy(header(&request, "Date").unwrap()), "a/b/c/abc", "",
+                                 "x-amz-server-side-encryption:AES256\n", "application/octet-stream", &creds);
+        assert_eq!(Some(signed.as_bytes()), header(&request, "Authorization"));
+        // Parts of multipart uploads are encrypted as the upload is.
+        let request = bucket.request(Method::Put, "a/b/c/abc?partNumber=1&uploadId=x", "", false, &creds);
+        assert_eq!(None, header(&request, "x-amz-server-side-encryption"));
+        let request = bucket.request(Method::Post, "a/b/c/abc?uploads", "", true, &creds);
+        assert_eq!(Some(&b"AES256"[..]), header(&request, "x-amz-server-side-encryption"));
+
+        let bucket = Bucket::new("bucket", "s3.amazonaws.com", Ssl::No, None, &core.handle()).unwrap();
+        let request = bucket.request(Method::Put, "a/b/c/abc", "application/octet-stream", true, &creds);
+        assert_eq!(None, header(&request, "x-amz-server-side-encryption"));
+    }
+
+    #[test]
+    fn test_xml_element() {
+        let body = "<InitiateMultipartUploadResult><Bucket>b</Bucket><Key>k</Key>\
+                    <UploadId>abc.def-123</UploadId></InitiateMultipartUploadResult>";
+        assert_eq!(Some("abc.def-123".to_owned()), xml_element(body, "UploadId"));
+        assert_eq!(None, xml_element(body, "Missing"));
+    }
+
+    #[test]
+    fn test_complete_multipart_body() {
+        let etags = vec!["\"a\"".to_owned(), "\"b\"".to_owned()];
+        assert_eq!("<CompleteMultipartUpload>\
+                    <Part><PartNumber>1</PartNumber><ETag>\"a\"</ETag></Part>\
+                    <Part><PartNumber>2</PartNumber><ETag>\"b\"</ETag></Part>\
+                    </CompleteMultipartUpload>",
+                   complete_multipart_body(&etags));
+    }
+}
@@ -48,4 +48,6 @@ impl Storage for MockStorage {
     fn location(&self) -> String { "Mock Storage".to_string() }
     fn current_size(&self) -> Option<u64> { None }
     fn max_size(&self) -> Option<u64> { None }
+    fn current_entries(&self) -> Option<u64> { None }
+    fn max_entries(&self) -> Option<u64> { None }
 }
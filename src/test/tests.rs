@@ -12,14 +12,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use ::cache::Storage;
 use ::cache::disk::DiskCache;
+use ::cache::timeout::TimeoutStorage;
 use ::client::{
     connect_to_server,
 };
 use ::commands::{
+    compiler_wrapper_name,
     do_compile,
+    request_get_provenance,
+    request_list_toolchains,
+    request_prune_older_than,
+    request_purge_stale_compilers,
     request_shutdown,
     request_stats,
+    unwrap_compiler,
 };
 use dist::NoopClient;
 use env_logger;
@@ -28,9 +36,11 @@ use futures_cpupool::CpuPool;
 use jobserver::Client;
 use ::mock_command::*;
 use ::server::{
+    ServerInfo,
     ServerMessage,
     SccacheServer,
 };
+use serde_json;
 use std::fs::File;
 use std::io::{
     Cursor,
@@ -41,7 +51,7 @@ use std::path::Path;
 use std::process::Command;
 use std::sync::{Arc,Mutex,mpsc};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::u64;
 use test::utils::*;
 use tokio_core::reactor::Core;
@@ -53,6 +63,16 @@ struct ServerOptions {
     idle_timeout: Option<u64>,
     /// The maximum size of the disk cache.
     cache_size: Option<u64>,
+    /// The number of compile requests in the warmup window.
+    warmup_requests: Option<u64>,
+    /// Whether the cache is read-only.
+    read_only: bool,
+    /// Use a cache whose operations never finish, timing them out after
+    /// this long.
+    stalling_storage: Option<Duration>,
+    /// Where to send the address of the metrics endpoint, if it's served.
+    #[cfg(feature = "metrics")]
+    metrics: Option<mpsc::Sender<::std::net::SocketAddr>>,
 }
 
 /// Run a server on a background thread, and return a tuple of useful things.
@@ -76,7 +96,8 @@ fn run_server_thread<T>(cache_dir: &Path, options: T)
                             .unwrap_or(u64::MAX);
     let pool = CpuPool::new(1);
     let dist_client = Arc::new(NoopClient);
-    let storage = Arc::new(DiskCache::new(&cache_dir, cache_size, &pool));
+    let storage = Arc::new(DiskCache::new(&cache_dir, cache_size, None, &pool));
+    let stalling_storage = options.as_ref().and_then(|o| o.stalling_storage);
 
     // Create a server on a background thread, get some useful bits from it.
     let (tx, rx) = mpsc::channel();
@@ -84,6 +105,10 @@ fn run_server_thread<T>(cache_dir: &Path, options: T)
     let handle = thread::spawn(move || {
         let core = Core::new().unwrap();
         let client = unsafe { Client::new() };
+        let storage: Arc<Storage> = match stalling_storage {
+            Some(timeout) => Arc::new(TimeoutStorage::new(Arc::new(StallingStorage), &core.handle(), timeout)),
+            None => storage,
+        };
         let srv = SccacheServer::new(0, pool, core, client, dist_client, storage).unwrap();
         let mut srv: SccacheServer<Arc<Mutex<MockCommandCreator>>> = srv;
         assert!(srv.port() > 0);
@@ -91,6 +116,16 @@ fn run_server_thread<T>(cache_dir: &Path, options: T)
             if let Some(timeout) = options.idle_timeout {
                  srv.set_idle_timeout(Duration::from_millis(timeout));
             }
+            if let Some(requests) = options.warmup_requests {
+                srv.set_warmup(None, Some(requests));
+            }
+            srv.set_read_only(options.read_only);
+            #[cfg(feature = "metrics")]
+            {
+                if let Some(metrics) = options.metrics {
+                    metrics.send(srv.serve_metrics("127.0.0.1:0".parse().unwrap()).unwrap()).unwrap();
+                }
+            }
         }
         let port = srv.port();
         let creator = srv.command_creator().clone();
@@ -158,6 +193,123 @@ fn test_server_stats() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_stats_json() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+        // Preprocessor invocation.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessor stdout", "")));
+        // Compiler invocation.
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |_| {
+            let mut f = File::create(&obj)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    let exe = &f.bins[0];
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    let client_creator = new_creator();
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let path = Some(f.paths);
+    let mut core = Core::new().unwrap();
+    let conn = connect_to_server(port).unwrap();
+    assert_eq!(0, do_compile(client_creator.clone(), &mut core, conn, None, exe, cmdline, cwd, path, vec![], &mut stdout, &mut stderr).unwrap());
+
+    // Serialized as `--stats-format=json` does.
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    let json = serde_json::to_string(&info).unwrap();
+    let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(Some(1), value["stats"]["compile_requests"].as_u64());
+    assert_eq!(Some(1), value["stats"]["cache_misses"].as_u64());
+    assert_eq!(Some(1), value["stats"]["compilers"]["gcc"]["misses"].as_u64());
+    assert_eq!(Some(0), value["stats"]["compilers"]["gcc"]["hits"].as_u64());
+    assert!(value["stats"]["cache_read_miss_duration"]["secs"].is_u64());
+    assert!(value["stats"]["cache_read_miss_duration"]["nanos"].is_u64());
+    assert!(value["cache_size"].is_u64());
+    assert!(value["cache_location"].is_string());
+    // And read back.
+    let parsed: ServerInfo = serde_json::from_str(&json).unwrap();
+    assert_eq!(1, parsed.stats.compile_requests);
+    assert_eq!(1, parsed.stats.cache_misses);
+    assert_eq!(1, parsed.stats.compilers["gcc"].misses);
+    assert_eq!(info.stats.cache_read_miss_duration, parsed.stats.cache_read_miss_duration);
+    assert_eq!(info.cache_size, parsed.cache_size);
+    // Stats from a server that doesn't know about newer fields still parse.
+    let old: ServerInfo = serde_json::from_str(r#"{
+        "stats": { "compile_requests": 2, "cache_hits": 1 },
+        "cache_location": "Local disk",
+        "cache_size": null,
+        "max_cache_size": null,
+        "cache_entries": null,
+        "max_cache_entries": null,
+        "shadow": null
+    }"#).unwrap();
+    assert_eq!(2, old.stats.compile_requests);
+    assert!(old.stats.compilers.is_empty());
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_list_toolchains_none() {
+    let f = TestFixture::new();
+    let (port, sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
+    // No compilers have been detected yet, so there's nothing to package.
+    let conn = connect_to_server(port).unwrap();
+    assert!(request_list_toolchains(conn).unwrap().is_empty());
+    // Now signal it to shut down.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_get_provenance_missing() {
+    let f = TestFixture::new();
+    let (port, sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
+    let conn = connect_to_server(port).unwrap();
+    assert!(request_get_provenance(conn, "not-a-key").unwrap().is_err());
+    // Now signal it to shut down.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_prune_older_than_empty() {
+    let f = TestFixture::new();
+    let (port, sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
+    let conn = connect_to_server(port).unwrap();
+    let stats = request_prune_older_than(conn, Duration::from_secs(60)).unwrap().unwrap();
+    assert_eq!(0, stats.entries);
+    assert_eq!(0, stats.bytes);
+    // Now signal it to shut down.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_purge_stale_compilers_empty() {
+    let f = TestFixture::new();
+    let (port, sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
+    let conn = connect_to_server(port).unwrap();
+    let stats = request_purge_stale_compilers(conn).unwrap().unwrap();
+    assert_eq!(0, stats.entries);
+    assert_eq!(0, stats.bytes);
+    // Now signal it to shut down.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
 #[test]
 fn test_server_unsupported_compiler() {
     let f = TestFixture::new();
@@ -182,7 +334,7 @@ fn test_server_unsupported_compiler() {
     let mut stderr = Cursor::new(Vec::new());
     let path = Some(f.paths);
     let mut core = Core::new().unwrap();
-    let res = do_compile(client_creator.clone(), &mut core, conn, exe, cmdline, cwd, path, vec![],
+    let res = do_compile(client_creator.clone(), &mut core, conn, None, exe, cmdline, cwd, path, vec![],
                          &mut stdout, &mut stderr);
     match res {
         Ok(_) => panic!("do_compile should have failed!"),
@@ -238,7 +390,7 @@ fn test_server_compile() {
     let mut stderr = Cursor::new(Vec::new());
     let path = Some(f.paths);
     let mut core = Core::new().unwrap();
-    assert_eq!(0, do_compile(client_creator.clone(), &mut core, conn, exe, cmdline, cwd, path, vec![], &mut stdout, &mut stderr).unwrap());
+    assert_eq!(0, do_compile(client_creator.clone(), &mut core, conn, None, exe, cmdline, cwd, path, vec![], &mut stdout, &mut stderr).unwrap());
     // Make sure we ran the mock processes.
     assert_eq!(0, server_creator.lock().unwrap().children.len());
     assert_eq!(STDOUT, stdout.into_inner().as_slice());
@@ -249,6 +401,226 @@ fn test_server_compile() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_compile_warmup() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(
+        &f.tempdir.path(),
+        ServerOptions {
+            warmup_requests: Some(1),
+            ..Default::default()
+        },
+    );
+    let conn = connect_to_server(port).unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+        // Preprocessor invocation.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessor stdout", "")));
+        // Compiler invocation.
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |_| {
+            let mut f = File::create(&obj)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    let exe = &f.bins[0];
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    let client_creator = new_creator();
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let path = Some(f.paths);
+    let mut core = Core::new().unwrap();
+    assert_eq!(0, do_compile(client_creator.clone(), &mut core, conn, None, exe, cmdline, cwd, path, vec![], &mut stdout, &mut stderr).unwrap());
+    // The miss happened during warmup, so it shouldn't count as a regular miss.
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(1, info.stats.warmup_cache_misses);
+    assert_eq!(0, info.stats.cache_misses);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_read_only() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(
+        &f.tempdir.path(),
+        ServerOptions {
+            read_only: true,
+            ..Default::default()
+        },
+    );
+    let conn = connect_to_server(port).unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+        // Preprocessor invocation.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessor stdout", "")));
+        // Compiler invocation.
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |_| {
+            let mut f = File::create(&obj)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    let exe = &f.bins[0];
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    let client_creator = new_creator();
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let path = Some(f.paths);
+    let mut core = Core::new().unwrap();
+    assert_eq!(0, do_compile(client_creator.clone(), &mut core, conn, None, exe, cmdline, cwd, path, vec![], &mut stdout, &mut stderr).unwrap());
+    // The compile succeeded, but its result wasn't stored.
+    let mut info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    for _ in 0..100 {
+        if info.stats.cache_writes_suppressed > 0 {
+            break
+        }
+        thread::sleep(Duration::from_millis(10));
+        info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    }
+    assert_eq!(1, info.stats.cache_misses);
+    assert_eq!(1, info.stats.cache_writes_suppressed);
+    assert_eq!(0, info.stats.cache_writes);
+    assert_eq!(Some(0), info.cache_entries);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_storage_timeout() {
+    let f = TestFixture::new();
+    let timeout = Duration::from_millis(200);
+    let (port, sender, server_creator, child) = run_server_thread(
+        &f.tempdir.path(),
+        ServerOptions {
+            stalling_storage: Some(timeout),
+            ..Default::default()
+        },
+    );
+    let conn = connect_to_server(port).unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+        // Preprocessor invocation.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessor stdout", "")));
+        // Compiler invocation.
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |_| {
+            let mut f = File::create(&obj)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    let exe = &f.bins[0];
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    let client_creator = new_creator();
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let path = Some(f.paths);
+    let mut core = Core::new().unwrap();
+    let start = Instant::now();
+    assert_eq!(0, do_compile(client_creator.clone(), &mut core, conn, None, exe, cmdline, cwd, path, vec![], &mut stdout, &mut stderr).unwrap());
+    // The lookup gave up after the timeout and the compile went ahead,
+    // which with mock processes takes next to no time.
+    assert!(start.elapsed() < timeout + Duration::from_secs(5), "Compile took {:?}", start.elapsed());
+    assert!(f.tempdir.path().join("file.o").exists());
+    let mut info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    for _ in 0..100 {
+        if info.stats.cache_write_timeouts > 0 {
+            break
+        }
+        thread::sleep(Duration::from_millis(10));
+        info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    }
+    assert_eq!(1, info.stats.cache_misses);
+    assert_eq!(1, info.stats.cache_timeouts);
+    assert_eq!(0, info.stats.cache_errors);
+    assert_eq!(1, info.stats.cache_write_timeouts);
+    assert_eq!(0, info.stats.cache_write_errors);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+#[cfg(feature = "metrics")]
+fn test_server_metrics() {
+    use reqwest;
+
+    let f = TestFixture::new();
+    let (metrics_tx, metrics_rx) = mpsc::channel();
+    let (port, sender, server_creator, child) = run_server_thread(
+        &f.tempdir.path(),
+        ServerOptions {
+            metrics: Some(metrics_tx),
+            ..Default::default()
+        },
+    );
+    let url = format!("http://{}/metrics", metrics_rx.recv().unwrap());
+    let scrape = || reqwest::get(&url).unwrap().text().unwrap();
+    let hits = |text: &str| {
+        text.lines()
+            .find(|line| line.starts_with("sccache_cache_results_total{compiler=\"gcc\",result=\"hit\"}"))
+            .map(|line| line.rsplit(' ').next().unwrap().to_owned())
+    };
+    assert_eq!(None, hits(&scrape()));
+
+    let obj = f.tempdir.path().join("file.o");
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+        // Preprocessor invocation.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessor stdout", "")));
+        // Compiler invocation.
+        let obj = obj.clone();
+        c.next_command_calls(move |_| {
+            let mut f = File::create(&obj)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        // The second compile only runs the preprocessor, and is a hit.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessor stdout", "")));
+    }
+    let exe = &f.bins[0];
+    let cmdline: Vec<::std::ffi::OsString> = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    let client_creator = new_creator();
+    let path = Some(f.paths.clone());
+    let mut core = Core::new().unwrap();
+    let mut compile = || {
+        let mut stdout = Cursor::new(Vec::new());
+        let mut stderr = Cursor::new(Vec::new());
+        let conn = connect_to_server(port).unwrap();
+        do_compile(client_creator.clone(), &mut core, conn, None, exe, cmdline.clone(), cwd, path.clone(), vec![],
+                   &mut stdout, &mut stderr).unwrap()
+    };
+    assert_eq!(0, compile());
+    // Wait for the result to be stored before compiling again.
+    for _ in 0..100 {
+        if request_stats(connect_to_server(port).unwrap()).unwrap().stats.cache_writes > 0 {
+            break
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    let text = scrape();
+    assert_eq!(Some("0".to_owned()), hits(&text));
+    assert!(text.lines().any(|line| line == "sccache_cache_results_total{compiler=\"gcc\",result=\"miss\"} 1"));
+    assert_eq!(0, compile());
+    assert_eq!(Some("1".to_owned()), hits(&scrape()));
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
 #[test]
 // test fails intermittently on macos:
 // https://github.com/mozilla/sccache/issues/234
@@ -267,3 +639,41 @@ fn test_server_port_in_use() {
     const MSG: &str = "Server startup failed:";
     assert!(s.contains(MSG), "Output did not contain '{}':\n========\n{}\n========", MSG, s);
 }
+
+#[test]
+#[cfg(unix)]
+fn test_unwrap_compiler_wrapper() {
+    use std::env;
+    use std::ffi::OsString;
+    use std::os::unix::fs::symlink;
+
+    let f = TestFixture::new();
+    let dir = f.tempdir.path();
+    let ccache = mk_bin(&dir.join("ccache-bin"), "ccache").unwrap();
+    // Like /usr/lib/ccache, a/gcc is ccache masquerading as gcc.
+    symlink(&ccache, dir.join("a").join("gcc")).unwrap();
+    let gcc = mk_bin(&dir.join("b"), "gcc").unwrap();
+    let path = Some(env::join_paths(&[dir.join("a"), dir.join("b")]).unwrap());
+    let args: Vec<OsString> = vec!["-c".into(), "foo.c".into()];
+
+    assert_eq!(Some("ccache"), compiler_wrapper_name(&ccache, dir));
+    assert_eq!(Some("ccache"), compiler_wrapper_name(&dir.join("a").join("gcc"), dir));
+    assert_eq!(None, compiler_wrapper_name(&gcc, dir));
+    // Relative paths are resolved against the given directory.
+    assert_eq!(Some("ccache"), compiler_wrapper_name(Path::new("a/gcc"), dir));
+    assert_eq!(None, compiler_wrapper_name(Path::new("b/gcc"), dir));
+
+    let (compiler, cmdline) = unwrap_compiler(&dir.join("a").join("gcc"), &args, dir, path.clone()).unwrap();
+    assert_eq!(gcc.canonicalize().unwrap(), compiler.canonicalize().unwrap());
+    assert_eq!(args, cmdline);
+
+    // Running the wrapper directly, with the compiler as its first argument.
+    let mut wrapped_args: Vec<OsString> = vec!["gcc".into()];
+    wrapped_args.extend(args.iter().cloned());
+    let (compiler, cmdline) = unwrap_compiler(&ccache, &wrapped_args, dir, path.clone()).unwrap();
+    assert_eq!(gcc.canonicalize().unwrap(), compiler.canonicalize().unwrap());
+    assert_eq!(args, cmdline);
+
+    // There's nothing to unwrap to if the only gcc is the wrapper.
+    assert!(unwrap_compiler(&dir.join("a").join("gcc"), &args, dir, Some(dir.join("a").into())).is_none());
+}
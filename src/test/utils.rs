@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use cache::{Cache, CacheWrite, Storage};
+use futures::future;
 #[cfg(unix)]
 use libc;
 use mock_command::*;
@@ -19,10 +21,13 @@ use std::collections::HashMap;
 use std::env;
 use std::ffi::OsString;
 use std::fs::{self,File};
-use std::io;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
 use std::path::{Path,PathBuf};
 
 use std::sync::{Arc,Mutex};
+use std::thread;
+use std::time::Duration;
 use tempdir::TempDir;
 use tokio_core::reactor::Core;
 
@@ -99,6 +104,82 @@ pub fn find_sccache_binary() -> PathBuf {
         .expect(&format!("Error: sccache binary not found, looked in `{:?}`. Do you need to run `cargo build`?", dirs))
 }
 
+/// A `Storage` whose operations never finish, like a hung endpoint's.
+pub struct StallingStorage;
+
+impl Storage for StallingStorage {
+    fn get(&self, _key: &str) -> SFuture<Cache> { Box::new(future::empty()) }
+    fn put(&self, _key: &str, _entry: CacheWrite) -> SFuture<Duration> { Box::new(future::empty()) }
+    fn location(&self) -> String { "stalling".to_owned() }
+    fn current_size(&self) -> Option<u64> { None }
+    fn max_size(&self) -> Option<u64> { None }
+    fn current_entries(&self) -> Option<u64> { None }
+    fn max_entries(&self) -> Option<u64> { None }
+}
+
+/// 4096 bytes that don't compress.
+pub fn incompressible_data() -> Vec<u8> {
+    (0..4096u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect()
+}
+
+/// A request made to a mock HTTP server.
+#[derive(Clone, Debug)]
+pub struct MockRequest {
+    pub method: String,
+    pub path: String,
+    /// The request's headers, with lowercase names.
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+/// Start a mock HTTP server replying to each request with the status,
+/// headers and body `reply` returns for it, and return its address.
+pub fn mock_http_server<F>(reply: F) -> String
+    where F: Fn(&MockRequest) -> (u16, Vec<(&'static str, String)>, Vec<u8>) + Send + Sync + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let reply = Arc::new(reply);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let reply = reply.clone();
+            thread::spawn(move || {
+                let mut w = stream.try_clone().unwrap();
+                let mut r = BufReader::new(stream);
+                let mut line = String::new();
+                r.read_line(&mut line).unwrap();
+                let (method, path) = {
+                    let mut words = line.split_whitespace();
+                    (words.next().unwrap().to_owned(), words.next().unwrap().to_owned())
+                };
+                let mut headers = HashMap::new();
+                loop {
+                    line.clear();
+                    r.read_line(&mut line).unwrap();
+                    match line.find(':') {
+                        Some(i) => {
+                            headers.insert(line[..i].to_lowercase(), line[i + 1..].trim().to_owned());
+                        }
+                        None => break,
+                    }
+                }
+                let len = headers.get("content-length").map_or(0, |len| len.parse().unwrap());
+                let mut body = vec![0; len];
+                r.read_exact(&mut body).unwrap();
+                let (status, headers, body) = reply(&MockRequest { method, path, headers, body });
+                write!(w, "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n", status, body.len()).unwrap();
+                for (name, value) in headers {
+                    write!(w, "{}: {}\r\n", name, value).unwrap();
+                }
+                write!(w, "\r\n").unwrap();
+                w.write_all(&body).unwrap();
+            });
+        }
+    });
+    addr
+}
+
 pub struct TestFixture {
     /// Temp directory.
     pub tempdir: TempDir,
@@ -15,17 +15,31 @@
 use bincode;
 use byteorder::{ByteOrder, BigEndian};
 use futures::Future;
+#[cfg(feature = "hyper")]
+use futures::{future, Stream};
+#[cfg(feature = "hyper")]
+use futures::future::Loop;
 use futures_cpupool::CpuPool;
+#[cfg(feature = "hyper")]
+use hyper::{self, header, StatusCode};
+#[cfg(feature = "hyper")]
+use hyper::client::{Client, Connect, Request};
 use mock_command::{CommandChild, RunCommand};
 use ring::digest::{SHA512, Context};
 use serde::Serialize;
+#[cfg(feature = "hyper")]
+use std::cell::{Cell, RefCell};
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::hash::Hasher;
-use std::io::BufReader;
+use std::io::{self, BufReader};
 use std::io::prelude::*;
-use std::path::PathBuf;
+#[cfg(feature = "hyper")]
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::process::{self,Stdio};
+#[cfg(feature = "hyper")]
+use std::rc::Rc;
 use std::time::Duration;
 
 use errors::*;
@@ -97,6 +111,55 @@ pub fn fmt_duration_as_secs(duration: &Duration) -> String
     format!("{}.{:03} s", duration.as_secs(), duration.subsec_nanos() / 1000_000)
 }
 
+/// Get the hostname of this machine, if it can be determined.
+#[cfg(unix)]
+pub fn hostname() -> Option<String> {
+    use libc;
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+/// Get the hostname of this machine, if it can be determined.
+#[cfg(windows)]
+pub fn hostname() -> Option<String> {
+    ::std::env::var("COMPUTERNAME").ok()
+}
+
+/// Get the space available to unprivileged users and the total size, in
+/// bytes, of the filesystem holding `path`.
+#[cfg(unix)]
+pub fn disk_space(path: &Path) -> io::Result<(u64, u64)> {
+    use libc;
+    use std::ffi::CString;
+    use std::mem;
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let frsize = stat.f_frsize as u64;
+    Ok((stat.f_bavail as u64 * frsize, stat.f_blocks as u64 * frsize))
+}
+
+/// Get the space available to this user and the total size, in bytes, of
+/// the filesystem holding `path`.
+#[cfg(windows)]
+pub fn disk_space(path: &Path) -> io::Result<(u64, u64)> {
+    use kernel32;
+    use std::ptr;
+    let path = path.as_os_str().encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+    let (mut available, mut total) = (0, 0);
+    if unsafe { kernel32::GetDiskFreeSpaceExW(path.as_ptr(), &mut available, &mut total, ptr::null_mut()) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((available, total))
+}
+
 /// If `input`, write it to `child`'s stdin while also reading `child`'s stdout and stderr, then wait on `child` and return its status and output.
 ///
 /// This was lifted from `std::process::Child::wait_with_output` and modified
@@ -176,6 +239,91 @@ pub fn write_length_prefixed_bincode<W, S>(mut writer: W, data: S) -> Result<()>
     Ok(())
 }
 
+/// The number of times `get_resumable` resumes an interrupted download before giving up.
+#[cfg(feature = "hyper")]
+const MAX_DOWNLOAD_RESUMES: usize = 3;
+
+/// A `Range` header requesting everything from byte `offset` onwards.
+#[cfg(feature = "hyper")]
+pub fn range_from(offset: u64) -> header::Range {
+    header::Range::Bytes(vec![header::ByteRangeSpec::AllFrom(offset)])
+}
+
+/// Download the body of the request built by `make_request`.
+///
+/// `make_request` is called with the number of bytes already received, and
+/// if that is non-zero it must ask for the rest of the object, usually by
+/// setting `range_from(offset)`. If the connection fails part way through,
+/// the download is resumed from the last received byte instead of fetching
+/// the whole object again. A server that ignores the range and sends the
+/// whole object is handled by starting over.
+#[cfg(feature = "hyper")]
+pub fn get_resumable<C, F>(client: Client<C>, make_request: F) -> SFuture<Vec<u8>>
+    where C: Connect,
+          F: Fn(u64) -> Request + 'static,
+{
+    let body = Rc::new(RefCell::new(Vec::new()));
+    // The length of the whole object, once a response has told us.
+    let total_len = Rc::new(Cell::new(None));
+    Box::new(future::loop_fn(0, move |resumes| {
+        let offset = body.borrow().len() as u64;
+        let request = make_request(offset);
+        let uri = request.uri().clone();
+        let uri2 = uri.clone();
+        let body = body.clone();
+        let body2 = body.clone();
+        let total_len = total_len.clone();
+        let total_len2 = total_len.clone();
+        client.request(request).chain_err(move || {
+            format!("failed GET: {}", uri)
+        }).and_then(move |res| {
+            let start = match res.status() {
+                StatusCode::PartialContent if offset > 0 => offset,
+                status if status.is_success() => {
+                    body.borrow_mut().clear();
+                    0
+                }
+                status => return Err(ErrorKind::BadHTTPStatus(status.clone()).into()),
+            };
+            if let Some(&header::ContentLength(len)) = res.headers().get::<header::ContentLength>() {
+                total_len.set(Some(start + len));
+            }
+            Ok(res.body().for_each(move |chunk| {
+                body.borrow_mut().extend_from_slice(&chunk);
+                Ok::<_, hyper::Error>(())
+            }).chain_err(|| {
+                "failed to read HTTP body"
+            }))
+        }).flatten().then(move |res| {
+            let received = body2.borrow().len() as u64;
+            let res = res.and_then(|()| {
+                match total_len2.get() {
+                    Some(len) if len != received => {
+                        bail!(format!("Bad HTTP body size read: {}, expected {}", received, len))
+                    }
+                    _ => Ok(()),
+                }
+            });
+            match res {
+                Ok(()) => {
+                    info!("Read {} bytes from {}", received, uri2);
+                    Ok(Loop::Break(mem::replace(&mut *body2.borrow_mut(), vec![])))
+                }
+                Err(e) => {
+                    if let ErrorKind::BadHTTPStatus(_) = *e.kind() {
+                        return Err(e)
+                    }
+                    if resumes >= MAX_DOWNLOAD_RESUMES {
+                        return Err(e)
+                    }
+                    warn!("Download of {} interrupted after {} bytes, resuming: {}", uri2, received, e);
+                    Ok(Loop::Continue(resumes + 1))
+                }
+            }
+        })
+    }))
+}
+
 pub trait OsStrExt {
     fn starts_with(&self, s: &str) -> bool;
     fn split_prefix(&self, s: &str) -> Option<OsString>;
@@ -292,10 +440,174 @@ impl<'a> Hasher for HashToDigest<'a> {
     }
 }
 
+/// Environment variables that a scrubbed compiler environment always keeps,
+/// because compilers need them to run or they affect the compiler's output.
+/// Names ending in `*` match any variable with that prefix.
+const COMPILER_ENV_VARS: &[&str] = &[
+    // Finding the compiler and its libraries, and a place for temporary files.
+    "PATH", "LD_LIBRARY_PATH", "DYLD_*", "TMPDIR", "TMP", "TEMP", "SYSTEMROOT",
+    // Where rustup proxies, xcrun and the like look for their settings.
+    "HOME", "USERPROFILE",
+    // Header and library search paths.
+    "CPATH", "C_INCLUDE_PATH", "CPLUS_INCLUDE_PATH", "OBJC_INCLUDE_PATH", "LIBRARY_PATH",
+    "COMPILER_PATH", "GCC_EXEC_PREFIX", "INCLUDE", "LIB", "LIBPATH", "CL", "_CL_",
+    // Part of the C/C++ cache key.
+    "MACOSX_DEPLOYMENT_TARGET", "IPHONEOS_DEPLOYMENT_TARGET", "SOURCE_DATE_EPOCH",
+    // Cargo-provided variables are part of the Rust cache key.
+    "CARGO_*", "RUSTC_*", "RUSTFLAGS", "RUSTUP_*",
+    // Per-compile sccache settings.
+    "SCCACHE_*",
+];
+
+fn env_var_matches(name: &OsStr, pattern: &str) -> bool {
+    let name = name.to_string_lossy();
+    // Variable names are case-insensitive on Windows.
+    let (name, pattern) = if cfg!(windows) {
+        (name.to_uppercase(), pattern.to_uppercase())
+    } else {
+        (name.into_owned(), pattern.to_owned())
+    };
+    if pattern.ends_with('*') {
+        name.starts_with(&pattern[..pattern.len() - 1])
+    } else {
+        name == pattern
+    }
+}
+
+/// Whether `path` matches the glob `pattern`, in which `?` matches any
+/// character but `/`, `*` matches any run of those, and `**` matches any run
+/// of characters including `/`, so `**/secret/*.c` matches `.c` files
+/// directly in any directory named `secret`.
+pub fn glob_matches(pattern: &str, path: &str) -> bool {
+    let p = pattern.chars().collect::<Vec<_>>();
+    let s = path.chars().collect::<Vec<_>>();
+    let (mut pi, mut si) = (0, 0);
+    // Where to carry on if the rest of the pattern doesn't match: the pattern
+    // index after the last `*`, and after the last `**`, along with how far
+    // into `path` each has matched so far.
+    let mut star: Option<(usize, usize)> = None;
+    let mut double_star: Option<(usize, usize, bool)> = None;
+    loop {
+        if pi < p.len() && p[pi] == '*' {
+            if p.get(pi + 1) == Some(&'*') {
+                // `**/` also matches no directories at all.
+                let slash = p.get(pi + 2) == Some(&'/');
+                pi += if slash { 3 } else { 2 };
+                double_star = Some((pi, si, slash));
+                // A `*` before a `**` never needs to match more, the `**`
+                // can match it instead.
+                star = None;
+            } else {
+                pi += 1;
+                star = Some((pi, si));
+            }
+            continue
+        }
+        if pi == p.len() && si == s.len() {
+            return true
+        }
+        if pi < p.len() && si < s.len() && (p[pi] == s[si] || (p[pi] == '?' && s[si] != '/')) {
+            pi += 1;
+            si += 1;
+            continue
+        }
+        // Have the last `*` match one more character, unless that leaves its
+        // path component, and otherwise the last `**`.
+        if let Some((sp, ss)) = star {
+            if ss < s.len() && s[ss] != '/' {
+                star = Some((sp, ss + 1));
+                pi = sp;
+                si = ss + 1;
+                continue
+            }
+            star = None;
+        }
+        if let Some((dp, ds, slash)) = double_star {
+            let next = if slash {
+                // `**/` matches whole directories
+                s[ds..].iter().position(|&c| c == '/').map(|i| ds + i + 1)
+            } else if ds < s.len() {
+                Some(ds + 1)
+            } else {
+                None
+            };
+            if let Some(next) = next {
+                double_star = Some((dp, next, slash));
+                pi = dp;
+                si = next;
+                continue
+            }
+        }
+        return false
+    }
+}
+
+/// Remove all variables from `env_vars` except those that compilers need and
+/// those in `allowlist`, where names ending in `*` match by prefix.
+pub fn scrub_compiler_env(env_vars: Vec<(OsString, OsString)>, allowlist: &[String])
+                          -> Vec<(OsString, OsString)> {
+    env_vars.into_iter()
+        .filter(|&(ref k, _)| {
+            COMPILER_ENV_VARS.iter().any(|p| env_var_matches(k, p)) ||
+                allowlist.iter().any(|p| env_var_matches(k, p))
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use std::ffi::{OsStr, OsString};
     use super::OsStrExt;
+    use super::{glob_matches, scrub_compiler_env};
+
+    #[test]
+    fn test_scrub_compiler_env() {
+        let env = vec![("PATH", "/usr/bin"),
+                       ("HOME", "/home/me"),
+                       ("LANG", "de_DE.UTF-8"),
+                       ("CARGO_PKG_NAME", "foo"),
+                       ("DISTCC_HOSTS", "localhost"),
+                       ("MY_FLAG", "1")];
+        let env = env.into_iter().map(|(k, v)| (OsString::from(k), OsString::from(v))).collect();
+        let scrubbed = scrub_compiler_env(env, &["MY_FLAG".to_owned(), "DISTCC_*".to_owned()]);
+        let names = scrubbed.iter().map(|&(ref k, _)| k.to_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["PATH", "HOME", "CARGO_PKG_NAME", "DISTCC_HOSTS", "MY_FLAG"]);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_scrub_compiler_env_case_insensitive() {
+        let env = vec![("Path", "C:\\Windows"), ("SystemRoot", "C:\\Windows"), ("my_flag", "1"), ("Other", "1")];
+        let env = env.into_iter().map(|(k, v)| (OsString::from(k), OsString::from(v))).collect();
+        let scrubbed = scrub_compiler_env(env, &["MY_FLAG".to_owned()]);
+        let names = scrubbed.iter().map(|&(ref k, _)| k.to_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["Path", "SystemRoot", "my_flag"]);
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("/src/secret.c", "/src/secret.c"));
+        assert!(!glob_matches("/src/secret.c", "/src/secret.cpp"));
+        assert!(glob_matches("/src/*.c", "/src/foo.c"));
+        assert!(!glob_matches("/src/*.c", "/src/a/foo.c"));
+        assert!(glob_matches("/src/fo?.c", "/src/foo.c"));
+        assert!(!glob_matches("/src/?", "/src//"));
+        assert!(glob_matches("**/secret/*.c", "/src/a/secret/foo.c"));
+        assert!(!glob_matches("**/secret/*.c", "/src/a/secret/b/foo.c"));
+        assert!(glob_matches("/src/**/*.c", "/src/foo.c"));
+        assert!(glob_matches("/src/**/*.c", "/src/a/b/foo.c"));
+        assert!(!glob_matches("/src/**/*.c", "/other/foo.c"));
+        assert!(glob_matches("/src/secret/**", "/src/secret/a/foo.c"));
+        assert!(glob_matches("**.c", "/src/a/foo.c"));
+        assert!(glob_matches("/src/*o*o.c", "/src/foxfoo.c"));
+        assert!(!glob_matches("/src/*o/*.c", "/src/a/b/foo.c"));
+        assert!(glob_matches("**/a/**/b/*.c", "/x/a/y/a/z/b/foo.c"));
+        assert!(!glob_matches("**/a/**/b/*.c", "/x/a/y/b/z/foo.c"));
+        // Patterns that backtrack a lot still match in reasonable time
+        let long = format!("/{}b", "a".repeat(200));
+        assert!(glob_matches(&format!("{}b", "*a".repeat(20)), &long[1..]));
+        assert!(!glob_matches(&format!("**/{}c", "*a".repeat(20)), &long));
+    }
 
     #[test]
     fn simple_starts_with() {
@@ -54,9 +54,9 @@ struct Compiler {
     pub env_vars: Vec<(OsString, OsString)>,
 }
 
-// Test GCC + clang on non-OS X platforms.
+// Test GCC + clang on non-OS X platforms, and nvcc where the CUDA toolkit is installed.
 #[cfg(all(unix, not(target_os="macos")))]
-const COMPILERS: &'static [&'static str] = &["gcc", "clang"];
+const COMPILERS: &'static [&'static str] = &["gcc", "clang", "nvcc"];
 
 // OS X ships a `gcc` that's just a clang wrapper, so only test clang there.
 #[cfg(target_os="macos")]
@@ -90,7 +90,7 @@ macro_rules! vec_from {
 
 fn compile_cmdline<T: AsRef<OsStr>>(compiler: &str, exe: T, input: &str, output: &str) -> Vec<OsString> {
     match compiler {
-        "gcc" | "clang" => vec_from!(OsString, exe.as_ref(), "-c", input, "-o", output),
+        "gcc" | "clang" | "nvcc" => vec_from!(OsString, exe.as_ref(), "-c", input, "-o", output),
         "cl.exe" => vec_from!(OsString, exe, "-c", input, format!("-Fo{}", output)),
         _ => panic!("Unsupported compiler: {}", compiler),
     }
@@ -161,6 +161,7 @@ fn test_basic_compile(compiler: Compiler, tempdir: &Path) {
         assert_eq!(2, info.stats.requests_executed);
         assert_eq!(1, info.stats.cache_hits);
         assert_eq!(1, info.stats.cache_misses);
+        assert_eq!(1, info.stats.compilers.values().map(|c| c.hits).sum::<u64>());
     });
 }
 
@@ -269,6 +270,124 @@ int main(int argc, char** argv) {
     });
 }
 
+fn test_nvcc_cuda_compile(compiler: Compiler, tempdir: &Path) {
+    let Compiler { name, exe, env_vars } = compiler;
+    trace!("test nvcc compiling device code");
+    zero_stats();
+    const SRC: &str = "kernel.cu";
+    write_source(&tempdir, SRC, "__global__ void add(int* a, int b) {
+  a[threadIdx.x] += b;
+}
+");
+    let out_file = tempdir.join(OUTPUT);
+    let dep_file = tempdir.join("kernel.d");
+    let mut args = compile_cmdline(name, &exe, SRC, OUTPUT);
+    args.extend(vec_from!(OsString, "-gencode", "arch=compute_52,code=sm_52", "-MD", "-MF", "kernel.d"));
+    for &(hits, misses) in &[(0, 1), (1, 1)] {
+        trace!("compile kernel.cu");
+        drop(fs::remove_file(&out_file));
+        drop(fs::remove_file(&dep_file));
+        Command::main_binary().unwrap()
+            .args(&args)
+            .current_dir(tempdir)
+            .envs(env_vars.clone())
+            .assert()
+            .success();
+        assert_eq!(true, fs::metadata(&out_file).and_then(|m| Ok(m.len() > 0)).unwrap());
+        assert_eq!(true, fs::metadata(&dep_file).and_then(|m| Ok(m.len() > 0)).unwrap());
+        get_stats(move |info| {
+            assert_eq!(hits, info.stats.cache_hits);
+            assert_eq!(misses, info.stats.cache_misses);
+            assert_eq!(hits, info.stats.compilers["nvcc"].hits);
+        });
+    }
+}
+
+fn test_split_dwarf(compiler: Compiler, tempdir: &Path) {
+    let Compiler { name, exe, env_vars } = compiler;
+    trace!("test -gsplit-dwarf");
+    zero_stats();
+    const SRC: &str = "split.c";
+    write_source(&tempdir, SRC, "int split(int x) {\n  return x + 1;\n}\n");
+    let out_file = tempdir.join("split.o");
+    let dwo_file = tempdir.join("split.dwo");
+    let mut args = compile_cmdline(name, &exe, SRC, "split.o");
+    args.extend(vec_from!(OsString, "-g", "-gsplit-dwarf"));
+    let mut dwo = vec![];
+    for &(hits, misses) in &[(0, 1), (1, 1)] {
+        trace!("compile split.c");
+        drop(fs::remove_file(&out_file));
+        drop(fs::remove_file(&dwo_file));
+        Command::main_binary().unwrap()
+            .args(&args)
+            .current_dir(tempdir)
+            .envs(env_vars.clone())
+            .assert()
+            .success();
+        assert_eq!(true, fs::metadata(&out_file).and_then(|m| Ok(m.len() > 0)).unwrap());
+        let mut contents = vec![];
+        File::open(&dwo_file).and_then(|mut f| f.read_to_end(&mut contents)).expect("Missing .dwo file");
+        assert!(!contents.is_empty());
+        if hits == 0 {
+            dwo = contents;
+        } else {
+            assert_eq!(dwo, contents);
+        }
+        get_stats(move |info| {
+            assert_eq!(hits, info.stats.cache_hits);
+            assert_eq!(misses, info.stats.cache_misses);
+        });
+    }
+}
+
+#[cfg(unix)]
+fn test_gcc_symlinked_inputs(compiler: Compiler, tempdir: &Path) {
+    use std::os::unix::fs::symlink;
+
+    let Compiler { name, exe, env_vars } = compiler;
+    trace!("test symlinked source and header files");
+    zero_stats();
+    write_source(&tempdir, "real_source.c", "#include \"header.h\"\nint main() { return VALUE; }\n");
+    write_source(&tempdir, "real_header.h", "#define VALUE 0\n");
+    write_source(&tempdir, "copied_header.h", "#define VALUE 0\n");
+    symlink(tempdir.join("real_source.c"), tempdir.join("link.c")).unwrap();
+    symlink(tempdir.join("real_header.h"), tempdir.join("header.h")).unwrap();
+    let args = compile_cmdline(name, &exe, "link.c", OUTPUT);
+    let compile = || {
+        Command::main_binary().unwrap()
+            .args(&args)
+            .current_dir(tempdir)
+            .envs(env_vars.clone())
+            .assert()
+            .success();
+    };
+    trace!("compile link.c (1)");
+    compile();
+    trace!("compile link.c (2)");
+    compile();
+    get_stats(|info| {
+        assert_eq!(1, info.stats.cache_hits);
+        assert_eq!(1, info.stats.cache_misses);
+    });
+    // Pointing the header link at a file with the same contents doesn't change the key.
+    fs::remove_file(tempdir.join("header.h")).unwrap();
+    symlink(tempdir.join("copied_header.h"), tempdir.join("header.h")).unwrap();
+    trace!("compile link.c (3)");
+    compile();
+    get_stats(|info| {
+        assert_eq!(2, info.stats.cache_hits);
+        assert_eq!(1, info.stats.cache_misses);
+    });
+    // Changing the contents behind the link does.
+    write_source(&tempdir, "copied_header.h", "#define VALUE 1\n");
+    trace!("compile link.c (4)");
+    compile();
+    get_stats(|info| {
+        assert_eq!(2, info.stats.cache_hits);
+        assert_eq!(2, info.stats.cache_misses);
+    });
+}
+
 fn run_sccache_command_tests(compiler: Compiler, tempdir: &Path) {
     test_basic_compile(compiler.clone(), tempdir);
     if compiler.name == "cl.exe" {
@@ -277,6 +396,15 @@ fn run_sccache_command_tests(compiler: Compiler, tempdir: &Path) {
     if compiler.name == "gcc" {
         test_gcc_mp_werror(compiler.clone(), tempdir);
         test_gcc_fprofile_generate_source_changes(compiler.clone(), tempdir);
+        #[cfg(unix)]
+        test_gcc_symlinked_inputs(compiler.clone(), tempdir);
+    }
+    // Split DWARF is an ELF thing, clang ignores it on macOS.
+    if cfg!(target_os = "linux") && (compiler.name == "gcc" || compiler.name == "clang") {
+        test_split_dwarf(compiler.clone(), tempdir);
+    }
+    if compiler.name == "nvcc" {
+        test_nvcc_cuda_compile(compiler.clone(), tempdir);
     }
 }
 